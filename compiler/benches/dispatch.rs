@@ -0,0 +1,155 @@
+//! Programs whose time goes to running instructions, rather than to the builtins they call.
+//! Run with `cargo bench -p compiler --bench dispatch`.
+
+use compiler::{compiler::CompilerOptions, init, module::NoModules, register::Backend, run_code_with_options, vm::VmOptions};
+use criterion::{criterion_group, Criterion};
+use futures::executor::block_on;
+
+const ARITHMETIC: &str = "
+var total = 0;
+for (var i = 0; i < 100000; i = i + 1) {
+  var x = i % 7;
+  total = total + x * x - i / 3;
+}
+";
+
+// ARITHMETIC in a try block that nothing throws from, which should take as long
+const ARITHMETIC_IN_TRY: &str = "
+var total = 0;
+try {
+  for (var i = 0; i < 100000; i = i + 1) {
+    var x = i % 7;
+    total = total + x * x - i / 3;
+  }
+} catch (error) {
+  print error;
+}
+";
+
+const LOCALS: &str = "
+function sum(n) {
+  var a = 0;
+  var b = 1;
+  for (var i = 0; i < n; i = i + 1) {
+    a = a + b;
+    b = a - b;
+  }
+  return a;
+}
+sum(100000);
+";
+
+const CALLS: &str = "
+function fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+fib(20);
+";
+
+const METHODS: &str = "
+class Counter {
+  init() { this.count = 0; }
+  increment(by) { this.count = this.count + by; return this; }
+}
+var counter = Counter();
+for (var i = 0; i < 20000; i = i + 1) {
+  counter.increment(1).increment(2);
+}
+";
+
+const CALLBACKS: &str = "
+var numbers = [];
+for (var i = 0; i < 2000; i = i + 1) {
+  numbers.push(i);
+}
+for (var round = 0; round < 10; round = round + 1) {
+  numbers.map(function(n) { return n * 2 + 1; }).filter(function(n) { return n % 3 == 0; }).reduce(function(a, b) { return a + b; }, 0);
+}
+";
+
+const GLOBALS: &str = "
+var a = 1;
+var b = 2;
+var c = 0;
+for (var i = 0; i < 100000; i = i + 1) {
+  c = a + b + c;
+}
+";
+
+const PROPERTIES: &str = "
+class Vec2 {
+  init(x, y) { this.x = x; this.y = y; }
+  add(other) { return Vec2(this.x + other.x, this.y + other.y); }
+  dot(other) { return this.x * other.x + this.y * other.y; }
+}
+var step = Vec2(1, 2);
+var total = 0;
+var position = Vec2(0, 0);
+for (var i = 0; i < 20000; i = i + 1) {
+  position = position.add(step);
+  total = total + position.dot(step);
+}
+";
+
+async fn read(_: String) -> String {
+    String::new()
+}
+
+fn run_on(code: &str, backend: Backend) {
+    let options = CompilerOptions {
+        backend,
+        ..Default::default()
+    };
+    block_on(run_code_with_options(code, NoModules, read, &options, |_| {}))
+        .result
+        .unwrap();
+}
+
+fn run(code: &str) {
+    run_on(code, Backend::Stack);
+}
+
+fn run_caching(code: &str, inline_caches: bool) {
+    let setup = |vm: &mut compiler::vm::Vm<_, _>| {
+        vm.configure(VmOptions {
+            inline_caches,
+            ..Default::default()
+        })
+    };
+    block_on(run_code_with_options(code, NoModules, read, &CompilerOptions::default(), setup))
+        .result
+        .unwrap();
+}
+
+fn arithmetic(c: &mut Criterion) {
+    c.bench_function("arithmetic", |b| b.iter(|| run(ARITHMETIC)));
+    c.bench_function("arithmetic in try", |b| b.iter(|| run(ARITHMETIC_IN_TRY)));
+    c.bench_function("locals", |b| b.iter(|| run(LOCALS)));
+    c.bench_function("locals on registers", |b| b.iter(|| run_on(LOCALS, Backend::Register)));
+}
+
+fn calls(c: &mut Criterion) {
+    c.bench_function("calls", |b| b.iter(|| run(CALLS)));
+    c.bench_function("methods", |b| b.iter(|| run(METHODS)));
+    c.bench_function("callbacks", |b| b.iter(|| run(CALLBACKS)));
+}
+
+/// Lookups of globals, fields and methods, with and without `VmOptions::inline_caches`
+fn inline_caches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("inline caches");
+    for (name, code) in [("globals", GLOBALS), ("properties", PROPERTIES)] {
+        group.bench_function(format!("{name} with caches"), |b| b.iter(|| run_caching(code, true)));
+        group.bench_function(format!("{name} without caches"), |b| b.iter(|| run_caching(code, false)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, arithmetic, calls, inline_caches);
+
+// Like `criterion_main!`, but the compiler has to be initialized first
+fn main() {
+    init(|_| {}, |_| {});
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
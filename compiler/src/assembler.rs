@@ -0,0 +1,456 @@
+//! Assembler of the textual bytecode that the disassembler writes, see `debug`, so that bytecode can be written by hand,
+//! like to test the VM on code the compiler doesn't write, or disassembled, edited and run again.
+//!
+//! Each line is an instruction, like `0004    2 JumpIfFalse 7 -> 14`. The offset and the line in the program before
+//! the name are optional: with one number it is the line, and an offset has to be where the instruction is. Without a
+//! line, an instruction is on the line of the one before it. Jumps go to the offset after `->`, and the distance
+//! before it is optional. Constants are `Idx` with their index and then the value, or only the value, which is added
+//! to the constants of the chunk unless it has it. A `== name ==` header, blank lines and comments after `;` are
+//! skipped.
+
+use crate::{
+    chunk::Chunk,
+    common::Opcode,
+    debug::constant_literal,
+    interner::Interner,
+    superinstruction::is_fusable_operator,
+    value::{Enum, EnumMember, RecordType, Value},
+    verify::verify_chunk,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{cell::RefCell, rc::Rc};
+
+/// Read the textual bytecode into a chunk, interning its strings in `interner`. The chunk is checked with
+/// `verify::verify_chunk`.
+pub fn assemble(text: &str, interner: &mut Interner) -> Result<Chunk> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    let mut line = 1;
+    for (index, text) in text.lines().enumerate() {
+        let parsed = parse_line(text, offset, &mut line, interner).map_err(|error| anyhow!("Line {}: {error}", index + 1))?;
+        match parsed {
+            Some(instruction) => {
+                offset += instruction.opcode.size();
+                instructions.push((index + 1, instruction));
+            }
+            None if text.trim_start().starts_with("==") && !instructions.is_empty() => {
+                bail!("Line {}: Only one chunk can be assembled at a time", index + 1)
+            }
+            None => {}
+        }
+    }
+
+    let mut chunk = Chunk::default();
+    add_indexed_constants(&mut chunk, &instructions, interner)?;
+    for (number, instruction) in &instructions {
+        let bytes = instruction.encode(&mut chunk).map_err(|error| anyhow!("Line {number}: {error}"))?;
+        for byte in bytes {
+            chunk.write_byte(byte, instruction.line);
+        }
+    }
+    verify_chunk(&chunk)?;
+    Ok(chunk)
+}
+
+/// Constants with an `Idx` go at their index, and the indices that no instruction gives are nil
+fn add_indexed_constants(chunk: &mut Chunk, instructions: &[(usize, Instruction)], interner: &Interner) -> Result<()> {
+    let mut constants: Vec<Option<(&Value, String)>> = Vec::new();
+    for (number, instruction) in instructions {
+        for operand in &instruction.operands {
+            let Operand::Constant(Some(index), value) = operand else {
+                continue;
+            };
+            if *index >= constants.len() {
+                constants.resize(index + 1, None);
+            }
+            let literal = constant_literal(value, interner);
+            match &constants[*index] {
+                Some((_, known)) if *known != literal => bail!("Line {number}: Constant {index} is {known} elsewhere, not {literal}"),
+                _ => constants[*index] = Some((value, literal)),
+            }
+        }
+    }
+    for constant in constants {
+        chunk.push_constant(constant.map_or(Value::Nil, |(value, _)| value.clone()));
+    }
+    Ok(())
+}
+
+struct Instruction {
+    opcode: Opcode,
+    line: usize,
+    offset: usize,
+    operands: Vec<Operand>,
+}
+
+enum Operand {
+    Byte(u8),
+    Target(usize),                  // Offset the instruction jumps to
+    Constant(Option<usize>, Value), // Index, if it is given
+    Operator(Opcode),               // Operator of a superinstruction
+}
+
+/// The instruction on the line, or None if the line has none
+fn parse_line(text: &str, offset: usize, line: &mut usize, interner: &mut Interner) -> Result<Option<Instruction>> {
+    let mut cursor = Cursor { rest: text };
+    if cursor.at_end() || cursor.rest.starts_with("==") {
+        return Ok(None);
+    }
+
+    let mut numbers = Vec::new();
+    let name = loop {
+        let word = cursor.word()?;
+        match word.parse::<usize>() {
+            Ok(number) => numbers.push(number),
+            Err(_) => break word,
+        }
+    };
+    match numbers[..] {
+        [] => {}
+        [given] => *line = given,
+        [given, given_line] if given == offset => *line = given_line,
+        [given, _] => bail!("Instruction is at offset {offset}, not {given}"),
+        _ => bail!("Expected an instruction after {}", numbers[1]),
+    }
+    let opcode = opcode_named(name).ok_or_else(|| anyhow!("Unknown instruction '{name}'"))?;
+
+    let mut operands = Vec::new();
+    match opcode.short() {
+        Opcode::Constant
+        | Opcode::DefineGlobal
+        | Opcode::GetGlobal
+        | Opcode::SetGlobal
+        | Opcode::GetProperty
+        | Opcode::SetProperty
+        | Opcode::Class
+        | Opcode::Method
+        | Opcode::Getter
+        | Opcode::Setter
+        | Opcode::StaticMember => operands.push(cursor.constant(interner)?),
+        Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfNil | Opcode::JumpIfNotNil | Opcode::Loop => operands.push(cursor.target()?),
+        Opcode::JumpIfArgPassed | Opcode::IterNext => {
+            operands.push(cursor.byte()?);
+            operands.push(cursor.target()?);
+        }
+        Opcode::PushHandler => {
+            cursor.keyword("catch")?;
+            operands.push(cursor.target()?);
+            cursor.keyword("finally")?;
+            operands.push(cursor.target()?);
+        }
+        Opcode::CallNamed => {
+            operands.push(cursor.byte()?);
+            operands.push(cursor.constant(interner)?);
+        }
+        Opcode::GetLocal
+        | Opcode::SetLocal
+        | Opcode::Call
+        | Opcode::TailCall
+        | Opcode::BuildMap
+        | Opcode::BuildArray
+        | Opcode::CallSpread
+        | Opcode::BuildTuple
+        | Opcode::GetLocalPlain => operands.push(cursor.byte()?),
+        Opcode::ConstantOperation => {
+            operands.push(cursor.constant(interner)?);
+            operands.push(cursor.operator()?);
+        }
+        Opcode::LocalConstantOperation => {
+            operands.push(cursor.byte()?);
+            operands.push(cursor.constant(interner)?);
+            operands.push(cursor.operator()?);
+        }
+        Opcode::LocalsOperation => {
+            operands.push(cursor.byte()?);
+            operands.push(cursor.byte()?);
+            operands.push(cursor.operator()?);
+        }
+        _ => {}
+    }
+    if !cursor.at_end() {
+        bail!("Unexpected '{}' after {opcode}", cursor.rest.trim());
+    }
+
+    Ok(Some(Instruction {
+        opcode,
+        line: *line,
+        offset,
+        operands,
+    }))
+}
+
+fn opcode_named(name: &str) -> Option<Opcode> {
+    (0..=u8::MAX)
+        .filter_map(|byte| Opcode::try_from(byte).ok())
+        .find(|opcode| opcode.to_string() == name)
+}
+
+impl Instruction {
+    /// The bytes of the instruction, adding the constants it has without an index to the chunk
+    fn encode(&self, chunk: &mut Chunk) -> Result<Vec<u8>> {
+        let opcode = self.opcode;
+        let width = if opcode.is_long() { 3 } else { 2 };
+        let mut bytes = vec![opcode as u8];
+        let mut jumps = opcode.jump_operands().iter();
+        for operand in &self.operands {
+            match operand {
+                Operand::Byte(byte) => bytes.push(*byte),
+                Operand::Target(target) => {
+                    let end = self.offset + jumps.next().expect("Targets are of jumps") + width;
+                    let distance = match opcode.short() {
+                        Opcode::Loop => end.checked_sub(*target).context("Loop goes backward, to before where it ends")?,
+                        _ => target
+                            .checked_sub(end)
+                            .with_context(|| format!("{opcode} goes forward, to after where it ends, and only loops go back"))?,
+                    };
+                    bytes.extend(encode_number(distance, width).with_context(|| format!("Jump is too far for {opcode}"))?);
+                }
+                Operand::Constant(index, value) => {
+                    let index = index.unwrap_or_else(|| chunk.add_constant(value.clone()));
+                    // Superinstructions have a short constant index, even though they aren't long variants
+                    let width = if opcode.is_long() { 3 } else { 1 };
+                    match encode_number(index, width) {
+                        Some(index) => bytes.extend(index),
+                        None if opcode.long().is_some() => {
+                            bail!("Constant {index} doesn't fit in {opcode}, use {}", opcode.long().unwrap())
+                        }
+                        None => bail!("Constant {index} doesn't fit in {opcode}"),
+                    }
+                }
+                Operand::Operator(operator) => bytes.push(*operator as u8),
+            }
+        }
+
+        // Superinstructions keep the instructions they replace, and reading a local variable pushes nil first
+        if !matches!(
+            opcode,
+            Opcode::GetLocalPlain | Opcode::LocalConstantOperation | Opcode::LocalsOperation
+        ) {
+            return Ok(bytes);
+        }
+        let nil = chunk.add_constant(Value::Nil);
+        let nil = u8::try_from(nil).map_err(|_| anyhow!("Constant nil is {nil}, which doesn't fit in {opcode}"))?;
+        let local = |slot: u8| [nil, Opcode::GetLocal as u8, slot];
+        let constant = Opcode::Constant as u8;
+        let bytes = match opcode {
+            Opcode::GetLocalPlain => [&bytes[..1], &local(bytes[1])].concat(),
+            Opcode::LocalConstantOperation => [&bytes[..1], &local(bytes[1]), &[constant], &bytes[2..]].concat(),
+            _ => [&bytes[..1], &local(bytes[1]), &[constant], &local(bytes[2]), &bytes[3..]].concat(),
+        };
+        debug_assert_eq!(bytes.len(), opcode.size());
+        Ok(bytes)
+    }
+}
+
+/// The number in `width` bytes, most significant first, or None if it doesn't fit
+fn encode_number(number: usize, width: usize) -> Option<Vec<u8>> {
+    if number >> (8 * width) != 0 {
+        return None;
+    }
+    Some((0..width).rev().map(|byte| (number >> (8 * byte)) as u8).collect())
+}
+
+/// What is left of a line to parse
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl Cursor<'_> {
+    fn skip_space(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_space();
+        self.rest.is_empty() || self.rest.starts_with(';')
+    }
+
+    /// The text up to the next space, or to one of the characters that end a value
+    fn word(&mut self) -> Result<&str> {
+        if self.at_end() {
+            bail!("Line ends early");
+        }
+        let end = self
+            .rest
+            .find(|c: char| c.is_whitespace() || matches!(c, ',' | ']' | ';'))
+            .unwrap_or(self.rest.len())
+            .max(1);
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(word)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<()> {
+        match self.word()? {
+            word if word == keyword => Ok(()),
+            word => bail!("Expected '{keyword}', found '{word}'"),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T> {
+        let word = self.word()?;
+        word.parse().map_err(|_| anyhow!("Expected a number, found '{word}'"))
+    }
+
+    fn byte(&mut self) -> Result<Operand> {
+        Ok(Operand::Byte(self.number()?))
+    }
+
+    /// Where a jump goes, after its distance, which is optional
+    fn target(&mut self) -> Result<Operand> {
+        if self.at_end() {
+            bail!("Expected '->' and the offset the jump goes to");
+        }
+        if self.word()? != "->" {
+            self.keyword("->")?;
+        }
+        Ok(Operand::Target(self.number()?))
+    }
+
+    fn operator(&mut self) -> Result<Operand> {
+        let name = self.word()?;
+        match opcode_named(name) {
+            Some(operator) if is_fusable_operator(operator as u8) => Ok(Operand::Operator(operator)),
+            _ => bail!("Expected an operator, found '{name}'"),
+        }
+    }
+
+    fn constant(&mut self, interner: &mut Interner) -> Result<Operand> {
+        self.skip_space();
+        let index = match self.rest.strip_prefix("Idx ") {
+            Some(rest) => {
+                self.rest = rest;
+                Some(self.number()?)
+            }
+            None => None,
+        };
+        Ok(Operand::Constant(index, self.value(interner)?))
+    }
+
+    /// A constant, written the way `debug::constant_literal` writes it
+    fn value(&mut self, interner: &mut Interner) -> Result<Value> {
+        self.skip_space();
+        if self.rest.starts_with('"') {
+            return Ok(Value::Str(interner.intern(&self.string()?)));
+        }
+        if let Some(rest) = self.rest.strip_prefix('[') {
+            self.rest = rest;
+            let mut elements = Vec::new();
+            loop {
+                self.skip_space();
+                if let Some(rest) = self.rest.strip_prefix(']') {
+                    self.rest = rest;
+                    break;
+                }
+                if !elements.is_empty() {
+                    self.rest = self.rest.strip_prefix(',').context("Expected ',' between elements of an array")?;
+                }
+                elements.push(self.value(interner)?);
+            }
+            return Ok(Value::Array(Rc::new(RefCell::new(elements))));
+        }
+        if let Some(rest) = self.rest.strip_prefix('<') {
+            let (inside, rest) = rest.split_once('>').context("Expected '>'")?;
+            self.rest = rest;
+            return angled(inside, interner);
+        }
+
+        let value = match self.word()? {
+            "nil" => Value::Nil,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "Identifier:" => Value::Identifier(interner.intern(self.word()?)),
+            #[cfg(feature = "bigint")]
+            word if word.ends_with('n') && word.len() > 1 => {
+                let digits = &word[..word.len() - 1];
+                Value::BigInt(Rc::new(
+                    crate::bigint::BigInt::parse(digits).with_context(|| format!("Invalid big integer '{word}'"))?,
+                ))
+            }
+            word => match (word.parse::<i64>(), word.parse::<f64>()) {
+                (Ok(int), _) => Value::Int(int),
+                (_, Ok(number)) => Value::Number(number),
+                _ => bail!("Expected a constant, found '{word}'"),
+            },
+        };
+        Ok(value)
+    }
+
+    /// A string in double quotes, with the escapes of Rust's `Debug` of strings
+    fn string(&mut self) -> Result<String> {
+        let mut chars = self.rest.char_indices().skip(1);
+        let mut string = String::new();
+        while let Some((at, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[at + 1..];
+                    return Ok(string);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some(c @ ('\\' | '"' | '\'')) => c,
+                        Some('u') => {
+                            let hex: String = chars
+                                .by_ref()
+                                .map(|(_, c)| c)
+                                .skip_while(|&c| c == '{')
+                                .take_while(|&c| c != '}')
+                                .collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .with_context(|| format!("Invalid escape '\\u{{{hex}}}'"))?
+                        }
+                        other => bail!("Invalid escape '\\{}'", other.map(String::from).unwrap_or_default()),
+                    };
+                    string.push(escaped);
+                }
+                c => string.push(c),
+            }
+        }
+        bail!("Unterminated string")
+    }
+}
+
+/// A constant in angle brackets, which is a function, a module, an enum or a record type
+fn angled(inside: &str, interner: &mut Interner) -> Result<Value> {
+    let mut words = inside.split_whitespace();
+    let kind = words.next().unwrap_or_default();
+    let value = match kind {
+        "Function" | "Module" => {
+            let index = words.next().and_then(|index| index.parse().ok()).context("Expected an index")?;
+            if words.next().is_some() {
+                bail!("Unexpected text in '<{inside}>'");
+            }
+            match kind {
+                "Function" => Value::Function(index),
+                _ => Value::Module(index),
+            }
+        }
+        "Enum" => {
+            let name = interner.intern(words.next().context("Expected the name of the enum")?);
+            let members = words.enumerate().map(|(ordinal, member)| {
+                Value::EnumMember(Rc::new(EnumMember {
+                    enum_name: name,
+                    name: interner.intern(member),
+                    ordinal,
+                }))
+            });
+            Value::Enum(Rc::new(Enum {
+                name,
+                members: members.collect(),
+            }))
+        }
+        "Record" => {
+            let name = interner.intern(words.next().context("Expected the name of the record type")?);
+            let fields = words.map(|field| interner.intern(field)).collect();
+            Value::RecordType(Rc::new(RecordType { name, fields }))
+        }
+        _ => bail!("Unknown constant '<{inside}>'"),
+    };
+    Ok(value)
+}
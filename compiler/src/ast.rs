@@ -0,0 +1,732 @@
+//! Syntax tree of a program, for tools that need more than its tokens, like editors and optimizations.
+//!
+//! The compiler builds the tree as it parses the program, with `TreeBuilder`, when it compiles it for tools with
+//! `compiler::analyze`. `parse_to_ast` returns it, and it can be dumped as JSON with `Program::to_json`.
+
+use crate::{
+    compiler::check,
+    diagnostic::CompileError,
+    json::write_string,
+    scanner::{Span, Token, TokenType},
+};
+
+/// Parse a program into its syntax tree, or fail with the errors it has if it doesn't compile
+pub fn parse_to_ast(source: &str) -> Result<Program, CompileError> {
+    check(source)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+}
+
+/// A name where it is declared or used as a property, with where it is in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub line: usize,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    /// `var name = value;`, or `var name[size];` for an array of that size
+    Var {
+        name: Name,
+        size: Option<Expr>,
+        value: Option<Expr>,
+        is_const: bool,
+    },
+    /// `var [a, b] = value;` or `var {key: name} = value;`
+    Destructure {
+        pattern: Pattern,
+        value: Expr,
+        is_const: bool,
+    },
+    Function(Function),
+    Class {
+        name: Name,
+        members: Vec<ClassMember>,
+    },
+    Enum {
+        name: Name,
+        members: Vec<Name>,
+    },
+    Record {
+        name: Name,
+        fields: Vec<Name>,
+    },
+    Import {
+        path: String,
+        name: Name,
+    },
+    Export(Box<Stmt>),
+    Test {
+        name: String,
+        body: Vec<Stmt>,
+    },
+    Block(Vec<Stmt>),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    ForIn {
+        variable: Name,
+        iterable: Expr,
+        body: Box<Stmt>,
+    },
+    /// The default case, if any, is after the others
+    Switch {
+        subject: Expr,
+        cases: Vec<SwitchCase>,
+        default: Option<Vec<Stmt>>,
+    },
+    Try {
+        body: Vec<Stmt>,
+        catch: Option<Catch>,
+        finally: Option<Vec<Stmt>>,
+    },
+    Return(Option<Expr>),
+    Throw(Expr),
+    Yield(Option<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Array(Vec<Name>),
+    Map(Vec<(Name, Name)>), // Keys, and the names of the variables they are put in
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchCase {
+    pub values: Vec<Expr>, // The case matches if the subject is equal to any of them
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catch {
+    pub variable: Option<Name>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClassMember {
+    Method { kind: MethodKind, function: Function },
+    StaticField { name: Name, value: Option<Expr> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodKind {
+    Method,
+    Getter,
+    Setter,
+    Static,
+}
+
+/// A function declaration, method, lambda or arrow function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: Option<Name>, // Lambdas and arrow functions have no name
+    pub params: Vec<Param>,
+    pub body: FunctionBody,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: Name,
+    pub default: Option<Expr>,
+    pub is_rest: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FunctionBody {
+    Block(Vec<Stmt>),
+    Expression(Box<Expr>), // Of an arrow function, like `(x) -> x * 2`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(String), // As written, since it may be an integer, a float or a big integer
+    String(String),
+    Bool(bool),
+    Nil,
+    This,
+    Variable(String),
+    Grouping(Box<Expr>),
+    Unary {
+        operator: UnaryOperator,
+        operand: Box<Expr>,
+    },
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// The target is a variable, a property or an index
+    Assign {
+        target: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Argument>,
+    },
+    /// `object.name`, or `object?.name` if it is optional, which skips the rest of the chain if the object is nil
+    Property {
+        object: Box<Expr>,
+        name: Name,
+        optional: bool,
+    },
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+        optional: bool,
+    },
+    Array(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>), // Keys written as names are strings
+    Tuple(Vec<Expr>),
+    Function(Box<Function>),
+    Spread(Box<Expr>), // `...value`, in arrays and arguments
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Argument {
+    pub name: Option<Name>, // Of a named argument, like `f(x: 1)`
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    In,
+    Union,
+    Intersection,
+    Range,
+    RangeInclusive,
+    And,
+    Or,
+    Coalesce,
+}
+
+impl UnaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOperator::Negate => "-",
+            UnaryOperator::Not => "!",
+        }
+    }
+}
+
+impl BinaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEqual => "<=",
+            BinaryOperator::In => "in",
+            BinaryOperator::Union => "|",
+            BinaryOperator::Intersection => "&",
+            BinaryOperator::Range => "..",
+            BinaryOperator::RangeInclusive => "..=",
+            BinaryOperator::And => "and",
+            BinaryOperator::Or => "or",
+            BinaryOperator::Coalesce => "??",
+        }
+    }
+
+    pub(crate) fn from_token(token_type: TokenType) -> Option<BinaryOperator> {
+        let operator = match token_type {
+            TokenType::Plus => BinaryOperator::Add,
+            TokenType::Minus => BinaryOperator::Subtract,
+            TokenType::Star => BinaryOperator::Multiply,
+            TokenType::Slash => BinaryOperator::Divide,
+            TokenType::Modulo => BinaryOperator::Modulo,
+            TokenType::EqualEqual => BinaryOperator::Equal,
+            TokenType::BangEqual => BinaryOperator::NotEqual,
+            TokenType::Greater => BinaryOperator::Greater,
+            TokenType::GreaterEqual => BinaryOperator::GreaterEqual,
+            TokenType::Less => BinaryOperator::Less,
+            TokenType::LessEqual => BinaryOperator::LessEqual,
+            TokenType::In => BinaryOperator::In,
+            TokenType::Pipe => BinaryOperator::Union,
+            TokenType::Ampersand => BinaryOperator::Intersection,
+            TokenType::DotDot => BinaryOperator::Range,
+            TokenType::DotDotEqual => BinaryOperator::RangeInclusive,
+            TokenType::And => BinaryOperator::And,
+            TokenType::Or => BinaryOperator::Or,
+            TokenType::QuestionQuestion => BinaryOperator::Coalesce,
+            _ => return None,
+        };
+        Some(operator)
+    }
+}
+
+/// Nodes of the tree that `Compiler` builds as it parses a program, which are not yet in the node they are part of.
+/// Each expression that is parsed pushes its node, and the node of the expression or statement it is part of takes it.
+#[derive(Default)]
+pub(crate) struct TreeBuilder {
+    exprs: Vec<Expr>,
+    stmts: Vec<Stmt>,
+}
+
+impl TreeBuilder {
+    /// The program, once all of its statements are parsed
+    pub(crate) fn program(self) -> Program {
+        Program { statements: self.stmts }
+    }
+
+    /// Number of expressions and statements that are not yet in a node, to take the ones parsed after with
+    /// `exprs_from` and `stmts_from`
+    pub(crate) fn marks(&self) -> (usize, usize) {
+        (self.exprs.len(), self.stmts.len())
+    }
+
+    pub(crate) fn push(&mut self, kind: ExprKind, start: usize, end: usize) {
+        self.exprs.push(Expr {
+            kind,
+            span: Span { start, end },
+        });
+    }
+
+    /// The last expression, or `nil` if there is none, which only happens after an error, when the tree isn't used
+    pub(crate) fn pop(&mut self) -> Box<Expr> {
+        Box::new(self.exprs.pop().unwrap_or(Expr {
+            kind: ExprKind::Nil,
+            span: Span::default(),
+        }))
+    }
+
+    pub(crate) fn last(&mut self) -> Option<&mut Expr> {
+        self.exprs.last_mut()
+    }
+
+    pub(crate) fn exprs_from(&mut self, mark: usize) -> Vec<Expr> {
+        self.exprs.split_off(mark.min(self.exprs.len()))
+    }
+
+    /// The function that the last expression is, see `Compiler::compile_function`
+    pub(crate) fn function(&mut self) -> Function {
+        match self.pop().kind {
+            ExprKind::Function(function) => *function,
+            _ => Function {
+                name: None,
+                params: Vec::new(),
+                body: FunctionBody::Block(Vec::new()),
+                span: Span::default(),
+            },
+        }
+    }
+
+    /// Add a statement, whose line and span are set by `finish_statement` once all of it is parsed
+    pub(crate) fn statement(&mut self, kind: StmtKind) {
+        self.stmts.push(Stmt {
+            kind,
+            line: 0,
+            span: Span::default(),
+        });
+    }
+
+    /// Set where the statement added after the mark is, from the start of the token to the end
+    pub(crate) fn finish_statement(&mut self, mark: usize, start: &Token, end: usize) {
+        if let Some(stmt) = self.stmts.get_mut(mark) {
+            stmt.line = start.line;
+            stmt.span = Span {
+                start: start.span.start,
+                end: end.max(start.span.start),
+            };
+        }
+    }
+
+    pub(crate) fn pop_statement(&mut self) -> Box<Stmt> {
+        Box::new(self.stmts.pop().unwrap_or(Stmt {
+            kind: StmtKind::Block(Vec::new()),
+            line: 0,
+            span: Span::default(),
+        }))
+    }
+
+    pub(crate) fn stmts_from(&mut self, mark: usize) -> Vec<Stmt> {
+        self.stmts.split_off(mark.min(self.stmts.len()))
+    }
+}
+
+impl Name {
+    pub(crate) fn new(token: &Token) -> Name {
+        Name {
+            name: token.source.to_string(),
+            span: token.span,
+        }
+    }
+}
+
+/// Text of a string literal without its quotes
+pub(crate) fn unquote(literal: &str) -> String {
+    literal.get(1..literal.len().saturating_sub(1)).unwrap_or_default().to_string()
+}
+
+/// Nodes as JSON objects, with their type in `type` and the span of the source they are from in `span`
+trait ToJson {
+    fn write_json(&self, out: &mut String);
+}
+
+fn object(out: &mut String, typ: &str, span: Option<Span>, fields: &[(&str, &dyn ToJson)]) {
+    out.push_str(r#"{"type":"#);
+    write_string(out, typ);
+    for (name, value) in fields {
+        out.push_str(&format!(r#","{name}":"#));
+        value.write_json(out);
+    }
+    if let Some(span) = span {
+        out.push_str(&format!(r#","span":{{"start":{},"end":{}}}"#, span.start, span.end));
+    }
+    out.push('}');
+}
+
+impl ToJson for str {
+    fn write_json(&self, out: &mut String) {
+        write_string(out, self);
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn write_json(&self, out: &mut String) {
+        (*self).write_json(out);
+    }
+}
+
+impl ToJson for String {
+    fn write_json(&self, out: &mut String) {
+        write_string(out, self);
+    }
+}
+
+impl ToJson for bool {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+impl ToJson for usize {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(&self.to_string());
+    }
+}
+
+impl<T: ToJson> ToJson for Box<T> {
+    fn write_json(&self, out: &mut String) {
+        self.as_ref().write_json(out);
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn write_json(&self, out: &mut String) {
+        out.push('[');
+        for (idx, value) in self.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            value.write_json(out);
+        }
+        out.push(']');
+    }
+}
+
+impl<A: ToJson, B: ToJson> ToJson for (A, B) {
+    fn write_json(&self, out: &mut String) {
+        out.push('[');
+        self.0.write_json(out);
+        out.push(',');
+        self.1.write_json(out);
+        out.push(']');
+    }
+}
+
+impl ToJson for Name {
+    fn write_json(&self, out: &mut String) {
+        object(out, "Name", Some(self.span), &[("name", &self.name)]);
+    }
+}
+
+impl Program {
+    /// The tree as JSON, like `{"type":"Program","statements":[{"type":"Print","value":{"type":"Number",...},...}]}`.
+    /// Each node is an object with its kind in `type`, and most have the bytes of the source they are from in `span`.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        object(&mut out, "Program", None, &[("statements", &self.statements)]);
+        out
+    }
+}
+
+impl ToJson for Stmt {
+    fn write_json(&self, out: &mut String) {
+        let line = &self.line;
+        let span = Some(self.span);
+        match &self.kind {
+            StmtKind::Expression(value) => object(out, "Expression", span, &[("line", line), ("value", value)]),
+            StmtKind::Print(value) => object(out, "Print", span, &[("line", line), ("value", value)]),
+            StmtKind::Var {
+                name,
+                size,
+                value,
+                is_const,
+            } => object(
+                out,
+                "Var",
+                span,
+                &[
+                    ("line", line),
+                    ("name", name),
+                    ("size", size),
+                    ("value", value),
+                    ("isConst", is_const),
+                ],
+            ),
+            StmtKind::Destructure { pattern, value, is_const } => object(
+                out,
+                "Destructure",
+                span,
+                &[("line", line), ("pattern", pattern), ("value", value), ("isConst", is_const)],
+            ),
+            StmtKind::Function(function) => object(out, "FunctionDeclaration", span, &[("line", line), ("function", function)]),
+            StmtKind::Class { name, members } => object(out, "Class", span, &[("line", line), ("name", name), ("members", members)]),
+            StmtKind::Enum { name, members } => object(out, "Enum", span, &[("line", line), ("name", name), ("members", members)]),
+            StmtKind::Record { name, fields } => object(out, "Record", span, &[("line", line), ("name", name), ("fields", fields)]),
+            StmtKind::Import { path, name } => object(out, "Import", span, &[("line", line), ("path", path), ("name", name)]),
+            StmtKind::Export(declaration) => object(out, "Export", span, &[("line", line), ("declaration", declaration)]),
+            StmtKind::Test { name, body } => object(out, "Test", span, &[("line", line), ("name", name), ("body", body)]),
+            StmtKind::Block(statements) => object(out, "Block", span, &[("line", line), ("statements", statements)]),
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => object(
+                out,
+                "If",
+                span,
+                &[
+                    ("line", line),
+                    ("condition", condition),
+                    ("thenBranch", then_branch),
+                    ("elseBranch", else_branch),
+                ],
+            ),
+            StmtKind::While { condition, body } => object(out, "While", span, &[("line", line), ("condition", condition), ("body", body)]),
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => object(
+                out,
+                "For",
+                span,
+                &[
+                    ("line", line),
+                    ("initializer", initializer),
+                    ("condition", condition),
+                    ("increment", increment),
+                    ("body", body),
+                ],
+            ),
+            StmtKind::ForIn { variable, iterable, body } => object(
+                out,
+                "ForIn",
+                span,
+                &[("line", line), ("variable", variable), ("iterable", iterable), ("body", body)],
+            ),
+            StmtKind::Switch { subject, cases, default } => object(
+                out,
+                "Switch",
+                span,
+                &[("line", line), ("subject", subject), ("cases", cases), ("default", default)],
+            ),
+            StmtKind::Try { body, catch, finally } => object(
+                out,
+                "Try",
+                span,
+                &[("line", line), ("body", body), ("catch", catch), ("finally", finally)],
+            ),
+            StmtKind::Return(value) => object(out, "Return", span, &[("line", line), ("value", value)]),
+            StmtKind::Throw(value) => object(out, "Throw", span, &[("line", line), ("value", value)]),
+            StmtKind::Yield(value) => object(out, "Yield", span, &[("line", line), ("value", value)]),
+        }
+    }
+}
+
+impl ToJson for Pattern {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Pattern::Array(names) => object(out, "ArrayPattern", None, &[("names", names)]),
+            Pattern::Map(entries) => object(out, "MapPattern", None, &[("entries", entries)]),
+        }
+    }
+}
+
+impl ToJson for SwitchCase {
+    fn write_json(&self, out: &mut String) {
+        object(out, "Case", None, &[("values", &self.values), ("body", &self.body)]);
+    }
+}
+
+impl ToJson for Catch {
+    fn write_json(&self, out: &mut String) {
+        object(out, "Catch", None, &[("variable", &self.variable), ("body", &self.body)]);
+    }
+}
+
+impl ToJson for ClassMember {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            ClassMember::Method { kind, function } => {
+                let kind = match kind {
+                    MethodKind::Method => "method",
+                    MethodKind::Getter => "getter",
+                    MethodKind::Setter => "setter",
+                    MethodKind::Static => "static",
+                };
+                object(out, "Method", Some(function.span), &[("kind", &kind), ("function", function)]);
+            }
+            ClassMember::StaticField { name, value } => object(out, "StaticField", None, &[("name", name), ("value", value)]),
+        }
+    }
+}
+
+impl ToJson for Function {
+    fn write_json(&self, out: &mut String) {
+        let (body, expression) = match &self.body {
+            FunctionBody::Block(statements) => (Some(statements), None),
+            FunctionBody::Expression(expr) => (None, Some(expr)),
+        };
+        object(
+            out,
+            "Function",
+            Some(self.span),
+            &[
+                ("name", &self.name),
+                ("params", &self.params),
+                ("body", &body),
+                ("expression", &expression),
+            ],
+        );
+    }
+}
+
+impl ToJson for Param {
+    fn write_json(&self, out: &mut String) {
+        object(
+            out,
+            "Param",
+            Some(self.name.span),
+            &[("name", &self.name), ("default", &self.default), ("isRest", &self.is_rest)],
+        );
+    }
+}
+
+impl ToJson for Argument {
+    fn write_json(&self, out: &mut String) {
+        object(
+            out,
+            "Argument",
+            Some(self.value.span),
+            &[("name", &self.name), ("value", &self.value)],
+        );
+    }
+}
+
+impl ToJson for Expr {
+    fn write_json(&self, out: &mut String) {
+        let span = Some(self.span);
+        match &self.kind {
+            ExprKind::Number(value) => object(out, "Number", span, &[("value", value)]),
+            ExprKind::String(value) => object(out, "String", span, &[("value", value)]),
+            ExprKind::Bool(value) => object(out, "Bool", span, &[("value", value)]),
+            ExprKind::Nil => object(out, "Nil", span, &[]),
+            ExprKind::This => object(out, "This", span, &[]),
+            ExprKind::Variable(name) => object(out, "Variable", span, &[("name", name)]),
+            ExprKind::Grouping(expr) => object(out, "Grouping", span, &[("expression", expr)]),
+            ExprKind::Unary { operator, operand } => object(out, "Unary", span, &[("operator", &operator.symbol()), ("operand", operand)]),
+            ExprKind::Binary { operator, left, right } => object(
+                out,
+                "Binary",
+                span,
+                &[("operator", &operator.symbol()), ("left", left), ("right", right)],
+            ),
+            ExprKind::Assign { target, value } => object(out, "Assign", span, &[("target", target), ("value", value)]),
+            ExprKind::Call { callee, arguments } => object(out, "Call", span, &[("callee", callee), ("arguments", arguments)]),
+            ExprKind::Property {
+                object: target,
+                name,
+                optional,
+            } => object(out, "Property", span, &[("object", target), ("name", name), ("optional", optional)]),
+            ExprKind::Index {
+                object: target,
+                index,
+                optional,
+            } => object(out, "Index", span, &[("object", target), ("index", index), ("optional", optional)]),
+            ExprKind::Array(elements) => object(out, "Array", span, &[("elements", elements)]),
+            ExprKind::Map(entries) => object(out, "Map", span, &[("entries", entries)]),
+            ExprKind::Tuple(elements) => object(out, "Tuple", span, &[("elements", elements)]),
+            ExprKind::Function(function) => function.write_json(out),
+            ExprKind::Spread(value) => object(out, "Spread", span, &[("value", value)]),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! A builder of chunks, so that hosts and tests can write bytecode without the compiler or the assembler:
+//!
+//! ```ignore
+//! let mut builder = ChunkBuilder::new();
+//! let (otherwise, end) = (builder.label(), builder.label());
+//! builder.op(Opcode::True).jump_label(Opcode::JumpIfFalse, otherwise).op(Opcode::Pop);
+//! builder.constant(Value::Int(1)).op(Opcode::Print).jump_label(Opcode::Jump, end);
+//! builder.bind(otherwise).op(Opcode::Pop);
+//! builder.bind(end).op(Opcode::Nil).op(Opcode::Return);
+//! let chunk = builder.build()?;
+//! ```
+//!
+//! Methods can be chained, and a mistake, like an operand an instruction doesn't have, is kept until `build`, which
+//! returns it. Jumps go to labels, which are patched in when the chunk is built. Superinstructions are only made by
+//! the compiler, so they can't be added.
+
+use crate::{
+    chunk::Chunk,
+    common::Opcode,
+    interner::StrId,
+    value::{Value, ValueArray},
+    verify::verify_chunk,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{cell::RefCell, rc::Rc};
+
+/// Where a jump of the builder goes, once it is bound with `ChunkBuilder::bind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    line: usize,
+    labels: Vec<Option<usize>>, // Offset of each label, once it is bound
+    jumps: Vec<Patch>,
+    error: Option<String>, // The first mistake, which `build` returns
+}
+
+/// A jump offset to fill in when the chunk is built
+struct Patch {
+    opcode: Opcode,
+    start: usize, // Where the jump starts
+    at: usize,    // Where the offset is
+    end: usize,   // Where the offset ends, which it is relative to
+    label: Option<Label>,
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkBuilder {
+    pub fn new() -> ChunkBuilder {
+        ChunkBuilder {
+            chunk: Chunk::default(),
+            line: 1,
+            labels: Vec::new(),
+            jumps: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Line in the program of the instructions added after this
+    pub fn line(&mut self, line: usize) -> &mut Self {
+        self.line = line;
+        self
+    }
+
+    /// Offset where the next instruction starts
+    pub fn offset(&self) -> usize {
+        self.chunk.code.len()
+    }
+
+    /// An instruction without operands, like `Opcode::Add`
+    pub fn op(&mut self, opcode: Opcode) -> &mut Self {
+        if opcode.size() != 1 {
+            return self.fail(format!("{opcode} has operands"));
+        }
+        self.chunk.write_opcode(opcode, self.line);
+        self
+    }
+
+    /// An instruction with a byte operand, like the slot of `Opcode::GetLocal` or the argument count of `Opcode::Call`
+    pub fn op_byte(&mut self, opcode: Opcode, operand: u8) -> &mut Self {
+        let takes_byte = matches!(
+            opcode,
+            Opcode::GetLocal
+                | Opcode::SetLocal
+                | Opcode::Call
+                | Opcode::TailCall
+                | Opcode::BuildMap
+                | Opcode::BuildArray
+                | Opcode::CallSpread
+                | Opcode::BuildTuple
+        );
+        if !takes_byte {
+            return self.fail(format!("{opcode} doesn't take a byte operand"));
+        }
+        self.chunk.write_opcode(opcode, self.line);
+        self.chunk.write_byte(operand, self.line);
+        self
+    }
+
+    /// Push the constant
+    pub fn constant(&mut self, value: Value) -> &mut Self {
+        self.op_constant(Opcode::Constant, value)
+    }
+
+    /// An instruction with a constant operand, like a name for `Opcode::GetGlobal`. It is the long variant if the
+    /// index of the constant needs it.
+    pub fn op_constant(&mut self, opcode: Opcode, value: Value) -> &mut Self {
+        let short = opcode.short();
+        let Some(long) = short
+            .long()
+            .filter(|_| short != Opcode::CallNamed && short.jump_operands().is_empty())
+        else {
+            return self.fail(format!("{opcode} doesn't take a constant operand"));
+        };
+        let index = self.chunk.add_constant(value);
+        self.write_constant(short, long, index, &[])
+    }
+
+    /// A call with named arguments, which come after the positional ones. `names` are the names of the last
+    /// arguments, in order.
+    pub fn call_named(&mut self, arg_count: u8, names: &[StrId]) -> &mut Self {
+        let names: ValueArray = names.iter().map(|&name| Value::Str(name)).collect();
+        let index = self.chunk.add_constant(Value::Array(Rc::new(RefCell::new(names))));
+        self.write_constant(Opcode::CallNamed, Opcode::CallNamedLong, index, &[arg_count])
+    }
+
+    fn write_constant(&mut self, opcode: Opcode, long: Opcode, index: usize, operands: &[u8]) -> &mut Self {
+        let (opcode, width) = if index <= u8::MAX as usize { (opcode, 1) } else { (long, 3) };
+        if index >= 1 << 24 {
+            return self.fail(format!("Chunk has more than {} constants", 1 << 24));
+        }
+        self.chunk.write_opcode(opcode, self.line);
+        for &operand in operands {
+            self.chunk.write_byte(operand, self.line);
+        }
+        for byte in (0..width).rev() {
+            self.chunk.write_byte((index >> (8 * byte)) as u8, self.line);
+        }
+        self
+    }
+
+    /// A label for jumps to go to, which is bound later
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Make the label go to where the next instruction starts
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        match self.labels.get_mut(label.0) {
+            Some(offset @ None) => *offset = Some(self.chunk.code.len()),
+            Some(Some(_)) => return self.fail(format!("Label {} is bound twice", label.0)),
+            None => return self.fail(format!("Label {} is from another builder", label.0)),
+        }
+        self
+    }
+
+    /// A jump to the label, like `Opcode::JumpIfFalse` forward or `Opcode::Loop` backward
+    pub fn jump_label(&mut self, opcode: Opcode, label: Label) -> &mut Self {
+        if !matches!(
+            opcode.short(),
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfNil | Opcode::JumpIfNotNil | Opcode::Loop
+        ) {
+            return self.fail(format!("{opcode} is not a jump"));
+        }
+        self.write_jumps(opcode, &[], &[Some(label)])
+    }
+
+    /// `Opcode::JumpIfArgPassed` with a parameter, or `Opcode::IterNext` with the slot of the iterable, and where it
+    /// jumps
+    pub fn byte_jump_label(&mut self, opcode: Opcode, operand: u8, label: Label) -> &mut Self {
+        if !matches!(opcode.short(), Opcode::JumpIfArgPassed | Opcode::IterNext) {
+            return self.fail(format!("{opcode} is not a jump with a byte operand"));
+        }
+        self.write_jumps(opcode, &[operand], &[Some(label)])
+    }
+
+    /// `Opcode::PushHandler`, with the catch block and the finally block, if the try has them
+    pub fn handler(&mut self, catch: Option<Label>, finally: Option<Label>) -> &mut Self {
+        self.write_jumps(Opcode::PushHandler, &[], &[catch, finally])
+    }
+
+    /// A jump with placeholder offsets, which `build` patches with where the labels are
+    fn write_jumps(&mut self, opcode: Opcode, operands: &[u8], labels: &[Option<Label>]) -> &mut Self {
+        let start = self.chunk.code.len();
+        let width = if opcode.is_long() { 3 } else { 2 };
+        self.chunk.write_opcode(opcode, self.line);
+        for &operand in operands {
+            self.chunk.write_byte(operand, self.line);
+        }
+        for (&at, &label) in opcode.jump_operands().iter().zip(labels) {
+            self.jumps.push(Patch {
+                opcode,
+                start,
+                at: start + at,
+                end: start + at + width,
+                label,
+            });
+            for _ in 0..width {
+                self.chunk.write_byte(0, self.line);
+            }
+        }
+        self
+    }
+
+    fn fail(&mut self, message: String) -> &mut Self {
+        let offset = self.chunk.code.len();
+        self.error.get_or_insert_with(|| format!("At offset {offset}: {message}"));
+        self
+    }
+
+    /// The chunk, with the jumps going to their labels, checked with `verify::verify_chunk`
+    pub fn build(mut self) -> Result<Chunk> {
+        if let Some(error) = self.error {
+            bail!(error);
+        }
+        for patch in &self.jumps {
+            // A handler without a block has an offset of 0
+            let Some(label) = patch.label else {
+                continue;
+            };
+            let target = self.labels[label.0].ok_or_else(|| anyhow!("Label {} is never bound", label.0))?;
+            let distance = match patch.opcode.short() {
+                Opcode::Loop => patch.end.checked_sub(target),
+                _ => target.checked_sub(patch.end),
+            };
+            let Some(distance) = distance else {
+                bail!("{} at offset {} goes the wrong way to label {}", patch.opcode, patch.start, label.0);
+            };
+            let width = patch.end - patch.at;
+            if distance >> (8 * width) != 0 {
+                bail!("Label {} is too far for {}, which needs its long variant", label.0, patch.opcode);
+            }
+            for (byte, slot) in self.chunk.code[patch.at..patch.end].iter_mut().rev().enumerate() {
+                *slot = (distance >> (8 * byte)) as u8;
+            }
+        }
+        verify_chunk(&self.chunk)?;
+        Ok(self.chunk)
+    }
+}
@@ -0,0 +1,322 @@
+//! A cache of compiled programs, so that running the same code again, like in the playground, skips compiling it.
+//!
+//! Programs are kept by a hash of their source and of the options they are compiled with, in the format of
+//! `serialize::serialize_program`, with the warnings of the compiler. The source and options are kept too, and compared
+//! when a program is found, so that a collision of hashes doesn't run another program. Modules they import are kept by the hash of their
+//! source, and a program is compiled again when one of them changes. The cache is of whole programs rather than of
+//! their functions, since a function is compiled with the slots and constants of the code around it, so a program
+//! that changed is compiled again from the start.
+//!
+//! The cache can be saved by the host with `CompileCache::to_bytes` and loaded again with `CompileCache::from_bytes`.
+
+use crate::{
+    compiler::{Compiler, CompilerOptions, Warnings},
+    fun::Fun,
+    interner::Interner,
+    lint::{Level, Lints},
+    module::{Module, ModuleLoader, ModuleRegistry, MAIN_MODULE},
+    register::Backend,
+    serialize::{deserialize_program, serialize_program},
+    INTERNER_DEFAULT_CAP,
+};
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use std::{cell::RefCell, rc::Rc};
+
+/// Changed whenever the format of a saved cache does
+const MAGIC: &[u8; 4] = b"LXC2";
+/// Programs kept before the one used longest ago is dropped
+const CAPACITY: usize = 16;
+
+pub struct CompileCache {
+    entries: IndexMap<u64, Entry>, // Used longest ago first
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    source: String,
+    options: Vec<u8>, // See `options_bytes`
+    program: Vec<u8>,
+    warnings: Vec<String>,
+    imports: Vec<(String, u64)>, // Path and hash of the source of each module the program imports
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl CacheStats {
+    /// The stats as JSON, like `{"hits":3,"misses":1,"entries":1}`
+    pub fn to_json(&self) -> String {
+        format!(r#"{{"hits":{},"misses":{},"entries":{}}}"#, self.hits, self.misses, self.entries)
+    }
+}
+
+/// A program ready to run, from the cache or just compiled
+pub struct CachedProgram {
+    pub interner: Interner,
+    pub functions: Vec<Fun>,
+    pub modules: Vec<Module>,
+    pub warnings: Vec<String>, // Rendered with the source of the program
+}
+
+impl Default for CompileCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompileCache {
+    pub fn new() -> CompileCache {
+        CompileCache {
+            entries: IndexMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+
+    /// The program compiled with the options, from the cache if it was compiled before and the modules it imports
+    /// haven't changed. Programs for the register backend are always compiled, as what it lowers functions to can't be
+    /// saved.
+    pub fn compile(&mut self, code: &str, loader: impl ModuleLoader + 'static, options: &CompilerOptions) -> Result<CachedProgram> {
+        let loader: Rc<dyn ModuleLoader> = Rc::new(loader);
+        let options_bytes = options_bytes(options);
+        let key = hash(code.as_bytes()) ^ hash(&options_bytes).rotate_left(1);
+        if let Some(entry) = self.entries.shift_remove(&key) {
+            let unchanged = entry.source == code
+                && entry.options == options_bytes
+                && entry
+                    .imports
+                    .iter()
+                    .all(|(path, source)| loader.load(path).is_ok_and(|code| hash(code.as_bytes()) == *source));
+            let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+            // A program that doesn't verify, from a cache saved by an older build, is compiled again
+            if let Some((functions, modules)) = unchanged.then(|| deserialize_program(&entry.program, &mut interner).ok()).flatten() {
+                let warnings = entry.warnings.clone();
+                self.entries.insert(key, entry);
+                self.hits += 1;
+                return Ok(CachedProgram {
+                    interner,
+                    functions,
+                    modules,
+                    warnings,
+                });
+            }
+        }
+        self.misses += 1;
+
+        let imports = Rc::new(RefCell::new(Vec::new()));
+        let recorder = RecordingLoader {
+            loader,
+            imports: imports.clone(),
+        };
+        let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+        let mut functions = Vec::new();
+        let mut modules = ModuleRegistry::new(Box::new(recorder));
+        let program = Compiler::compile_program(Rc::from(code), &mut interner, &mut functions, &mut modules, options)?;
+        functions.push(program.script);
+        modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+        let warnings: Vec<String> = program
+            .warnings
+            .iter()
+            .map(|warning| warning.render(code).trim_end().to_string())
+            .collect();
+
+        if options.backend != Backend::Register {
+            if self.entries.len() >= CAPACITY {
+                self.entries.shift_remove_index(0);
+            }
+            let entry = Entry {
+                source: code.to_string(),
+                options: options_bytes,
+                program: serialize_program(&functions, &modules.modules, &interner),
+                warnings: warnings.clone(),
+                imports: imports.take(),
+            };
+            self.entries.insert(key, entry);
+        }
+        Ok(CachedProgram {
+            interner,
+            functions,
+            modules: modules.modules,
+            warnings,
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The programs of the cache, for the host to save and load with `from_bytes`. The stats aren't saved.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        write_u32(&mut out, self.entries.len());
+        for (key, entry) in &self.entries {
+            out.extend_from_slice(&key.to_le_bytes());
+            write_bytes(&mut out, entry.source.as_bytes());
+            write_bytes(&mut out, &entry.options);
+            write_bytes(&mut out, &entry.program);
+            write_u32(&mut out, entry.warnings.len());
+            entry.warnings.iter().for_each(|warning| write_bytes(&mut out, warning.as_bytes()));
+            write_u32(&mut out, entry.imports.len());
+            for (path, source) in &entry.imports {
+                write_bytes(&mut out, path.as_bytes());
+                out.extend_from_slice(&source.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// A cache with the programs saved with `to_bytes`. Programs are checked when they are used, and one that doesn't
+    /// verify is compiled again.
+    pub fn from_bytes(bytes: &[u8]) -> Result<CompileCache> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("Not a saved compile cache");
+        }
+        let mut cache = CompileCache::new();
+        for _ in 0..reader.u32()? {
+            let key = reader.u64()?;
+            let source = reader.string()?;
+            let options = reader.bytes()?.to_vec();
+            let program = reader.bytes()?.to_vec();
+            let warnings = (0..reader.u32()?).map(|_| reader.string()).collect::<Result<_>>()?;
+            let imports = (0..reader.u32()?)
+                .map(|_| Ok((reader.string()?, reader.u64()?)))
+                .collect::<Result<_>>()?;
+            cache.entries.insert(
+                key,
+                Entry {
+                    source,
+                    options,
+                    program,
+                    warnings,
+                    imports,
+                },
+            );
+        }
+        if reader.offset != bytes.len() {
+            bail!("Saved compile cache has bytes after its end");
+        }
+        Ok(cache)
+    }
+}
+
+/// Loads modules with another loader, and keeps the hash of the source of each
+struct RecordingLoader {
+    loader: Rc<dyn ModuleLoader>,
+    imports: Rc<RefCell<Vec<(String, u64)>>>,
+}
+
+impl ModuleLoader for RecordingLoader {
+    fn load(&self, path: &str) -> Result<String> {
+        let code = self.loader.load(path)?;
+        self.imports.borrow_mut().push((path.to_string(), hash(code.as_bytes())));
+        Ok(code)
+    }
+}
+
+/// The options as bytes, each field in turn. Fields are named so that one added to the options has to be added here.
+fn options_bytes(options: &CompilerOptions) -> Vec<u8> {
+    let CompilerOptions {
+        backend,
+        warnings,
+        lints,
+        opt_level,
+    } = options;
+    let Warnings {
+        unused_variables,
+        shadowing,
+        unreachable_code,
+        removed_code,
+        assignment_in_condition,
+    } = warnings;
+    let Lints {
+        naming,
+        empty_blocks,
+        constant_conditions,
+        deep_nesting,
+        max_nesting,
+    } = lints;
+    let level = |level: &Level| match level {
+        Level::Allow => 0,
+        Level::Warn => 1,
+        Level::Deny => 2,
+    };
+    let mut out = vec![
+        match backend {
+            Backend::Stack => 0,
+            Backend::Register => 1,
+        },
+        *opt_level,
+    ];
+    out.extend([unused_variables, shadowing, unreachable_code, removed_code, assignment_in_condition].map(|&flag| flag as u8));
+    out.extend([naming, empty_blocks, constant_conditions, deep_nesting].map(level));
+    out.extend_from_slice(&(*max_nesting as u64).to_le_bytes());
+    out
+}
+
+/// FNV-1a, which is the same on every platform and in every run, unlike the hasher of the standard library
+fn hash(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn write_u32(out: &mut Vec<u8>, len: usize) {
+    let len = u32::try_from(len).expect("Compile cache is too big to save");
+    out.extend_from_slice(&len.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .context("Saved compile cache ends too early")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(std::str::from_utf8(self.bytes()?)
+            .context("String of saved compile cache is not UTF-8")?
+            .to_string())
+    }
+}
@@ -1,18 +1,93 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, rc::Weak};
 
 use crate::{
     common::*,
-    dbgln,
-    debug::disassemble_instruction,
-    interner::Interner,
-    value::{Value, ValueArray},
+    debug::{disassemble_at, DisassembledInstruction},
+    interner::{Interner, StrId},
+    scanner::Span,
+    value::{Class, Value, ValueArray},
+    xprint,
 };
 
 #[derive(Default, Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: HashMap<usize, usize>,
+    pub(crate) lines: Vec<(usize, usize)>, // Line of each run of bytes, and how many bytes it has
+    pub(crate) spans: Vec<(Option<Span>, usize)>, // Source of the statement of each run of bytes, like `lines`
     pub constants: ValueArray,
+    pub(crate) caches: Vec<InlineCache>, // By offset of the instruction, filled in as the instructions run
+    constant_indices: HashMap<ConstantKey, usize>, // Constants that `add_constant` reuses
+}
+
+/// Add `length` bytes with the value to a run-length table, like the lines of a chunk
+pub(crate) fn push_run<T: PartialEq>(runs: &mut Vec<(T, usize)>, value: T, length: usize) {
+    match runs.last_mut() {
+        Some((last, run)) if *last == value => *run += length,
+        _ => runs.push((value, length)),
+    }
+}
+
+/// Remove the last `excess` bytes from a run-length table
+fn truncate_runs<T>(runs: &mut Vec<(T, usize)>, mut excess: usize) {
+    while let Some((_, length)) = runs.last_mut() {
+        if *length > excess {
+            *length -= excess;
+            break;
+        }
+        excess -= *length;
+        runs.pop();
+    }
+}
+
+/// Value of the run-length table at `offset`
+fn run_at<T: Copy>(runs: &[(T, usize)], offset: usize) -> Option<T> {
+    let mut start = 0;
+    for &(value, length) in runs {
+        start += length;
+        if offset < start {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Constants that are the same wherever they are used, so a chunk needs only one of each
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(u64), // Bits of the f64, so that 0 and -0 are different constants
+    Str(StrId),
+    Identifier(StrId),
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<ConstantKey> {
+        let key = match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(bool) => ConstantKey::Bool(*bool),
+            Value::Int(int) => ConstantKey::Int(*int),
+            Value::Number(number) => ConstantKey::Number(number.to_bits()),
+            Value::Str(id) => ConstantKey::Str(*id),
+            Value::Identifier(id) => ConstantKey::Identifier(*id),
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+/// Where an instruction found what it looked up the last time it ran. The VM checks that a field or method still holds
+/// before using it, while a global always does, since the caches are emptied when a global is removed.
+#[derive(Debug, Default, Clone)]
+pub(crate) enum InlineCache {
+    #[default]
+    Empty,
+    Slot(usize), // Index of a global or a field in its `SlotMap`
+    Method {
+        class: Weak<Class>, // The weak reference keeps another class from being created at the same address
+        method: usize,
+    },
 }
 
 impl Chunk {
@@ -21,13 +96,128 @@ impl Chunk {
     }
 
     pub fn write_byte(&mut self, data: u8, line: usize) {
-        self.lines.insert(self.code.len(), line);
+        push_run(&mut self.lines, line, 1);
+        push_run(&mut self.spans, None, 1);
         self.code.push(data);
     }
 
+    /// Remove the code from `len` on, with its lines
+    pub(crate) fn truncate(&mut self, len: usize) {
+        let excess = self.code.len().saturating_sub(len);
+        truncate_runs(&mut self.lines, excess);
+        truncate_runs(&mut self.spans, excess);
+        self.code.truncate(len);
+        self.caches.truncate(len);
+    }
+
+    /// Line of the byte at `offset`, or 0 if the chunk doesn't have it
+    pub fn line_at(&self, offset: usize) -> usize {
+        run_at(&self.lines, offset).unwrap_or(0)
+    }
+
+    /// Source of the statement that the byte at `offset` was compiled from, or None if it isn't from one, like the
+    /// return at the end of a function or code that wasn't compiled
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        run_at(&self.spans, offset).flatten()
+    }
+
+    /// Each run of offsets that were compiled from the same statement, with the source of the statement
+    pub fn source_map(&self) -> Vec<(Range<usize>, Span)> {
+        let mut start = 0;
+        let mut map = Vec::new();
+        for &(span, length) in &self.spans {
+            if let Some(span) = span {
+                map.push((start..start + length, span));
+            }
+            start += length;
+        }
+        map
+    }
+
+    /// Give the bytes from `start` on that aren't from a statement yet the span of the statement that is being compiled.
+    /// Statements nested in it were compiled before it ends, and keep their own span.
+    pub(crate) fn set_statement_span(&mut self, start: usize, span: Span) {
+        let mut tail = Vec::new();
+        let mut offset = self.code.len();
+        while offset > start {
+            let Some((statement, length)) = self.spans.pop() else {
+                break;
+            };
+            let taken = length.min(offset - start);
+            if taken < length {
+                self.spans.push((statement, length - taken));
+            }
+            tail.push((statement.or(Some(span)), taken));
+            offset -= taken;
+        }
+        for (statement, length) in tail.into_iter().rev() {
+            push_run(&mut self.spans, statement, length);
+        }
+    }
+
+    /// Index of the constant, which is added unless the chunk already has it
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::of(&value);
+        if let Some(&index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return index;
+        }
         self.constants.push(value);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    /// Add the constant at the end, even if the chunk already has it, like when the constants are read back
+    pub(crate) fn push_constant(&mut self, value: Value) {
+        if let Some(key) = ConstantKey::of(&value) {
+            self.constant_indices.entry(key).or_insert(self.constants.len());
+        }
+        self.constants.push(value);
+    }
+
+    /// Offsets where each instruction starts
+    pub(crate) fn instruction_starts(&self) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut offset = 0;
+        while let Some(Ok(instruction)) = self.code.get(offset).map(|&byte| Opcode::try_from(byte)) {
+            starts.push(offset);
+            offset += instruction.size();
+        }
+        starts
+    }
+
+    /// Offsets the jumps of the instruction at `offset` go to
+    pub(crate) fn jump_targets(&self, offset: usize) -> Vec<usize> {
+        let Ok(instruction) = Opcode::try_from(self.code[offset]) else {
+            return Vec::new();
+        };
+        let width = if instruction.is_long() { 3 } else { 2 };
+        let jump_targets = instruction.jump_operands().iter().filter_map(|&at| {
+            let end = offset + at + width;
+            let jump = self.code[end - width..end].iter().fold(0, |jump, &byte| jump << 8 | byte as usize);
+            match instruction.short() {
+                Opcode::Loop => end.checked_sub(jump),
+                _ => Some(end + jump),
+            }
+        });
+        jump_targets.collect()
+    }
+
+    /// Constant index at `offset`, which takes 3 bytes for the long variants of instructions
+    pub fn constant_index(&self, offset: usize, long: bool) -> usize {
+        match long {
+            true => (self.code[offset] as usize) << 16 | (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize,
+            false => self.code[offset] as usize,
+        }
+    }
+
+    pub(crate) fn set_cache(&mut self, offset: usize, cache: InlineCache) {
+        if self.caches.len() < self.code.len() {
+            self.caches.resize(self.code.len(), InlineCache::Empty);
+        }
+        self.caches[offset] = cache;
     }
 
     // Unused
@@ -39,13 +229,28 @@ impl Chunk {
 
 // Disassemble related methods
 impl Chunk {
-    #[allow(unused_variables)]
     pub fn disassemble(&self, name: &str, interner: &Interner) {
-        dbgln!("== {name} ==");
+        xprint!("{}", self.disassembly(name, interner));
+    }
+
+    /// The disassembly of the chunk, under a `== name ==` header, which `assembler::assemble` reads back
+    pub fn disassembly(&self, name: &str, interner: &Interner) -> String {
+        let mut out = format!("== {name} ==\n");
+        for instruction in self.disassembled(interner) {
+            out.push_str(&format!("{instruction}\n"));
+        }
+        out
+    }
 
+    /// The instructions of the chunk, with their operands read
+    pub fn disassembled(&self, interner: &Interner) -> Vec<DisassembledInstruction> {
+        let mut instructions = Vec::new();
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = disassemble_instruction(self, offset, interner);
+            let instruction = disassemble_at(self, offset, interner);
+            offset = instruction.next;
+            instructions.push(instruction);
         }
+        instructions
     }
 }
@@ -0,0 +1,117 @@
+use std::{cell::RefCell, rc::Rc};
+use web_time::{Instant, SystemTime};
+
+/// Source of time for the time natives. The default uses `web_time`, which is backed by `performance.now` and `Date`
+/// on the web and `std::time` natively. Hosts can provide their own, for example to make programs deterministic.
+pub trait HostClock {
+    /// Seconds since some fixed point, which never go backwards
+    fn monotonic_seconds(&self) -> f64;
+    /// Milliseconds since the Unix epoch
+    fn epoch_millis(&self) -> i64;
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl HostClock for SystemClock {
+    fn monotonic_seconds(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn epoch_millis(&self) -> i64 {
+        let epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        epoch.as_millis() as i64
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Rc<dyn HostClock>> = RefCell::new(Rc::new(SystemClock::default()));
+}
+
+/// Replace the clock used by the time natives
+pub fn set_host_clock(clock: impl HostClock + 'static) {
+    CLOCK.with(|current| *current.borrow_mut() = Rc::new(clock));
+}
+
+pub(crate) fn host_clock() -> Rc<dyn HostClock> {
+    CLOCK.with(|clock| clock.borrow().clone())
+}
+
+/// A point in time, broken down into its parts in UTC
+pub(crate) struct DateTime {
+    pub year: i64,
+    pub month: i64, // 1 to 12
+    pub day: i64,   // 1 to 31
+    pub hour: i64,
+    pub minute: i64,
+    pub second: i64,
+    pub millisecond: i64,
+    pub weekday: i64, // 0 is Sunday
+}
+
+impl DateTime {
+    pub fn from_epoch_millis(millis: i64) -> DateTime {
+        let days = millis.div_euclid(86_400_000);
+        let millis_of_day = millis.rem_euclid(86_400_000);
+
+        // Civil date from days since the epoch, from Howard Hinnant's date algorithms
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153; // March is 0
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: millis_of_day / 3_600_000,
+            minute: millis_of_day / 60_000 % 60,
+            second: millis_of_day / 1000 % 60,
+            millisecond: millis_of_day % 1000,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 4).rem_euclid(7),
+        }
+    }
+
+    /// Format with `%Y` (year), `%m` (month), `%d` (day), `%H` (hour), `%M` (minute), `%S` (second),
+    /// `%L` (millisecond) and `%%`. Other characters are copied as they are.
+    pub fn format(&self, format: &str) -> String {
+        let mut result = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                result.push(c);
+                continue;
+            }
+
+            match chars.next() {
+                Some('Y') => result.push_str(&format!("{:04}", self.year)),
+                Some('m') => result.push_str(&format!("{:02}", self.month)),
+                Some('d') => result.push_str(&format!("{:02}", self.day)),
+                Some('H') => result.push_str(&format!("{:02}", self.hour)),
+                Some('M') => result.push_str(&format!("{:02}", self.minute)),
+                Some('S') => result.push_str(&format!("{:02}", self.second)),
+                Some('L') => result.push_str(&format!("{:03}", self.millisecond)),
+                Some('%') => result.push('%'),
+                Some(other) => {
+                    result.push('%');
+                    result.push(other);
+                }
+                None => result.push('%'),
+            }
+        }
+        result
+    }
+}
@@ -2,7 +2,7 @@ use crate::scanner::Token;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 #[repr(u8)]
-#[derive(Eq, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
+#[derive(Debug, Clone, Copy, Eq, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
 pub enum Opcode {
     Constant,
     Not,
@@ -22,15 +22,216 @@ pub enum Opcode {
     Less,
     DefineGlobal,
     DeclareArray,
+    BuildMap,
     SetGlobal,
     GetGlobal,
     GetLocal,
     SetLocal,
     JumpIfFalse,
+    JumpIfArgPassed,
     Jump,
     Loop,
     Call,
+    TailCall,
+    CallNamed,
     Return,
+    PushHandler,
+    PopHandler,
+    Throw,
+    EndFinally,
+    Import,
+    Yield,
+    GetProperty,
+    IterStart,
+    IterNext,
+    Range,
+    RangeInclusive,
+    Class,
+    Method,
+    SetProperty,
+    Getter,
+    Setter,
+    StaticMember,
+    JumpIfNil,
+    JumpIfNotNil,
+    GetIndex,
+    SetIndex,
+    In,
+    Spread,
+    BuildArray,
+    CallSpread,
+    BuildTuple,
+    Union,
+    Intersection,
+    // Like the instructions they are named after, with a 24-bit constant index, for chunks with more than 256 constants
+    // or jumps over more than 64 KiB of code
+    ConstantLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    ClassLong,
+    MethodLong,
+    GetterLong,
+    SetterLong,
+    StaticMemberLong,
+    CallNamedLong,
+    JumpLong,
+    JumpIfFalseLong,
+    JumpIfNilLong,
+    JumpIfNotNilLong,
+    LoopLong,
+    JumpIfArgPassedLong,
+    IterNextLong,
+    PushHandlerLong,
+    // Superinstructions, fused from the instructions they replace when a function has been compiled. They keep the
+    // bytes of those instructions, and read their operands from them.
+    ConstantOperation,      // Constant, operator
+    GetLocalPlain,          // Constant nil, GetLocal
+    LocalConstantOperation, // Constant nil, GetLocal, Constant, operator
+    LocalsOperation,        // Constant nil, GetLocal, Constant nil, GetLocal, operator
+}
+
+impl Opcode {
+    /// The opcode whose value is the byte
+    ///
+    /// # Safety
+    /// The byte must be the value of an opcode
+    pub(crate) unsafe fn from_u8_unchecked(byte: u8) -> Opcode {
+        debug_assert!(Opcode::try_from(byte).is_ok(), "{byte} is not an opcode");
+        unsafe { std::mem::transmute::<u8, Opcode>(byte) }
+    }
+
+    /// Variant of the instruction with a 24-bit constant index or jump offsets, for the instructions that have them
+    pub fn long(&self) -> Option<Opcode> {
+        let long = match self {
+            Opcode::Constant => Opcode::ConstantLong,
+            Opcode::DefineGlobal => Opcode::DefineGlobalLong,
+            Opcode::GetGlobal => Opcode::GetGlobalLong,
+            Opcode::SetGlobal => Opcode::SetGlobalLong,
+            Opcode::GetProperty => Opcode::GetPropertyLong,
+            Opcode::SetProperty => Opcode::SetPropertyLong,
+            Opcode::Class => Opcode::ClassLong,
+            Opcode::Method => Opcode::MethodLong,
+            Opcode::Getter => Opcode::GetterLong,
+            Opcode::Setter => Opcode::SetterLong,
+            Opcode::StaticMember => Opcode::StaticMemberLong,
+            Opcode::CallNamed => Opcode::CallNamedLong,
+            Opcode::Jump => Opcode::JumpLong,
+            Opcode::JumpIfFalse => Opcode::JumpIfFalseLong,
+            Opcode::JumpIfNil => Opcode::JumpIfNilLong,
+            Opcode::JumpIfNotNil => Opcode::JumpIfNotNilLong,
+            Opcode::Loop => Opcode::LoopLong,
+            Opcode::JumpIfArgPassed => Opcode::JumpIfArgPassedLong,
+            Opcode::IterNext => Opcode::IterNextLong,
+            Opcode::PushHandler => Opcode::PushHandlerLong,
+            _ => return None,
+        };
+        Some(long)
+    }
+
+    /// Instruction this one is the long variant of, or itself
+    pub fn short(&self) -> Opcode {
+        match self {
+            Opcode::ConstantLong => Opcode::Constant,
+            Opcode::DefineGlobalLong => Opcode::DefineGlobal,
+            Opcode::GetGlobalLong => Opcode::GetGlobal,
+            Opcode::SetGlobalLong => Opcode::SetGlobal,
+            Opcode::GetPropertyLong => Opcode::GetProperty,
+            Opcode::SetPropertyLong => Opcode::SetProperty,
+            Opcode::ClassLong => Opcode::Class,
+            Opcode::MethodLong => Opcode::Method,
+            Opcode::GetterLong => Opcode::Getter,
+            Opcode::SetterLong => Opcode::Setter,
+            Opcode::StaticMemberLong => Opcode::StaticMember,
+            Opcode::CallNamedLong => Opcode::CallNamed,
+            Opcode::JumpLong => Opcode::Jump,
+            Opcode::JumpIfFalseLong => Opcode::JumpIfFalse,
+            Opcode::JumpIfNilLong => Opcode::JumpIfNil,
+            Opcode::JumpIfNotNilLong => Opcode::JumpIfNotNil,
+            Opcode::LoopLong => Opcode::Loop,
+            Opcode::JumpIfArgPassedLong => Opcode::JumpIfArgPassed,
+            Opcode::IterNextLong => Opcode::IterNext,
+            Opcode::PushHandlerLong => Opcode::PushHandler,
+            _ => *self,
+        }
+    }
+
+    /// Whether the constant index or the jump offsets of the instruction take 3 bytes
+    pub fn is_long(&self) -> bool {
+        matches!(
+            self,
+            Opcode::ConstantLong
+                | Opcode::DefineGlobalLong
+                | Opcode::GetGlobalLong
+                | Opcode::SetGlobalLong
+                | Opcode::GetPropertyLong
+                | Opcode::SetPropertyLong
+                | Opcode::ClassLong
+                | Opcode::MethodLong
+                | Opcode::GetterLong
+                | Opcode::SetterLong
+                | Opcode::StaticMemberLong
+                | Opcode::CallNamedLong
+                | Opcode::JumpLong
+                | Opcode::JumpIfFalseLong
+                | Opcode::JumpIfNilLong
+                | Opcode::JumpIfNotNilLong
+                | Opcode::LoopLong
+                | Opcode::JumpIfArgPassedLong
+                | Opcode::IterNextLong
+                | Opcode::PushHandlerLong
+        )
+    }
+
+    /// Where the jump offsets of the instruction are, from where it starts. Each one is relative to where it ends, and
+    /// goes backward for loops.
+    pub fn jump_operands(&self) -> &'static [usize] {
+        match self.short() {
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfNil | Opcode::JumpIfNotNil | Opcode::Loop => &[1],
+            Opcode::JumpIfArgPassed | Opcode::IterNext => &[2],
+            Opcode::PushHandler if self.is_long() => &[1, 4],
+            Opcode::PushHandler => &[1, 3],
+            _ => &[],
+        }
+    }
+
+    /// Bytes the instruction takes, including its operands
+    pub fn size(&self) -> usize {
+        match self {
+            Opcode::Constant
+            | Opcode::DefineGlobal
+            | Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::GetProperty
+            | Opcode::SetProperty
+            | Opcode::Class
+            | Opcode::Method
+            | Opcode::Getter
+            | Opcode::Setter
+            | Opcode::StaticMember
+            | Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::Call
+            | Opcode::TailCall
+            | Opcode::BuildMap
+            | Opcode::BuildArray
+            | Opcode::CallSpread
+            | Opcode::BuildTuple => 2,
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfNil | Opcode::JumpIfNotNil | Opcode::Loop | Opcode::CallNamed => 3,
+            Opcode::JumpIfArgPassed | Opcode::IterNext => 4,
+            Opcode::CallNamedLong | Opcode::JumpIfArgPassedLong | Opcode::IterNextLong => 5,
+            Opcode::PushHandlerLong => 7,
+            long if long.is_long() => 4,
+            Opcode::PushHandler => 5,
+            Opcode::ConstantOperation => 3,
+            Opcode::GetLocalPlain => 4,
+            Opcode::LocalConstantOperation => 7,
+            Opcode::LocalsOperation => 9,
+            _ => 1,
+        }
+    }
 }
 
 pub fn variant_eq<T>(a: &T, b: &T) -> bool {
@@ -1,116 +1,259 @@
 use crate::{
+    ast::{
+        self, BinaryOperator, Catch, ClassMember, ExprKind, Function, FunctionBody, MethodKind, Name, Param, Pattern, Program, Stmt,
+        StmtKind, SwitchCase, TreeBuilder, UnaryOperator,
+    },
     common::{identifiers_equal, Opcode},
-    dbgln,
-    fun::{Fun, FunType},
-    interner::Interner,
-    scanner::{Scanner, Token, TokenType},
-    value::Value,
-    xprint, xprintln,
+    dead_code,
+    diagnostic::{Code, CompileError, Diagnostic, Severity},
+    fold,
+    fun::{Fun, FunType, LocalVariable},
+    interner::{Interner, StrId},
+    lint::{self, Level, Lints, NameKind},
+    long_jump,
+    module::{Module, ModuleRegistry, NoModules, MAIN_MODULE},
+    named_args::{self, KnownFunctions},
+    peephole,
+    register::{self, Backend},
+    scanner::{Scanner, Span, Token, TokenType},
+    superinstruction,
+    testing::TestCase,
+    value::{Enum, EnumMember, RecordType, Value},
+    vm::COMPLETION_NORMAL,
+    INTERNER_DEFAULT_CAP,
 };
-use anyhow::*;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::{collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+/// Options for compiling a program, with `Compiler::compile_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct CompilerOptions {
+    pub backend: Backend,
+    pub warnings: Warnings,
+    pub lints: Lints,
+    pub opt_level: u8, // 0 emits the code as written, and 1 folds constants, removes dead code and optimizes the bytecode
+}
+
+/// Which warnings the compiler reports, all of them by default
+#[derive(Debug, Clone)]
+pub struct Warnings {
+    pub unused_variables: bool,        // Local variables that are never read. Names starting with `_` are left out.
+    pub shadowing: bool,               // Local variables with the name of a local variable of an outer block
+    pub unreachable_code: bool,        // Statements after a `return` in the same block
+    pub removed_code: bool,            // Code that `dead_code` removes at `opt_level` 1 or more. Off by default.
+    pub assignment_in_condition: bool, // Conditions that are an assignment, which is usually meant to be `==`
+}
+
+impl Default for Warnings {
+    fn default() -> Self {
+        Warnings {
+            unused_variables: true,
+            shadowing: true,
+            unreachable_code: true,
+            removed_code: false,
+            assignment_in_condition: true,
+        }
+    }
+}
+
+impl Warnings {
+    fn enabled(&self, code: Code) -> bool {
+        match code {
+            Code::UnusedVariable => self.unused_variables,
+            Code::Shadowing => self.shadowing,
+            Code::UnreachableCode => self.unreachable_code,
+            Code::AssignmentInCondition => self.assignment_in_condition,
+            Code::RemovedCode => self.removed_code,
+            _ => true,
+        }
+    }
+}
+
+/// Compile the source only to find its errors, for tools that work on its code, and return its syntax tree if it has
+/// none. Modules it imports are not needed for that, so they are not loaded.
+pub(crate) fn check(source: &str) -> Result<Program, CompileError> {
+    match analyze(source) {
+        (_, Some(program)) => Ok(program),
+        (diagnostics, None) => Err(CompileError { diagnostics }),
+    }
+}
+
+/// The errors and warnings of the source, like `check`, for editors that show them as the code is written, with the
+/// syntax tree if there are no errors
+pub(crate) fn analyze(source: &str) -> (Vec<Diagnostic>, Option<Program>) {
+    let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let mut functions = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(NoModules));
+    let options = CompilerOptions::default();
+    let compiler = Compiler::for_module(
+        Rc::from(source),
+        &mut interner,
+        &mut functions,
+        &mut modules,
+        FunType::Script,
+        MAIN_MODULE,
+        &options,
+    );
+    let (mut diagnostics, program) = compiler.compile_tree();
+    diagnostics.retain(|diagnostic| diagnostic.code != Code::ModuleNotFound);
+    let has_errors = diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error);
+    (diagnostics, (!has_errors).then_some(program))
+}
+
+/// Name given to anonymous functions
+const LAMBDA_NAME: &str = "<lambda>";
+
+/// Name of the method that is called when creating an instance
+pub const INIT_METHOD: &str = "init";
 
 #[repr(u8)]
 #[derive(Eq, Clone, Copy, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
 // Low to High precedence
 enum Precedence {
     None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,   // =
+    Coalesce,     // ??
+    Or,           // or
+    And,          // and
+    Equality,     // == !=
+    Comparison,   // < > <= >=
+    Union,        // |
+    Intersection, // &
+    Range,        // .. ..=
+    Term,         // + -
+    Factor,       // * /
+    Unary,        // ! -
+    Call,         // . ()
     Primary,
 }
 
-type Parsefn<'src> = fn(&mut Compiler<'src>, bool);
+type Parsefn = for<'src> fn(&mut Compiler<'src>, bool);
 
-struct ParseRule<'src> {
-    prefix: Option<Parsefn<'src>>,
-    infix: Option<Parsefn<'src>>,
+#[derive(Clone, Copy)]
+struct ParseRule {
+    prefix: Option<Parsefn>,
+    infix: Option<Parsefn>,
     precedence: Precedence,
 }
 
 struct Local {
     name: Token,
     depth: isize,
+    is_const: bool,
+    used: bool,         // Whether it is read, for `Warnings::unused_variables`
+    fun: Option<usize>, // Function it always holds, see `named_args`
 }
 
 /// This is a table that, given a token type, lets us find
 /// 1. the function to compile a prefix expression starting with a token of that type,
 /// 2. the function to compile an infix expression whose left operand is followed by a token of that type, and
 /// 3. the precedence of an infix expression that uses that token as an operator.
-fn get_rules<'src>() -> HashMap<TokenType, ParseRule<'src>> {
-    let mut map = HashMap::new();
+///
+/// It is indexed by `TokenType as usize`, and `EOF` is the last token type. Token types without a rule can't start or
+/// continue an expression.
+static RULES: [ParseRule; TokenType::EOF as usize + 1] = {
     use TokenType::*;
 
+    let mut rules = [ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    }; EOF as usize + 1];
+
+    // A method of `Compiler<'src>` only takes compilers with that `'src`, so the table holds closures that take any
+    macro_rules! parse_fn {
+        (None) => {
+            None
+        };
+        (Some $method: path) => {
+            Some(|compiler, can_assign| $method(compiler, can_assign))
+        };
+    }
+
     macro_rules! add_rule {
-        ($map: expr, $tokentype: expr, $prefix: expr, $infix: expr, $precedence: expr) => {
-            $map.insert(
-                $tokentype,
-                ParseRule {
-                    prefix: $prefix,
-                    infix: $infix,
-                    precedence: $precedence,
-                },
-            );
+        ($tokentype: expr, $prefix: ident $(($prefix_fn: path))?, $infix: ident $(($infix_fn: path))?, $precedence: expr) => {
+            rules[$tokentype as usize] = ParseRule {
+                prefix: parse_fn!($prefix $($prefix_fn)?),
+                infix: parse_fn!($infix $($infix_fn)?),
+                precedence: $precedence,
+            };
         };
     }
 
-    add_rule!(map, LeftParen, Some(Compiler::grouping), Some(Compiler::call), Precedence::Call);
-    add_rule!(map, RightParen, None, None, Precedence::None);
-    add_rule!(map, LeftBrace, None, None, Precedence::None);
-    add_rule!(map, RightBrace, None, None, Precedence::None);
-    add_rule!(map, LeftBracket, None, None, Precedence::None);
-    add_rule!(map, RightBracket, None, None, Precedence::None);
-    add_rule!(map, Comma, None, None, Precedence::None);
-    add_rule!(map, Dot, None, None, Precedence::None);
-    add_rule!(map, Minus, Some(Compiler::unary), Some(Compiler::binary), Precedence::Term);
-    add_rule!(map, Plus, None, Some(Compiler::binary), Precedence::Term);
-    add_rule!(map, Semicolon, None, None, Precedence::None);
-    add_rule!(map, Slash, None, Some(Compiler::binary), Precedence::Factor);
-    add_rule!(map, Star, None, Some(Compiler::binary), Precedence::Factor);
-    add_rule!(map, Modulo, None, Some(Compiler::binary), Precedence::Factor);
-    add_rule!(map, Bang, Some(Compiler::unary), None, Precedence::None);
-    add_rule!(map, BangEqual, None, Some(Compiler::binary), Precedence::Equality);
-    add_rule!(map, Equal, None, None, Precedence::None);
-    add_rule!(map, EqualEqual, None, Some(Compiler::binary), Precedence::Equality);
-    add_rule!(map, Greater, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, GreaterEqual, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, Less, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, LessEqual, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, Identifier, Some(Compiler::variable), None, Precedence::None);
-    add_rule!(map, String, Some(Compiler::string), None, Precedence::None);
-    add_rule!(map, Number, Some(Compiler::number), None, Precedence::None);
-    add_rule!(map, And, None, Some(Compiler::and), Precedence::And);
-    add_rule!(map, Class, None, None, Precedence::None);
-    add_rule!(map, Else, None, None, Precedence::None);
-    add_rule!(map, False, Some(Compiler::literal), None, Precedence::None);
-    add_rule!(map, For, None, None, Precedence::None);
-    add_rule!(map, Fun, None, None, Precedence::None);
-    add_rule!(map, If, None, None, Precedence::None);
-    add_rule!(map, Nil, Some(Compiler::literal), None, Precedence::None);
-    add_rule!(map, Or, None, Some(Compiler::or), Precedence::Or);
-    add_rule!(map, Print, None, None, Precedence::None);
-    add_rule!(map, Return, None, None, Precedence::None);
-    add_rule!(map, Super, None, None, Precedence::None);
-    add_rule!(map, This, None, None, Precedence::None);
-    add_rule!(map, True, Some(Compiler::literal), None, Precedence::None);
-    add_rule!(map, Var, None, None, Precedence::None);
-    add_rule!(map, While, None, None, Precedence::None);
-    add_rule!(map, Error, None, None, Precedence::None);
-    add_rule!(map, EOF, None, None, Precedence::None);
-
-    map
-}
+    add_rule!(LeftParen, Some(Compiler::grouping), Some(Compiler::call), Precedence::Call);
+    add_rule!(RightParen, None, None, Precedence::None);
+    add_rule!(LeftBrace, Some(Compiler::map), None, Precedence::None);
+    add_rule!(RightBrace, None, None, Precedence::None);
+    add_rule!(LeftBracket, Some(Compiler::list), Some(Compiler::index), Precedence::Call);
+    add_rule!(RightBracket, None, None, Precedence::None);
+    add_rule!(Comma, None, None, Precedence::None);
+    add_rule!(Dot, None, Some(Compiler::dot), Precedence::Call);
+    add_rule!(Ellipsis, None, None, Precedence::None);
+    add_rule!(QuestionDot, None, Some(Compiler::optional_chain), Precedence::Call);
+    add_rule!(QuestionQuestion, None, Some(Compiler::coalesce), Precedence::Coalesce);
+    add_rule!(DotDot, None, Some(Compiler::binary), Precedence::Range);
+    add_rule!(Pipe, None, Some(Compiler::binary), Precedence::Union);
+    add_rule!(Ampersand, None, Some(Compiler::binary), Precedence::Intersection);
+    add_rule!(DotDotEqual, None, Some(Compiler::binary), Precedence::Range);
+    add_rule!(Colon, None, None, Precedence::None);
+    add_rule!(Arrow, None, None, Precedence::None);
+    add_rule!(Minus, Some(Compiler::unary), Some(Compiler::binary), Precedence::Term);
+    add_rule!(Plus, None, Some(Compiler::binary), Precedence::Term);
+    add_rule!(Semicolon, None, None, Precedence::None);
+    add_rule!(Slash, None, Some(Compiler::binary), Precedence::Factor);
+    add_rule!(Star, None, Some(Compiler::binary), Precedence::Factor);
+    add_rule!(Modulo, None, Some(Compiler::binary), Precedence::Factor);
+    add_rule!(Bang, Some(Compiler::unary), None, Precedence::None);
+    add_rule!(BangEqual, None, Some(Compiler::binary), Precedence::Equality);
+    add_rule!(Equal, None, None, Precedence::None);
+    add_rule!(EqualEqual, None, Some(Compiler::binary), Precedence::Equality);
+    add_rule!(Greater, None, Some(Compiler::binary), Precedence::Comparison);
+    add_rule!(GreaterEqual, None, Some(Compiler::binary), Precedence::Comparison);
+    add_rule!(Less, None, Some(Compiler::binary), Precedence::Comparison);
+    add_rule!(LessEqual, None, Some(Compiler::binary), Precedence::Comparison);
+    add_rule!(Identifier, Some(Compiler::variable), None, Precedence::None);
+    add_rule!(String, Some(Compiler::string), None, Precedence::None);
+    add_rule!(Number, Some(Compiler::number), None, Precedence::None);
+    add_rule!(And, None, Some(Compiler::and), Precedence::And);
+    add_rule!(Class, None, None, Precedence::None);
+    add_rule!(Else, None, None, Precedence::None);
+    add_rule!(False, Some(Compiler::literal), None, Precedence::None);
+    add_rule!(For, None, None, Precedence::None);
+    add_rule!(Fun, Some(Compiler::lambda), None, Precedence::None);
+    add_rule!(If, None, None, Precedence::None);
+    add_rule!(Nil, Some(Compiler::literal), None, Precedence::None);
+    add_rule!(Or, None, Some(Compiler::or), Precedence::Or);
+    add_rule!(Print, None, None, Precedence::None);
+    add_rule!(Return, None, None, Precedence::None);
+    add_rule!(Super, None, None, Precedence::None);
+    add_rule!(This, Some(Compiler::this), None, Precedence::None);
+    add_rule!(True, Some(Compiler::literal), None, Precedence::None);
+    add_rule!(Var, None, None, Precedence::None);
+    add_rule!(While, None, None, Precedence::None);
+    add_rule!(Switch, None, None, Precedence::None);
+    add_rule!(Case, None, None, Precedence::None);
+    add_rule!(Default, None, None, Precedence::None);
+    add_rule!(Try, None, None, Precedence::None);
+    add_rule!(Catch, None, None, Precedence::None);
+    add_rule!(Finally, None, None, Precedence::None);
+    add_rule!(Throw, None, None, Precedence::None);
+    add_rule!(Yield, None, None, Precedence::None);
+    add_rule!(Const, None, None, Precedence::None);
+    add_rule!(Static, None, None, Precedence::None);
+    add_rule!(Enum, None, None, Precedence::None);
+    add_rule!(In, None, Some(Compiler::binary), Precedence::Comparison);
+    add_rule!(Import, None, None, Precedence::None);
+    add_rule!(Export, None, None, Precedence::None);
+    add_rule!(As, None, None, Precedence::None);
+    add_rule!(Error, None, None, Precedence::None);
+    add_rule!(EOF, None, None, Precedence::None);
+
+    rules
+};
 
+/// The next higher precedence, for the right operand of a left-associative operator
 fn increment_prec(prec: Precedence) -> Precedence {
-    (prec as u8 + 1).try_into().unwrap()
+    (prec as u8 + 1).try_into().unwrap_or(Precedence::Primary)
 }
 
 struct Parser {
@@ -119,6 +262,7 @@ struct Parser {
     pub previous: Token,
     pub had_error: bool,
     pub panic_mode: bool,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
@@ -129,49 +273,64 @@ impl Parser {
             previous: Token::new(),
             had_error: false,
             panic_mode: false,
+            diagnostics: Vec::new(),
         }
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(true, message);
+    fn error_at_current(&mut self, code: Code, message: &str) {
+        self.error_at(true, code, message);
     }
 
-    fn error_at_previous(&mut self, message: &str) {
-        self.error_at(false, message);
+    fn error_at_previous(&mut self, code: Code, message: &str) {
+        self.error_at(false, code, message);
     }
 
-    fn error_at(&mut self, current: bool, message: &str) {
-        let current = if current { &self.current } else { &self.previous };
+    fn error_at(&mut self, current: bool, code: Code, message: &str) {
+        let token = if current { &self.current } else { &self.previous };
 
         if self.panic_mode {
             return;
         }
 
         self.panic_mode = true;
-        xprint!(" [line {}] Error", current.line);
-
-        if current.typ == TokenType::EOF {
-            xprint!(" at end");
-        } else if current.typ == TokenType::Error {
-            // Nothing.
-        } else {
-            eprint!(" at '{}'", current.source);
-        }
-
-        xprintln!(": {}", message);
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            notes: Vec::new(),
+            file: None,
+        });
         self.had_error = true;
     }
 
+    /// Report a warning, or an error of a lint, about the token. These don't stop compilation, so they are reported even
+    /// after an error.
+    fn report_at(&mut self, token: &Token, severity: Severity, code: Code, message: String, notes: Vec<String>) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            code,
+            message,
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            notes,
+            file: None,
+        });
+    }
+
     fn consume(&mut self, typ: TokenType, message: &str) {
         if self.current.typ == typ {
             self.advance();
             return;
         }
 
-        self.error_at_current(message);
+        self.error_at_current(Code::UnexpectedToken, message);
     }
 
-    fn check_tt(&mut self, typ: TokenType) -> bool {
+    fn check_tt(&self, typ: TokenType) -> bool {
         self.current.typ == typ
     }
 
@@ -190,31 +349,53 @@ impl Parser {
 
         loop {
             self.current = self.scanner.scan_token();
-            dbgln!("Current token: {}", self.current.typ);
             if self.current.typ != TokenType::Error {
                 break;
             }
 
             let current_source: Rc<str> = self.current.source.clone();
-            self.error_at_current(current_source.as_ref());
+            self.error_at_current(Code::InvalidToken, current_source.as_ref());
         }
     }
 
-    fn synchronize(&mut self) {
+    /// Skip to where the next statement or member of a class starts after an error, so that the errors after it are
+    /// reported too. Blocks in the code that is skipped are skipped whole. In a block, the `}` that ends it ends the
+    /// statement as well.
+    fn synchronize(&mut self, in_block: bool) {
         self.panic_mode = false;
 
+        let mut depth = 0;
         while self.current.typ != TokenType::EOF {
-            if self.previous.typ == TokenType::Semicolon {
+            if depth == 0 && matches!(self.previous.typ, TokenType::Semicolon | TokenType::RightBrace) {
                 return;
             }
 
             match self.current.typ {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth == 0 && in_block => return,
+                TokenType::RightBrace if depth > 0 => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                _ if depth > 0 => {}
                 TokenType::Class
+                | TokenType::Enum
+                | TokenType::Record
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Switch
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::Yield
+                | TokenType::Import
+                | TokenType::Export
                 | TokenType::Print
                 | TokenType::Return => {
                     return;
@@ -227,22 +408,121 @@ impl Parser {
     }
 }
 
+/// A program compiled with `Compiler::compile_program`, whose script runs the declarations of the program
+pub struct CompiledProgram {
+    pub script: Fun,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// An expression compiled with `Compiler::compile_expression`, whose script returns the value of the expression
+pub struct CompiledExpression {
+    pub script: Fun,
+    pub warnings: Vec<Diagnostic>,
+}
+
 pub struct Compiler<'src> {
     fun: Fun,
     fun_typ: FunType,
     parser: Parser,
     interner: &'src mut Interner,
-    rules: HashMap<TokenType, ParseRule<'src>>,
+    options: CompilerOptions,
     locals: Vec<Local>,
     scope_depth: isize,
     functions: &'src mut Vec<Fun>,
+    modules: &'src mut ModuleRegistry,
+    module: usize,                               // Module whose code is being compiled
+    echo: bool,                                  // Whether a trailing expression statement at the top level is the result of the script
+    call_end: usize,                             // Offset right after the last `Call`, to find calls in tail position
+    far_jumps: Vec<(usize, usize)>,              // Jumps too far for 16 bits, by where their offset is and where they go, see `long_jump`
+    expression_depth: usize,                     // Number of expressions being compiled, one in the other, in this function
+    assignment: Option<(usize, Token)>,          // Expression depth and `=` of the last assignment, to find ones in conditions
+    operand_start: usize,                        // Offset of the code of the left operand of the infix expression being compiled
+    known_functions: KnownFunctions,             // Functions that names of the module always hold, see `named_args`
+    known_callee: Option<(usize, usize, usize)>, // Code of the last read of a name that holds a known function, and the function
+    tree: Option<TreeBuilder>,                   // Syntax tree of the code, if it is compiled for tools, see `analyze`
 }
 
 impl<'src> Compiler<'src> {
-    pub fn compile(source: Rc<str>, interner: &mut Interner, functions: &'src mut Vec<Fun>, fun_typ: FunType) -> Result<Fun> {
+    pub fn compile(
+        source: Rc<str>,
+        interner: &mut Interner,
+        functions: &'src mut Vec<Fun>,
+        modules: &'src mut ModuleRegistry,
+        fun_typ: FunType,
+    ) -> Result<Fun, CompileError> {
+        let compiled = Compiler::compile_with_options(source, interner, functions, modules, fun_typ, &CompilerOptions::default());
+        compiled.map(|(fun, _)| fun)
+    }
+
+    /// Like `compile`, with options for how the program is compiled. Modules it imports are compiled the same way.
+    /// The program is returned with the warnings about it, and if it doesn't compile, the warnings are in the error.
+    pub fn compile_with_options(
+        source: Rc<str>,
+        interner: &mut Interner,
+        functions: &'src mut Vec<Fun>,
+        modules: &'src mut ModuleRegistry,
+        fun_typ: FunType,
+        options: &CompilerOptions,
+    ) -> Result<(Fun, Vec<Diagnostic>), CompileError> {
+        Compiler::for_module(source, interner, functions, modules, fun_typ, MAIN_MODULE, options).finish()
+    }
+
+    /// Compile a program, a list of declarations up to the end of the source, as the script of the main module
+    pub fn compile_program(
+        source: Rc<str>,
+        interner: &mut Interner,
+        functions: &'src mut Vec<Fun>,
+        modules: &'src mut ModuleRegistry,
+        options: &CompilerOptions,
+    ) -> Result<CompiledProgram, CompileError> {
+        let compiler = Compiler::for_module(source, interner, functions, modules, FunType::Script, MAIN_MODULE, options);
+        let (script, warnings) = compiler.finish()?;
+        Ok(CompiledProgram { script, warnings })
+    }
+
+    /// Compile a single expression, like `1 + 2 * x`, which must be all of the source, for calculators and REPLs that
+    /// only take expressions. The script returns its value, and globals it uses are those of the main module.
+    pub fn compile_expression(
+        source: Rc<str>,
+        interner: &mut Interner,
+        functions: &'src mut Vec<Fun>,
+        modules: &'src mut ModuleRegistry,
+        options: &CompilerOptions,
+    ) -> Result<CompiledExpression, CompileError> {
+        let compiler = Compiler::for_module(source, interner, functions, modules, FunType::Script, MAIN_MODULE, options);
+        let (script, warnings) = Compiler::fail_on_errors(compiler.compile_expression_source())?;
+        Ok(CompiledExpression { script, warnings })
+    }
+
+    /// Compile a snippet of a REPL or notebook, like `compile`.
+    /// If the snippet ends with an expression statement, the script returns its value. The last `;` is optional.
+    pub fn compile_snippet(
+        source: Rc<str>,
+        interner: &mut Interner,
+        functions: &'src mut Vec<Fun>,
+        modules: &'src mut ModuleRegistry,
+    ) -> Result<Fun, CompileError> {
+        let options = CompilerOptions::default();
+        let mut compiler = Compiler::for_module(source, interner, functions, modules, FunType::Script, MAIN_MODULE, &options);
+        compiler.echo = true;
+        // Later snippets can declare the functions of this one again
+        compiler.known_functions.rebound = None;
+        compiler.finish().map(|(fun, _)| fun)
+    }
+
+    /// Compiler for the top-level code of a module
+    fn for_module(
+        source: Rc<str>,
+        interner: &'src mut Interner,
+        functions: &'src mut Vec<Fun>,
+        modules: &'src mut ModuleRegistry,
+        fun_typ: FunType,
+        module: usize,
+        options: &CompilerOptions,
+    ) -> Compiler<'src> {
+        let known_functions = KnownFunctions::for_module(&source, interner);
         let scanner: Scanner = Scanner::new(source);
         let parser = Parser::new(scanner);
-        let rules = get_rules();
 
         let locals = Vec::new();
 
@@ -252,25 +532,78 @@ impl<'src> Compiler<'src> {
         //     depth: 0,
         // });
 
-        let mut compiler = Compiler {
+        Compiler {
             fun: Fun::new(),
             fun_typ,
             parser,
             interner,
-            rules,
+            options: options.clone(),
             locals,
             scope_depth: 0,
             functions,
-        };
+            modules,
+            module,
+            echo: false,
+            call_end: 0,
+            far_jumps: Vec::new(),
+            expression_depth: 0,
+            assignment: None,
+            operand_start: 0,
+            known_functions,
+            known_callee: None,
+            tree: None,
+        }
+    }
+
+    /// Compile the code up to the end of the source, and return it with the problems found
+    fn compile_declarations(mut self) -> (Fun, Vec<Diagnostic>) {
+        let fun = self.compile_to_end();
+        (fun, self.parser.diagnostics)
+    }
 
-        dbgln!("== Parser (Scan on demand) ==");
+    /// Compile the code like `compile_declarations`, and return the syntax tree that is built as it is parsed instead
+    fn compile_tree(mut self) -> (Vec<Diagnostic>, Program) {
+        self.tree = Some(TreeBuilder::default());
+        self.compile_to_end();
+        (self.parser.diagnostics, self.tree.unwrap_or_default().program())
+    }
 
-        compiler.parser.advance();
-        while !compiler.parser.match_tt(TokenType::EOF) {
-            compiler.declaration();
+    fn compile_to_end(&mut self) -> Fun {
+        self.parser.advance();
+        while !self.parser.match_tt(TokenType::EOF) {
+            self.declaration();
         }
+        self.end()
+    }
 
-        Ok(compiler.end())
+    /// Compile the expression that is all of the source, returning its value, and return it with the problems found
+    fn compile_expression_source(mut self) -> (Fun, Vec<Diagnostic>) {
+        self.parser.advance();
+        let source_start = self.parser.current.span.start;
+        self.expression();
+        self.parser.consume(TokenType::EOF, "Expect end of expression");
+        self.emit_byte(Opcode::Return as u8);
+        let span = Span {
+            start: source_start,
+            end: self.parser.previous.span.end,
+        };
+        self.fun.chunk.set_statement_span(0, span);
+
+        let fun = self.end();
+        (fun, self.parser.diagnostics)
+    }
+
+    /// Compile the code, with the warnings about it, which fails if there are errors
+    fn finish(self) -> Result<(Fun, Vec<Diagnostic>), CompileError> {
+        Compiler::fail_on_errors(self.compile_declarations())
+    }
+
+    /// The compiled code with the warnings about it, or the diagnostics if there are errors
+    fn fail_on_errors((fun, diagnostics): (Fun, Vec<Diagnostic>)) -> Result<(Fun, Vec<Diagnostic>), CompileError> {
+        match diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error) {
+            true => Err(CompileError { diagnostics }),
+            false => Ok((fun, diagnostics)),
+        }
     }
 
     fn line(&self) -> usize {
@@ -278,22 +611,76 @@ impl<'src> Compiler<'src> {
     }
 
     fn end(&mut self) -> Fun {
-        self.emit_return();
-
-        #[cfg(feature = "print_code")]
-        if !self.parser.had_error {
-            let name = if self.fun_typ == FunType::Script {
-                "script"
-            } else if let Some(fn_name) = self.fun.name {
-                self.interner.lookup(&fn_name)
-            } else {
-                "unnamed"
-            };
-
-            self.fun.chunk.disassemble(name, self.interner);
+        // Instructions of the return at the end
+        let implicit = match self.fun_typ {
+            FunType::Module => {
+                // Importing a module evaluates to the module itself
+                self.emit_constant(Value::Module(self.module));
+                self.emit_byte(Opcode::Return as u8);
+                2
+            }
+            FunType::Initializer => {
+                self.emit_return();
+                3
+            }
+            _ => {
+                self.emit_return();
+                2
+            }
+        };
+        self.fun.module = self.module;
+        if !self.far_jumps.is_empty() && !long_jump::widen(&mut self.fun, &self.far_jumps) {
+            self.parser.error_at_previous(Code::LimitExceeded, "Too much code to jump over");
+        }
+        if self.options.opt_level >= 1 && !self.parser.had_error {
+            self.optimize(implicit);
+        }
+        // Lowered before the superinstructions are fused, which the register backend doesn't know
+        if self.options.backend == Backend::Register {
+            self.fun.register = register::lower(&self.fun, &self.fun_typ).map(Rc::new);
         }
+        superinstruction::fuse(&mut self.fun.chunk);
 
-        std::mem::replace(&mut self.fun, Fun::new())
+        std::mem::take(&mut self.fun)
+    }
+
+    /// Remove the code that can never run and optimize the rest, reporting what was removed if the warning is enabled
+    fn optimize(&mut self, implicit: usize) {
+        let Some(lines) = dead_code::eliminate(&mut self.fun, implicit) else {
+            self.parser.error_at_previous(Code::LimitExceeded, "Too much code to jump over");
+            return;
+        };
+        if self.options.warnings.removed_code {
+            // Code after a `return` was already reported where it is compiled
+            let reported: HashSet<usize> = self
+                .parser
+                .diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.code == Code::UnreachableCode)
+                .map(|diagnostic| diagnostic.line)
+                .collect();
+            for line in lines.into_iter().filter(|line| !reported.contains(line)) {
+                let (column, span) = self.parser.scanner.line_span(line);
+                let token = Token {
+                    typ: TokenType::Error,
+                    source: Rc::from(""),
+                    line,
+                    column,
+                    span,
+                };
+                let notes = vec!["It is removed when compiling with an optimization level".to_string()];
+                self.parser.report_at(
+                    &token,
+                    Severity::Warning,
+                    Code::RemovedCode,
+                    "Unreachable code removed".to_string(),
+                    notes,
+                );
+            }
+        }
+        if !peephole::optimize(&mut self.fun) {
+            self.parser.error_at_previous(Code::LimitExceeded, "Too much code to jump over");
+        }
     }
 
     fn begin_scope(&mut self) {
@@ -305,18 +692,106 @@ impl<'src> Compiler<'src> {
 
         while !self.locals.is_empty() && self.locals.last().unwrap().depth > self.scope_depth {
             self.emit_byte(Opcode::Pop as u8);
-            self.locals.pop();
+            let local = self.locals.pop().unwrap();
+            self.warn_if_unused(&local);
+            self.end_local_variable(self.locals.len());
         }
     }
 
-    fn get_rule(&self, token_type: TokenType) -> &ParseRule<'src> {
-        self.rules.get(&token_type).unwrap()
+    fn warn_if_unused(&mut self, local: &Local) {
+        if !local.used && !local.name.source.starts_with('_') {
+            let message = format!("Unused variable '{}'", local.name.source);
+            self.warning(&local.name, Code::UnusedVariable, message, Vec::new());
+        }
+    }
+
+    fn warning(&mut self, token: &Token, code: Code, message: String, notes: Vec<String>) {
+        if self.options.warnings.enabled(code) {
+            self.parser.report_at(token, Severity::Warning, code, message, notes);
+        }
+    }
+
+    fn lint(&mut self, token: &Token, code: Code, message: String) {
+        let lints = &self.options.lints;
+        let level = match code {
+            Code::Naming => lints.naming,
+            Code::EmptyBlock => lints.empty_blocks,
+            Code::ConstantCondition => lints.constant_conditions,
+            Code::DeepNesting => lints.deep_nesting,
+            _ => Level::Warn,
+        };
+        match level {
+            Level::Allow => {}
+            Level::Warn => self.parser.report_at(token, Severity::Warning, code, message, Vec::new()),
+            Level::Deny => self.parser.report_at(token, Severity::Error, code, message, Vec::new()),
+        }
+    }
+
+    fn lint_name(&mut self, token: &Token, kind: NameKind) {
+        if token.typ != TokenType::Identifier {
+            return;
+        }
+        if let Some(convention) = lint::naming_problem(&token.source, kind) {
+            self.lint(token, Code::Naming, format!("'{}' should be in {convention}", token.source));
+        }
+    }
+
+    /// Add nodes to the syntax tree, if it is being built, given the end of the last token
+    fn tree(&mut self, build: impl FnOnce(&mut TreeBuilder, usize)) {
+        if let Some(tree) = &mut self.tree {
+            build(tree, self.parser.previous.span.end);
+        }
+    }
+
+    /// Marks of the expressions and statements parsed from now on, see `TreeBuilder::marks`
+    fn tree_marks(&self) -> (usize, usize) {
+        self.tree.as_ref().map_or((0, 0), TreeBuilder::marks)
+    }
+
+    /// Statements parsed since the mark, if the syntax tree is being built
+    fn tree_statements(&mut self, mark: usize) -> Vec<Stmt> {
+        self.tree.as_mut().map_or_else(Vec::new, |tree| tree.stmts_from(mark))
+    }
+
+    /// Add the node of the token that was just parsed as an expression
+    fn leaf_node(&mut self, kind: ExprKind) {
+        let span = self.parser.previous.span;
+        self.tree(|tree, _| tree.push(kind, span.start, span.end));
+    }
+
+    /// Add the node of a binary expression whose operands were just parsed
+    fn binary_node(&mut self, operator: TokenType) {
+        self.tree(|tree, end| {
+            let right = tree.pop();
+            let left = tree.pop();
+            let start = left.span.start;
+            let operator = BinaryOperator::from_token(operator).unwrap_or(BinaryOperator::Add);
+            tree.push(ExprKind::Binary { operator, left, right }, start, end);
+        });
+    }
+
+    /// Make the expression that was just parsed, after `...` at the offset, spread
+    fn spread_node(&mut self, start: usize) {
+        self.tree(|tree, end| {
+            let value = tree.pop();
+            tree.push(ExprKind::Spread(value), start, end);
+        });
+    }
+
+    fn get_rule(&self, token_type: TokenType) -> &'static ParseRule {
+        &RULES[token_type as usize]
     }
 
     fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.typ;
         let rule = self.get_rule(operator_type);
+        let left_start = self.operand_start;
+        let right_start = self.fun.chunk.code.len();
         self.parse_precedence(increment_prec(rule.precedence));
+        self.binary_node(operator_type);
+        if self.fold_binary(operator_type, left_start, right_start) {
+            return;
+        }
 
         match operator_type {
             TokenType::Plus => self.emit_byte(Opcode::Add as u8),
@@ -330,24 +805,89 @@ impl<'src> Compiler<'src> {
             TokenType::GreaterEqual => self.emit_bytes(Opcode::Less as u8, Opcode::Not as u8),
             TokenType::Less => self.emit_byte(Opcode::Less as u8),
             TokenType::LessEqual => self.emit_bytes(Opcode::Greater as u8, Opcode::Not as u8),
+            TokenType::In => self.emit_byte(Opcode::In as u8),
+            TokenType::Pipe => self.emit_byte(Opcode::Union as u8),
+            TokenType::Ampersand => self.emit_byte(Opcode::Intersection as u8),
+            TokenType::DotDot => self.emit_byte(Opcode::Range as u8),
+            TokenType::DotDotEqual => self.emit_byte(Opcode::RangeInclusive as u8),
             _ => (),
         }
     }
 
     fn call(&mut self, _can_assign: bool) {
-        let arg_count = self.argument_list();
-        self.emit_bytes(Opcode::Call as u8, arg_count);
+        // Taken before the arguments, which can read other names
+        let callee = (self.operand_start, self.fun.chunk.code.len());
+        let known = self
+            .known_callee
+            .filter(|&(start, end, _)| (start, end) == callee)
+            .map(|(_, _, fun)| fun);
+        let (arg_count, named, has_spread) = self.argument_list();
+
+        if has_spread {
+            // The number of arguments is only known at runtime
+            self.emit_bytes(Opcode::CallSpread as u8, arg_count);
+        } else if named.is_empty() || self.order_named_arguments(known, arg_count, &named) {
+            self.emit_bytes(Opcode::Call as u8, arg_count);
+            self.call_end = self.fun.chunk.code.len();
+        } else {
+            // Named arguments are matched to parameters by the VM, since the callee is only known at runtime
+            let names = named.into_iter().map(|(name, _)| Value::Str(name)).collect();
+            let names_idx = self.make_constant(Value::Array(Rc::new(RefCell::new(names))));
+            match names_idx {
+                0..=0xff => {
+                    self.emit_bytes(Opcode::CallNamed as u8, arg_count);
+                    self.emit_byte(names_idx as u8);
+                }
+                _ => {
+                    self.emit_bytes(Opcode::CallNamedLong as u8, arg_count);
+                    self.emit_u24(names_idx);
+                }
+            }
+        }
     }
 
-    fn argument_list(&mut self) -> u8 {
+    /// Returns the number of arguments, the names of the named arguments (which follow the positional ones) with the
+    /// offset where the code of each one starts, and whether any argument is spread, like `f(...args)`.
+    /// The call, whose callee was parsed before, is added to the syntax tree.
+    fn argument_list(&mut self) -> (u8, Vec<(StrId, usize)>, bool) {
         let mut arg_count = 0;
+        let mut named: Vec<(StrId, usize)> = Vec::new();
+        let mut has_spread = false;
+        let mark = self.tree_marks().0;
+        let mut names = Vec::new(); // Of the arguments in the syntax tree
 
         if !self.parser.check_tt(TokenType::RightParen) {
             loop {
-                self.expression();
+                let is_named = self.is_named_argument();
+                if self.tree.is_some() {
+                    names.push(is_named.then(|| Name::new(&self.parser.current)));
+                }
+                if is_named {
+                    self.parser.advance();
+                    let name = self.interner.intern(self.parser.previous.source.as_ref());
+                    if named.iter().any(|&(other, _)| other == name) {
+                        self.parser.error_at_previous(Code::Duplicate, "Duplicate named argument");
+                    }
+                    named.push((name, self.fun.chunk.code.len()));
+                    self.parser.consume(TokenType::Colon, "Expect ':' after argument name");
+                } else if !named.is_empty() {
+                    self.parser
+                        .error_at_current(Code::InvalidArguments, "Positional arguments can't follow named arguments");
+                }
+
+                if self.parser.match_tt(TokenType::Ellipsis) {
+                    has_spread = true;
+                    let start = self.parser.previous.span.start;
+                    self.expression();
+                    self.spread_node(start);
+                    self.emit_byte(Opcode::Spread as u8);
+                } else {
+                    self.expression();
+                }
 
                 if arg_count == 255 {
-                    self.parser.error_at_previous("Can't have more than 255 arguments.");
+                    self.parser
+                        .error_at_previous(Code::LimitExceeded, "Can't have more than 255 arguments.");
                 }
 
                 arg_count += 1;
@@ -358,11 +898,79 @@ impl<'src> Compiler<'src> {
             }
         }
 
+        if has_spread && !named.is_empty() {
+            self.parser
+                .error_at_previous(Code::InvalidArguments, "Spread arguments can't be combined with named arguments");
+        }
+
         self.parser.consume(TokenType::RightParen, "Expect ')' after arguments.");
-        arg_count
+        self.tree(|tree, end| {
+            let values = tree.exprs_from(mark);
+            let arguments = names
+                .into_iter()
+                .zip(values)
+                .map(|(name, value)| ast::Argument { name, value })
+                .collect();
+            let callee = tree.pop();
+            let start = callee.span.start;
+            tree.push(ExprKind::Call { callee, arguments }, start, end);
+        });
+        (arg_count, named, has_spread)
+    }
+
+    /// Put the named arguments of a call in the order of the parameters of the function it calls, if the callee reads a
+    /// known function, see `named_args`, and the call can be a plain `Call`. Returns whether they were.
+    fn order_named_arguments(&mut self, known: Option<usize>, arg_count: u8, named: &[(StrId, usize)]) -> bool {
+        let Some(fun) = known else {
+            return false;
+        };
+        let names: Vec<StrId> = named.iter().map(|&(name, _)| name).collect();
+        let Some(params) = named_args::parameters(&self.functions[fun], arg_count as usize, &names) else {
+            return false;
+        };
+        if params.is_sorted() {
+            return true;
+        }
+
+        // Moving an argument changes when it is evaluated, which only literals and variables can't tell
+        let end = self.fun.chunk.code.len();
+        let ends = named.iter().skip(1).map(|&(_, start)| start).chain([end]);
+        let ranges: Vec<(usize, usize)> = named.iter().map(|&(_, start)| start).zip(ends).collect();
+        if !ranges
+            .iter()
+            .all(|&(start, end)| self.constant_at(start, end).is_some() || self.local_at(start, end))
+        {
+            return false;
+        }
+
+        let chunk = &mut self.fun.chunk;
+        let moved: Vec<(Vec<u8>, usize)> = ranges
+            .iter()
+            .map(|&(start, end)| (chunk.code[start..end].to_vec(), chunk.line_at(start)))
+            .collect();
+        chunk.truncate(ranges[0].0);
+        let mut order: Vec<usize> = (0..named.len()).collect();
+        order.sort_by_key(|&arg| params[arg]);
+        for arg in order {
+            let (code, line) = &moved[arg];
+            for &byte in code {
+                chunk.write_byte(byte, *line);
+            }
+        }
+        true
+    }
+
+    /// Looks ahead to check if the next argument is a named one, like `x: 10`
+    fn is_named_argument(&self) -> bool {
+        self.parser.check_tt(TokenType::Identifier) && self.parser.scanner.clone().scan_token().typ == TokenType::Colon
     }
 
     fn literal(&mut self, _can_assign: bool) {
+        self.leaf_node(match self.parser.previous.typ {
+            TokenType::True => ExprKind::Bool(true),
+            TokenType::False => ExprKind::Bool(false),
+            _ => ExprKind::Nil,
+        });
         match self.parser.previous.typ {
             TokenType::False => self.emit_byte(Opcode::False as u8),
             TokenType::Nil => self.emit_byte(Opcode::Nil as u8),
@@ -372,12 +980,63 @@ impl<'src> Compiler<'src> {
     }
 
     fn expression(&mut self) {
+        self.expression_depth += 1;
         self.parse_precedence(Precedence::Assignment);
+        self.expression_depth -= 1;
+    }
+
+    /// Compile the condition of an `if` or a loop. An assignment there is usually meant to be a comparison, unless it is
+    /// in parentheses.
+    fn condition(&mut self, is_loop: bool) {
+        self.assignment = None;
+        let start = self.parser.current.clone();
+        self.expression();
+        let is_literal = matches!(
+            start.typ,
+            TokenType::True | TokenType::False | TokenType::Nil | TokenType::Number | TokenType::String
+        );
+        // `while (true)` is how loops that end with a `return` or a `throw` are written
+        if is_literal && self.parser.previous.span == start.span && !(is_loop && start.typ == TokenType::True) {
+            self.lint(&start, Code::ConstantCondition, format!("The condition is always {}", start.source));
+        }
+        if let Some((depth, equal)) = self.assignment.take() {
+            if depth == self.expression_depth + 1 {
+                let message = "Assignment in a condition, which may be meant to be '=='".to_string();
+                self.warning(&equal, Code::AssignmentInCondition, message, Vec::new());
+            }
+        }
+    }
+
+    /// Note that an assignment was just compiled, see `condition`
+    fn assigned(&mut self, equal: Token) {
+        self.assignment = Some((self.expression_depth, equal));
     }
 
     fn block(&mut self) {
+        let brace = self.parser.previous.clone();
+        let is_function_body = !matches!(self.fun_typ, FunType::Script | FunType::Module) && self.scope_depth == 1;
+        if self.parser.check_tt(TokenType::RightBrace) && !is_function_body {
+            self.lint(&brace, Code::EmptyBlock, "Empty block".to_string());
+        }
+        if self.scope_depth as usize == self.options.lints.max_nesting + 1 {
+            let message = format!("Blocks are nested more than {} deep", self.options.lints.max_nesting);
+            self.lint(&brace, Code::DeepNesting, message);
+        }
+
+        let mut returned = false;
         while !self.parser.check_tt(TokenType::RightBrace) && !self.parser.check_tt(TokenType::EOF) {
+            if returned {
+                let token = self.parser.current.clone();
+                self.warning(
+                    &token,
+                    Code::UnreachableCode,
+                    "Unreachable code after 'return'".to_string(),
+                    Vec::new(),
+                );
+            }
+            let is_return = self.parser.check_tt(TokenType::Return);
             self.declaration();
+            returned = is_return;
         }
 
         self.parser.consume(TokenType::RightBrace, "Expect '}' after block");
@@ -385,6 +1044,23 @@ impl<'src> Compiler<'src> {
 
     fn function(&mut self, typ: FunType) {
         let name = Some(self.interner.intern(self.parser.previous.source.as_ref()));
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after function name");
+        self.function_body(name, typ, false);
+    }
+
+    /// Compiles the parameter list (after the opening parenthesis) and the body of a function,
+    /// and emits the function as a constant.
+    /// Arrow functions have a single expression (or a block) as their body.
+    fn function_body(&mut self, name: Option<StrId>, typ: FunType, is_arrow: bool) {
+        let fun = self.compile_function(name, typ, is_arrow);
+        self.emit_constant(Value::Function(fun));
+    }
+
+    /// Compiles a function like `function_body`, and returns its index in the function list without emitting it.
+    /// Tests have no parameter list, only a block.
+    /// The function is added to the syntax tree as an expression without a name, which the declaration it is in takes.
+    fn compile_function(&mut self, name: Option<StrId>, typ: FunType, is_arrow: bool) -> usize {
+        let start = self.parser.previous.span.start;
         let dummy_parser = Parser::new(Scanner::new(Rc::from("")));
 
         let mut fn_compiler = Compiler {
@@ -392,72 +1068,638 @@ impl<'src> Compiler<'src> {
             fun_typ: typ,
             parser: std::mem::replace(&mut self.parser, dummy_parser),
             interner: self.interner,
-            rules: get_rules(),
+            options: self.options.clone(),
             locals: Vec::new(),
             scope_depth: 0,
             functions: self.functions,
+            modules: self.modules,
+            module: self.module,
+            echo: false,
+            call_end: 0,
+            far_jumps: Vec::new(),
+            expression_depth: 0,
+            assignment: None,
+            operand_start: 0,
+            known_functions: std::mem::take(&mut self.known_functions),
+            known_callee: None,
+            tree: self.tree.take(),
         };
 
         fn_compiler.fun.name = name;
         fn_compiler.begin_scope();
-        fn_compiler.parser.consume(TokenType::LeftParen, "Expect '(' after function name");
-        if !fn_compiler.parser.check_tt(TokenType::RightParen) {
+
+        if fn_compiler.fun_typ == FunType::Method || fn_compiler.fun_typ == FunType::Initializer {
+            fn_compiler.fun.is_method = true;
+            fn_compiler.add_hidden_local("this");
+        }
+
+        let mut params = Vec::new();
+        if fn_compiler.fun_typ != FunType::Test {
+            params = fn_compiler.parameter_list();
+        }
+
+        if is_arrow {
+            fn_compiler
+                .parser
+                .consume(TokenType::Arrow, "Expect '->' after arrow function parameters");
+        }
+
+        let mark = fn_compiler.tree_marks().1;
+        let is_expression = is_arrow && !fn_compiler.parser.match_tt(TokenType::LeftBrace);
+        if is_expression {
+            fn_compiler.expression();
+            fn_compiler.emit_tail_return();
+        } else {
+            if fn_compiler.fun_typ == FunType::Test {
+                fn_compiler.parser.consume(TokenType::LeftBrace, "Expect '{' before test body");
+            } else if !is_arrow {
+                fn_compiler.parser.consume(TokenType::LeftBrace, "Expect '{' before function body");
+            }
+            fn_compiler.block();
+        }
+        fn_compiler.tree(|tree, end| {
+            let body = match is_expression {
+                true => FunctionBody::Expression(tree.pop()),
+                false => FunctionBody::Block(tree.stmts_from(mark)),
+            };
+            let span = Span { start, end };
+            let function = Function {
+                name: None,
+                params,
+                body,
+                span,
+            };
+            tree.push(ExprKind::Function(Box::new(function)), start, end);
+        });
+
+        // The scope of the function isn't ended, since returning pops its locals
+        for local in std::mem::take(&mut fn_compiler.locals) {
+            fn_compiler.warn_if_unused(&local);
+        }
+        let fun = fn_compiler.end();
+
+        fn_compiler.functions.push(fun);
+        _ = std::mem::replace(&mut self.parser, fn_compiler.parser);
+        self.known_functions = fn_compiler.known_functions;
+        self.tree = fn_compiler.tree;
+        self.functions.len() - 1
+    }
+
+    /// Compiles the parameters of the function being compiled, up to and including the closing parenthesis, and returns
+    /// them if the syntax tree is being built
+    fn parameter_list(&mut self) -> Vec<Param> {
+        let mut params = Vec::new();
+        // Default values are compiled into the function prologue, and only run if the argument was not passed
+        let mut has_default = false;
+        if !self.parser.check_tt(TokenType::RightParen) {
             loop {
-                fn_compiler.fun.arity += 1;
-                if fn_compiler.fun.arity > 255 {
-                    fn_compiler.parser.error_at_current("Can't have more than 255 parameters");
+                self.fun.arity += 1;
+                if self.fun.arity > 255 {
+                    self.parser
+                        .error_at_current(Code::LimitExceeded, "Can't have more than 255 parameters");
                 }
 
-                let (constant, is_array) = fn_compiler.parse_variable("Expect parameter name");
+                let is_rest = self.parser.match_tt(TokenType::Ellipsis);
+                let (constant, is_array) = self.parse_variable("Expect parameter name");
+                let name = self.parser.previous.clone();
+                self.lint_name(&name, NameKind::Value);
+                let param_name = self.interner.intern(self.parser.previous.source.as_ref());
+                self.fun.param_names.push(param_name);
+                if let Some(local) = self.locals.last_mut() {
+                    local.used = true;
+                }
 
                 if is_array {
-                    fn_compiler.parser.error_at_current("Array parameters are not supported");
+                    self.parser
+                        .error_at_current(Code::InvalidParameters, "Array parameters are not supported");
                 }
 
-                fn_compiler.define_global_if_needed(constant, is_array);
+                self.define_global_if_needed(constant, is_array);
+
+                let param = self.fun.arity - 1;
+                let slot = self.locals.len() - 1;
+                let mut is_default = false;
+                if is_rest {
+                    self.fun.is_variadic = true;
+                    if !self.parser.check_tt(TokenType::RightParen) {
+                        self.parser
+                            .error_at_current(Code::InvalidParameters, "Rest parameter must be the last parameter");
+                    }
+                } else if self.parser.match_tt(TokenType::Equal) {
+                    has_default = true;
+                    is_default = true;
+                    self.emit_bytes(Opcode::JumpIfArgPassed as u8, param as u8);
+                    let skip_default = self.emit_bytes_placeholder();
+                    self.emit_constant(Value::Nil); // Not an array access
+                    self.expression();
+                    self.emit_bytes(Opcode::SetLocal as u8, slot as u8);
+                    self.emit_byte(Opcode::Pop as u8);
+                    self.patch_jump(skip_default);
+                } else if has_default {
+                    self.parser.error_at_previous(
+                        Code::InvalidParameters,
+                        "Parameters without a default value can't follow ones with a default value",
+                    );
+                } else {
+                    self.fun.min_arity = self.fun.arity;
+                }
+                self.tree(|tree, _| {
+                    let default = is_default.then(|| *tree.pop());
+                    let name = Name::new(&name);
+                    params.push(Param { name, default, is_rest });
+                });
 
-                if !fn_compiler.parser.match_tt(TokenType::Comma) {
+                if !self.parser.match_tt(TokenType::Comma) {
                     break;
                 }
             }
         }
-        fn_compiler.parser.consume(TokenType::RightParen, "Expect ')' after parameters");
-        fn_compiler.parser.consume(TokenType::LeftBrace, "Expect '{' before function body");
-        fn_compiler.block();
-        let fun = fn_compiler.end();
+        self.parser.consume(TokenType::RightParen, "Expect ')' after parameters");
+        params
+    }
 
-        fn_compiler.functions.push(fun);
-        _ = std::mem::replace(&mut self.parser, fn_compiler.parser);
-        let constant_idx = self.make_constant(Value::Function(self.functions.len() - 1)) as u8;
-        self.emit_bytes(Opcode::Constant as u8, constant_idx);
+    /// Anonymous function expression, like `function (a, b) { return a + b; }`
+    fn lambda(&mut self, _can_assign: bool) {
+        let start = self.parser.previous.span.start;
+        let name = Some(self.interner.intern(LAMBDA_NAME));
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'function'");
+        self.function_body(name, FunType::Function, false);
+        self.function_node_start(start);
+    }
+
+    /// Set where the function that was just compiled as an expression starts in the syntax tree
+    fn function_node_start(&mut self, start: usize) {
+        self.tree(|tree, _| {
+            if let Some(expr) = tree.last() {
+                expr.span.start = start;
+                if let ExprKind::Function(function) = &mut expr.kind {
+                    function.span.start = start;
+                }
+            }
+        });
+    }
+
+    /// Looks ahead (without consuming any tokens) to check if the '(' that was just consumed
+    /// starts the parameter list of an arrow function, like `(a, b) -> a + b`.
+    fn is_arrow_function(&self) -> bool {
+        let mut scanner = self.parser.scanner.clone();
+        let mut token = self.parser.current.clone();
+        let mut depth = 0;
+
+        // Find the matching ')'
+        loop {
+            match token.typ {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen if depth == 0 => break,
+                TokenType::RightParen => depth -= 1,
+                TokenType::EOF => return false,
+                _ => {}
+            }
+            token = scanner.scan_token();
+        }
+
+        scanner.scan_token().typ == TokenType::Arrow
+    }
+
+    /// `class Name { method() { ... } ... }`
+    fn class_declaration(&mut self) {
+        let name = self.parser.current.clone();
+        let (global, is_array) = self.parse_variable("Expect class name");
+        self.lint_name(&name, NameKind::Type);
+        if is_array {
+            self.parser.error_at_previous(Code::ArrayName, "Class name can't be an array");
+        }
+
+        let name_constant = self.identifier_constant(&name);
+        self.emit_with_constant(Opcode::Class, name_constant);
+        self.define_global_if_needed(global, is_array);
+
+        // Keep the class on the stack while its methods are added
+        self.named_variable(&name, false);
+        self.tree(|tree, _| _ = tree.pop());
+        self.parser.consume(TokenType::LeftBrace, "Expect '{' before class body");
+        let mut members = Vec::new();
+        while !self.parser.check_tt(TokenType::RightBrace) && !self.parser.check_tt(TokenType::EOF) {
+            let start = self.parser.current.span.start;
+            let member = if self.parser.match_tt(TokenType::Static) {
+                self.static_member(start)
+            } else {
+                self.method(start)
+            };
+            members.extend(member);
+            if self.parser.panic_mode {
+                self.parser.synchronize(true);
+            }
+        }
+        self.parser.consume(TokenType::RightBrace, "Expect '}' after class body");
+        self.emit_byte(Opcode::Pop as u8);
+        self.tree(|tree, _| {
+            let name = Name::new(&name);
+            tree.statement(StmtKind::Class { name, members });
+        });
+    }
+
+    /// The member of a class that starts at the offset, with the function that was just compiled, if the syntax tree is
+    /// being built
+    fn member_node(&mut self, start: usize, name: &Token, kind: MethodKind) -> Option<ClassMember> {
+        let tree = self.tree.as_mut()?;
+        let mut function = tree.function();
+        function.name = Some(Name::new(name));
+        function.span.start = start;
+        Some(ClassMember::Method { kind, function })
+    }
+
+    /// A method, or a computed property like `get area() { ... }` or `set name(value) { ... }`, which starts at the offset
+    fn method(&mut self, start: usize) -> Option<ClassMember> {
+        self.parser.consume(TokenType::Identifier, "Expect method name");
+
+        // `get` and `set` are only keywords when followed by the property name
+        let mut opcode = Opcode::Method;
+        if self.parser.check_tt(TokenType::Identifier) {
+            match self.parser.previous.source.as_ref() {
+                "get" => opcode = Opcode::Getter,
+                "set" => opcode = Opcode::Setter,
+                _ => self.parser.error_at_current(Code::UnexpectedToken, "Expect '(' after method name"),
+            }
+            self.parser.advance();
+        }
+
+        let name_token = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&name_token);
+        let typ = if name_token.source.as_ref() == INIT_METHOD && opcode == Opcode::Method {
+            FunType::Initializer
+        } else {
+            FunType::Method
+        };
+
+        self.function(typ);
+
+        let arity = self.functions.last().map_or(0, |fun| fun.arity);
+        if opcode == Opcode::Getter && arity != 0 {
+            self.parser
+                .error_at_previous(Code::InvalidParameters, "Getters can't have parameters");
+        } else if opcode == Opcode::Setter && arity != 1 {
+            self.parser
+                .error_at_previous(Code::InvalidParameters, "Setters must have exactly one parameter");
+        }
+
+        self.emit_with_constant(opcode, name_constant);
+        let kind = match opcode {
+            Opcode::Getter => MethodKind::Getter,
+            Opcode::Setter => MethodKind::Setter,
+            _ => MethodKind::Method,
+        };
+        self.member_node(start, &name_token, kind)
+    }
+
+    /// `static name(params) { ... }` or `static name = value;`, stored on the class itself, which starts at the offset
+    fn static_member(&mut self, start: usize) -> Option<ClassMember> {
+        self.parser.consume(TokenType::Identifier, "Expect static member name");
+        let name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+
+        let member = if self.parser.check_tt(TokenType::LeftParen) {
+            self.function(FunType::StaticMethod);
+            self.member_node(start, &name, MethodKind::Static)
+        } else {
+            let has_value = self.parser.match_tt(TokenType::Equal);
+            if has_value {
+                self.expression();
+            } else {
+                self.emit_byte(Opcode::Nil as u8);
+            }
+            self.parser.consume(TokenType::Semicolon, "Expect ';' after static field");
+            self.tree.as_mut().map(|tree| ClassMember::StaticField {
+                name: Name::new(&name),
+                value: has_value.then(|| *tree.pop()),
+            })
+        };
+
+        self.emit_with_constant(Opcode::StaticMember, name_constant);
+        member
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.fun_typ == FunType::StaticMethod {
+            self.parser
+                .error_at_previous(Code::Misplaced, "Can't use 'this' in a static method");
+            return;
+        }
+
+        if self.fun_typ != FunType::Method && self.fun_typ != FunType::Initializer {
+            self.parser
+                .error_at_previous(Code::Misplaced, "Can't use 'this' outside of a method");
+            return;
+        }
+
+        // `this` can't be assigned to
+        self.named_variable(&self.parser.previous.clone(), false);
+    }
+
+    /// `enum Name { First, Second }`. The enum is created at compile time, as a constant.
+    fn enum_declaration(&mut self) {
+        let name = self.parser.current.clone();
+        let (global, is_array) = self.parse_variable("Expect enum name");
+        self.lint_name(&name, NameKind::Type);
+        if is_array {
+            self.parser.error_at_previous(Code::ArrayName, "Enum name can't be an array");
+        }
+        if self.scope_depth == 0 {
+            self.declare_global_constness(&name, true);
+        }
+
+        let enum_name = self.interner.intern(name.source.as_ref());
+        let mut members: Vec<Value> = Vec::new();
+        let mut member_names = Vec::new(); // In the syntax tree
+        self.parser.consume(TokenType::LeftBrace, "Expect '{' before enum members");
+        while !self.parser.check_tt(TokenType::RightBrace) {
+            self.parser.consume(TokenType::Identifier, "Expect enum member name");
+            if self.tree.is_some() {
+                member_names.push(Name::new(&self.parser.previous));
+            }
+            let member_name = self.interner.intern(self.parser.previous.source.as_ref());
+            let is_duplicate = members
+                .iter()
+                .any(|member| matches!(member, Value::EnumMember(member) if member.name == member_name));
+            if is_duplicate {
+                self.parser.error_at_previous(Code::Duplicate, "Duplicate enum member");
+            }
+
+            members.push(Value::EnumMember(Rc::new(EnumMember {
+                enum_name,
+                name: member_name,
+                ordinal: members.len(),
+            })));
+
+            if !self.parser.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+        self.parser.consume(TokenType::RightBrace, "Expect '}' after enum members");
+
+        self.emit_constant(Value::Enum(Rc::new(Enum { name: enum_name, members })));
+        self.define_global_if_needed(global, is_array);
+        self.tree(|tree, _| {
+            let name = Name::new(&name);
+            tree.statement(StmtKind::Enum {
+                name,
+                members: member_names,
+            });
+        });
+
+        if self.scope_depth > 0 {
+            self.locals.last_mut().unwrap().is_const = true;
+        }
+    }
+
+    /// `record Name(field, ...)`, a constant type whose instances hold a value for each field
+    fn record_declaration(&mut self) {
+        let name = self.parser.current.clone();
+        let (global, is_array) = self.parse_variable("Expect record name");
+        self.lint_name(&name, NameKind::Type);
+        if is_array {
+            self.parser.error_at_previous(Code::ArrayName, "Record name can't be an array");
+        }
+        if self.scope_depth == 0 {
+            self.declare_global_constness(&name, true);
+        }
+
+        let record_name = self.interner.intern(name.source.as_ref());
+        let mut fields: Vec<StrId> = Vec::new();
+        let mut field_names = Vec::new(); // In the syntax tree
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after record name");
+        while !self.parser.check_tt(TokenType::RightParen) {
+            self.parser.consume(TokenType::Identifier, "Expect field name");
+            if self.tree.is_some() {
+                field_names.push(Name::new(&self.parser.previous));
+            }
+            let field = self.interner.intern(self.parser.previous.source.as_ref());
+            if fields.contains(&field) {
+                self.parser.error_at_previous(Code::Duplicate, "Duplicate record field");
+            }
+            if fields.len() == 255 {
+                self.parser
+                    .error_at_previous(Code::LimitExceeded, "Can't have more than 255 fields in a record");
+            }
+            fields.push(field);
+
+            if !self.parser.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+        self.parser.consume(TokenType::RightParen, "Expect ')' after record fields");
+        self.parser.match_tt(TokenType::Semicolon);
+
+        self.emit_constant(Value::RecordType(Rc::new(RecordType { name: record_name, fields })));
+        self.define_global_if_needed(global, is_array);
+        self.tree(|tree, _| {
+            let name = Name::new(&name);
+            tree.statement(StmtKind::Record { name, fields: field_names });
+        });
+
+        if self.scope_depth > 0 {
+            self.locals.last_mut().unwrap().is_const = true;
+        }
     }
 
     fn fun_declaration(&mut self) {
+        let start = self.parser.previous.span.start;
         let (global, is_array) = self.parse_variable("Expect function name");
+        let name = self.parser.previous.clone();
+        self.lint_name(&name, NameKind::Value);
         self.mark_initialized();
         self.function(FunType::Function);
         self.define_global_if_needed(global, is_array);
+        self.tree(|tree, _| {
+            let mut function = tree.function();
+            function.name = Some(Name::new(&name));
+            function.span.start = start;
+            tree.statement(StmtKind::Function(function));
+        });
+
+        let name = self.interner.intern(name.source.as_ref());
+        if !is_array && self.known_functions.keeps_declaration(name) {
+            self.known_function(name, self.functions.len() - 1);
+        }
+    }
+
+    /// Record that the variable that was just declared always holds the function, so calls of it can name arguments
+    fn known_function(&mut self, name: StrId, fun: usize) {
+        if self.scope_depth > 0 {
+            self.locals.last_mut().unwrap().fun = Some(fun);
+        } else {
+            self.known_functions.globals.insert(name, fun);
+        }
     }
 
-    fn var_declaration(&mut self) {
+    /// `var` declaration, or `const` declaration if `is_const` is set
+    fn var_declaration(&mut self, is_const: bool) {
+        if self.parser.match_tt(TokenType::LeftBracket) {
+            self.destructuring_declaration(false, is_const);
+            return;
+        } else if self.parser.match_tt(TokenType::LeftBrace) {
+            self.destructuring_declaration(true, is_const);
+            return;
+        }
+
+        let name = self.parser.current.clone();
         let (global_variable_idx, is_array) = self.parse_variable("Expect variable name");
+        self.lint_name(&name, if is_const { NameKind::Constant } else { NameKind::Value });
+        if self.scope_depth == 0 {
+            self.declare_global_constness(&name, is_const);
+        }
 
+        let mut fun = None;
+        let has_value = !is_array && self.parser.match_tt(TokenType::Equal);
         if is_array {
             self.expression();
             self.emit_byte(Opcode::DeclareArray as u8);
             self.parser.consume(TokenType::RightBracket, "Expect ']' after array size");
-        } else if self.parser.match_tt(TokenType::Equal) {
+        } else if has_value {
+            let start = self.fun.chunk.code.len();
             self.expression();
+            if let Some(Value::Function(literal)) = self.constant_at(start, self.fun.chunk.code.len()) {
+                fun = Some(literal);
+            }
         } else {
+            if is_const {
+                self.parser
+                    .error_at_current(Code::UninitializedConstant, "Constants must be initialized");
+            }
             self.emit_byte(Opcode::Nil as u8);
         }
 
         self.parser.consume(TokenType::Semicolon, "Expect ';' after variable declaration");
         self.define_global_if_needed(global_variable_idx, is_array);
+        self.tree(|tree, _| {
+            let size = is_array.then(|| *tree.pop());
+            let value = has_value.then(|| *tree.pop());
+            let name = Name::new(&name);
+            tree.statement(StmtKind::Var {
+                name,
+                size,
+                value,
+                is_const,
+            });
+        });
+
+        if self.scope_depth > 0 {
+            self.locals.last_mut().unwrap().is_const = is_const;
+        }
+        let name = self.interner.intern(name.source.as_ref());
+        if let Some(fun) = fun.filter(|_| is_const || self.known_functions.keeps_declaration(name)) {
+            self.known_function(name, fun);
+        }
+    }
+
+    /// Constant globals are recorded in their module, so that assignments can be rejected
+    fn declare_global_constness(&mut self, name: &Token, is_const: bool) {
+        let name_id = self.interner.intern(name.source.as_ref());
+        let constants = &mut self.modules.modules[self.module].constants;
+
+        if constants.contains(&name_id) {
+            self.parser
+                .error_at_previous(Code::RedeclaredConstant, &format!("Cannot redeclare constant '{}'", name.source));
+        } else if is_const {
+            constants.push(name_id);
+        }
+    }
+
+    /// `var [a, b] = array;` or `var {x, y: renamed} = map;`
+    /// The source value is kept in a hidden local, and each name is initialized by indexing into it.
+    fn destructuring_declaration(&mut self, is_map: bool, is_const: bool) {
+        let closing = if is_map { TokenType::RightBrace } else { TokenType::RightBracket };
+        let mut targets: Vec<(Token, Value)> = Vec::new();
+        let mut entries = Vec::new(); // Keys and names of the pattern in the syntax tree
+
+        loop {
+            self.parser
+                .consume(TokenType::Identifier, "Expect variable name in destructuring pattern");
+            let key_token = self.parser.previous.clone();
+
+            let name = if is_map && self.parser.match_tt(TokenType::Colon) {
+                self.parser.consume(TokenType::Identifier, "Expect variable name after ':'");
+                self.parser.previous.clone()
+            } else {
+                key_token.clone()
+            };
+
+            if self.tree.is_some() {
+                entries.push((Name::new(&key_token), Name::new(&name)));
+            }
+            let key = if is_map {
+                Value::Str(self.interner.intern(key_token.source.as_ref()))
+            } else {
+                Value::Int(targets.len() as i64)
+            };
+
+            targets.push((name, key));
+
+            if !self.parser.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.parser.consume(closing, "Expect end of destructuring pattern");
+        self.parser.consume(TokenType::Equal, "Expect '=' after destructuring pattern");
+
+        // Globals still need a scope to hold the hidden local
+        let is_global = self.scope_depth == 0;
+        if is_global {
+            self.begin_scope();
+        }
+
+        self.expression();
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after variable declaration");
+        self.tree(|tree, _| {
+            let pattern = match is_map {
+                true => Pattern::Map(entries),
+                false => Pattern::Array(entries.into_iter().map(|(_, name)| name).collect()),
+            };
+            let value = *tree.pop();
+            tree.statement(StmtKind::Destructure { pattern, value, is_const });
+        });
+
+        self.add_hidden_local("destructuring source");
+        let source_slot = self.locals.len() - 1;
+
+        for (name, key) in targets {
+            self.emit_constant(key);
+            self.emit_bytes(Opcode::GetLocal as u8, source_slot as u8);
+
+            if is_global {
+                let global = self.identifier_constant(&name);
+                self.emit_with_constant(Opcode::DefineGlobal, global);
+                self.declare_global_constness(&name, is_const);
+            } else {
+                self.declare_local_variable(Some(name));
+                self.mark_initialized();
+                self.locals.last_mut().unwrap().is_const = is_const;
+            }
+        }
+
+        if is_global {
+            self.end_scope();
+        }
     }
 
     fn expression_statement(&mut self) {
         self.expression();
+        self.tree(|tree, _| {
+            let value = *tree.pop();
+            tree.statement(StmtKind::Expression(value));
+        });
+        if self.echo {
+            let terminated = self.parser.match_tt(TokenType::Semicolon);
+            if self.scope_depth == 0 && self.parser.check_tt(TokenType::EOF) {
+                self.emit_byte(Opcode::Return as u8);
+                return;
+            }
+            if terminated {
+                self.emit_byte(Opcode::Pop as u8);
+                return;
+            }
+        }
         self.parser.consume(TokenType::Semicolon, "Expect ';' after expression");
         self.emit_byte(Opcode::Pop as u8);
     }
@@ -466,118 +1708,613 @@ impl<'src> Compiler<'src> {
         self.begin_scope();
         self.parser.consume(TokenType::LeftParen, "Expect '(' after for");
 
-        if self.parser.match_tt(TokenType::Semicolon) {
+        let initializer_start = self.parser.current.clone();
+        let mark = self.tree_marks().1;
+        let has_initializer = !self.parser.match_tt(TokenType::Semicolon);
+        if !has_initializer {
             // No initializer
         } else if self.parser.match_tt(TokenType::Var) {
-            self.var_declaration();
+            if self.is_for_in() {
+                self.for_in_loop();
+                self.end_scope();
+                return;
+            }
+            self.var_declaration(false);
+        } else if self.is_for_in() {
+            self.for_in_loop();
+            self.end_scope();
+            return;
         } else {
             self.expression_statement();
         }
+        self.tree(|tree, end| tree.finish_statement(mark, &initializer_start, end));
 
         let mut loop_start = self.fun.chunk.code.len();
 
         let mut exit_jump = usize::MAX;
-        if !self.parser.match_tt(TokenType::Semicolon) {
-            self.expression();
+        let has_condition = !self.parser.match_tt(TokenType::Semicolon);
+        if has_condition {
+            self.condition(true);
             self.parser.consume(TokenType::Semicolon, "Expect ';' after loop condition");
 
             exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
             self.emit_byte(Opcode::Pop as u8);
         }
 
-        if !self.parser.match_tt(TokenType::RightParen) {
-            let body_jump = self.emit_jump(Opcode::Jump as u8);
-            let increment_start = self.fun.chunk.code.len();
-            self.expression();
-            self.emit_byte(Opcode::Pop as u8);
-            self.parser.consume(TokenType::RightParen, "Expect ')' after for clauses");
+        let has_increment = !self.parser.match_tt(TokenType::RightParen);
+        if has_increment {
+            let body_jump = self.emit_jump(Opcode::Jump as u8);
+            let increment_start = self.fun.chunk.code.len();
+            self.expression();
+            self.emit_byte(Opcode::Pop as u8);
+            self.parser.consume(TokenType::RightParen, "Expect ')' after for clauses");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+        self.tree(|tree, _| {
+            let body = tree.pop_statement();
+            let increment = has_increment.then(|| *tree.pop());
+            let condition = has_condition.then(|| *tree.pop());
+            let initializer = has_initializer.then(|| tree.pop_statement());
+            tree.statement(StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            });
+        });
+
+        if exit_jump != usize::MAX {
+            self.patch_jump(exit_jump);
+            self.emit_byte(Opcode::Pop as u8);
+        }
+
+        self.end_scope();
+    }
+
+    /// Looks ahead to check if this is a `for (x in collection)` loop
+    fn is_for_in(&self) -> bool {
+        self.parser.check_tt(TokenType::Identifier) && self.parser.scanner.clone().scan_token().typ == TokenType::In
+    }
+
+    /// `for (x in collection) body`, starting at the loop variable.
+    /// The iterable and the position in it are kept in hidden locals.
+    fn for_in_loop(&mut self) {
+        self.parser.consume(TokenType::Identifier, "Expect loop variable name");
+        let name = self.parser.previous.clone();
+        let variable = Name::new(&name);
+        self.parser.consume(TokenType::In, "Expect 'in' after loop variable");
+        self.expression();
+        self.parser.consume(TokenType::RightParen, "Expect ')' after for clauses");
+
+        self.emit_byte(Opcode::IterStart as u8);
+        self.add_hidden_local("for iterable");
+        let iterable_slot = self.locals.len() - 1;
+        self.emit_constant(Value::Int(0));
+        self.add_hidden_local("for index");
+
+        let loop_start = self.fun.chunk.code.len();
+        self.emit_bytes(Opcode::IterNext as u8, iterable_slot as u8);
+        let exit_jump = self.emit_bytes_placeholder();
+
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+        self.tree(|tree, _| {
+            let body = tree.pop_statement();
+            let iterable = *tree.pop();
+            tree.statement(StmtKind::ForIn { variable, iterable, body });
+        });
+
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+    }
+
+    /// Add an initialized local for the value at the top of the stack.
+    /// Space in the name ensures that user code cannot refer to it.
+    fn add_hidden_local(&mut self, name: &str) {
+        let token = Token {
+            typ: TokenType::Identifier,
+            source: Rc::from(name),
+            ..self.parser.previous.clone()
+        };
+        self.add_local(token);
+        self.mark_initialized();
+        if let Some(local) = self.locals.last_mut() {
+            local.used = true;
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.tree(|tree, _| {
+            let value = *tree.pop();
+            tree.statement(StmtKind::Print(value));
+        });
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after expression");
+        self.emit_byte(Opcode::Print as u8);
+    }
+
+    fn return_statement(&mut self) {
+        if self.fun_typ == FunType::Script || self.fun_typ == FunType::Module {
+            self.parser.error_at_previous(Code::Misplaced, "Can't return from top-level code");
+        }
+
+        let has_value = !self.parser.match_tt(TokenType::Semicolon);
+        if !has_value {
+            self.emit_return();
+        } else {
+            if self.fun_typ == FunType::Initializer {
+                self.parser
+                    .error_at_previous(Code::Misplaced, "Can't return a value from an initializer");
+            }
+            self.expression();
+            self.parser.consume(TokenType::Semicolon, "Expect ';' after return value");
+            self.emit_tail_return();
+        }
+        self.tree(|tree, _| {
+            let value = has_value.then(|| *tree.pop());
+            tree.statement(StmtKind::Return(value));
+        });
+    }
+
+    fn if_statement(&mut self) {
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'if'");
+        self.condition(false);
+        self.parser.consume(TokenType::RightParen, "Expect ')' after condition");
+
+        let then_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit_byte(Opcode::Pop as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(Opcode::Jump as u8);
+        self.patch_jump(then_jump);
+        self.emit_byte(Opcode::Pop as u8);
+
+        let has_else = self.parser.match_tt(TokenType::Else);
+        if has_else {
+            self.statement();
+        }
+        self.tree(|tree, _| {
+            let else_branch = has_else.then(|| tree.pop_statement());
+            let then_branch = tree.pop_statement();
+            let condition = *tree.pop();
+            tree.statement(StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            });
+        });
+
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.fun.chunk.code.len();
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'while'");
+        self.condition(true);
+        self.parser.consume(TokenType::RightParen, "Expect ')' after condition");
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit_byte(Opcode::Pop as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+        self.tree(|tree, _| {
+            let body = tree.pop_statement();
+            let condition = *tree.pop();
+            tree.statement(StmtKind::While { condition, body });
+        });
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(Opcode::Pop as u8);
+    }
+
+    /// Compiles a `switch` statement into a chain of equality tests.
+    /// The subject is stored in a hidden local, and cases do not fall through.
+    fn switch_statement(&mut self) {
+        self.begin_scope();
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'switch'");
+        self.expression();
+        self.parser.consume(TokenType::RightParen, "Expect ')' after switch value");
+
+        self.add_hidden_local("switch subject");
+        let subject_slot = self.locals.len() - 1;
+
+        self.parser.consume(TokenType::LeftBrace, "Expect '{' before switch cases");
+
+        let mut end_jumps = Vec::new();
+        let mut seen_default = false;
+        let mut cases = Vec::new(); // In the syntax tree
+        let mut default = None;
+
+        while !self.parser.check_tt(TokenType::RightBrace) && !self.parser.check_tt(TokenType::EOF) {
+            if self.parser.match_tt(TokenType::Case) {
+                if seen_default {
+                    self.parser
+                        .error_at_previous(Code::Misplaced, "Can't have a case after the default case");
+                }
+
+                // Any of the comma-separated values may match
+                let (values, _) = self.tree_marks();
+                let mut body_jumps = Vec::new();
+                let next_case_jump = loop {
+                    self.emit_constant(Value::Nil); // Not an array access
+                    self.emit_bytes(Opcode::GetLocal as u8, subject_slot as u8);
+                    self.expression();
+                    self.emit_byte(Opcode::Equal as u8);
+
+                    if !self.parser.match_tt(TokenType::Comma) {
+                        break self.emit_jump(Opcode::JumpIfFalse as u8);
+                    }
+
+                    let next_value_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+                    self.emit_byte(Opcode::Pop as u8);
+                    body_jumps.push(self.emit_jump(Opcode::Jump as u8));
+                    self.patch_jump(next_value_jump);
+                    self.emit_byte(Opcode::Pop as u8);
+                };
+
+                self.parser.consume(TokenType::Colon, "Expect ':' after case value");
+                self.emit_byte(Opcode::Pop as u8);
+                for jump in body_jumps {
+                    self.patch_jump(jump);
+                }
+                let (_, body) = self.tree_marks();
+                self.case_body();
+                self.tree(|tree, _| {
+                    cases.push(SwitchCase {
+                        values: tree.exprs_from(values),
+                        body: tree.stmts_from(body),
+                    });
+                });
+                end_jumps.push(self.emit_jump(Opcode::Jump as u8));
+
+                self.patch_jump(next_case_jump);
+                self.emit_byte(Opcode::Pop as u8);
+            } else if self.parser.match_tt(TokenType::Default) {
+                if seen_default {
+                    self.parser
+                        .error_at_previous(Code::Misplaced, "Can't have more than one default case");
+                }
+                seen_default = true;
+
+                self.parser.consume(TokenType::Colon, "Expect ':' after 'default'");
+                let (_, body) = self.tree_marks();
+                self.case_body();
+                default = Some(self.tree_statements(body));
+            } else {
+                self.parser
+                    .error_at_current(Code::UnexpectedToken, "Expect 'case' or 'default' in switch body");
+                break;
+            }
+        }
+
+        self.parser.consume(TokenType::RightBrace, "Expect '}' after switch cases");
+        self.tree(|tree, _| {
+            let subject = *tree.pop();
+            tree.statement(StmtKind::Switch { subject, cases, default });
+        });
 
-            self.emit_loop(loop_start);
-            loop_start = increment_start;
-            self.patch_jump(body_jump);
+        for jump in end_jumps {
+            self.patch_jump(jump);
         }
 
-        self.statement();
-        self.emit_loop(loop_start);
+        self.end_scope();
+    }
 
-        if exit_jump != usize::MAX {
-            self.patch_jump(exit_jump);
-            self.emit_byte(Opcode::Pop as u8);
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.parser.check_tt(TokenType::Case)
+            && !self.parser.check_tt(TokenType::Default)
+            && !self.parser.check_tt(TokenType::RightBrace)
+            && !self.parser.check_tt(TokenType::EOF)
+        {
+            self.declaration();
         }
-
         self.end_scope();
     }
 
-    fn print_statement(&mut self) {
+    fn throw_statement(&mut self) {
         self.expression();
-        self.parser.consume(TokenType::Semicolon, "Expect ';' after expression");
-        self.emit_byte(Opcode::Print as u8);
+        self.tree(|tree, _| {
+            let value = *tree.pop();
+            tree.statement(StmtKind::Throw(value));
+        });
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after thrown value");
+        self.emit_byte(Opcode::Throw as u8);
     }
 
-    fn return_statement(&mut self) {
-        if self.fun_typ == FunType::Script {
-            self.parser.error_at_previous("Can't return from top-level code");
+    /// `yield value;` gives the value to the loop iterating over the generator, and suspends it until the next iteration.
+    /// A function that contains `yield` is a generator function.
+    fn yield_statement(&mut self) {
+        match self.fun_typ {
+            FunType::Script | FunType::Module => self.parser.error_at_previous(Code::Misplaced, "Can't yield from top-level code"),
+            FunType::Initializer => self.parser.error_at_previous(Code::Misplaced, "Can't yield from an initializer"),
+            FunType::Test => self.parser.error_at_previous(Code::Misplaced, "Can't yield from a test"),
+            _ => {}
         }
+        self.fun.is_generator = true;
 
-        if self.parser.match_tt(TokenType::Semicolon) {
-            self.emit_return();
+        let has_value = !self.parser.match_tt(TokenType::Semicolon);
+        if !has_value {
+            self.emit_byte(Opcode::Nil as u8);
         } else {
             self.expression();
-            self.parser.consume(TokenType::Semicolon, "Expect ';' after return value");
-            self.emit_byte(Opcode::Return as u8);
+            self.parser.consume(TokenType::Semicolon, "Expect ';' after yielded value");
         }
+        self.emit_byte(Opcode::Yield as u8);
+        self.tree(|tree, _| {
+            let value = has_value.then(|| *tree.pop());
+            tree.statement(StmtKind::Yield(value));
+        });
     }
 
-    fn if_statement(&mut self) {
-        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'if'");
-        self.expression();
-        self.parser.consume(TokenType::RightParen, "Expect ')' after condition");
+    /// `try { ... } catch (e) { ... } finally { ... }`, where either `catch` or `finally` may be left out.
+    /// The handler pushed before the try block has the offsets of both the catch and the finally blocks.
+    /// The finally block is entered with a completion record (the value, and how it was entered) on the stack,
+    /// which `EndFinally` uses to resume throwing or returning afterwards.
+    fn try_statement(&mut self) {
+        self.emit_byte(Opcode::PushHandler as u8);
+        let catch_offset = self.emit_bytes_placeholder();
+        let finally_offset = self.emit_bytes_placeholder();
+
+        self.parser.consume(TokenType::LeftBrace, "Expect '{' after 'try'");
+        let (_, mark) = self.tree_marks();
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_byte(Opcode::PopHandler as u8);
+        let body = self.tree_statements(mark);
+        let mut catch = None;
+        let mut finally = None;
 
-        let then_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-        self.emit_byte(Opcode::Pop as u8);
-        self.statement();
+        let has_catch = self.parser.match_tt(TokenType::Catch);
+        if has_catch {
+            let end_jump = self.emit_jump(Opcode::Jump as u8);
+            self.patch_jump(catch_offset);
 
-        let else_jump = self.emit_jump(Opcode::Jump as u8);
-        self.patch_jump(then_jump);
-        self.emit_byte(Opcode::Pop as u8);
+            // The exception is on top of the stack
+            self.begin_scope();
+            let mut variable = None;
+            if self.parser.match_tt(TokenType::LeftParen) {
+                self.parser.consume(TokenType::Identifier, "Expect exception variable name");
+                variable = Some(Name::new(&self.parser.previous));
+                self.add_local(self.parser.previous.clone());
+                self.mark_initialized();
+                self.parser.consume(TokenType::RightParen, "Expect ')' after exception variable");
+            } else {
+                self.emit_byte(Opcode::Pop as u8);
+            }
 
-        if self.parser.match_tt(TokenType::Else) {
-            self.statement();
+            self.parser.consume(TokenType::LeftBrace, "Expect '{' after catch clause");
+            self.block();
+            self.end_scope();
+            let body = self.tree_statements(mark);
+            catch = Some(Catch { variable, body });
+
+            // Pops the handler that the VM pushes to run the finally block if the catch block throws
+            self.emit_byte(Opcode::PopHandler as u8);
+            self.patch_jump(end_jump);
+        } else {
+            self.write_u16_at(catch_offset, 0); // No catch block
         }
 
-        self.patch_jump(else_jump);
+        if self.parser.match_tt(TokenType::Finally) {
+            self.emit_constant(Value::Nil);
+            self.emit_constant(Value::Number(COMPLETION_NORMAL));
+            self.patch_jump(finally_offset);
+
+            // The completion record occupies two stack slots
+            self.begin_scope();
+            self.add_hidden_local("finally value");
+            self.add_hidden_local("finally completion");
+
+            self.parser.consume(TokenType::LeftBrace, "Expect '{' after 'finally'");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            finally = Some(self.tree_statements(mark));
+
+            // EndFinally pops the completion record itself
+            self.scope_depth -= 1;
+            self.locals.truncate(self.locals.len() - 2);
+            self.emit_byte(Opcode::EndFinally as u8);
+        } else {
+            if !has_catch {
+                self.parser
+                    .error_at_current(Code::UnexpectedToken, "Expect 'catch' or 'finally' after try block");
+            }
+            self.write_u16_at(finally_offset, 0); // No finally block
+        }
+        self.tree(|tree, _| tree.statement(StmtKind::Try { body, catch, finally }));
     }
 
-    fn while_statement(&mut self) {
-        let loop_start = self.fun.chunk.code.len();
-        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'while'");
-        self.expression();
-        self.parser.consume(TokenType::RightParen, "Expect ')' after condition");
+    /// `import "path/to/module.lox" as name;`
+    /// The module is compiled along with the importing code, and its top-level code runs on the first import.
+    fn import_statement(&mut self) {
+        self.parser.consume(TokenType::String, "Expect module path after 'import'");
+        let path = self.parser.previous.source.clone();
+        let module = self.load_module(&path[1..path.len() - 1]);
+        self.parser.consume(TokenType::As, "Expect 'as' after module path");
+        let (global, is_array) = self.parse_variable("Expect module name after 'as'");
+        let name = self.parser.previous.clone();
+        self.lint_name(&name, NameKind::Value);
+        self.tree(|tree, _| {
+            let path = ast::unquote(&path);
+            let name = Name::new(&name);
+            tree.statement(StmtKind::Import { path, name });
+        });
+        if is_array {
+            self.parser.error_at_previous(Code::ArrayName, "Module name can't be an array");
+        }
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after import");
 
-        let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-        self.emit_byte(Opcode::Pop as u8);
-        self.statement();
-        self.emit_loop(loop_start);
+        self.emit_constant(Value::Module(module));
+        self.emit_byte(Opcode::Import as u8);
+        self.define_global_if_needed(global, is_array);
+    }
 
-        self.patch_jump(exit_jump);
-        self.emit_byte(Opcode::Pop as u8);
+    /// Compile the module at the given path, if it has not been compiled yet
+    fn load_module(&mut self, path: &str) -> usize {
+        if let Some(module) = self.modules.find(path) {
+            if self.modules.modules[module].script.is_none() {
+                self.parser
+                    .error_at_previous(Code::CircularImport, &format!("Circular import of '{path}'"));
+            }
+            return module;
+        }
+
+        let source = self.modules.load(path).unwrap_or_else(|err| {
+            self.parser
+                .error_at_previous(Code::ModuleNotFound, &format!("Could not load module '{path}': {err}"));
+            String::new()
+        });
+
+        let module = self.modules.modules.len();
+        self.modules.modules.push(Module {
+            path: path.to_string(),
+            exports: Vec::new(),
+            constants: Vec::new(),
+            script: None,
+            tests: Vec::new(),
+        });
+
+        let (mut fun, diagnostics) = Compiler::for_module(
+            Rc::from(source),
+            self.interner,
+            self.functions,
+            self.modules,
+            FunType::Module,
+            module,
+            &self.options,
+        )
+        .compile_declarations();
+        // Errors in the module fail the program
+        self.parser.had_error |= diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error);
+        self.parser.diagnostics.extend(diagnostics.into_iter().map(|mut diagnostic| {
+            diagnostic.file.get_or_insert_with(|| path.to_string());
+            diagnostic
+        }));
+        fun.name = Some(self.interner.intern(path));
+        self.functions.push(fun);
+        self.modules.modules[module].script = Some(self.functions.len() - 1);
+
+        module
+    }
+
+    /// `export function ...` or `export var ...` at the top level of a module
+    fn export_declaration(&mut self) {
+        if self.scope_depth > 0 || self.fun_typ == FunType::Function {
+            self.parser
+                .error_at_previous(Code::Misplaced, "Can only export top-level declarations");
+        }
+
+        let start = self.parser.current.clone();
+        let is_declaration =
+            self.parser.match_tt(TokenType::Fun) || self.parser.match_tt(TokenType::Var) || self.parser.match_tt(TokenType::Const);
+        let keyword = self.parser.previous.typ;
+        if !is_declaration || !self.parser.check_tt(TokenType::Identifier) {
+            self.parser
+                .error_at_current(Code::UnexpectedToken, "Expect function or variable declaration after 'export'");
+            return;
+        }
+
+        let name = self.interner.intern(self.parser.current.source.as_ref());
+        self.modules.modules[self.module].exports.push(name);
+
+        let (_, mark) = self.tree_marks();
+        if keyword == TokenType::Fun {
+            self.fun_declaration();
+        } else {
+            self.var_declaration(keyword == TokenType::Const);
+        }
+        self.tree(|tree, end| {
+            tree.finish_statement(mark, &start, end);
+            let declaration = tree.pop_statement();
+            tree.statement(StmtKind::Export(declaration));
+        });
+    }
+
+    /// `test` is only a keyword when a string follows it, so it can still be used as a name
+    fn is_test_declaration(&self) -> bool {
+        self.parser.check_tt(TokenType::Identifier)
+            && self.parser.current.source.as_ref() == "test"
+            && self.parser.scanner.clone().scan_token().typ == TokenType::String
+    }
+
+    /// `test "name" { ... }` at the top level. The body is only run by `Vm::run_tests`, not when the program runs.
+    fn test_declaration(&mut self) {
+        let line = self.line();
+        if self.scope_depth > 0 || !matches!(self.fun_typ, FunType::Script | FunType::Module) {
+            self.parser
+                .error_at_previous(Code::Misplaced, "Tests must be declared at the top level");
+        }
+
+        self.parser.consume(TokenType::String, "Expect test name");
+        let source = self.parser.previous.source.clone();
+        let name = source[1..source.len() - 1].to_string();
+        let fun_name = Some(self.interner.intern(&format!("test \"{name}\"")));
+        let fun = self.compile_function(fun_name, FunType::Test, false);
+        self.tree(|tree, _| {
+            let body = match tree.function().body {
+                FunctionBody::Block(body) => body,
+                FunctionBody::Expression(_) => Vec::new(),
+            };
+            tree.statement(StmtKind::Test { name: name.clone(), body });
+        });
+        self.modules.modules[self.module].tests.push(TestCase { name, fun, line });
     }
 
     fn declaration(&mut self) {
-        if self.parser.match_tt(TokenType::Fun) {
+        let start = self.fun.chunk.code.len();
+        let start_token = self.parser.current.clone();
+        let (_, mark) = self.tree_marks();
+        if self.is_test_declaration() {
+            self.parser.advance();
+            self.test_declaration();
+        } else if self.parser.match_tt(TokenType::Import) {
+            self.import_statement();
+        } else if self.parser.match_tt(TokenType::Export) {
+            self.export_declaration();
+        } else if self.parser.match_tt(TokenType::Class) {
+            self.class_declaration();
+        } else if self.parser.match_tt(TokenType::Enum) {
+            self.enum_declaration();
+        } else if self.parser.match_tt(TokenType::Record) {
+            self.record_declaration();
+        } else if self.parser.match_tt(TokenType::Fun) {
             self.fun_declaration();
         } else if self.parser.match_tt(TokenType::Var) {
-            self.var_declaration();
+            self.var_declaration(false);
+        } else if self.parser.match_tt(TokenType::Const) {
+            self.var_declaration(true);
         } else {
             self.statement();
         }
 
+        let span = Span {
+            start: start_token.span.start,
+            end: self.parser.previous.span.end,
+        };
+        self.fun.chunk.set_statement_span(start, span);
+        self.tree(|tree, end| tree.finish_statement(mark, &start_token, end));
         if self.parser.panic_mode {
-            self.parser.synchronize();
+            self.parser.synchronize(self.scope_depth > 0);
         }
     }
 
     fn statement(&mut self) {
+        let start = self.parser.current.clone();
+        let (_, mark) = self.tree_marks();
+        // Statements nested in this one, like the body of an `if`, never give the result of a snippet
+        let echo = std::mem::take(&mut self.echo);
         if self.parser.match_tt(TokenType::Print) {
             self.print_statement();
         } else if self.parser.match_tt(TokenType::Return) {
@@ -588,56 +2325,388 @@ impl<'src> Compiler<'src> {
             self.while_statement();
         } else if self.parser.match_tt(TokenType::For) {
             self.for_statement();
+        } else if self.parser.match_tt(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.parser.match_tt(TokenType::Try) {
+            self.try_statement();
+        } else if self.parser.match_tt(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.parser.match_tt(TokenType::Yield) {
+            self.yield_statement();
         } else if self.parser.match_tt(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
             self.end_scope();
+            self.tree(|tree, _| {
+                let statements = tree.stmts_from(mark);
+                tree.statement(StmtKind::Block(statements));
+            });
         } else {
+            self.echo = echo;
             self.expression_statement();
         }
+        self.echo = echo;
+        self.tree(|tree, end| tree.finish_statement(mark, &start, end));
     }
 
     fn grouping(&mut self, _can_assign: bool) {
+        let start = self.parser.previous.span.start;
+        if self.is_arrow_function() {
+            let name = Some(self.interner.intern(LAMBDA_NAME));
+            self.function_body(name, FunType::Function, true);
+            self.function_node_start(start);
+            return;
+        }
+
+        // `()` is the empty tuple
+        if self.parser.match_tt(TokenType::RightParen) {
+            self.tree(|tree, end| tree.push(ExprKind::Tuple(Vec::new()), start, end));
+            self.emit_bytes(Opcode::BuildTuple as u8, 0);
+            return;
+        }
+
+        let mark = self.tree_marks().0;
         self.expression();
+
+        // A comma makes it a tuple, like `(1, 2)` or `(1,)`
+        if self.parser.match_tt(TokenType::Comma) {
+            let mut element_count: usize = 1;
+            while !self.parser.check_tt(TokenType::RightParen) && !self.parser.check_tt(TokenType::EOF) {
+                self.expression();
+                if element_count == 255 {
+                    self.parser
+                        .error_at_previous(Code::LimitExceeded, "Can't have more than 255 elements in a tuple");
+                }
+                element_count += 1;
+
+                if !self.parser.match_tt(TokenType::Comma) {
+                    break;
+                }
+            }
+
+            self.parser.consume(TokenType::RightParen, "Expect ')' after tuple elements");
+            self.tree(|tree, end| {
+                let elements = tree.exprs_from(mark);
+                tree.push(ExprKind::Tuple(elements), start, end);
+            });
+            self.emit_bytes(Opcode::BuildTuple as u8, element_count as u8);
+            return;
+        }
+
         self.parser.consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.tree(|tree, end| {
+            let expr = tree.pop();
+            tree.push(ExprKind::Grouping(expr), start, end);
+        });
     }
 
     fn number(&mut self, _can_assign: bool) {
-        let num = self.parser.previous.source.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(num));
+        self.leaf_node(ExprKind::Number(self.parser.previous.source.to_string()));
+        let source = self.parser.previous.source.as_ref();
+        if let Some(digits) = source.strip_suffix('n') {
+            #[cfg(feature = "bigint")]
+            match crate::bigint::BigInt::parse(digits) {
+                Some(value) => self.emit_constant(Value::BigInt(Rc::new(value))),
+                None => self
+                    .parser
+                    .error_at_previous(Code::InvalidToken, &format!("Invalid big integer literal {source}")),
+            }
+            #[cfg(not(feature = "bigint"))]
+            self.parser.error_at_previous(
+                Code::MissingFeature,
+                &format!("Big integer literal {digits}n requires the 'bigint' feature"),
+            );
+            return;
+        }
+
+        // Literals without a fractional part are integers, unless they are too big for one
+        let value = match (source.parse::<i64>(), source.parse::<f64>()) {
+            (Ok(int), _) => Value::Int(int),
+            (_, Ok(number)) if number.is_finite() => Value::Number(number),
+            (_, Ok(_)) => return self.parser.error_at_previous(Code::OutOfRange, "Number literal out of range"),
+            (_, Err(_)) => {
+                return self
+                    .parser
+                    .error_at_previous(Code::InvalidToken, &format!("Invalid number literal {source}"))
+            }
+        };
+        self.emit_constant(value);
     }
 
     fn string(&mut self, _can_assign: bool) {
         let data = self.parser.previous.source.clone();
         let data = &data[1..data.len() - 1];
+        self.leaf_node(ExprKind::String(data.to_string()));
         let id = self.interner.intern(data);
         self.emit_constant(Value::Str(id));
     }
 
+    /// Map literal, like `{name: "Lox", "version": 1}`.
+    /// Bare identifiers used as keys are treated as strings.
+    fn map(&mut self, _can_assign: bool) {
+        let start = self.parser.previous.span.start;
+        let mark = self.tree_marks().0;
+        let mut entry_count: usize = 0;
+
+        while !self.parser.check_tt(TokenType::RightBrace) && !self.parser.check_tt(TokenType::EOF) {
+            if self.parser.match_tt(TokenType::Identifier) {
+                self.leaf_node(ExprKind::String(self.parser.previous.source.to_string()));
+                let key = self.parser.previous.source.clone();
+                let id = self.interner.intern(key.as_ref());
+                self.emit_constant(Value::Str(id));
+            } else {
+                self.expression();
+            }
+
+            self.parser.consume(TokenType::Colon, "Expect ':' after map key");
+            self.expression();
+
+            if entry_count == 255 {
+                self.parser
+                    .error_at_previous(Code::LimitExceeded, "Can't have more than 255 entries in a map literal");
+            }
+            entry_count += 1;
+
+            if !self.parser.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.parser.consume(TokenType::RightBrace, "Expect '}' after map entries");
+        self.tree(|tree, end| {
+            let mut entries = Vec::new();
+            let mut exprs = tree.exprs_from(mark).into_iter();
+            while let (Some(key), Some(value)) = (exprs.next(), exprs.next()) {
+                entries.push((key, value));
+            }
+            tree.push(ExprKind::Map(entries), start, end);
+        });
+        self.emit_bytes(Opcode::BuildMap as u8, entry_count as u8);
+    }
+
+    /// Array literal, like `[1, 2, ...others]`
+    fn list(&mut self, _can_assign: bool) {
+        let start = self.parser.previous.span.start;
+        let mark = self.tree_marks().0;
+        let mut element_count: usize = 0;
+
+        while !self.parser.check_tt(TokenType::RightBracket) && !self.parser.check_tt(TokenType::EOF) {
+            if self.parser.match_tt(TokenType::Ellipsis) {
+                let spread_start = self.parser.previous.span.start;
+                self.expression();
+                self.spread_node(spread_start);
+                self.emit_byte(Opcode::Spread as u8);
+            } else {
+                self.expression();
+            }
+
+            if element_count == 255 {
+                self.parser
+                    .error_at_previous(Code::LimitExceeded, "Can't have more than 255 elements in an array literal");
+            }
+            element_count += 1;
+
+            if !self.parser.match_tt(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.parser.consume(TokenType::RightBracket, "Expect ']' after array elements");
+        self.tree(|tree, end| {
+            let elements = tree.exprs_from(mark);
+            tree.push(ExprKind::Array(elements), start, end);
+        });
+        self.emit_bytes(Opcode::BuildArray as u8, element_count as u8);
+    }
+
+    /// Member access, like `module.name` or `instance.field`
+    fn dot(&mut self, can_assign: bool) {
+        self.parser.consume(TokenType::Identifier, "Expect property name after '.'");
+        let name_token = self.parser.previous.clone();
+        let name = self.identifier_constant(&name_token);
+
+        let is_assignment = can_assign && self.parser.match_tt(TokenType::Equal);
+        if is_assignment {
+            let equal = self.parser.previous.clone();
+            self.expression();
+            self.emit_with_constant(Opcode::SetProperty, name);
+            self.assigned(equal);
+        } else {
+            self.emit_with_constant(Opcode::GetProperty, name);
+        }
+        self.tree(|tree, end| {
+            let value = is_assignment.then(|| tree.pop());
+            let object = tree.pop();
+            let start = object.span.start;
+            let name = Name::new(&name_token);
+            tree.push(
+                ExprKind::Property {
+                    object,
+                    name,
+                    optional: false,
+                },
+                start,
+                name_token.span.end,
+            );
+            if let Some(value) = value {
+                let target = tree.pop();
+                tree.push(ExprKind::Assign { target, value }, start, end);
+            }
+        });
+    }
+
+    /// Indexing the result of an expression, like `f()[0]` or `obj.items[0]`
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.parser.consume(TokenType::RightBracket, "Expect ']' after index");
+        let index_end = self.parser.previous.span.end;
+
+        let is_assignment = can_assign && self.parser.match_tt(TokenType::Equal);
+        if is_assignment {
+            let equal = self.parser.previous.clone();
+            self.expression();
+            self.emit_byte(Opcode::SetIndex as u8);
+            self.assigned(equal);
+        } else {
+            self.emit_byte(Opcode::GetIndex as u8);
+        }
+        self.tree(|tree, end| {
+            let value = is_assignment.then(|| tree.pop());
+            let index = tree.pop();
+            let object = tree.pop();
+            let start = object.span.start;
+            tree.push(
+                ExprKind::Index {
+                    object,
+                    index,
+                    optional: false,
+                },
+                start,
+                index_end,
+            );
+            if let Some(value) = value {
+                let target = tree.pop();
+                tree.push(ExprKind::Assign { target, value }, start, end);
+            }
+        });
+    }
+
+    /// Optional chaining, like `obj?.field`, `obj?.method()` or `obj?.[index]`.
+    /// If the object is nil, the rest of the chain is skipped and the result is nil.
+    fn optional_chain(&mut self, _can_assign: bool) {
+        let nil_jump = self.emit_jump(Opcode::JumpIfNil as u8);
+
+        if self.parser.match_tt(TokenType::LeftBracket) {
+            self.index(false);
+        } else {
+            self.dot(false);
+        }
+        self.tree(|tree, _| {
+            if let Some(ExprKind::Property { optional, .. } | ExprKind::Index { optional, .. }) = tree.last().map(|expr| &mut expr.kind) {
+                *optional = true;
+            }
+        });
+
+        while matches!(
+            self.parser.current.typ,
+            TokenType::Dot | TokenType::LeftParen | TokenType::LeftBracket | TokenType::QuestionDot
+        ) {
+            self.parser.advance();
+            if let Some(infix_rule) = self.get_rule(self.parser.previous.typ).infix {
+                infix_rule(self, false);
+            }
+        }
+
+        self.patch_jump(nil_jump);
+    }
+
+    /// `a ?? b` evaluates to `b` only if `a` is nil
+    fn coalesce(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(Opcode::JumpIfNotNil as u8);
+        self.emit_byte(Opcode::Pop as u8);
+        self.parse_precedence(Precedence::Coalesce);
+        self.binary_node(TokenType::QuestionQuestion);
+        self.patch_jump(end_jump);
+    }
+
     fn named_variable(&mut self, token: &Token, can_assign: bool) {
         let get_op: Opcode;
         let set_op: Opcode;
+        let start = self.fun.chunk.code.len();
         let mut arg: isize = self.resolve_local(token);
+        let is_const;
+        let known;
 
         if arg != -1 {
             set_op = Opcode::SetLocal;
             get_op = Opcode::GetLocal;
+            is_const = self.locals[arg as usize].is_const;
+            known = self.locals[arg as usize].fun;
         } else {
             arg = self.identifier_constant(token) as isize;
             set_op = Opcode::SetGlobal;
             get_op = Opcode::GetGlobal;
+            // Constants declared later are checked at runtime instead
+            let name_id = self.interner.intern(token.source.as_ref());
+            is_const = self.modules.modules[self.module].constants.contains(&name_id);
+            known = self.known_functions.globals.get(&name_id).copied();
         }
 
-        if self.array_access_index() {
-            dbgln!("{} is array access", token.source);
-        }
+        let is_index = self.array_access_index();
+        let index_end = self.parser.previous.span.end;
 
-        if can_assign && self.parser.match_tt(TokenType::Equal) {
+        let is_assignment = can_assign && self.parser.match_tt(TokenType::Equal);
+        // Setting an element reads the variable
+        if get_op == Opcode::GetLocal && (is_index || !is_assignment) {
+            self.locals[arg as usize].used = true;
+        }
+        if is_assignment {
+            // The elements of a constant array or map can still be modified
+            if is_const && !is_index {
+                self.parser
+                    .error_at_previous(Code::AssignToConstant, &format!("Cannot assign to constant '{}'", token.source));
+            }
+            let equal = self.parser.previous.clone();
             self.expression();
-            self.emit_bytes(set_op as u8, arg as u8);
+            self.emit_variable_instruction(set_op, arg as usize);
+            self.assigned(equal);
         } else {
-            self.emit_bytes(get_op as u8, arg as u8);
+            self.emit_variable_instruction(get_op, arg as usize);
+            if !is_index {
+                self.known_callee = known.map(|fun| (start, self.fun.chunk.code.len(), fun));
+            }
         }
+        self.variable_node(token, is_index.then_some(index_end), is_assignment);
+    }
+
+    /// Add the node of a variable that was just parsed, which may be indexed, ending at the offset, and assigned to
+    fn variable_node(&mut self, token: &Token, index_end: Option<usize>, is_assignment: bool) {
+        self.tree(|tree, end| {
+            let value = is_assignment.then(|| tree.pop());
+            let kind = match token.typ {
+                TokenType::This => ExprKind::This,
+                _ => ExprKind::Variable(token.source.to_string()),
+            };
+            let start = token.span.start;
+            tree.push(kind, start, token.span.end);
+            if let Some(index_end) = index_end {
+                let object = tree.pop();
+                let index = tree.pop();
+                tree.push(
+                    ExprKind::Index {
+                        object,
+                        index,
+                        optional: false,
+                    },
+                    start,
+                    index_end,
+                );
+            }
+            if let Some(value) = value {
+                let target = tree.pop();
+                tree.push(ExprKind::Assign { target, value }, start, end);
+            }
+        });
     }
 
     // Array index (or max value if not an array index)
@@ -660,7 +2729,20 @@ impl<'src> Compiler<'src> {
     fn unary(&mut self, can_assign: bool) {
         let _ = can_assign;
         let operator_type = self.parser.previous.typ;
+        let start = self.parser.previous.span.start;
+        let operand_start = self.fun.chunk.code.len();
         self.parse_precedence(Precedence::Unary);
+        self.tree(|tree, end| {
+            let operator = match operator_type {
+                TokenType::Minus => UnaryOperator::Negate,
+                _ => UnaryOperator::Not,
+            };
+            let operand = tree.pop();
+            tree.push(ExprKind::Unary { operator, operand }, start, end);
+        });
+        if self.fold_unary(operator_type, operand_start) {
+            return;
+        }
 
         match operator_type {
             TokenType::Minus => self.emit_byte(Opcode::Negate as u8),
@@ -693,6 +2775,7 @@ impl<'src> Compiler<'src> {
 
     /// Parse expressions with equal or higher precedence
     fn parse_precedence(&mut self, precedence: Precedence) {
+        let start = self.fun.chunk.code.len();
         self.parser.advance();
         let prefix_rule = self.get_rule(self.parser.previous.typ).prefix;
         let can_assign = precedence <= Precedence::Assignment;
@@ -700,7 +2783,7 @@ impl<'src> Compiler<'src> {
         match prefix_rule {
             Some(rule) => rule(self, can_assign),
             None => {
-                self.parser.error_at_previous("Expect expression");
+                self.parser.error_at_previous(Code::UnexpectedToken, "Expect expression");
                 return;
             }
         }
@@ -708,18 +2791,20 @@ impl<'src> Compiler<'src> {
         while precedence <= self.get_rule(self.parser.current.typ).precedence {
             self.parser.advance();
             let infix_rule = self.get_rule(self.parser.previous.typ).infix;
+            self.operand_start = start;
 
             match infix_rule {
                 Some(rule) => rule(self, can_assign),
                 None => {
-                    self.parser.error_at_previous("Expect expression");
+                    self.parser.error_at_previous(Code::UnexpectedToken, "Expect expression");
                     return;
                 }
             }
         }
 
         if can_assign && self.parser.match_tt(TokenType::Equal) {
-            self.parser.error_at_current("Invalid assignment target");
+            self.parser
+                .error_at_current(Code::InvalidAssignmentTarget, "Invalid assignment target");
         }
     }
 
@@ -732,23 +2817,47 @@ impl<'src> Compiler<'src> {
         if self.scope_depth == 0 {
             return;
         }
-        self.locals.last_mut().unwrap().depth = self.scope_depth;
+        let local = self.locals.last_mut().unwrap();
+        let was_initialized = local.depth != -1;
+        local.depth = self.scope_depth;
+
+        // Hidden locals have names with spaces, which the debugger doesn't show
+        if !was_initialized && !local.name.source.contains(' ') {
+            let name = self.interner.intern(&local.name.source);
+            self.fun.locals.push(LocalVariable {
+                name,
+                slot: self.locals.len() - 1,
+                start: self.fun.chunk.code.len(),
+                end: usize::MAX,
+            });
+        }
+    }
+
+    /// Record where the scope of the local in the slot ends
+    fn end_local_variable(&mut self, slot: usize) {
+        let end = self.fun.chunk.code.len();
+        if let Some(local) = self
+            .fun
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot && local.end == usize::MAX)
+        {
+            local.end = end;
+        }
     }
 
     fn resolve_local(&mut self, name: &Token) -> isize {
-        dbgln!("Resolving local: {}", name.source);
         for (i, local) in self.locals.iter().enumerate().rev() {
             if identifiers_equal(&local.name, name) {
                 if local.depth == -1 {
-                    self.parser.error_at_current("Can't read local variable in its own initializer")
+                    self.parser
+                        .error_at_current(Code::SelfReferentialInitializer, "Can't read local variable in its own initializer")
                 }
-
-                dbgln!("Resolved to {i}");
                 return i as isize;
             }
         }
 
-        dbgln!("Resolved to -1");
         -1
     }
 
@@ -756,10 +2865,11 @@ impl<'src> Compiler<'src> {
         let local = Local {
             name: name.clone(),
             depth: -1,
+            is_const: false,
+            used: false,
+            fun: None,
         };
 
-        dbgln!("Adding local: {}", name.source);
-
         self.locals.push(local);
     }
 
@@ -779,10 +2889,22 @@ impl<'src> Compiler<'src> {
             }
 
             if identifiers_equal(&name, &local.name) {
-                self.parser.error_at_current("Already a variable with this name in this scope");
+                self.parser
+                    .error_at_current(Code::Duplicate, "Already a variable with this name in this scope");
             }
         }
 
+        let outer = self
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.depth != -1 && local.depth < self.scope_depth && identifiers_equal(&name, &local.name));
+        if let Some(outer) = outer {
+            let message = format!("Variable '{}' shadows a variable of an outer block", name.source);
+            let notes = vec![format!("The other variable is declared on line {}", outer.name.line)];
+            self.warning(&name, Code::Shadowing, message, notes);
+        }
+
         self.add_local(name);
     }
 
@@ -792,7 +2914,7 @@ impl<'src> Compiler<'src> {
             return;
         }
 
-        self.emit_bytes(Opcode::DefineGlobal as u8, global as u8);
+        self.emit_with_constant(Opcode::DefineGlobal, global);
     }
 
     fn and(&mut self, _can_assign: bool) {
@@ -800,6 +2922,7 @@ impl<'src> Compiler<'src> {
 
         self.emit_byte(Opcode::Pop as u8);
         self.parse_precedence(Precedence::And);
+        self.binary_node(TokenType::And);
 
         self.patch_jump(end_jump);
     }
@@ -812,32 +2935,157 @@ impl<'src> Compiler<'src> {
         self.emit_byte(Opcode::Pop as u8);
 
         self.parse_precedence(Precedence::Or);
+        self.binary_node(TokenType::Or);
         self.patch_jump(end_jump);
     }
 
+    /// Replace the code of a binary operator whose operands are constants with its result, see `fold`
+    fn fold_binary(&mut self, operator: TokenType, left_start: usize, right_start: usize) -> bool {
+        if self.options.opt_level == 0 {
+            return false;
+        }
+        let right_end = self.fun.chunk.code.len();
+        let (Some(a), Some(b)) = (self.constant_at(left_start, right_start), self.constant_at(right_start, right_end)) else {
+            return false;
+        };
+        match fold::binary(operator, &a, &b, self.interner) {
+            Some(value) => self.replace_with_constant(left_start, value),
+            None => false,
+        }
+    }
+
+    /// Replace the code of a unary operator whose operand is a constant with its result
+    fn fold_unary(&mut self, operator: TokenType, operand_start: usize) -> bool {
+        if self.options.opt_level == 0 {
+            return false;
+        }
+        let value = self.constant_at(operand_start, self.fun.chunk.code.len());
+        match value.and_then(|value| fold::unary(operator, &value)) {
+            Some(value) => self.replace_with_constant(operand_start, value),
+            None => false,
+        }
+    }
+
+    /// The value that the code from `start` to `end` loads, if it is a single instruction that loads a constant
+    fn constant_at(&self, start: usize, end: usize) -> Option<Value> {
+        let chunk = &self.fun.chunk;
+        let instruction = Opcode::try_from(*chunk.code.get(start)?).ok()?;
+        if start + instruction.size() != end {
+            return None;
+        }
+        match instruction {
+            Opcode::True => Some(Value::Bool(true)),
+            Opcode::False => Some(Value::Bool(false)),
+            Opcode::Nil => Some(Value::Nil),
+            Opcode::Constant | Opcode::ConstantLong => {
+                let index = chunk.constant_index(start + 1, instruction.is_long());
+                Some(chunk.constants[index].clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the code from `start` to `end` reads a local variable, without indexing it
+    fn local_at(&self, start: usize, end: usize) -> bool {
+        let code = &self.fun.chunk.code;
+        let Some(Ok(instruction)) = code.get(start).map(|&byte| Opcode::try_from(byte)) else {
+            return false;
+        };
+        let get = start + instruction.size();
+        matches!(self.constant_at(start, get), Some(Value::Nil))
+            && code.get(get) == Some(&(Opcode::GetLocal as u8))
+            && get + Opcode::GetLocal.size() == end
+    }
+
+    /// Replace the code from `start` with the value, and return true
+    fn replace_with_constant(&mut self, start: usize, value: Value) -> bool {
+        self.fun.chunk.truncate(start);
+        match value {
+            Value::Bool(true) => self.emit_byte(Opcode::True as u8),
+            Value::Bool(false) => self.emit_byte(Opcode::False as u8),
+            Value::Nil => self.emit_byte(Opcode::Nil as u8),
+            value => self.emit_constant(value),
+        }
+        true
+    }
+
     fn make_constant(&mut self, value: Value) -> usize {
         self.fun.chunk.add_constant(value)
     }
 
     fn emit_constant(&mut self, value: Value) {
         let index = self.fun.chunk.add_constant(value);
-        self.emit_bytes(Opcode::Constant as u8, index as u8);
+        self.emit_with_constant(Opcode::Constant, index);
+    }
+
+    /// Emit an instruction with a constant index, or its long variant if the index doesn't fit in a byte
+    fn emit_with_constant(&mut self, instruction: Opcode, index: usize) {
+        match (index, instruction.long()) {
+            (0..=0xff, _) => self.emit_bytes(instruction as u8, index as u8),
+            (_, Some(long)) => {
+                self.emit_byte(long as u8);
+                self.emit_u24(index);
+            }
+            (_, None) => unreachable!("{instruction} has no constant index"),
+        }
+    }
+
+    /// Emit a 24-bit operand, most significant byte first like jump offsets
+    fn emit_u24(&mut self, value: usize) {
+        if value >= 1 << 24 {
+            self.parser
+                .error_at_previous(Code::LimitExceeded, "Too many constants in one function");
+        }
+        self.emit_bytes((value >> 16) as u8, (value >> 8) as u8);
+        self.emit_byte(value as u8);
+    }
+
+    /// Emit an instruction that reads or writes a variable, whose operand is a slot for locals and a constant index for
+    /// globals
+    fn emit_variable_instruction(&mut self, instruction: Opcode, arg: usize) {
+        match instruction {
+            Opcode::GetLocal | Opcode::SetLocal => self.emit_bytes(instruction as u8, arg as u8),
+            _ => self.emit_with_constant(instruction, arg),
+        }
+    }
+
+    fn write_u16_at(&mut self, offset: usize, value: u16) {
+        self.fun.chunk.code[offset] = ((value >> 8) & 0xff) as u8;
+        self.fun.chunk.code[offset + 1] = (value & 0xff) as u8;
     }
 
     fn patch_jump(&mut self, offset: usize) {
         let jump = self.fun.chunk.code.len() - offset - 2;
 
         if jump > u16::MAX as usize {
-            self.parser.error_at_current("Too much code to jump over");
+            // Widened when the function ends
+            self.far_jumps.push((offset, self.fun.chunk.code.len()));
+            return;
         }
 
-        let jump = jump as u16;
-        self.fun.chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.fun.chunk.code[offset + 1] = (jump & 0xff) as u8;
+        self.write_u16_at(offset, jump as u16);
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(Opcode::Nil as u8);
+        if self.fun_typ == FunType::Initializer {
+            // Initializers return the new instance
+            self.emit_constant(Value::Nil); // Not an array access
+            self.emit_bytes(Opcode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(Opcode::Nil as u8);
+        }
+        self.emit_byte(Opcode::Return as u8);
+    }
+
+    /// Return the value of the expression just compiled. If it ends with a call, the call is a tail call, which reuses
+    /// the frame of the function, so recursion in tail position does not grow the stack.
+    fn emit_tail_return(&mut self) {
+        let code = &mut self.fun.chunk.code;
+        // After an error, nothing may have been emitted
+        if self.call_end == code.len() && code.len() >= 2 {
+            let call = code.len() - 2;
+            code[call] = Opcode::TailCall as u8;
+        }
         self.emit_byte(Opcode::Return as u8);
     }
 
@@ -847,6 +3095,11 @@ impl<'src> Compiler<'src> {
 
     fn emit_jump(&mut self, instr: u8) -> usize {
         self.emit_byte(instr);
+        self.emit_bytes_placeholder()
+    }
+
+    /// Emit two bytes to be patched later by `patch_jump`, and return their offset
+    fn emit_bytes_placeholder(&mut self) -> usize {
         self.emit_bytes(0xff, 0xff);
         self.fun.chunk.code.len() - 2
     }
@@ -861,7 +3114,9 @@ impl<'src> Compiler<'src> {
 
         let offset = self.fun.chunk.code.len() - loop_start + 2;
         if offset > u16::MAX as usize {
-            self.parser.error_at_current("Loop body too large");
+            self.far_jumps.push((self.fun.chunk.code.len(), loop_start));
+            self.emit_bytes(0, 0);
+            return;
         }
 
         let offset = offset as u16;
@@ -0,0 +1,94 @@
+use crate::{common::Opcode, fun::Fun};
+
+/// Offsets of the instructions that ran in each function, for `VmOptions::coverage`
+#[derive(Default)]
+pub(crate) struct CoverageCounter {
+    executed: Vec<Vec<bool>>, // By function, then by offset
+}
+
+impl CoverageCounter {
+    pub fn mark(&mut self, fun_idx: usize, offset: usize) {
+        if self.executed.len() <= fun_idx {
+            self.executed.resize_with(fun_idx + 1, Vec::new);
+        }
+        let function = &mut self.executed[fun_idx];
+        if function.len() <= offset {
+            function.resize(offset + 1, false);
+        }
+        function[offset] = true;
+    }
+
+    /// Coverage of each module, in the order of `paths`
+    pub fn coverage(&self, functions: &[Fun], paths: &[String]) -> Vec<Coverage> {
+        let mut modules: Vec<Coverage> = paths
+            .iter()
+            .map(|path| Coverage {
+                module: path.clone(),
+                ..Default::default()
+            })
+            .collect();
+        for (fun_idx, fun) in functions.iter().enumerate() {
+            let Some(module) = modules.get_mut(fun.module) else {
+                continue;
+            };
+            // A superinstruction covers the lines of the instructions it replaces, which can be on several lines
+            let code = &fun.chunk.code;
+            let mut executed = vec![false; code.len()];
+            for (offset, _) in self.executed.get(fun_idx).into_iter().flatten().enumerate().filter(|(_, &ran)| ran) {
+                let size = Opcode::try_from(code[offset]).map_or(1, |instruction| instruction.size());
+                executed[offset..(offset + size).min(code.len())].fill(true);
+            }
+            let mut start = 0;
+            for &(line, length) in &fun.chunk.lines {
+                module.executable.insert(line);
+                if executed[start..start + length].contains(&true) {
+                    module.executed.insert(line);
+                }
+                start += length;
+            }
+        }
+        modules
+    }
+}
+
+/// Set of line numbers, as a bitmap where bit `n % 64` of word `n / 64` is line `n`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineSet(Vec<u64>);
+
+impl LineSet {
+    pub fn insert(&mut self, line: usize) {
+        if self.0.len() <= line / 64 {
+            self.0.resize(line / 64 + 1, 0);
+        }
+        self.0[line / 64] |= 1 << (line % 64);
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        self.0.get(line / 64).is_some_and(|word| word & (1 << (line % 64)) != 0)
+    }
+
+    /// The lines, in increasing order
+    pub fn lines(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.0.len() * 64).filter(|&line| self.contains(line))
+    }
+
+    /// The words of the bitmap, to send it to the host as it is
+    pub fn words(&self) -> &[u64] {
+        &self.0
+    }
+}
+
+/// Lines of a module that ran, returned by `Vm::coverage` after a run with `VmOptions::coverage`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    pub module: String,
+    pub executable: LineSet, // Lines with instructions
+    pub executed: LineSet,   // Lines with instructions that ran
+}
+
+impl Coverage {
+    /// Lines with instructions that never ran
+    pub fn unreached(&self) -> Vec<usize> {
+        self.executable.lines().filter(|&line| !self.executed.contains(line)).collect()
+    }
+}
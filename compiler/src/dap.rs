@@ -0,0 +1,394 @@
+//! Debug Adapter Protocol server, so that editors like VS Code can debug programs with the debugger of the VM.
+//! Messages are read from stdin and written to stdout, so what the program prints is sent to the editor as
+//! `output` events: pass `print` and `println` of this module to `init`.
+
+use std::{
+    fs,
+    io::{self, BufRead},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    compiler::Compiler,
+    debugger::DebugEvent,
+    error::RuntimeError,
+    fun::{Fun, FunType},
+    interner::Interner,
+    module::{ModuleLoader, ModuleRegistry, MAIN_MODULE},
+    protocol::{object, read_message, send, Json},
+    vm::Vm,
+    INTERNER_DEFAULT_CAP,
+};
+
+/// Sequence number of the next message sent to the editor
+static SEQ: AtomicU64 = AtomicU64::new(1);
+
+/// Lines that the editor can step back through
+const HISTORY_STEPS: usize = 10_000;
+
+/// The only thread. Tasks are not shown as threads, since only the running one can be inspected.
+const THREAD_ID: usize = 1;
+
+fn send_event(event: &str, body: Json) {
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed) as usize;
+    send(object([
+        ("seq", seq.into()),
+        ("type", "event".into()),
+        ("event", event.into()),
+        ("body", body),
+    ]));
+}
+
+fn respond(request: &Json, body: Json) {
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed) as usize;
+    send(object([
+        ("seq", seq.into()),
+        ("type", "response".into()),
+        ("request_seq", request.get("seq").clone()),
+        ("success", true.into()),
+        ("command", request.get("command").clone()),
+        ("body", body),
+    ]));
+}
+
+fn respond_error(request: &Json, message: &str) {
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed) as usize;
+    send(object([
+        ("seq", seq.into()),
+        ("type", "response".into()),
+        ("request_seq", request.get("seq").clone()),
+        ("success", false.into()),
+        ("command", request.get("command").clone()),
+        ("message", message.into()),
+    ]));
+}
+
+/// Writer for `init`, that sends what the program prints to the editor
+pub fn print(output: String) {
+    send_event("output", object([("category", "stdout".into()), ("output", output.into())]));
+}
+
+/// Writer for `init`, like `print` with a newline
+pub fn println(output: String) {
+    print(output + "\n");
+}
+
+/// Programs can't read input while they are debugged, since stdin carries the protocol
+async fn no_input(prompt: String) -> String {
+    println(prompt);
+    String::new()
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").as_str().unwrap_or_default()
+}
+
+/// Lines of a `setBreakpoints` request, and the path of the source they are in
+fn breakpoint_lines(request: &Json) -> (PathBuf, Vec<usize>) {
+    let arguments = request.get("arguments");
+    let path = PathBuf::from(arguments.get("source").get("path").as_str().unwrap_or_default());
+    let lines = match arguments.get("breakpoints") {
+        Json::Array(breakpoints) => breakpoints
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line").as_usize())
+            .collect(),
+        _ => Vec::new(),
+    };
+    (path, lines)
+}
+
+/// Breakpoints can only be set in the main program, so the others are reported as unverified
+fn respond_breakpoints(request: &Json, lines: &[usize], verified: bool) {
+    let breakpoints = lines
+        .iter()
+        .map(|&line| object([("verified", verified.into()), ("line", line.into())]))
+        .collect::<Vec<_>>();
+    respond(request, object([("breakpoints", breakpoints.into())]));
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    canonical(a) == canonical(b)
+}
+
+fn capabilities() -> Json {
+    object([
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsTerminateRequest", true.into()),
+        ("supportsStepBack", true.into()),
+    ])
+}
+
+/// Serve one debugging session over stdin and stdout. The editor launches a program with its path, and the modules it
+/// imports are loaded with the loader that `new_loader` makes for that path.
+pub async fn serve<L: ModuleLoader + 'static>(new_loader: impl FnOnce(&Path) -> L) -> io::Result<()> {
+    let mut input = io::stdin().lock();
+    let mut breakpoints = Vec::new(); // Set before the launch, for any source
+    let mut configured = false;
+
+    let launch = loop {
+        let Some(request) = read_message(&mut input)? else {
+            return Ok(());
+        };
+        match command(&request) {
+            "initialize" => {
+                respond(&request, capabilities());
+                send_event("initialized", object([]));
+            }
+            "setBreakpoints" => {
+                let (path, lines) = breakpoint_lines(&request);
+                respond_breakpoints(&request, &lines, true);
+                breakpoints.push((path, lines));
+            }
+            "configurationDone" => {
+                configured = true;
+                respond(&request, object([]));
+            }
+            "launch" => break request,
+            "disconnect" | "terminate" => {
+                respond(&request, object([]));
+                return Ok(());
+            }
+            _ => respond_error(&request, "No program was launched"),
+        }
+    };
+
+    let arguments = launch.get("arguments");
+    let program = PathBuf::from(arguments.get("program").as_str().unwrap_or_default());
+    let stop_on_entry = arguments.get("stopOnEntry").as_bool().unwrap_or(false);
+    let source = match fs::read_to_string(&program) {
+        Ok(source) => source,
+        Err(err) => {
+            respond_error(&launch, &format!("Failed to read {}: {err}", program.display()));
+            return Ok(());
+        }
+    };
+
+    let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let mut functions: Vec<Fun> = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(new_loader(&program)));
+    match Compiler::compile(Rc::from(source), &mut interner, &mut functions, &mut modules, FunType::Script) {
+        Ok(fun) => functions.push(fun),
+        Err(err) => {
+            respond_error(&launch, &err.to_string());
+            return Ok(());
+        }
+    }
+    modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+    let main_path = modules.modules[MAIN_MODULE].path.clone();
+    respond(&launch, object([]));
+
+    let mut debuggee = Debuggee {
+        vm: Vm::new(&mut interner, functions, modules.modules, no_input),
+        lines: Vec::new(),
+        stop_on_entry,
+        program,
+        main_path,
+    };
+    debuggee.vm.record_history(HISTORY_STEPS);
+    for (path, lines) in breakpoints {
+        if same_file(&path, &debuggee.program) {
+            debuggee.set_breakpoints(lines);
+        }
+    }
+    if configured {
+        debuggee.start().await;
+    }
+    debuggee.serve(&mut input).await
+}
+
+/// A launched program, and the breakpoints in it
+struct Debuggee<'src, F, Fut>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = String>,
+{
+    vm: Vm<'src, F, Fut>,
+    program: PathBuf,
+    main_path: String, // Path of the main module in the frames of the VM
+    lines: Vec<usize>, // Lines with a breakpoint
+    stop_on_entry: bool,
+}
+
+impl<F, Fut> Debuggee<'_, F, Fut>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = String>,
+{
+    async fn serve(&mut self, input: &mut impl BufRead) -> io::Result<()> {
+        while let Some(request) = read_message(input)? {
+            match command(&request) {
+                "setBreakpoints" => {
+                    let (path, lines) = breakpoint_lines(&request);
+                    let verified = same_file(&path, &self.program);
+                    respond_breakpoints(&request, &lines, verified);
+                    if verified {
+                        self.set_breakpoints(lines);
+                    }
+                }
+                "configurationDone" => {
+                    respond(&request, object([]));
+                    self.start().await;
+                }
+                "threads" => {
+                    let thread = object([("id", THREAD_ID.into()), ("name", "main".into())]);
+                    respond(&request, object([("threads", vec![thread].into())]));
+                }
+                "stackTrace" => respond(&request, self.stack_trace()),
+                "scopes" => {
+                    let frame = request.get("arguments").get("frameId").as_usize().unwrap_or_default();
+                    let scope = |name: &str, reference: usize| {
+                        object([
+                            ("name", name.into()),
+                            ("variablesReference", reference.into()),
+                            ("expensive", false.into()),
+                        ])
+                    };
+                    let scopes = vec![scope("Locals", 2 * frame + 1), scope("Globals", 2 * frame + 2)];
+                    respond(&request, object([("scopes", scopes.into())]));
+                }
+                "variables" => {
+                    let reference = request.get("arguments").get("variablesReference").as_usize().unwrap_or_default();
+                    respond(&request, self.variables(reference));
+                }
+                "continue" => {
+                    respond(&request, object([("allThreadsContinued", true.into())]));
+                    let result = self.vm.continue_execution().await;
+                    report(result, "breakpoint");
+                }
+                "next" => {
+                    respond(&request, object([]));
+                    let result = self.vm.step_over().await;
+                    report(result, "step");
+                }
+                "stepIn" => {
+                    respond(&request, object([]));
+                    let result = self.vm.step_into().await;
+                    report(result, "step");
+                }
+                "stepOut" => {
+                    respond(&request, object([]));
+                    let result = self.vm.step_out().await;
+                    report(result, "step");
+                }
+                "stepBack" => {
+                    respond(&request, object([]));
+                    self.vm.step_back();
+                    send_stopped("step");
+                }
+                "reverseContinue" => {
+                    respond(&request, object([]));
+                    while let Some(frame) = self.vm.step_back() {
+                        if frame.module == self.main_path && self.lines.contains(&frame.line) {
+                            break;
+                        }
+                    }
+                    send_stopped("breakpoint");
+                }
+                "disconnect" | "terminate" => {
+                    respond(&request, object([]));
+                    return Ok(());
+                }
+                other => respond_error(&request, &format!("Unsupported request {other}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the program until its first pause, once the editor has sent the breakpoints
+    async fn start(&mut self) {
+        if let Err(error) = self.vm.start_debugging() {
+            return report(Err(error), "entry");
+        }
+        if self.stop_on_entry {
+            let result = self.vm.step_into().await;
+            report(result, "entry");
+        } else {
+            let result = self.vm.continue_execution().await;
+            report(result, "breakpoint");
+        }
+    }
+
+    fn set_breakpoints(&mut self, lines: Vec<usize>) {
+        for &line in &self.lines {
+            self.vm.clear_breakpoint(line);
+        }
+        for &line in &lines {
+            self.vm.set_breakpoint(line);
+        }
+        self.lines = lines;
+    }
+
+    fn stack_trace(&self) -> Json {
+        let main_dir = self.program.parent().unwrap_or(Path::new(""));
+        let frames = self
+            .vm
+            .call_stack()
+            .into_iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let path = match frame.module == self.main_path {
+                    true => self.program.clone(),
+                    false => main_dir.join(&frame.module),
+                };
+                object([
+                    ("id", id.into()),
+                    ("name", frame.function.into()),
+                    ("line", frame.line.into()),
+                    ("column", 1usize.into()),
+                    ("source", object([("path", path.display().to_string().into())])),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let total = frames.len();
+        object([("stackFrames", frames.into()), ("totalFrames", total.into())])
+    }
+
+    /// Odd references are the locals of a frame, and even ones its globals, see the `scopes` request
+    fn variables(&self, reference: usize) -> Json {
+        let frame = reference.saturating_sub(1) / 2;
+        let variables = match reference % 2 {
+            1 => self.vm.locals(frame),
+            _ => self.vm.global_variables(frame),
+        };
+        let variables = variables
+            .iter()
+            .map(|variable| {
+                object([
+                    ("name", variable.name.as_str().into()),
+                    ("value", self.vm.format_value(&variable.value).into()),
+                    ("variablesReference", 0usize.into()),
+                ])
+            })
+            .collect::<Vec<_>>();
+        object([("variables", variables.into())])
+    }
+}
+
+fn send_stopped(reason: &str) {
+    let body = object([
+        ("reason", reason.into()),
+        ("threadId", THREAD_ID.into()),
+        ("allThreadsStopped", true.into()),
+    ]);
+    send_event("stopped", body);
+}
+
+/// Tell the editor why the program stopped, or that it ended
+fn report(result: Result<DebugEvent, RuntimeError>, reason: &str) {
+    let exit_code = match result {
+        Ok(DebugEvent::Paused(_)) => return send_stopped(reason),
+        Ok(DebugEvent::Finished(_)) => 0,
+        Err(error) => {
+            send_event(
+                "output",
+                object([("category", "stderr".into()), ("output", format!("{error}\n").into())]),
+            );
+            1
+        }
+    };
+    send_event("exited", object([("exitCode", exit_code.into())]));
+    send_event("terminated", object([]));
+}
@@ -0,0 +1,141 @@
+//! Dead code elimination, which removes the code that can never run when a function has been compiled, with
+//! `CompilerOptions::opt_level` 1 or more. That is the code after a `return`, a `throw` or a jump that nothing jumps
+//! to, and the branches that a condition which is a constant, like a folded `1 < 2`, never takes.
+//!
+//! The code that can run is found by following the instructions from the start of the function, and where they jump
+//! to. Handlers are followed to their catch and finally blocks, since exceptions and returns go there.
+
+use crate::{
+    common::Opcode,
+    fun::Fun,
+    long_jump::{decode, encode, Instruction},
+    value::Value,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Remove the code of the function that can never run. The last `implicit` instructions are the return the compiler
+/// adds at the end, which is removed but not reported. Returns the line of each region of the program that was
+/// removed, or None if a jump is too far even for a long jump.
+pub(crate) fn eliminate(fun: &mut Fun, implicit: usize) -> Option<Vec<usize>> {
+    let mut instructions = decode(&fun.chunk, &HashMap::new());
+    let targets: HashSet<usize> = instructions
+        .iter()
+        .flat_map(|instruction| instruction.targets.iter().flatten().copied())
+        .collect();
+    let conditions: Vec<Option<bool>> = (0..instructions.len())
+        .map(|index| condition(fun, &instructions, &targets, index))
+        .collect();
+
+    let index_of: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.start, index))
+        .collect();
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        // Jumps to the end of the code leave the function
+        if index >= instructions.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+
+        let instruction = &instructions[index];
+        let jumps = instruction
+            .targets
+            .iter()
+            .flatten()
+            .map(|target| index_of.get(target).copied().unwrap_or(instructions.len()));
+        match conditions[index] {
+            Some(true) => pending.extend(jumps),
+            Some(false) => pending.push(index + 1),
+            None => {
+                pending.extend(jumps);
+                if falls_through(instruction.opcode) {
+                    pending.push(index + 1);
+                }
+            }
+        }
+    }
+
+    for (index, instruction) in instructions.iter_mut().enumerate() {
+        // Jumps that are never taken are removed too
+        instruction.removed = !reachable[index] || conditions[index] == Some(false);
+    }
+    let lines = removed_lines(fun, &instructions, &reachable, implicit);
+    encode(fun, &mut instructions).then_some(lines)
+}
+
+/// The line of the first instruction of the program in each run of instructions that can't be reached
+fn removed_lines(fun: &Fun, instructions: &[Instruction], reachable: &[bool], implicit: usize) -> Vec<usize> {
+    let old_lines: Vec<usize> = fun
+        .chunk
+        .lines
+        .iter()
+        .flat_map(|&(line, length)| std::iter::repeat_n(line, length))
+        .collect();
+    // Before a finally block, the completion record of the block before it is pushed, which is never reached if that
+    // block returns
+    let finally_starts: HashSet<usize> = instructions
+        .iter()
+        .filter(|instruction| instruction.opcode.short() == Opcode::PushHandler)
+        .filter_map(|instruction| instruction.targets[1])
+        .collect();
+
+    let mut lines = Vec::new();
+    let mut index = 0;
+    while index < instructions.len() {
+        if reachable[index] {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < instructions.len() && !reachable[index] {
+            index += 1;
+        }
+        let mut end = index.min(instructions.len().saturating_sub(implicit));
+        if instructions.get(index).is_some_and(|next| finally_starts.contains(&next.start)) {
+            end = end.saturating_sub(2);
+        }
+        if let Some(first) = (start..end).find(|&index| !is_bookkeeping(instructions[index].opcode)) {
+            lines.push(old_lines[instructions[first].start]);
+        }
+    }
+    lines
+}
+
+/// Whether the instruction at `index` is a conditional jump on a constant, and if so whether it jumps
+fn condition(fun: &Fun, instructions: &[Instruction], targets: &HashSet<usize>, index: usize) -> Option<bool> {
+    let jump = &instructions[index];
+    let push = &instructions[index.checked_sub(1)?];
+    if targets.contains(&jump.start) {
+        return None;
+    }
+
+    let value = match push.opcode {
+        Opcode::Nil => Value::Nil,
+        Opcode::True => Value::Bool(true),
+        Opcode::False => Value::Bool(false),
+        Opcode::Constant | Opcode::ConstantLong => {
+            fun.chunk.constants[fun.chunk.constant_index(push.start + 1, push.opcode.is_long())].clone()
+        }
+        _ => return None,
+    };
+    match jump.opcode.short() {
+        Opcode::JumpIfFalse => Some(value.is_falsey()),
+        Opcode::JumpIfNil => Some(value == Value::Nil),
+        Opcode::JumpIfNotNil => Some(value != Value::Nil),
+        _ => None,
+    }
+}
+
+/// Whether the instruction after this one can run after it
+fn falls_through(opcode: Opcode) -> bool {
+    !matches!(opcode.short(), Opcode::Jump | Opcode::Loop | Opcode::Return | Opcode::Throw)
+}
+
+/// Whether the instruction is one the compiler adds around the code of the program, like the pops at the end of a
+/// block, which isn't reported when it is removed
+fn is_bookkeeping(opcode: Opcode) -> bool {
+    matches!(opcode.short(), Opcode::Pop | Opcode::Jump | Opcode::Loop | Opcode::PopHandler)
+}
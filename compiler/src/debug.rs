@@ -1,23 +1,103 @@
-use crate::{chunk::Chunk, common::Opcode, dbg, dbgln, interner::Interner};
+//! Disassembly of chunks, one line per instruction, which `assembler::assemble` reads back into a chunk. A line is
+//! the offset of the instruction, its line in the program, its name and its operands, like
+//! `0004    2 JumpIfFalse 7 -> 14`. Constants are written as `Idx` with their index, and then the value, which is written
+//! the way the assembler reads it.
 
+use crate::{
+    chunk::Chunk,
+    common::Opcode,
+    interner::{Interner, StrId},
+    json::write_string,
+    scanner::Span,
+    value::{value_as_string, Value},
+    xprintln,
+};
+use std::fmt;
+
+/// Print the instruction at `offset`, and return the offset of the next one
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize, interner: &Interner) -> usize {
-    dbg!("{offset:04} ");
-    dbg!("{:4} ", chunk.lines[&offset]);
+    let instruction = disassemble_at(chunk, offset, interner);
+    xprintln!("{instruction}");
+    instruction.next
+}
 
-    let instruction = Opcode::try_from(chunk.code[offset]);
-    let Ok(instruction) = instruction else {
-        dbg!("Invalid opcode {:04}", chunk.code[offset],);
-        return offset + 1;
+/// An instruction of a chunk, with its operands read, for tools that show bytecode their own way, like the web UI
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    pub offset: usize,
+    pub next: usize, // Offset of the instruction after it
+    pub line: usize,
+    pub span: Option<Span>,     // Source of the statement it was compiled from, see `Chunk::span_at`
+    pub opcode: Option<Opcode>, // None if the byte at the offset is not an opcode, which is then the only operand
+    pub operands: Vec<Operand>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Number(usize), // Slot of a local variable, or how many arguments or elements there are
+    Constant {
+        index: usize,
+        value: String, // The value the way the assembler reads it, see `constant_literal`
+    },
+    Jump(Jump),
+    Handler {
+        catch: Jump,
+        finally: Jump,
+    },
+    Operator(Opcode), // Operator of a superinstruction
+}
+
+/// Where a jump goes, and how far that is from where its offset ends. A handler block at distance 0 is no block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Jump {
+    pub distance: usize,
+    pub target: usize,
+}
+
+/// The instruction at `offset`
+pub fn disassemble_at(chunk: &Chunk, offset: usize, interner: &Interner) -> DisassembledInstruction {
+    let mut disassembled = DisassembledInstruction {
+        offset,
+        next: offset + 1,
+        line: chunk.line_at(offset),
+        span: chunk.span_at(offset),
+        opcode: None,
+        operands: Vec::new(),
+    };
+    let Ok(instruction) = Opcode::try_from(chunk.code[offset]) else {
+        disassembled.operands.push(Operand::Number(chunk.code[offset] as usize));
+        return disassembled;
     };
 
-    let ret: usize = match instruction {
-        Opcode::Constant | Opcode::DefineGlobal | Opcode::GetGlobal | Opcode::SetGlobal => {
-            constant_instruction(chunk, instruction, offset, interner)
-        }
+    disassembled.operands = match instruction {
+        Opcode::Constant
+        | Opcode::DefineGlobal
+        | Opcode::GetGlobal
+        | Opcode::SetGlobal
+        | Opcode::GetProperty
+        | Opcode::SetProperty
+        | Opcode::Class
+        | Opcode::Method
+        | Opcode::Getter
+        | Opcode::Setter
+        | Opcode::StaticMember
+        | Opcode::ConstantLong
+        | Opcode::DefineGlobalLong
+        | Opcode::GetGlobalLong
+        | Opcode::SetGlobalLong
+        | Opcode::GetPropertyLong
+        | Opcode::SetPropertyLong
+        | Opcode::ClassLong
+        | Opcode::MethodLong
+        | Opcode::GetterLong
+        | Opcode::SetterLong
+        | Opcode::StaticMemberLong => vec![constant(chunk, chunk.constant_index(offset + 1, instruction.is_long()), interner)],
         Opcode::Add
         | Opcode::Return
         | Opcode::Negate
         | Opcode::Subtract
+        | Opcode::Union
+        | Opcode::Intersection
         | Opcode::Multiply
         | Opcode::Modulo
         | Opcode::Divide
@@ -30,82 +110,230 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize, interner: &Interner
         | Opcode::Print
         | Opcode::DeclareArray
         | Opcode::Pop
-        | Opcode::Not => simple_instruction(chunk, instruction, offset),
+        | Opcode::PopHandler
+        | Opcode::Throw
+        | Opcode::EndFinally
+        | Opcode::Import
+        | Opcode::Yield
+        | Opcode::IterStart
+        | Opcode::Range
+        | Opcode::GetIndex
+        | Opcode::In
+        | Opcode::Spread
+        | Opcode::SetIndex
+        | Opcode::RangeInclusive
+        | Opcode::Not => Vec::new(),
 
-        Opcode::Jump | Opcode::JumpIfFalse => jump_instruction(chunk, instruction, 1, offset),
+        Opcode::Jump
+        | Opcode::JumpIfFalse
+        | Opcode::JumpIfNil
+        | Opcode::JumpIfNotNil
+        | Opcode::Loop
+        | Opcode::JumpLong
+        | Opcode::JumpIfFalseLong
+        | Opcode::JumpIfNilLong
+        | Opcode::JumpIfNotNilLong
+        | Opcode::LoopLong => vec![Operand::Jump(jump(chunk, instruction, offset, 0))],
 
-        Opcode::Loop => jump_instruction(chunk, instruction, -1, offset),
+        Opcode::JumpIfArgPassed | Opcode::IterNext | Opcode::JumpIfArgPassedLong | Opcode::IterNextLong => {
+            vec![
+                Operand::Number(chunk.code[offset + 1] as usize),
+                Operand::Jump(jump(chunk, instruction, offset, 0)),
+            ]
+        }
 
-        Opcode::GetLocal | Opcode::SetLocal | Opcode::Call => byte_instruction(chunk, instruction, offset),
-    };
+        Opcode::PushHandler | Opcode::PushHandlerLong => vec![Operand::Handler {
+            catch: jump(chunk, instruction, offset, 0),
+            finally: jump(chunk, instruction, offset, 1),
+        }],
 
-    dbgln!("");
+        Opcode::CallNamed | Opcode::CallNamedLong => vec![
+            Operand::Number(chunk.code[offset + 1] as usize),
+            constant(chunk, chunk.constant_index(offset + 2, instruction.is_long()), interner),
+        ],
 
-    ret
+        Opcode::GetLocal
+        | Opcode::SetLocal
+        | Opcode::Call
+        | Opcode::TailCall
+        | Opcode::BuildMap
+        | Opcode::BuildArray
+        | Opcode::CallSpread
+        | Opcode::BuildTuple => vec![Operand::Number(chunk.code[offset + 1] as usize)],
+
+        Opcode::ConstantOperation | Opcode::GetLocalPlain | Opcode::LocalConstantOperation | Opcode::LocalsOperation => {
+            superinstruction(chunk, instruction, offset, interner)
+        }
+    };
+
+    disassembled.opcode = Some(instruction);
+    disassembled.next = offset + instruction.size();
+    disassembled
 }
 
-///////////////////////////
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04} {:4} ", self.offset, self.line)?;
+        match self.opcode {
+            Some(opcode) => write!(f, "{opcode}")?,
+            None => write!(f, "Invalid opcode")?,
+        }
+        for operand in &self.operands {
+            write!(f, " {operand}")?;
+        }
+        Ok(())
+    }
+}
 
-#[cfg(feature = "tracing")]
-fn jump_instruction(chunk: &Chunk, instruction: Opcode, sign: i32, offset: usize) -> usize {
-    let jump = chunk.code[offset + 1] as u16 | (chunk.code[offset + 2] as u16) << 8;
-    let mut target: isize = offset as isize + 3;
-    target += (sign * jump as i32) as isize;
-    dbgln!("{instruction} {jump} -> {}", target);
-    offset + 3
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Number(number) => write!(f, "{number}"),
+            Operand::Constant { index, value } => write!(f, "Idx {index} {value}"),
+            Operand::Jump(jump) => write!(f, "{jump}"),
+            Operand::Handler { catch, finally } => write!(f, "catch {catch} finally {finally}"),
+            Operand::Operator(operator) => write!(f, "{operator}"),
+        }
+    }
 }
 
-#[cfg(not(feature = "tracing"))]
-fn jump_instruction(_chunk: &Chunk, _instruction: Opcode, _sign: i32, offset: usize) -> usize {
-    offset + 3
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.distance, self.target)
+    }
 }
 
-///////////////////////////
+impl DisassembledInstruction {
+    /// The instruction as a JSON object, like
+    /// `{"offset":4,"line":2,"span":{"start":9,"end":40},"opcode":"JumpIfFalse","operands":[{"kind":"jump",...}]}`.
+    /// The span of an instruction that isn't from a statement, and the opcode of an invalid instruction, are null.
+    pub fn to_json(&self) -> String {
+        let mut out = format!(r#"{{"offset":{},"line":{},"span":"#, self.offset, self.line);
+        match self.span {
+            Some(span) => out.push_str(&format!(r#"{{"start":{},"end":{}}}"#, span.start, span.end)),
+            None => out.push_str("null"),
+        }
+        out.push_str(r#","opcode":"#);
+        match self.opcode {
+            Some(opcode) => write_string(&mut out, &opcode.to_string()),
+            None => out.push_str("null"),
+        }
+        out.push_str(r#","operands":["#);
+        for (idx, operand) in self.operands.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            match operand {
+                Operand::Number(number) => out.push_str(&format!(r#"{{"kind":"number","value":{number}}}"#)),
+                Operand::Constant { index, value } => {
+                    out.push_str(&format!(r#"{{"kind":"constant","index":{index},"value":"#));
+                    write_string(&mut out, value);
+                    out.push('}');
+                }
+                Operand::Jump(jump) => out.push_str(&format!(r#"{{"kind":"jump",{}}}"#, jump.json_fields())),
+                Operand::Handler { catch, finally } => out.push_str(&format!(
+                    r#"{{"kind":"handler","catch":{{{}}},"finally":{{{}}}}}"#,
+                    catch.json_fields(),
+                    finally.json_fields()
+                )),
+                Operand::Operator(operator) => out.push_str(&format!(r#"{{"kind":"operator","value":"{operator}"}}"#)),
+            }
+        }
+        out.push_str("]}");
+        out
+    }
+}
 
-#[allow(unused_variables)]
-fn simple_instruction(_chunk: &Chunk, instruction: Opcode, offset: usize) -> usize {
-    dbg!("{instruction}");
-    offset + 1
+impl Jump {
+    fn json_fields(&self) -> String {
+        format!(r#""distance":{},"target":{}"#, self.distance, self.target)
+    }
 }
 
 ///////////////////////////
 
-#[cfg(feature = "tracing")]
-fn constant_instruction(chunk: &Chunk, instruction: Opcode, offset: usize, interner: &Interner) -> usize {
-    use crate::value::print_value;
+/// The jump at `index` in the jump offsets of the instruction
+fn jump(chunk: &Chunk, instruction: Opcode, offset: usize, index: usize) -> Jump {
+    let width = if instruction.is_long() { 3 } else { 2 };
+    let end = offset + instruction.jump_operands()[index] + width;
+    let target = chunk.jump_targets(offset)[index];
+    Jump {
+        distance: target.abs_diff(end),
+        target,
+    }
+}
 
-    let constant_idx: usize = chunk.code[offset + 1].into();
-    dbg!("{instruction} Idx {constant_idx} ");
-    print_value(&chunk.constants[constant_idx], interner);
+///////////////////////////
 
-    offset + 2
+/// Superinstructions keep the instructions they replace, so their operands are where those have them
+fn superinstruction(chunk: &Chunk, instruction: Opcode, offset: usize, interner: &Interner) -> Vec<Operand> {
+    let code = &chunk.code[offset..offset + instruction.size()];
+    let operator = |byte: u8| Opcode::try_from(byte).map_or(Operand::Number(byte as usize), Operand::Operator);
+    match instruction {
+        Opcode::ConstantOperation => vec![constant(chunk, code[1] as usize, interner), operator(code[2])],
+        Opcode::GetLocalPlain => vec![Operand::Number(code[3] as usize)],
+        Opcode::LocalConstantOperation => vec![
+            Operand::Number(code[3] as usize),
+            constant(chunk, code[5] as usize, interner),
+            operator(code[6]),
+        ],
+        _ => vec![
+            Operand::Number(code[3] as usize),
+            Operand::Number(code[7] as usize),
+            operator(code[8]),
+        ],
+    }
 }
 
-#[cfg(not(feature = "tracing"))]
-fn constant_instruction(_chunk: &Chunk, _instruction: Opcode, offset: usize, _interner: &Interner) -> usize {
-    offset + 2
+///////////////////////////
+
+/// A constant operand, with its index and its value
+fn constant(chunk: &Chunk, index: usize, interner: &Interner) -> Operand {
+    Operand::Constant {
+        index,
+        value: constant_literal(&chunk.constants[index], interner),
+    }
 }
 
 ///////////////////////////
 
-#[cfg(feature = "tracing")]
-fn byte_instruction(chunk: &Chunk, instruction: Opcode, offset: usize) -> usize {
-    let slot = chunk.code[offset + 1];
-    dbg!("{instruction} {slot}");
-    offset + 2
+/// The constant the way the assembler reads it. Unlike when a value is printed, strings are quoted, numbers always
+/// have a fraction or an exponent, and arrays, enums and record types are written with all they hold.
+pub fn constant_literal(value: &Value, interner: &Interner) -> String {
+    match value {
+        Value::Nil => "nil".to_string(),
+        Value::Number(number) => format!("{number:?}"),
+        #[cfg(feature = "bigint")]
+        Value::BigInt(bigint) => format!("{bigint}n"),
+        Value::Str(id) => format!("{:?}", interner.lookup(id)),
+        Value::Array(array) => {
+            let elements: Vec<String> = array.borrow().iter().map(|element| constant_literal(element, interner)).collect();
+            format!("[{}]", elements.join(", "))
+        }
+        Value::Enum(enum_) => {
+            let members = enum_.members.iter().map(|member| match member {
+                Value::EnumMember(member) => member.name,
+                _ => unreachable!("Members of an enum are enum members"),
+            });
+            format!("<Enum {}{}>", interner.lookup(&enum_.name), names(members, interner))
+        }
+        Value::RecordType(record) => format!(
+            "<Record {}{}>",
+            interner.lookup(&record.name),
+            names(record.fields.iter().copied(), interner)
+        ),
+        _ => value_as_string(value, interner),
+    }
 }
 
-#[cfg(not(feature = "tracing"))]
-fn byte_instruction(_chunk: &Chunk, _instruction: Opcode, offset: usize) -> usize {
-    offset + 2
+///////////////////////////
+
+fn names(ids: impl Iterator<Item = StrId>, interner: &Interner) -> String {
+    ids.map(|id| format!(" {}", interner.lookup(&id))).collect()
 }
 
 ///////////////////////////
 
-#[cfg(feature = "tracing")]
 pub fn line() {
-    dbgln!("");
+    xprintln!("");
 }
-
-#[cfg(not(feature = "tracing"))]
-pub fn line() {}
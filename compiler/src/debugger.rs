@@ -0,0 +1,41 @@
+use std::collections::HashSet;
+
+use crate::{error::TraceFrame, value::Value, vm::History};
+
+/// Where the debugger pauses the program next, besides at breakpoints
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum StepMode {
+    /// At the next line, including lines of the functions it calls
+    Into,
+    /// At the next line of a frame that is at most this deep, so calls run without pausing
+    Over(usize),
+    /// Only at breakpoints
+    #[default]
+    Continue,
+}
+
+/// State of the debugger of a VM, see `Vm::start_debugging`
+#[derive(Debug, Default)]
+pub(crate) struct Debugger {
+    pub active: bool,                // Whether the program is being debugged, rather than run with `Vm::interpret`
+    pub breakpoints: HashSet<usize>, // Lines of the main module
+    pub step: StepMode,
+    pub resuming: bool,   // The program continues from a pause, so it doesn't pause again before the same instruction
+    pub history: History, // Lines that ran, see `Vm::record_history`
+}
+
+/// Why the debugger gave control back to the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugEvent {
+    /// Stopped before the first instruction of a line, in the given frame
+    Paused(TraceFrame),
+    /// The main script returned this value
+    Finished(Value),
+}
+
+/// A variable shown by the debugger
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub value: Value,
+}
@@ -0,0 +1,304 @@
+//! Problems found while compiling a program, which hosts can show however they like.
+
+use crate::{error::RuntimeError, json::write_string, scanner::Span};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Kind of problem, shown as `E` and the number for errors, like `E0001`, `W` and the number less 1000 for warnings,
+/// and `L` and the number less 2000 for lints
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    UnexpectedToken = 1,        // The parser expected something else
+    InvalidToken,               // The scanner could not make a token
+    InvalidAssignmentTarget,    // Assigning to something that isn't a variable, a field or an element
+    LimitExceeded,              // More arguments, constants, code and so on than the bytecode can hold
+    Duplicate,                  // A name declared twice in the same place
+    AssignToConstant,           // Assigning to a constant
+    UninitializedConstant,      // A constant without a value
+    SelfReferentialInitializer, // A local variable read in its own initializer
+    InvalidParameters,          // Parameters in an order or of a kind that functions can't have
+    InvalidArguments,           // Arguments of a call in an order that can't be passed
+    Misplaced,                  // A statement or expression where it is not allowed, like `return` at the top level
+    ArrayName,                  // A destructuring array where only a name is allowed
+    ModuleNotFound,             // An import of a module that could not be loaded
+    CircularImport,             // An import of a module that is still being compiled
+    MissingFeature,             // Code that needs a cargo feature the compiler was built without
+    OutOfRange,                 // A number literal too big for a number
+    RedeclaredConstant,         // A global with the name of a constant of the module
+
+    UnusedVariable = 1001, // A local variable that is never read
+    Shadowing,             // A local variable with the name of one in an outer block
+    UnreachableCode,       // Statements after a `return`
+    AssignmentInCondition, // A condition that is an assignment
+    RemovedCode,           // Code that optimizations remove, like a branch that a constant condition never takes
+
+    Naming = 2001,     // A name that doesn't follow the conventions, see `Lints::naming`
+    EmptyBlock,        // A block without statements
+    ConstantCondition, // A condition that is a literal
+    DeepNesting,       // Blocks nested too deep
+}
+
+impl Code {
+    /// How problems of this kind are usually fixed
+    pub fn hint(&self) -> Option<&'static str> {
+        let hint = match self {
+            Code::InvalidAssignmentTarget => "Only variables, fields and elements can be assigned to",
+            Code::Duplicate => "Rename one of them",
+            Code::AssignToConstant => "Declare it with 'var' to be able to change it",
+            Code::UninitializedConstant => "Give the constant a value, like 'const answer = 42;'",
+            Code::SelfReferentialInitializer => "Give the variable another name, or give it a value that doesn't use it",
+            Code::ArrayName => "Use a single name",
+            Code::CircularImport => "Move what the modules need from each other to another module",
+            Code::MissingFeature => "Build the compiler with the feature",
+            Code::OutOfRange => "Numbers go up to about 1.8e308",
+            Code::RedeclaredConstant => "Give the new variable another name, or remove one of the declarations",
+            Code::UnusedVariable => "Start the name with '_' if it is meant to be unused",
+            Code::Shadowing => "Rename one of them",
+            Code::UnreachableCode => "Remove the code, or move it before the 'return'",
+            Code::RemovedCode => "Remove the code, or change the condition that keeps it from running",
+            Code::AssignmentInCondition => "Put the assignment in parentheses if it is meant to be one",
+            Code::EmptyBlock => "Remove the block, or add a comment about why it is empty",
+            Code::DeepNesting => "Move some of the code to functions, or return early",
+            _ => return None,
+        };
+        Some(hint)
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self as u16 {
+            code @ 2000.. => write!(f, "L{:04}", code - 2000),
+            code @ 1000.. => write!(f, "W{:04}", code - 1000),
+            code => write!(f, "E{:04}", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,           // Bytes of the source the problem is about
+    pub notes: Vec<String>,   // More about the problem or how to fix it
+    pub file: Option<String>, // Path of the module the problem is in, or None if it is in the code that was compiled
+}
+
+impl Severity {
+    fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        }
+    }
+}
+
+impl Diagnostic {
+    /// The diagnostic as a JSON object, like
+    /// `{"severity":"error","code":"E0001","message":"Expect expression","file":null,"line":3,"column":14,"span":{"start":40,"end":41},"notes":[],"hint":null}`
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            r#"{{"severity":"{}","code":"{}","message":"#,
+            self.severity.name().to_lowercase(),
+            self.code
+        );
+        write_string(&mut out, &self.message);
+        out.push_str(r#","file":"#);
+        match &self.file {
+            Some(file) => write_string(&mut out, file),
+            None => out.push_str("null"),
+        }
+        out.push_str(&format!(
+            r#","line":{},"column":{},"span":{{"start":{},"end":{}}},"notes":["#,
+            self.line, self.column, self.span.start, self.span.end
+        ));
+        for (idx, note) in self.notes.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            write_string(&mut out, note);
+        }
+        out.push_str(r#"],"hint":"#);
+        match self.code.hint() {
+            Some(hint) => write_string(&mut out, hint),
+            None => out.push_str("null"),
+        }
+        out.push('}');
+        out
+    }
+
+    /// The diagnostic, with the line of `source` it is about and its span underlined, like
+    /// ```text
+    /// Error E0001: Expect expression
+    ///  --> line 3:14
+    ///   |
+    /// 3 |   var y = x +;
+    ///   |              ^
+    /// ```
+    /// followed by its notes and a hint. `source` is the code that was compiled, and the line is left out if the
+    /// diagnostic is about other code, like a module it imports.
+    pub fn render(&self, source: &str) -> String {
+        self.render_in(self.file.is_none().then_some(source))
+    }
+
+    /// `render`, with the line from `source`, which is the code of the file the diagnostic is in, if it is known
+    pub(crate) fn render_in(&self, source: Option<&str>) -> String {
+        let mut text = format!("{} {}: {}\n", self.severity.name(), self.code, self.message);
+        let gutter = " ".repeat(self.line.to_string().len());
+        text.push_str(&format!("{gutter}--> line {}:{}", self.line, self.column));
+        if let Some(file) = &self.file {
+            text.push_str(&format!(" of {file}"));
+        }
+        text.push('\n');
+
+        if let Some(source) = source.filter(|source| self.in_source(source)) {
+            let end = self.span.end.min(source.len());
+            let start = self.span.start.min(end);
+            let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+            let line_end = source[start..].find('\n').map_or(source.len(), |newline| start + newline);
+            let line = source[line_start..line_end].trim_end_matches('\r');
+            // Tabs stay tabs, so that the underline lines up with the code whatever their width
+            let indent: String = source[line_start..start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let width = source[start..end.min(line_end)].chars().count().max(1);
+            text.push_str(&format!(
+                "{gutter} |\n{} | {line}\n{gutter} | {indent}{}\n",
+                self.line,
+                "^".repeat(width)
+            ));
+        }
+
+        for note in &self.notes {
+            text.push_str(&format!("{gutter} = note: {note}\n"));
+        }
+        if let Some(hint) = self.code.hint() {
+            text.push_str(&format!("{gutter} = help: {hint}\n"));
+        }
+        text
+    }
+
+    /// Whether the span is in the source, on the line of the diagnostic
+    fn in_source(&self, source: &str) -> bool {
+        let end = self.span.end.min(source.len());
+        let start = self.span.start.min(end);
+        source.is_char_boundary(start) && source.is_char_boundary(end) && source[..end].matches('\n').count() + 1 == self.line
+    }
+
+    /// `render`, with the characters that are special in HTML escaped, to show it in a web page
+    pub fn render_html(&self, source: &str) -> String {
+        escape_html(&self.render(source))
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}{}] {} {}: {}",
+            self.line,
+            self.column,
+            self.file.as_ref().map_or(String::new(), |file| format!(" of {file}")),
+            self.severity.name(),
+            self.code,
+            self.message
+        )?;
+        for note in &self.notes {
+            write!(f, "\n  note: {note}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Error of a program that didn't compile, with the problems in the order they were found
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CompileError {
+    /// The diagnostics rendered with `Diagnostic::render`, separated by blank lines
+    pub fn render(&self, source: &str) -> String {
+        let rendered: Vec<String> = self.diagnostics.iter().map(|diagnostic| diagnostic.render(source)).collect();
+        rendered.join("\n")
+    }
+
+    /// `render`, with the characters that are special in HTML escaped
+    pub fn render_html(&self, source: &str) -> String {
+        escape_html(&self.render(source))
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, diagnostic) in self.diagnostics.iter().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Text of an error of compiling and running `source`, with the code that compile errors are about
+pub fn render_error(error: &anyhow::Error, source: &str) -> String {
+    match error.downcast_ref::<CompileError>() {
+        Some(error) => error.render(source).trim_end().to_string(),
+        None => error.to_string(),
+    }
+}
+
+/// Result of compiling and running a program as a JSON object, for tools that show it their own way, like
+/// `{"ok":false,"diagnostics":[...],"runtimeError":null}`. Diagnostics are objects of `Diagnostic::to_json`, with the
+/// warnings about a program that compiled or the errors of one that didn't, and a runtime error is an object of
+/// `RuntimeError::to_json`. Other errors are in `error`.
+pub fn result_json(result: &anyhow::Result<()>, warnings: &[Diagnostic]) -> String {
+    let error = result.as_ref().err();
+    let compile_error = error.and_then(|error| error.downcast_ref::<CompileError>());
+    let runtime_error = error.and_then(|error| error.downcast_ref::<RuntimeError>());
+    let diagnostics = compile_error.map_or(warnings, |error| &error.diagnostics);
+
+    let mut out = format!(r#"{{"ok":{},"diagnostics":["#, result.is_ok());
+    for (idx, diagnostic) in diagnostics.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        out.push_str(&diagnostic.to_json());
+    }
+    out.push_str(r#"],"runtimeError":"#);
+    out.push_str(&runtime_error.map_or("null".to_string(), RuntimeError::to_json));
+    if let Some(error) = error.filter(|_| compile_error.is_none() && runtime_error.is_none()) {
+        out.push_str(r#","error":"#);
+        write_string(&mut out, &error.to_string());
+    }
+    out.push('}');
+    out
+}
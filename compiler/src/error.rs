@@ -0,0 +1,89 @@
+use crate::json::write_string;
+use std::fmt;
+
+/// Frames shown at each end of a traceback. Frames in between are summarized, since deep recursion has thousands.
+const TRACEBACK_EDGE: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    /// The program threw a value, like a runtime error or `throw`, and nothing caught it
+    Uncaught,
+    /// The program ran more instructions than `VmOptions::max_instructions`
+    ExecutionLimitExceeded { limit: u64 },
+    /// The program was stopped with `VmOptions::cancel_token`
+    Cancelled,
+    /// The code of a function failed `verify::verify_function`, so the program didn't run
+    InvalidBytecode,
+}
+
+/// A function that was running when the error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: String, // `<script>` for the top level of a module
+    pub module: String,   // Path of the module the function is in
+    pub line: usize,
+    pub offset: usize, // Offset of the instruction in the chunk of the function
+}
+
+/// Error that stopped a program, returned by `Vm::interpret`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub offset: usize,          // Offset of the instruction that failed
+    pub stack: Vec<TraceFrame>, // Most recent call first
+}
+
+impl RuntimeError {
+    /// The error as a JSON object, like
+    /// `{"kind":"uncaught","message":"...","line":3,"offset":12,"stack":[{"function":"f","module":"main","line":3,"offset":12}]}`.
+    /// An execution limit error also has the limit, in `limit`.
+    pub fn to_json(&self) -> String {
+        let kind = match self.kind {
+            RuntimeErrorKind::Uncaught => "uncaught",
+            RuntimeErrorKind::ExecutionLimitExceeded { .. } => "executionLimitExceeded",
+            RuntimeErrorKind::Cancelled => "cancelled",
+            RuntimeErrorKind::InvalidBytecode => "invalidBytecode",
+        };
+        let mut out = format!(r#"{{"kind":"{kind}","message":"#);
+        write_string(&mut out, &self.message);
+        if let RuntimeErrorKind::ExecutionLimitExceeded { limit } = self.kind {
+            out.push_str(&format!(r#","limit":{limit}"#));
+        }
+        out.push_str(&format!(r#","line":{},"offset":{},"stack":["#, self.line, self.offset));
+        for (idx, frame) in self.stack.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            out.push_str(r#"{"function":"#);
+            write_string(&mut out, &frame.function);
+            out.push_str(r#","module":"#);
+            write_string(&mut out, &frame.module);
+            out.push_str(&format!(r#","line":{},"offset":{}}}"#, frame.line, frame.offset));
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Runtime error: {}", self.message)?;
+        write!(f, "Traceback (most recent call first):")?;
+        let depth = self.stack.len();
+        let collapsed = depth > 2 * TRACEBACK_EDGE + 1;
+        for (idx, frame) in self.stack.iter().enumerate() {
+            if collapsed && idx == TRACEBACK_EDGE {
+                write!(f, "\n... {} more calls ...", depth - 2 * TRACEBACK_EDGE)?;
+            }
+            if collapsed && (TRACEBACK_EDGE..depth - TRACEBACK_EDGE).contains(&idx) {
+                continue;
+            }
+            write!(f, "\n[line {:3}] in {}", frame.line, frame.function)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -0,0 +1,84 @@
+//! Constant folding, which computes operators on literals when the program is compiled, like `2 * 3 + 1` into `7`.
+//!
+//! It is done as the operators are emitted: when both operands of an operator have just been emitted as constants,
+//! they are replaced by a constant of the result, which can then be an operand of the operator around it. Results are
+//! computed as the VM does, and operators that would fail at runtime are left for the VM to report.
+
+use crate::{
+    common::Opcode,
+    interner::Interner,
+    scanner::TokenType,
+    value::{value_as_string, Value},
+    vm::arithmetic,
+};
+use std::cmp::Ordering;
+
+/// Whether the value can be folded, which is only true of the values of literals
+fn is_literal(value: &Value) -> bool {
+    match value {
+        Value::Nil | Value::Bool(_) | Value::Int(_) | Value::Number(_) | Value::Str(_) => true,
+        #[cfg(feature = "bigint")]
+        Value::BigInt(_) => true,
+        _ => false,
+    }
+}
+
+/// Result of the binary operator on two constants, or `None` if it can't be folded
+pub(crate) fn binary(operator: TokenType, a: &Value, b: &Value, interner: &mut Interner) -> Option<Value> {
+    if !is_literal(a) || !is_literal(b) {
+        return None;
+    }
+
+    let value = match operator {
+        TokenType::Plus => match (a, b) {
+            (Value::Str(a), Value::Str(b)) => {
+                let text = format!("{}{}", interner.lookup(a), interner.lookup(b));
+                Value::Str(interner.intern(&text))
+            }
+            (Value::Str(a), b @ (Value::Int(_) | Value::Number(_))) => {
+                let text = format!("{}{}", interner.lookup(a), value_as_string(b, interner));
+                Value::Str(interner.intern(&text))
+            }
+            _ => arithmetic(Opcode::Add, a, b)?,
+        },
+        TokenType::Minus => arithmetic(Opcode::Subtract, a, b)?,
+        TokenType::Star => arithmetic(Opcode::Multiply, a, b)?,
+        TokenType::Slash => arithmetic(Opcode::Divide, a, b)?,
+        TokenType::Modulo => arithmetic(Opcode::Modulo, a, b)?,
+        TokenType::EqualEqual => Value::Bool(a == b),
+        TokenType::BangEqual => Value::Bool(a != b),
+        // `>=` and `<=` are compiled as the negation of `<` and `>`, which is different for NaN
+        TokenType::Greater => Value::Bool(compare(a, b, interner)? == Some(Ordering::Greater)),
+        TokenType::Less => Value::Bool(compare(a, b, interner)? == Some(Ordering::Less)),
+        TokenType::GreaterEqual => Value::Bool(compare(a, b, interner)? != Some(Ordering::Less)),
+        TokenType::LessEqual => Value::Bool(compare(a, b, interner)? != Some(Ordering::Greater)),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// How two numbers or two strings compare, which is `Some(None)` for NaN, or `None` if they can't be compared
+fn compare(a: &Value, b: &Value, interner: &Interner) -> Option<Option<Ordering>> {
+    let ordering = match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+        (Value::Str(a), Value::Str(b)) => interner.lookup(a).partial_cmp(interner.lookup(b)),
+        (Value::Int(_) | Value::Number(_), Value::Int(_) | Value::Number(_)) => a.as_number()?.partial_cmp(&b.as_number()?),
+        _ => return None,
+    };
+    Some(ordering)
+}
+
+/// Result of the unary operator on a constant, or `None` if it can't be folded
+pub(crate) fn unary(operator: TokenType, value: &Value) -> Option<Value> {
+    if !is_literal(value) {
+        return None;
+    }
+
+    match (operator, value) {
+        (TokenType::Bang, value) => Some(Value::Bool(value.is_falsey())),
+        (TokenType::Minus, Value::Number(n)) => Some(Value::Number(-n)),
+        // The VM turns `-i64::MIN` into a float or a big integer, which is left to it
+        (TokenType::Minus, Value::Int(i)) => i.checked_neg().map(Value::Int),
+        _ => None,
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{
+    interner::Interner,
+    value::{value_as_string, Value},
+};
+
+/// How a single `{...}` placeholder should be written, parsed from `[[fill]align][+][0][width][.precision][type]`
+#[derive(Debug, Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<char>, // '<', '>' or '^'
+    sign: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    typ: Option<char>, // 'x', 'X', 'b', 'o' or 'e'
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Result<Spec, String> {
+        let mut result = Spec::default();
+        let mut chars: Vec<char> = spec.chars().collect();
+
+        if chars.len() >= 2 && matches!(chars[1], '<' | '>' | '^') {
+            result.fill = Some(chars[0]);
+            result.align = Some(chars[1]);
+            chars.drain(..2);
+        } else if !chars.is_empty() && matches!(chars[0], '<' | '>' | '^') {
+            result.align = Some(chars[0]);
+            chars.remove(0);
+        }
+
+        let mut rest = chars.into_iter().peekable();
+        if rest.next_if_eq(&'+').is_some() {
+            result.sign = true;
+        }
+        if rest.next_if_eq(&'0').is_some() {
+            result.zero = true;
+        }
+        while let Some(digit) = rest.next_if(char::is_ascii_digit) {
+            result.width = result.width * 10 + digit.to_digit(10).unwrap() as usize;
+        }
+        if rest.next_if_eq(&'.').is_some() {
+            let mut precision = 0;
+            while let Some(digit) = rest.next_if(char::is_ascii_digit) {
+                precision = precision * 10 + digit.to_digit(10).unwrap() as usize;
+            }
+            result.precision = Some(precision);
+        }
+        result.typ = rest.next_if(|c| matches!(c, 'x' | 'X' | 'b' | 'o' | 'e'));
+
+        match rest.next() {
+            Some(c) => Err(format!("Unexpected '{c}' in format spec '{spec}'")),
+            None => Ok(result),
+        }
+    }
+
+    fn apply(&self, value: &Value, interner: &Interner) -> Result<String, String> {
+        let body = match (value, self.typ) {
+            (Value::Int(i), Some('x')) => format!("{i:x}"),
+            (Value::Int(i), Some('X')) => format!("{i:X}"),
+            (Value::Int(i), Some('b')) => format!("{i:b}"),
+            (Value::Int(i), Some('o')) => format!("{i:o}"),
+            (_, Some(typ @ ('x' | 'X' | 'b' | 'o'))) => return Err(format!("Format type '{typ}' needs an integer, got {value}")),
+            (Value::Int(_) | Value::Number(_), Some('e')) => match self.precision {
+                Some(precision) => format!("{:.*e}", precision, value.as_number().unwrap()),
+                None => format!("{:e}", value.as_number().unwrap()),
+            },
+            (_, Some('e')) => return Err(format!("Format type 'e' needs a number, got {value}")),
+            (Value::Int(_) | Value::Number(_), None) if self.precision.is_some() => {
+                format!("{:.*}", self.precision.unwrap(), value.as_number().unwrap())
+            }
+            (Value::Str(_), None) if self.precision.is_some() => {
+                value_as_string(value, interner).chars().take(self.precision.unwrap()).collect()
+            }
+            _ => value_as_string(value, interner),
+        };
+
+        let is_number = value.as_number().is_some();
+        let body = if self.sign && is_number && !body.starts_with('-') {
+            format!("+{body}")
+        } else {
+            body
+        };
+
+        let len = body.chars().count();
+        if len >= self.width {
+            return Ok(body);
+        }
+        let padding = self.width - len;
+
+        // Zero padding goes between the sign and the digits
+        if self.zero && self.align.is_none() && is_number {
+            let (sign, digits) = match body.strip_prefix(['-', '+']) {
+                Some(digits) => body.split_at(body.len() - digits.len()),
+                None => ("", body.as_str()),
+            };
+            return Ok(format!("{sign}{}{digits}", "0".repeat(padding)));
+        }
+
+        // Numbers are right aligned and everything else left aligned, unless asked otherwise
+        let fill = self.fill.unwrap_or(' ').to_string();
+        let align = self.align.unwrap_or(if is_number { '>' } else { '<' });
+        Ok(match align {
+            '<' => format!("{body}{}", fill.repeat(padding)),
+            '>' => format!("{}{body}", fill.repeat(padding)),
+            _ => format!("{}{body}{}", fill.repeat(padding / 2), fill.repeat(padding - padding / 2)),
+        })
+    }
+}
+
+/// Replace the `{}` placeholders in `format` with the arguments, in order. Placeholders can also pick an argument
+/// by position like `{1}`, and take a spec after a colon like `{:>8.2}`. `{{` and `}}` are literal braces.
+pub(crate) fn format(format: &str, args: &[Value], interner: &Interner) -> Result<String, String> {
+    let mut result = String::new();
+    let mut next_arg = 0;
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'{').is_some() => result.push('{'),
+            '}' if chars.next_if_eq(&'}').is_some() => result.push('}'),
+            '}' => return Err("Unmatched '}' in format string".to_string()),
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err("Unterminated '{' in format string".to_string()),
+                    }
+                }
+
+                let (position, spec) = placeholder.split_once(':').unwrap_or((&placeholder, ""));
+                let index = if position.is_empty() {
+                    next_arg += 1;
+                    next_arg - 1
+                } else {
+                    position
+                        .parse()
+                        .map_err(|_| format!("Invalid argument position '{position}' in format string"))?
+                };
+
+                let Some(arg) = args.get(index) else {
+                    return Err(format!(
+                        "Format string refers to argument {index} but only {} were given",
+                        args.len()
+                    ));
+                };
+                result.push_str(&Spec::parse(spec)?.apply(arg, interner)?);
+            }
+            c => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
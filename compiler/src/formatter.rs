@@ -0,0 +1,258 @@
+//! Formatter, which prints programs with the same indentation and spacing whatever they were written with.
+//!
+//! The program is compiled first, so that only valid programs are formatted, and then its tokens are printed again
+//! with the spacing between them decided by the kinds of tokens and the brackets they are in. Comments are kept, on
+//! their own line or after the code on a line as they were, and so are single blank lines between statements.
+
+use crate::{
+    compiler::check,
+    scanner::{Scanner, Token, TokenType},
+};
+use std::rc::Rc;
+
+const INDENT: &str = "    ";
+
+/// What the innermost bracket is, which decides how the tokens in it are laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    Block,  // Statements, one per line: the top level, blocks and bodies of classes
+    Inline, // Maps, destructuring declarations, parentheses and arrays, on one line
+    Enum,   // The variants of an enum, on one line that ends after them
+}
+
+/// Format `source` with canonical indentation and spacing, or fail with the errors it has if it doesn't compile
+pub fn format_source(source: &str) -> anyhow::Result<String> {
+    check(source)?;
+    Ok(Formatter::new(source).format())
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    out: String,
+    indent: usize,
+    contexts: Vec<(Context, bool)>, // With whether the block is a `switch` whose case is being laid out
+    previous: Option<Token>,
+    before_previous: Option<TokenType>,
+    at_line_start: bool,
+    closed_block: bool, // Whether the previous token is the `}` of a block, after which the line ends
+    unary_minus: bool,  // Whether the previous token is a `-` that negates what follows
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str) -> Formatter<'a> {
+        Formatter {
+            source,
+            out: String::new(),
+            indent: 0,
+            contexts: vec![(Context::Block, false)],
+            previous: None,
+            before_previous: None,
+            at_line_start: true,
+            closed_block: false,
+            unary_minus: false,
+        }
+    }
+
+    fn format(mut self) -> String {
+        let mut scanner = Scanner::new(Rc::from(self.source));
+        loop {
+            let token = scanner.scan_token();
+            let gap_start = self.previous.as_ref().map_or(0, |previous| previous.span.end);
+            let continues_line = matches!(
+                token.typ,
+                TokenType::Else
+                    | TokenType::Catch
+                    | TokenType::Finally
+                    | TokenType::RightParen
+                    | TokenType::RightBracket
+                    | TokenType::Comma
+                    | TokenType::Semicolon
+                    | TokenType::Dot
+            );
+            if self.closed_block && !continues_line {
+                self.newline();
+            }
+            self.comments(&self.source[gap_start..token.span.start], token.typ);
+            if token.typ == TokenType::EOF {
+                break;
+            }
+            self.token(token);
+        }
+        if !self.at_line_start {
+            self.out.push('\n');
+        }
+        self.out
+    }
+
+    fn context(&self) -> Context {
+        self.contexts.last().map_or(Context::Block, |&(context, _)| context)
+    }
+
+    fn newline(&mut self) {
+        if !self.at_line_start {
+            self.out.push('\n');
+            self.at_line_start = true;
+        }
+    }
+
+    fn write(&mut self, text: &str, space: bool) {
+        if self.at_line_start {
+            self.out.push_str(&INDENT.repeat(self.indent));
+            self.at_line_start = false;
+        } else if space {
+            self.out.push(' ');
+        }
+        self.out.push_str(text);
+    }
+
+    /// Write the comments in the text between two tokens, and a blank line where it has one between statements
+    fn comments(&mut self, gap: &str, next: TokenType) {
+        let mut newlines = 0;
+        let mut rest = gap;
+        while let Some(start) = rest.find("//") {
+            newlines += rest[..start].matches('\n').count();
+            let end = rest[start..].find('\n').map_or(rest.len(), |newline| start + newline);
+            let comment = rest[start..end].trim_end();
+            if newlines == 0 && self.previous.is_some() {
+                // After the code on its line, which may have been ended already
+                if self.at_line_start && self.out.ends_with('\n') {
+                    self.out.pop();
+                    self.at_line_start = false;
+                }
+                self.write(comment, true);
+            } else {
+                self.newline();
+                self.blank_line(newlines);
+                self.write(comment, false);
+            }
+            self.newline();
+            newlines = 0;
+            rest = &rest[end..];
+        }
+        newlines += rest.matches('\n').count();
+        if self.at_line_start && !matches!(next, TokenType::RightBrace | TokenType::EOF) {
+            self.blank_line(newlines);
+        }
+    }
+
+    /// Keep a blank line of the source, other than at the start of a block or of the program
+    fn blank_line(&mut self, newlines: usize) {
+        let after_brace = self.out.trim_end().ends_with('{') || self.out.is_empty();
+        if newlines >= 2 && self.at_line_start && !after_brace && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn token(&mut self, token: Token) {
+        use TokenType::*;
+        let previous = self.previous.as_ref().map(|previous| previous.typ);
+        let ends_operand = matches!(
+            previous,
+            Some(Identifier | String | Number | True | False | Nil | This | Super | RightParen | RightBracket)
+        ) || (previous == Some(RightBrace) && !self.closed_block);
+        self.closed_block = false;
+        let after_opening = matches!(previous, Some(LeftParen | LeftBracket | Dot | QuestionDot | Ellipsis | Bang))
+            || (previous == Some(LeftBrace) && self.context() != Context::Block)
+            || (previous == Some(Minus) && self.unary_minus);
+
+        match token.typ {
+            LeftBrace => {
+                let is_block = match previous {
+                    None | Some(Semicolon | RightBrace | RightParen | Else | Try | Finally | Arrow | String) => true,
+                    Some(LeftBrace) => self.context() == Context::Block,
+                    Some(Colon) => self.context() == Context::Block,
+                    Some(Identifier) => self.before_previous != Some(Enum),
+                    _ => false,
+                };
+                if is_block {
+                    self.write("{", !self.at_line_start);
+                    self.contexts.push((Context::Block, false));
+                    self.indent += 1;
+                    self.newline();
+                } else if previous == Some(Identifier) {
+                    self.write("{", true);
+                    self.contexts.push((Context::Enum, false));
+                } else {
+                    self.write("{", !after_opening);
+                    self.contexts.push((Context::Inline, false));
+                }
+            }
+            RightBrace => {
+                let (context, in_case) = self.contexts.pop().unwrap_or((Context::Block, false));
+                match context {
+                    Context::Block => {
+                        self.indent = self.indent.saturating_sub(1 + in_case as usize);
+                        if self.out.trim_end().ends_with('{') {
+                            // An empty block stays `{}`
+                            self.out.truncate(self.out.trim_end().len());
+                            self.at_line_start = false;
+                            self.out.push('}');
+                        } else {
+                            self.newline();
+                            self.write("}", false);
+                        }
+                        self.closed_block = true;
+                    }
+                    Context::Inline => self.write("}", false),
+                    Context::Enum => {
+                        self.write("}", false);
+                        self.newline();
+                    }
+                }
+            }
+            LeftParen | LeftBracket => {
+                let space = match previous {
+                    Some(If | While | For | Switch | Catch) => true,
+                    _ => !ends_operand && !after_opening && previous != Some(Fun),
+                };
+                self.write(&token.source, space && !self.at_line_start);
+                self.contexts.push((Context::Inline, false));
+            }
+            RightParen | RightBracket => {
+                self.contexts.pop();
+                self.write(&token.source, false);
+            }
+            Comma | Dot | QuestionDot | DotDot | DotDotEqual => self.write(&token.source, false),
+            Semicolon => {
+                self.write(";", false);
+                if self.context() == Context::Block {
+                    self.newline();
+                }
+            }
+            Colon => {
+                self.write(":", false);
+                // The colon of a case of a switch, whose statements are indented under it
+                if self.context() == Context::Block {
+                    if let Some((_, in_case)) = self.contexts.last_mut() {
+                        if !*in_case {
+                            *in_case = true;
+                            self.indent += 1;
+                        }
+                    }
+                    self.newline();
+                }
+            }
+            Case | Default => {
+                if let Some((_, in_case)) = self.contexts.last_mut() {
+                    if *in_case {
+                        *in_case = false;
+                        self.indent -= 1;
+                    }
+                }
+                self.newline();
+                self.write(&token.source, false);
+            }
+            Minus => {
+                self.unary_minus = !ends_operand;
+                self.write("-", !after_opening);
+            }
+            _ => {
+                let after_range = matches!(previous, Some(DotDot | DotDotEqual));
+                self.write(&token.source, !after_opening && !after_range);
+            }
+        }
+
+        self.before_previous = previous;
+        self.previous = Some(token);
+    }
+}
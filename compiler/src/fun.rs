@@ -1,10 +1,33 @@
-use crate::{chunk::Chunk, interner::StrId};
+use crate::{
+    chunk::Chunk,
+    interner::{Interner, StrId},
+    module::{Module, MAIN_MODULE},
+    register::RegisterFunction,
+};
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct Fun {
     pub arity: usize,
+    pub min_arity: usize,  // Parameters after this have default values
+    pub is_variadic: bool, // Last parameter collects the remaining arguments into an array
+    pub param_names: Vec<StrId>,
     pub chunk: Chunk,
     pub name: Option<StrId>,
+    pub module: usize,                          // Module whose globals the function uses
+    pub is_method: bool,                        // Methods have the receiver in slot 0, before the parameters
+    pub is_generator: bool,                     // Contains `yield`, so calling it creates a generator instead of running it
+    pub locals: Vec<LocalVariable>,             // Named locals, for the debugger
+    pub register: Option<Rc<RegisterFunction>>, // The function lowered by the register backend, if it could be
+}
+
+/// Slot of a local variable, and the instructions that are in its scope
+#[derive(Debug, Clone)]
+pub struct LocalVariable {
+    pub name: StrId,
+    pub slot: usize,
+    pub start: usize, // Offset of the first instruction after the variable is initialized
+    pub end: usize,   // Offset after the scope ends, or usize::MAX if it lasts until the function returns
 }
 
 impl Default for Fun {
@@ -17,8 +40,27 @@ impl Fun {
     pub fn new() -> Fun {
         Fun {
             arity: 0,
+            min_arity: 0,
+            is_variadic: false,
+            param_names: Vec::new(),
             chunk: Chunk::default(),
             name: None,
+            module: 0,
+            is_method: false,
+            is_generator: false,
+            locals: Vec::new(),
+            register: None,
+        }
+    }
+}
+
+impl Fun {
+    /// Name of the function where its code is shown, which is the path of the module for the top level of a module
+    pub fn display_name<'a>(&self, interner: &'a Interner, modules: &'a [Module]) -> &'a str {
+        match self.name {
+            Some(name) => interner.lookup(&name),
+            None if self.module == MAIN_MODULE => "script",
+            None => &modules[self.module].path,
         }
     }
 }
@@ -27,4 +69,9 @@ impl Fun {
 pub enum FunType {
     Function,
     Script,
+    Module,
+    Method,
+    Initializer,
+    StaticMethod,
+    Test, // Body of a `test` declaration, which has no parameter list
 }
@@ -0,0 +1,310 @@
+//! Garbage collector for reference cycles.
+//!
+//! Values are reference counted, which frees everything except cycles, like an array that contains itself or an
+//! instance whose field points back to it. Containers that can be part of a cycle register a header with the heap when
+//! they are created. When enough of them have been allocated, the VM marks everything reachable from its roots, and
+//! the containers it did not reach are cleared, which breaks the cycles so their memory is freed.
+//!
+//! A container that is reachable but missed by the marking, for example because a native holds it where the VM can't
+//! see it, would be cleared while still in use. To find such bugs, the VM can collect after every instruction
+//! (`VmOptions::gc_stress`) and remember what it cleared, so `verify` can report a cleared container that is
+//! reachable again (`VmOptions::gc_verify`).
+
+use crate::{
+    interner::StrId,
+    value::{Class, Generator, GeneratorState, Instance, MapKey, Value, ValueArray, ValueMap, ValueQueue},
+};
+use rustc_hash::FxHashSet;
+use std::{
+    cell::RefCell,
+    mem::{size_of, size_of_val},
+    rc::{Rc, Weak},
+};
+
+/// Allocations before the first collection. Later collections happen after twice as many allocations as survived.
+const INITIAL_THRESHOLD: usize = 10_000;
+
+/// Containers that hold values, and so can be part of a cycle
+#[derive(Clone)]
+enum Object {
+    Array(Weak<RefCell<ValueArray>>),
+    Map(Weak<RefCell<ValueMap>>),
+    Instance(Weak<RefCell<Instance>>),
+    Class(Weak<Class>),
+    Generator(Weak<RefCell<Generator>>),
+    Channel(Weak<RefCell<ValueQueue>>),
+}
+
+/// Header of a tracked container. Only the VM that created a container collects it, so several VMs can share a thread.
+struct Header {
+    owner: usize,
+    object: Object,
+}
+
+impl Header {
+    fn upgrade(&self) -> Option<Value> {
+        match &self.object {
+            Object::Array(weak) => weak.upgrade().map(Value::Array),
+            Object::Map(weak) => weak.upgrade().map(Value::Map),
+            Object::Instance(weak) => weak.upgrade().map(Value::Instance),
+            Object::Class(weak) => weak.upgrade().map(Value::Class),
+            Object::Generator(weak) => weak.upgrade().map(Value::Generator),
+            Object::Channel(weak) => weak.upgrade().map(Value::Channel),
+        }
+    }
+}
+
+struct Heap {
+    objects: Vec<Header>,
+    cleared: Vec<Header>, // Containers that were cleared while verification was on, for `verify`
+    active_vm: usize,     // VM that is running, which owns new containers
+    next_vm: usize,
+    allocations: usize, // Since the last collection
+    threshold: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap {
+            objects: Vec::new(),
+            cleared: Vec::new(),
+            active_vm: 0,
+            next_vm: 1,
+            allocations: 0,
+            threshold: INITIAL_THRESHOLD,
+        })
+    };
+}
+
+fn track(object: Object) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        let owner = heap.active_vm;
+        heap.objects.push(Header { owner, object });
+        heap.allocations += 1;
+    });
+}
+
+pub(crate) fn new_vm_id() -> usize {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.next_vm += 1;
+        heap.next_vm - 1
+    })
+}
+
+/// Containers created from now on belong to the given VM, until another one becomes active
+pub(crate) fn set_active_vm(id: usize) {
+    HEAP.with(|heap| heap.borrow_mut().active_vm = id);
+}
+
+/// Whether enough containers have been allocated to collect. When stressing, any allocation is enough.
+pub(crate) fn collection_due(stress: bool) -> bool {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocations >= if stress { 1 } else { heap.threshold }
+    })
+}
+
+/// Number of tracked containers, including ones that were freed since the last collection
+pub fn tracked_objects() -> usize {
+    HEAP.with(|heap| heap.borrow().objects.len())
+}
+
+pub(crate) fn new_array(elements: ValueArray) -> Rc<RefCell<ValueArray>> {
+    let array = Rc::new(RefCell::new(elements));
+    track(Object::Array(Rc::downgrade(&array)));
+    array
+}
+
+pub(crate) fn new_map(map: ValueMap) -> Rc<RefCell<ValueMap>> {
+    let map = Rc::new(RefCell::new(map));
+    track(Object::Map(Rc::downgrade(&map)));
+    map
+}
+
+pub(crate) fn new_instance(instance: Instance) -> Rc<RefCell<Instance>> {
+    let instance = Rc::new(RefCell::new(instance));
+    track(Object::Instance(Rc::downgrade(&instance)));
+    instance
+}
+
+pub(crate) fn new_class(class: Class) -> Rc<Class> {
+    let class = Rc::new(class);
+    track(Object::Class(Rc::downgrade(&class)));
+    class
+}
+
+pub(crate) fn new_generator(generator: Generator) -> Rc<RefCell<Generator>> {
+    let generator = Rc::new(RefCell::new(generator));
+    track(Object::Generator(Rc::downgrade(&generator)));
+    generator
+}
+
+pub(crate) fn new_channel() -> Rc<RefCell<ValueQueue>> {
+    let channel = Rc::new(RefCell::new(ValueQueue::new()));
+    track(Object::Channel(Rc::downgrade(&channel)));
+    channel
+}
+
+/// Values in the slots of a generator that is not running. The slots of a running one are on the stack.
+fn suspended_slots(generator: &Generator) -> &[Value] {
+    match &generator.state {
+        GeneratorState::Suspended(suspended) => &suspended.slots,
+        _ => &[],
+    }
+}
+
+/// Result of a collection
+pub(crate) struct Collection {
+    pub cleared: usize,
+    pub live_bytes: usize, // Estimated size of the containers of the VM that survived
+}
+
+/// Rough number of bytes a container uses itself, not counting the containers its values point to
+pub(crate) fn container_bytes(value: &Value) -> usize {
+    match value {
+        Value::Array(array) => size_of::<RefCell<ValueArray>>() + array.borrow().capacity() * size_of::<Value>(),
+        Value::Map(map) => size_of::<RefCell<ValueMap>>() + map.borrow().capacity() * size_of::<(MapKey, Value)>(),
+        Value::Instance(instance) => size_of::<RefCell<Instance>>() + instance.borrow().fields.capacity() * size_of::<(StrId, Value)>(),
+        Value::Class(class) => size_of::<Class>() + class.statics.borrow().capacity() * size_of::<(StrId, Value)>(),
+        Value::Channel(queue) => size_of::<RefCell<ValueQueue>>() + queue.borrow().capacity() * size_of::<Value>(),
+        Value::Generator(generator) => size_of::<RefCell<Generator>>() + size_of_val(suspended_slots(&generator.borrow())),
+        _ => 0,
+    }
+}
+
+/// Marks everything reachable from the roots, using a gray stack rather than recursion
+struct Marker {
+    marked: FxHashSet<*const ()>,
+    gray: Vec<Value>,
+}
+
+impl Marker {
+    fn from_roots<'a>(roots: impl Iterator<Item = &'a Value>) -> Marker {
+        let mut marker = Marker {
+            marked: FxHashSet::default(),
+            gray: roots.cloned().collect(),
+        };
+        marker.trace();
+        marker
+    }
+
+    fn is_marked(&self, value: &Value) -> bool {
+        let ptr = match value {
+            Value::Array(array) => Rc::as_ptr(array) as *const (),
+            Value::Map(map) => Rc::as_ptr(map) as *const (),
+            Value::Instance(instance) => Rc::as_ptr(instance) as *const (),
+            Value::Class(class) => Rc::as_ptr(class) as *const (),
+            Value::Generator(generator) => Rc::as_ptr(generator) as *const (),
+            Value::Channel(queue) => Rc::as_ptr(queue) as *const (),
+            _ => return false,
+        };
+        self.marked.contains(&ptr)
+    }
+
+    /// Returns true the first time an object is seen
+    fn mark<T: ?Sized>(&mut self, object: &Rc<T>) -> bool {
+        self.marked.insert(Rc::as_ptr(object) as *const ())
+    }
+
+    fn trace(&mut self) {
+        while let Some(value) = self.gray.pop() {
+            match &value {
+                Value::Array(array) if self.mark(array) => self.gray.extend(array.borrow().iter().cloned()),
+                Value::Map(map) if self.mark(map) => self.gray.extend(map.borrow().values().cloned()),
+                Value::Instance(instance) if self.mark(instance) => {
+                    let instance = instance.borrow();
+                    self.gray.push(Value::Class(instance.class.clone()));
+                    self.gray.extend(instance.fields.values().cloned());
+                }
+                Value::Class(class) if self.mark(class) => self.gray.extend(class.statics.borrow().values().cloned()),
+                Value::Channel(queue) if self.mark(queue) => self.gray.extend(queue.borrow().iter().cloned()),
+                Value::Generator(generator) if self.mark(generator) => {
+                    self.gray.extend(suspended_slots(&generator.borrow()).iter().cloned())
+                }
+                Value::Tuple(elements) if self.mark(elements) => self.gray.extend(elements.iter().cloned()),
+                Value::Spread(elements) if self.mark(elements) => self.gray.extend(elements.iter().cloned()),
+                Value::Record(record) if self.mark(record) => self.gray.extend(record.values.iter().cloned()),
+                Value::BoundMethod(bound) => self.gray.push(bound.receiver.clone()),
+                Value::ListCallback(callback) => self.gray.push(Value::Array(callback.list.clone())),
+                Value::NativeFunction(native) => self.gray.extend(native.receiver().cloned()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Clear the containers of the VM that are not reachable from the roots, and measure the ones that are.
+/// With `remember`, the cleared containers are kept track of, so `verify` can check that they stay unreachable.
+pub(crate) fn collect<'a>(owner: usize, roots: impl Iterator<Item = &'a Value>, remember: bool) -> Collection {
+    let marker = Marker::from_roots(roots);
+
+    // Hold the garbage until every header has been checked, since clearing it frees other containers
+    let mut garbage = Vec::new();
+    let mut live_bytes = 0;
+    HEAP.with(|heap| {
+        let heap = &mut *heap.borrow_mut();
+        let cleared = &mut heap.cleared;
+        heap.objects.retain(|header| {
+            let Some(value) = header.upgrade() else {
+                return false;
+            };
+            if header.owner != owner {
+                return true;
+            }
+            if marker.is_marked(&value) {
+                live_bytes += container_bytes(&value);
+                return true;
+            }
+
+            if remember {
+                cleared.push(Header {
+                    owner,
+                    object: header.object.clone(),
+                });
+            }
+            garbage.push(value);
+            false
+        });
+        heap.allocations = 0;
+        heap.threshold = INITIAL_THRESHOLD.max(heap.objects.len() * 2);
+    });
+
+    let cleared = garbage.len();
+    for value in &garbage {
+        match value {
+            Value::Array(array) => array.borrow_mut().clear(),
+            Value::Map(map) => map.borrow_mut().clear(),
+            Value::Instance(instance) => instance.borrow_mut().fields.clear(),
+            Value::Class(class) => class.statics.borrow_mut().clear(),
+            Value::Generator(generator) => generator.borrow_mut().state = GeneratorState::Done,
+            Value::Channel(queue) => queue.borrow_mut().clear(),
+            _ => {}
+        }
+    }
+    Collection { cleared, live_bytes }
+}
+
+/// Check that no container the collector cleared is reachable from the roots, which would mean it was still in use.
+/// Only containers cleared by collections with `remember` are checked.
+pub(crate) fn verify<'a>(owner: usize, roots: impl Iterator<Item = &'a Value>) -> Result<(), String> {
+    let marker = Marker::from_roots(roots);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        let mut reachable = 0;
+        heap.cleared.retain(|header| match header.upgrade() {
+            None => false,
+            Some(value) => {
+                if header.owner == owner && marker.is_marked(&value) {
+                    reachable += 1;
+                }
+                true
+            }
+        });
+        match reachable {
+            0 => Ok(()),
+            n => Err(format!("The garbage collector cleared {n} container(s) that are still reachable")),
+        }
+    })
+}
@@ -0,0 +1,352 @@
+//! Snapshots of the objects a program can reach, for teaching how values are laid out and for finding leaks.
+//!
+//! A snapshot walks everything reachable from the globals and the stacks of the VM, like the garbage collector does,
+//! but keeps each object with its size and the named references to other objects. Numbers, booleans, ranges and
+//! modules are stored inline in the values that hold them, so they are not objects of their own.
+
+use std::{fmt, mem::size_of, rc::Rc};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    fun::Fun,
+    gc::container_bytes,
+    interner::{Interner, StrId},
+    json::write_string,
+    value::{GeneratorState, MapKey, Value},
+};
+
+/// Strings whose text is shown in full in a label, longer ones are shortened
+const MAX_LABEL_TEXT: usize = 40;
+
+/// What makes two values the same object
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Identity {
+    Pointer(*const ()),
+    Str(StrId),
+    Function(usize),
+}
+
+/// An object in a `HeapSnapshot`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapObject {
+    pub id: usize,          // Index in `HeapSnapshot::objects`
+    pub kind: &'static str, // Type name as seen by programs
+    pub label: String,      // Short description, like the text of a string or the class of an instance
+    pub size: usize,        // Rough number of bytes the object uses itself, not counting the objects it refers to
+    pub references: Vec<HeapReference>,
+}
+
+/// A named reference to an object, like an element of a list or a field of an instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapReference {
+    pub name: String,
+    pub to: usize, // Id of the object
+}
+
+/// The objects reachable from the globals and the stacks of a VM, returned by `Vm::heap_snapshot`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapSnapshot {
+    pub roots: Vec<HeapReference>, // Globals, named like `x` in the main module and `path:x` in others, and stack slots
+    pub objects: Vec<HeapObject>,  // In the order they were reached
+}
+
+impl HeapSnapshot {
+    pub fn total_bytes(&self) -> usize {
+        self.objects.iter().map(|object| object.size).sum()
+    }
+
+    /// The snapshot as a JSON object, with the same fields
+    pub fn to_json(&self) -> String {
+        fn write_references(out: &mut String, references: &[HeapReference]) {
+            out.push('[');
+            for (idx, reference) in references.iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"name\":");
+                write_string(out, &reference.name);
+                out.push_str(&format!(",\"to\":{}}}", reference.to));
+            }
+            out.push(']');
+        }
+
+        let mut out = String::from("{\"roots\":");
+        write_references(&mut out, &self.roots);
+        out.push_str(",\"objects\":[");
+        for (idx, object) in self.objects.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            out.push_str(&format!("{{\"id\":{},\"kind\":", object.id));
+            write_string(&mut out, object.kind);
+            out.push_str(",\"label\":");
+            write_string(&mut out, &object.label);
+            out.push_str(&format!(",\"size\":{},\"references\":", object.size));
+            write_references(&mut out, &object.references);
+            out.push('}');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// Number and size of the objects of each kind, largest total first
+impl fmt::Display for HeapSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kinds: Vec<(&str, usize, usize)> = Vec::new();
+        for object in &self.objects {
+            match kinds.iter_mut().find(|(kind, _, _)| *kind == object.kind) {
+                Some((_, count, size)) => {
+                    *count += 1;
+                    *size += object.size;
+                }
+                None => kinds.push((object.kind, 1, object.size)),
+            }
+        }
+        kinds.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)));
+
+        write!(f, "{} objects, {} bytes", self.objects.len(), self.total_bytes())?;
+        for (kind, count, size) in kinds {
+            write!(f, "\n{count:>10} {size:>12}  {kind}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Walks the objects reachable from the roots it is given, using a list of objects to visit rather than recursion
+pub(crate) struct SnapshotBuilder<'a> {
+    interner: &'a Interner,
+    functions: &'a [Fun],
+    ids: FxHashMap<Identity, usize>,
+    snapshot: HeapSnapshot,
+    unvisited: Vec<(usize, Value)>,
+}
+
+impl<'a> SnapshotBuilder<'a> {
+    pub fn new(interner: &'a Interner, functions: &'a [Fun]) -> SnapshotBuilder<'a> {
+        SnapshotBuilder {
+            interner,
+            functions,
+            ids: FxHashMap::default(),
+            snapshot: HeapSnapshot::default(),
+            unvisited: Vec::new(),
+        }
+    }
+
+    pub fn root(&mut self, name: String, value: &Value) {
+        if let Some(to) = self.object(value) {
+            self.snapshot.roots.push(HeapReference { name, to });
+        }
+    }
+
+    pub fn finish(mut self) -> HeapSnapshot {
+        while let Some((id, value)) = self.unvisited.pop() {
+            let references = self
+                .references(&value)
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    Some(HeapReference {
+                        name,
+                        to: self.object(&value)?,
+                    })
+                })
+                .collect();
+            self.snapshot.objects[id].references = references;
+        }
+        self.snapshot
+    }
+
+    /// Id of the object a value is, adding it to the snapshot the first time. None for values stored inline.
+    fn object(&mut self, value: &Value) -> Option<usize> {
+        let identity = match value {
+            Value::Str(id) | Value::Identifier(id) => Identity::Str(*id),
+            Value::Function(fun_idx) => Identity::Function(*fun_idx),
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => Identity::Pointer(Rc::as_ptr(n) as *const ()),
+            Value::Array(array) => Identity::Pointer(Rc::as_ptr(array) as *const ()),
+            Value::Map(map) => Identity::Pointer(Rc::as_ptr(map) as *const ()),
+            Value::Set(set) => Identity::Pointer(Rc::as_ptr(set) as *const ()),
+            Value::NativeFunction(native) => Identity::Pointer(Rc::as_ptr(native) as *const ()),
+            Value::Class(class) => Identity::Pointer(Rc::as_ptr(class) as *const ()),
+            Value::Instance(instance) => Identity::Pointer(Rc::as_ptr(instance) as *const ()),
+            Value::BoundMethod(bound) => Identity::Pointer(Rc::as_ptr(bound) as *const ()),
+            Value::ListCallback(callback) => Identity::Pointer(Rc::as_ptr(callback) as *const ()),
+            Value::Enum(enumeration) => Identity::Pointer(Rc::as_ptr(enumeration) as *const ()),
+            Value::EnumMember(member) => Identity::Pointer(Rc::as_ptr(member) as *const ()),
+            Value::RecordType(typ) => Identity::Pointer(Rc::as_ptr(typ) as *const ()),
+            Value::Record(record) => Identity::Pointer(Rc::as_ptr(record) as *const ()),
+            Value::Generator(generator) => Identity::Pointer(Rc::as_ptr(generator) as *const ()),
+            Value::Channel(queue) => Identity::Pointer(Rc::as_ptr(queue) as *const ()),
+            Value::Tuple(elements) => Identity::Pointer(Rc::as_ptr(elements) as *const ()),
+            Value::Spread(elements) => Identity::Pointer(Rc::as_ptr(elements) as *const ()),
+            Value::Bool(_) | Value::Number(_) | Value::Int(_) | Value::Module(_) | Value::Range(_) | Value::Nil => return None,
+        };
+        if let Some(&id) = self.ids.get(&identity) {
+            return Some(id);
+        }
+
+        let id = self.snapshot.objects.len();
+        self.ids.insert(identity, id);
+        self.snapshot.objects.push(HeapObject {
+            id,
+            kind: value.type_name(),
+            label: self.label(value),
+            size: self.size(value),
+            references: Vec::new(),
+        });
+        self.unvisited.push((id, value.clone()));
+        Some(id)
+    }
+
+    fn label(&self, value: &Value) -> String {
+        let name = |id: &StrId| self.interner.lookup(id).to_string();
+        match value {
+            Value::Str(id) | Value::Identifier(id) => shorten(self.interner.lookup(id)),
+            Value::Function(fun_idx) => self.function_name(*fun_idx),
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => shorten(&format!("{n}n")),
+            Value::Array(array) => format!("{} elements", array.borrow().len()),
+            Value::Map(map) => format!("{} entries", map.borrow().len()),
+            Value::Set(set) => format!("{} elements", set.borrow().len()),
+            Value::Channel(queue) => format!("{} values", queue.borrow().len()),
+            Value::Tuple(elements) => format!("{} elements", elements.len()),
+            Value::Spread(elements) => format!("{} elements", elements.len()),
+            Value::NativeFunction(native) => native.name().to_string(),
+            Value::Class(class) => name(&class.name),
+            Value::Instance(instance) => format!("{} instance", name(&instance.borrow().class.name)),
+            Value::BoundMethod(bound) => self.function_name(bound.method),
+            Value::ListCallback(callback) => callback.kind.to_string().to_lowercase(),
+            Value::Enum(enumeration) => name(&enumeration.name),
+            Value::EnumMember(member) => format!("{}.{}", name(&member.enum_name), name(&member.name)),
+            Value::RecordType(typ) => name(&typ.name),
+            Value::Record(record) => name(&record.typ.name),
+            Value::Generator(generator) => self.function_name(generator.borrow().fun_idx),
+            Value::Bool(_) | Value::Number(_) | Value::Int(_) | Value::Module(_) | Value::Range(_) | Value::Nil => String::new(),
+        }
+    }
+
+    fn size(&self, value: &Value) -> usize {
+        match value {
+            Value::Str(id) | Value::Identifier(id) => self.interner.lookup(id).len(),
+            Value::Function(fun_idx) => {
+                let chunk = &self.functions[*fun_idx].chunk;
+                size_of::<Fun>() + chunk.code.capacity() + chunk.constants.capacity() * size_of::<Value>()
+            }
+            #[cfg(feature = "bigint")]
+            Value::BigInt(_) => size_of::<crate::bigint::BigInt>(),
+            Value::Set(set) => size_of_rc(&**set) + set.borrow().capacity() * size_of::<MapKey>(),
+            Value::NativeFunction(native) => size_of_rc(&**native),
+            Value::BoundMethod(bound) => size_of_rc(&**bound),
+            Value::ListCallback(callback) => size_of_rc(&**callback),
+            Value::Enum(enumeration) => size_of_rc(&**enumeration) + enumeration.members.capacity() * size_of::<Value>(),
+            Value::EnumMember(member) => size_of_rc(&**member),
+            Value::RecordType(typ) => size_of_rc(&**typ) + typ.fields.capacity() * size_of::<StrId>(),
+            Value::Record(record) => size_of_rc(&**record) + record.values.capacity() * size_of::<Value>(),
+            Value::Tuple(elements) => size_of_rc(&**elements),
+            Value::Spread(elements) => size_of_rc(&**elements) + elements.capacity() * size_of::<Value>(),
+            value => container_bytes(value),
+        }
+    }
+
+    /// Values an object refers to, with the names of the references
+    fn references(&self, value: &Value) -> Vec<(String, Value)> {
+        let name = |id: &StrId| self.interner.lookup(id).to_string();
+        let indexed = |elements: &mut dyn Iterator<Item = &Value>| {
+            elements
+                .enumerate()
+                .map(|(idx, value)| (format!("[{idx}]"), value.clone()))
+                .collect()
+        };
+        match value {
+            Value::Array(array) => indexed(&mut array.borrow().iter()),
+            Value::Channel(queue) => indexed(&mut queue.borrow().iter()),
+            Value::Tuple(elements) => indexed(&mut elements.iter()),
+            Value::Spread(elements) => indexed(&mut elements.iter()),
+            Value::Map(map) => {
+                let mut references = Vec::new();
+                for (key, value) in map.borrow().iter() {
+                    let key = key.to_value();
+                    references.push((format!("[{}]", self.key_text(&key)), value.clone()));
+                    references.push(("key".to_string(), key));
+                }
+                references
+            }
+            Value::Set(set) => set.borrow().iter().map(|key| ("element".to_string(), key.to_value())).collect(),
+            Value::Class(class) => {
+                let methods = class.methods.borrow();
+                let methods = methods.iter().map(|(method, &fun_idx)| (name(method), Value::Function(fun_idx)));
+                let statics = class.statics.borrow();
+                methods
+                    .chain(statics.iter().map(|(field, value)| (name(field), value.clone())))
+                    .collect()
+            }
+            Value::Instance(instance) => {
+                let instance = instance.borrow();
+                let fields = instance.fields.iter().map(|(field, value)| (name(field), value.clone()));
+                std::iter::once(("class".to_string(), Value::Class(instance.class.clone())))
+                    .chain(fields)
+                    .collect()
+            }
+            Value::BoundMethod(bound) => vec![
+                ("receiver".to_string(), bound.receiver.clone()),
+                ("method".to_string(), Value::Function(bound.method)),
+            ],
+            Value::ListCallback(callback) => vec![("list".to_string(), Value::Array(callback.list.clone()))],
+            Value::NativeFunction(native) => native
+                .receiver()
+                .map(|receiver| ("receiver".to_string(), receiver.clone()))
+                .into_iter()
+                .collect(),
+            Value::Enum(enumeration) => enumeration
+                .members
+                .iter()
+                .map(|member| (self.label(member), member.clone()))
+                .collect(),
+            Value::Record(record) => {
+                let fields = record.typ.fields.iter().map(name).zip(record.values.iter().cloned());
+                std::iter::once(("type".to_string(), Value::RecordType(record.typ.clone())))
+                    .chain(fields)
+                    .collect()
+            }
+            Value::Generator(generator) => {
+                let generator = generator.borrow();
+                let mut references = vec![("function".to_string(), Value::Function(generator.fun_idx))];
+                if let GeneratorState::Suspended(suspended) = &generator.state {
+                    let slots = suspended.slots.iter().enumerate();
+                    references.extend(slots.map(|(slot, value)| (format!("slot {slot}"), value.clone())));
+                }
+                references
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn function_name(&self, fun_idx: usize) -> String {
+        self.functions[fun_idx]
+            .name
+            .map_or("<script>", |name| self.interner.lookup(&name))
+            .to_string()
+    }
+
+    fn key_text(&self, key: &Value) -> String {
+        match key {
+            Value::Str(id) => shorten(self.interner.lookup(id)),
+            Value::Int(i) => i.to_string(),
+            Value::Number(n) => n.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Size of a value behind an `Rc`, with the reference counts
+fn size_of_rc<T: ?Sized>(value: &T) -> usize {
+    2 * size_of::<usize>() + std::mem::size_of_val(value)
+}
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(MAX_LABEL_TEXT) {
+        Some((cut, _)) => format!("{}...", &text[..cut]),
+        None => text.to_string(),
+    }
+}
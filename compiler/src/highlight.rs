@@ -0,0 +1,182 @@
+//! Classification of the tokens of a program for syntax highlighting, made with the scanner of the compiler rather than
+//! regexes, so that editors color the code the way the compiler reads it.
+//!
+//! Identifiers are told apart by how they are used. A name declared with `class`, `enum` or `record` is a class wherever
+//! it is used, one declared with `function` or that is called is a function, and one after a `.` is a property.
+
+use crate::scanner::{Scanner, Span, Token, TokenType};
+use std::{collections::HashSet, iter, rc::Rc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword,
+    String,
+    Number,
+    Operator,
+    Function,
+    Variable,
+    Class,
+    Property,
+    Comment,
+}
+
+impl TokenKind {
+    /// Name of the kind, which is the name of the semantic token type of the Language Server Protocol
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenKind::Keyword => "keyword",
+            TokenKind::String => "string",
+            TokenKind::Number => "number",
+            TokenKind::Operator => "operator",
+            TokenKind::Function => "function",
+            TokenKind::Variable => "variable",
+            TokenKind::Class => "class",
+            TokenKind::Property => "property",
+            TokenKind::Comment => "comment",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub kind: TokenKind,
+    pub span: Span,
+    pub line: usize,   // Line of the first character, starting at 1
+    pub column: usize, // Column of the first character, in characters, starting at 1
+}
+
+impl SemanticToken {
+    /// The token as JSON, like `{"kind":"keyword","line":1,"column":1,"span":{"start":0,"end":3}}`
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"kind":"{}","line":{},"column":{},"span":{{"start":{},"end":{}}}}}"#,
+            self.kind.name(),
+            self.line,
+            self.column,
+            self.span.start,
+            self.span.end
+        )
+    }
+}
+
+/// The tokens of the source to highlight, with its comments, in the order they are in. Punctuation, like brackets and
+/// `;`, and mistakes the scanner finds, are left out.
+pub fn semantic_tokens(source: &str) -> Vec<SemanticToken> {
+    let tokens: Vec<Token> = Scanner::new(Rc::from(source)).collect();
+    let (classes, functions) = declared_names(&tokens);
+    let line_starts: Vec<usize> = iter::once(0)
+        .chain(source.match_indices('\n').map(|(newline, _)| newline + 1))
+        .collect();
+
+    let mut highlighted = Vec::new();
+    let mut push = |kind, span: Span| {
+        let line = line_starts.partition_point(|&start| start <= span.start);
+        let column = source[line_starts[line - 1]..span.start].chars().count() + 1;
+        highlighted.push(SemanticToken { kind, span, line, column });
+    };
+    let mut gap_start = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        comments(source, gap_start, token.span.start, &mut push);
+        gap_start = token.span.end;
+        if let Some(kind) = classify(&tokens, idx, &classes, &functions) {
+            push(kind, token.span);
+        }
+    }
+    comments(source, gap_start, source.len(), &mut push);
+    highlighted
+}
+
+/// `semantic_tokens` as a JSON array of `SemanticToken::to_json`
+pub fn semantic_tokens_json(source: &str) -> String {
+    let tokens: Vec<String> = semantic_tokens(source).iter().map(SemanticToken::to_json).collect();
+    format!("[{}]", tokens.join(","))
+}
+
+/// Names declared as classes, enums and records, and names declared as functions
+fn declared_names(tokens: &[Token]) -> (HashSet<&str>, HashSet<&str>) {
+    let mut classes = HashSet::new();
+    let mut functions = HashSet::new();
+    for pair in tokens.windows(2) {
+        if pair[1].typ != TokenType::Identifier {
+            continue;
+        }
+        match pair[0].typ {
+            TokenType::Class | TokenType::Enum | TokenType::Record => classes.insert(pair[1].source.as_ref()),
+            TokenType::Fun => functions.insert(pair[1].source.as_ref()),
+            _ => false,
+        };
+    }
+    (classes, functions)
+}
+
+/// The comments between the tokens, in the source from `start` to `end`, which has nothing else but whitespace
+fn comments(source: &str, start: usize, end: usize, push: &mut impl FnMut(TokenKind, Span)) {
+    let mut rest = start;
+    while let Some(comment) = source[rest..end].find("//") {
+        let comment = rest + comment;
+        rest = source[comment..end].find('\n').map_or(end, |newline| comment + newline);
+        let text = source[comment..rest].trim_end();
+        push(
+            TokenKind::Comment,
+            Span {
+                start: comment,
+                end: comment + text.len(),
+            },
+        );
+    }
+}
+
+fn classify(tokens: &[Token], idx: usize, classes: &HashSet<&str>, functions: &HashSet<&str>) -> Option<TokenKind> {
+    let kind = match tokens[idx].typ {
+        TokenType::String => TokenKind::String,
+        TokenType::Number => TokenKind::Number,
+        TokenType::Identifier => identifier_kind(tokens, idx, classes, functions),
+        typ if (TokenType::And as usize..=TokenType::As as usize).contains(&(typ as usize)) => TokenKind::Keyword,
+        TokenType::Ellipsis
+        | TokenType::DotDot
+        | TokenType::DotDotEqual
+        | TokenType::QuestionQuestion
+        | TokenType::Arrow
+        | TokenType::Minus
+        | TokenType::Plus
+        | TokenType::Slash
+        | TokenType::Star
+        | TokenType::Modulo
+        | TokenType::Pipe
+        | TokenType::Ampersand
+        | TokenType::Bang
+        | TokenType::BangEqual
+        | TokenType::Equal
+        | TokenType::EqualEqual
+        | TokenType::Greater
+        | TokenType::GreaterEqual
+        | TokenType::Less
+        | TokenType::LessEqual => TokenKind::Operator,
+        _ => return None,
+    };
+    Some(kind)
+}
+
+fn identifier_kind(tokens: &[Token], idx: usize, classes: &HashSet<&str>, functions: &HashSet<&str>) -> TokenKind {
+    let name = tokens[idx].source.as_ref();
+    let previous = idx.checked_sub(1).map(|previous| tokens[previous].typ);
+    let next = tokens.get(idx + 1).map(|next| next.typ);
+    let called = next == Some(TokenType::LeftParen);
+    if classes.contains(name) {
+        TokenKind::Class
+    } else if matches!(previous, Some(TokenType::Dot | TokenType::QuestionDot)) {
+        // A field, or a method that is called
+        if called {
+            TokenKind::Function
+        } else {
+            TokenKind::Property
+        }
+    } else if called || functions.contains(name) {
+        TokenKind::Function
+    } else if next == Some(TokenType::Colon) && previous != Some(TokenType::Case) {
+        // A key of a map literal, or a named argument
+        TokenKind::Property
+    } else {
+        TokenKind::Variable
+    }
+}
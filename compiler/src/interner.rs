@@ -39,6 +39,12 @@ impl Interner {
         id
     }
 
+    /// Bytes allocated for the interned strings and the tables that find them. Strings are never freed.
+    pub fn allocated_bytes(&self) -> usize {
+        let buffers = self.buf.capacity() + self.full.iter().map(String::capacity).sum::<usize>();
+        buffers + self.vec.capacity() * mem::size_of::<&str>() + self.map.capacity() * mem::size_of::<(&str, StrId)>()
+    }
+
     /// Get a string, given it's ID
     pub fn lookup(&self, id: &StrId) -> &str {
         self.vec[id.0 as usize]
@@ -0,0 +1,342 @@
+use crate::{
+    gc,
+    interner::Interner,
+    value::{value_as_string, MapKey, Value, ValueMap},
+};
+
+/// Deeper values are assumed to be cyclic, since containers can hold themselves
+const MAX_DEPTH: usize = 512;
+
+/// Parse JSON text into maps, arrays, strings, numbers, bools and nil
+pub(crate) fn parse(text: &str, interner: &mut Interner) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        current: 0,
+        interner,
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        Some(c) => Err(parser.error(&format!("Unexpected '{c}' after the value"))),
+        None => Ok(value),
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    current: usize,
+    interner: &'a mut Interner,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON at position {}: {message}", self.current)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.current).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.current += 1;
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.current += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.advance() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(self.error(&format!("Expected '{expected}' but got '{c}'"))),
+            None => Err(self.error(&format!("Expected '{expected}' but the text ended"))),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value, String> {
+        for expected in keyword.chars() {
+            if self.advance() != Some(expected) {
+                return Err(self.error(&format!("Expected '{keyword}'")));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("Too deeply nested"));
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(depth),
+            Some('[') => self.array(depth),
+            Some('"') => {
+                let s = self.string()?;
+                Ok(Value::Str(self.interner.intern(&s)))
+            }
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('n') => self.keyword("null", Value::Nil),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(self.error(&format!("Unexpected '{c}'"))),
+            None => Err(self.error("Expected a value but the text ended")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut map = ValueMap::default();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.advance();
+            return Ok(Value::Map(gc::new_map(map)));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("Expected a string as the key"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            let value = self.value(depth + 1)?;
+            map.insert(MapKey::Str(self.interner.intern(&key)), value);
+
+            self.skip_whitespace();
+            match self.advance() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Map(gc::new_map(map))),
+                _ => return Err(self.error("Expected ',' or '}' in object")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut array = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.advance();
+            return Ok(Value::Array(gc::new_array(array)));
+        }
+
+        loop {
+            array.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.advance() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(gc::new_array(array))),
+                _ => return Err(self.error("Expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.advance(); // The opening quote
+        let mut s = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.advance() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => {
+                        let high = self.hex4()?;
+                        let code = if (0xD800..0xDC00).contains(&high) {
+                            // Surrogate pair
+                            if self.advance() != Some('\\') || self.advance() != Some('u') {
+                                return Err(self.error("Expected the low half of a surrogate pair"));
+                            }
+                            let low = self.hex4()?;
+                            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                        } else {
+                            high
+                        };
+                        s.push(char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))?);
+                    }
+                    _ => return Err(self.error("Invalid escape in string")),
+                },
+                Some(c) if (c as u32) < 0x20 => return Err(self.error("Control character in string")),
+                Some(c) => s.push(c),
+                None => return Err(self.error("Unterminated string")),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.advance().and_then(|c| c.to_digit(16));
+            code = code * 16 + digit.ok_or_else(|| self.error("Expected 4 hex digits in unicode escape"))?;
+        }
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.current;
+        let mut is_float = false;
+        if self.peek() == Some('-') {
+            self.advance();
+        }
+        while let Some(c) = self.peek() {
+            match c {
+                '0'..='9' | '+' | '-' => {}
+                '.' | 'e' | 'E' => is_float = true,
+                _ => break,
+            }
+            self.advance();
+        }
+
+        let text: String = self.chars[start..self.current].iter().collect();
+        if !is_float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(Value::Int(i));
+            }
+        }
+        text.parse::<f64>()
+            .map(Value::Number)
+            .map_err(|_| self.error(&format!("Invalid number '{text}'")))
+    }
+}
+
+/// Convert a value to JSON text. With an indent, objects and arrays are written over multiple lines.
+/// Maps are written with their keys sorted, since they have no order of their own.
+pub(crate) fn stringify(value: &Value, indent: Option<&str>, interner: &Interner) -> Result<String, String> {
+    let mut out = String::new();
+    write_value(&mut out, value, indent, 0, interner)?;
+    Ok(out)
+}
+
+pub(crate) fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_newline(out: &mut String, indent: Option<&str>, depth: usize) {
+    if let Some(indent) = indent {
+        out.push('\n');
+        out.push_str(&indent.repeat(depth));
+    }
+}
+
+/// Write the items between `open` and `close`, separated by commas
+fn write_items<T>(
+    out: &mut String,
+    (open, close): (char, char),
+    items: &[T],
+    indent: Option<&str>,
+    depth: usize,
+    mut write_item: impl FnMut(&mut String, &T) -> Result<(), String>,
+) -> Result<(), String> {
+    out.push(open);
+    for (i, item) in items.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        write_newline(out, indent, depth + 1);
+        write_item(out, item)?;
+    }
+    if !items.is_empty() {
+        write_newline(out, indent, depth);
+    }
+    out.push(close);
+    Ok(())
+}
+
+fn write_value(out: &mut String, value: &Value, indent: Option<&str>, depth: usize, interner: &Interner) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err("Cannot convert a cyclic or too deeply nested value to JSON".to_string());
+    }
+
+    let separator = if indent.is_some() { ": " } else { ":" };
+    let write_fields = |out: &mut String, fields: Vec<(String, Value)>| {
+        write_items(out, ('{', '}'), &fields, indent, depth, |out, (key, value)| {
+            write_string(out, key);
+            out.push_str(separator);
+            write_value(out, value, indent, depth + 1, interner)
+        })
+    };
+
+    match value {
+        Value::Nil => out.push_str("null"),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Int(i) => out.push_str(&i.to_string()),
+        Value::Number(n) if n.is_finite() => out.push_str(&n.to_string()),
+        Value::Number(n) => return Err(format!("Cannot convert {n} to JSON")),
+        #[cfg(feature = "bigint")]
+        Value::BigInt(n) => out.push_str(&n.to_string()),
+        Value::Str(s) => write_string(out, interner.lookup(s)),
+        Value::Array(array) => {
+            let array = array.borrow().clone();
+            write_items(out, ('[', ']'), &array, indent, depth, |out, item| {
+                write_value(out, item, indent, depth + 1, interner)
+            })?;
+        }
+        Value::Tuple(elements) => {
+            write_items(out, ('[', ']'), elements, indent, depth, |out, item| {
+                write_value(out, item, indent, depth + 1, interner)
+            })?;
+        }
+        Value::Set(set) => {
+            let mut elements: Vec<Value> = set.borrow().iter().map(|element| element.to_value()).collect();
+            elements.sort_by_key(|element| value_as_string(element, interner));
+            write_items(out, ('[', ']'), &elements, indent, depth, |out, item| {
+                write_value(out, item, indent, depth + 1, interner)
+            })?;
+        }
+        Value::Map(map) => {
+            let mut fields: Vec<(String, Value)> = map
+                .borrow()
+                .iter()
+                .map(|(key, value)| (value_as_string(&key.to_value(), interner), value.clone()))
+                .collect();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            write_fields(out, fields)?;
+        }
+        Value::Record(record) => {
+            let fields = record
+                .typ
+                .fields
+                .iter()
+                .map(|field| interner.lookup(field).to_string())
+                .zip(record.values.iter().cloned());
+            write_fields(out, fields.collect())?;
+        }
+        Value::Instance(instance) => {
+            let mut fields: Vec<(String, Value)> = instance
+                .borrow()
+                .fields
+                .iter()
+                .map(|(name, value)| (interner.lookup(name).to_string(), value.clone()))
+                .collect();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            write_fields(out, fields)?;
+        }
+        Value::EnumMember(member) => write_string(out, interner.lookup(&member.name)),
+        other => return Err(format!("Cannot convert {other} to JSON")),
+    }
+    Ok(())
+}
@@ -1,19 +1,61 @@
+pub mod assembler;
+pub mod ast;
+#[cfg(feature = "bigint")]
+pub mod bigint;
+pub mod builder;
+pub mod cache;
 pub mod chunk;
+pub mod clock;
 pub mod common;
 pub mod compiler;
+pub mod coverage;
+#[cfg(feature = "dap")]
+pub mod dap;
+pub mod dead_code;
 pub mod debug;
+pub mod debugger;
+pub mod diagnostic;
+pub mod error;
+pub mod fold;
+pub mod format;
+pub mod formatter;
 pub mod fun;
+pub mod gc;
+pub mod heap;
+pub mod highlight;
 pub mod interner;
+pub mod json;
+pub mod lint;
+pub mod long_jump;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod module;
+pub mod named_args;
 pub mod native;
+pub mod options;
+pub mod peephole;
+pub mod profile;
+#[cfg(any(feature = "dap", feature = "lsp"))]
+mod protocol;
+pub mod regex;
+pub mod register;
 pub mod scanner;
+pub mod serialize;
+pub mod session;
+pub mod stats;
+pub mod superinstruction;
+pub mod testing;
+pub mod unit;
 pub mod value;
+pub mod verify;
 pub mod vm;
 use std::{future::Future, sync::OnceLock};
 
+use crate::module::{ModuleLoader, ModuleRegistry, MAIN_MODULE};
 use crate::vm::Vm;
 use std::rc::Rc;
 
-const INTERNER_DEFAULT_CAP: usize = 1024;
+pub(crate) const INTERNER_DEFAULT_CAP: usize = 1024;
 
 struct Imports {
     print_fn: fn(String) -> (),
@@ -36,53 +78,210 @@ macro_rules! xprintln {
     }
 }
 
-#[macro_export]
-#[cfg(not(feature = "tracing"))]
-macro_rules! dbg {
-    // No-op version
-    ($($arg:tt)*) => {};
+pub fn init(print_fn: fn(String) -> (), println_fn: fn(String) -> ()) {
+    let res = WRITERS.set(Imports { print_fn, println_fn });
+
+    if res.is_err() {
+        panic!("Compiler already initialized");
+    }
 }
 
-#[macro_export]
-#[cfg(not(feature = "tracing"))]
-macro_rules! dbgln {
-    // No-op version
-    ($($arg:tt)*) => {};
+/// What running a program gave: the warnings the compiler gave about it, for the host to report, and whether it ran.
+/// Compile errors are a `diagnostic::CompileError` in `result`, with the warnings about the code that didn't compile,
+/// and runtime errors are an `error::RuntimeError`.
+pub struct RunResult {
+    pub warnings: Vec<diagnostic::Diagnostic>,
+    pub result: anyhow::Result<()>,
 }
 
-#[macro_export]
-#[cfg(feature = "tracing")]
-macro_rules! dbg {
-    ($($arg:tt)*) => {
-        $crate::xprint!($($arg)*)
-    }
+/// Compile and run the program
+pub async fn run_code<F, Fut>(code: &str, loader: impl ModuleLoader + 'static, read_async: F) -> RunResult
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    run_code_with(code, loader, read_async, |_| {}).await
 }
 
-#[macro_export]
-#[cfg(feature = "tracing")]
-macro_rules! dbgln {
-    ($($arg:tt)*) => {
-        $crate::xprintln!($($arg)*)
-    }
+/// Compile the program, run it, and then run the tests it declares with `Vm::run_tests`
+pub async fn run_tests<F, Fut>(code: &str, loader: impl ModuleLoader + 'static, read_async: F) -> anyhow::Result<testing::TestReport>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    let source: Rc<str> = Rc::from(code);
+    let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let mut functions: Vec<fun::Fun> = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(loader));
+    let fun = compiler::Compiler::compile(source, &mut interner, &mut functions, &mut modules, fun::FunType::Script)?;
+    functions.push(fun);
+    modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+    let mut vm = Vm::new(&mut interner, functions, modules.modules, read_async);
+    vm.run_tests().await
 }
 
-pub fn init(print_fn: fn(String) -> (), println_fn: fn(String) -> ()) {
-    let res = WRITERS.set(Imports { print_fn, println_fn });
+/// Like `run_code`, but `setup` is called with the VM before the program runs, for example to define natives with
+/// `Vm::define_native`.
+pub async fn run_code_with<F, Fut>(
+    code: &str,
+    loader: impl ModuleLoader + 'static,
+    read_async: F,
+    setup: impl FnOnce(&mut Vm<F, Fut>),
+) -> RunResult
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    run_code_with_options(code, loader, read_async, &compiler::CompilerOptions::default(), setup).await
+}
 
-    if res.is_err() {
-        panic!("Compiler already initialized");
+/// Compile the program without running it, and return the instructions of each of its functions as JSON, like
+/// `[{"name":"script","instructions":[...]}]`, with the instructions of `debug::DisassembledInstruction::to_json`
+pub fn disassemble_to_json(code: &str, loader: impl ModuleLoader + 'static, options: &compiler::CompilerOptions) -> anyhow::Result<String> {
+    let source: Rc<str> = Rc::from(code);
+    let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let mut functions: Vec<fun::Fun> = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(loader));
+    let program = compiler::Compiler::compile_program(source, &mut interner, &mut functions, &mut modules, options)?;
+    functions.push(program.script);
+
+    let mut out = String::from("[");
+    for (idx, fun) in functions.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        out.push_str(r#"{"name":"#);
+        json::write_string(&mut out, fun.display_name(&interner, &modules.modules));
+        out.push_str(r#","instructions":["#);
+        let instructions: Vec<String> = fun
+            .chunk
+            .disassembled(&interner)
+            .iter()
+            .map(|instruction| instruction.to_json())
+            .collect();
+        out.push_str(&instructions.join(","));
+        out.push_str("]}");
     }
+    out.push(']');
+    Ok(out)
+}
+
+/// Assemble the textual bytecode with `assembler::assemble`, and run it as the script of a program, which has no other
+/// functions. `setup` is called with the VM before it runs, like for `run_code_with`.
+pub async fn run_bytecode_with<F, Fut>(
+    text: &str,
+    loader: impl ModuleLoader + 'static,
+    read_async: F,
+    setup: impl FnOnce(&mut Vm<F, Fut>),
+) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let chunk = assembler::assemble(text, &mut interner)?;
+    run_chunk_with(chunk, interner, loader, read_async, setup).await
 }
 
-pub async fn run_code<F, Fut>(code: &str, read_async: F)
+/// Run the chunk as the script of a program, which has no other functions, like one made with `builder::ChunkBuilder`.
+/// The strings of its constants are in `interner`. `setup` is called with the VM before it runs.
+pub async fn run_chunk_with<F, Fut>(
+    chunk: chunk::Chunk,
+    mut interner: interner::Interner,
+    loader: impl ModuleLoader + 'static,
+    read_async: F,
+    setup: impl FnOnce(&mut Vm<F, Fut>),
+) -> anyhow::Result<()>
 where
     F: Fn(String) -> Fut,
     Fut: Future<Output = String>,
 {
+    let mut fun = fun::Fun::new();
+    fun.chunk = chunk;
+    let mut modules = ModuleRegistry::new(Box::new(loader));
+    modules.modules[MAIN_MODULE].script = Some(0);
+    let mut vm = Vm::new(&mut interner, vec![fun], modules.modules, read_async);
+    setup(&mut vm);
+    vm.interpret().await?;
+    Ok(())
+}
+
+/// Run a program from `cache::CompileCache::compile`. The warnings about it are left in `program.warnings` for the host
+/// to report.
+pub async fn run_compiled<F, Fut>(program: cache::CachedProgram, read_async: F, setup: impl FnOnce(&mut Vm<F, Fut>)) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    let mut interner = program.interner;
+    let mut vm = Vm::new(&mut interner, program.functions, program.modules, read_async);
+    setup(&mut vm);
+    vm.interpret().await?;
+    Ok(())
+}
+
+/// Like `run_code_with`, but the program is compiled with `options`
+pub async fn run_code_with_options<F, Fut>(
+    code: &str,
+    loader: impl ModuleLoader + 'static,
+    read_async: F,
+    options: &compiler::CompilerOptions,
+    setup: impl FnOnce(&mut Vm<F, Fut>),
+) -> RunResult
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    match compile_code(code, loader, options) {
+        Ok(mut program) => RunResult {
+            warnings: std::mem::take(&mut program.warnings),
+            result: run_program(program, read_async, setup).await,
+        },
+        Err(error) => RunResult {
+            warnings: Vec::new(),
+            result: Err(error.into()),
+        },
+    }
+}
+
+/// A program compiled with `compile_code`, to run with `run_program`
+pub struct Program {
+    interner: interner::Interner,
+    functions: Vec<fun::Fun>,
+    modules: Vec<module::Module>,
+    pub warnings: Vec<diagnostic::Diagnostic>, // For the host to report, before or after it runs
+}
+
+/// Compile the program with `options`, without running it
+pub fn compile_code(
+    code: &str,
+    loader: impl ModuleLoader + 'static,
+    options: &compiler::CompilerOptions,
+) -> Result<Program, diagnostic::CompileError> {
     let source: Rc<str> = Rc::from(code);
     let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
     let mut functions: Vec<fun::Fun> = Vec::new();
-    let fun = compiler::Compiler::compile(source, &mut interner, &mut functions, fun::FunType::Script).unwrap();
-    functions.push(fun);
-    Vm::interpret(functions, &mut interner, read_async).await.unwrap();
+    let mut modules = ModuleRegistry::new(Box::new(loader));
+    let program = compiler::Compiler::compile_program(source, &mut interner, &mut functions, &mut modules, options)?;
+    functions.push(program.script);
+    modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+    Ok(Program {
+        interner,
+        functions,
+        modules: modules.modules,
+        warnings: program.warnings,
+    })
+}
+
+/// Run a program from `compile_code`. `setup` is called with the VM before it runs, like for `run_code_with`.
+pub async fn run_program<F, Fut>(program: Program, read_async: F, setup: impl FnOnce(&mut Vm<F, Fut>)) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    let mut interner = program.interner;
+    let mut vm = Vm::new(&mut interner, program.functions, program.modules, read_async);
+    setup(&mut vm);
+    vm.interpret().await?;
+    Ok(())
 }
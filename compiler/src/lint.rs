@@ -0,0 +1,63 @@
+//! Lints, which find code that works but is likely to be a mistake or hard to read. They are checked while the
+//! program is compiled, and each one can be allowed, reported as a warning or denied, which makes it an error.
+
+/// What a lint does when code breaks it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Level {
+    #[default]
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// Level of each lint, in `CompilerOptions::lints`. They are all allowed by default.
+#[derive(Debug, Clone)]
+pub struct Lints {
+    pub naming: Level,              // Classes, enums and records in PascalCase, functions and variables in camelCase
+    pub empty_blocks: Level,        // Blocks without statements, other than the bodies of functions
+    pub constant_conditions: Level, // Conditions that are a literal, other than `while (true)`
+    pub deep_nesting: Level,        // Blocks nested more than `max_nesting` deep in a function
+    pub max_nesting: usize,
+}
+
+impl Default for Lints {
+    fn default() -> Self {
+        Lints::all(Level::Allow)
+    }
+}
+
+impl Lints {
+    /// Every lint at the same level
+    pub fn all(level: Level) -> Lints {
+        Lints {
+            naming: level,
+            empty_blocks: level,
+            constant_conditions: level,
+            deep_nesting: level,
+            max_nesting: 4,
+        }
+    }
+}
+
+/// What a name is given to, which decides how it should be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NameKind {
+    Type,     // Classes, enums and records
+    Value,    // Functions, variables and parameters
+    Constant, // Constants, which can also be in SCREAMING_SNAKE_CASE
+}
+
+/// The convention the name breaks, if any. Leading underscores are left out, since they mark names that are unused.
+pub(crate) fn naming_problem(name: &str, kind: NameKind) -> Option<&'static str> {
+    let name = name.trim_start_matches('_');
+    let first = name.chars().next()?;
+    let is_camel_case = first.is_lowercase() && !name.contains('_');
+    let is_pascal_case = first.is_uppercase() && !name.contains('_');
+    let is_screaming_snake_case = !name.chars().any(char::is_lowercase);
+    match kind {
+        NameKind::Type if !is_pascal_case => Some("PascalCase"),
+        NameKind::Value if !is_camel_case => Some("camelCase"),
+        NameKind::Constant if !is_camel_case && !is_screaming_snake_case => Some("camelCase or SCREAMING_SNAKE_CASE"),
+        _ => None,
+    }
+}
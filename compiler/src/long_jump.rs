@@ -0,0 +1,170 @@
+//! Jumps over more than 64 KiB of code, which don't fit in the 16-bit offset of a jump.
+//!
+//! The compiler only knows how far a forward jump goes once it patches it, when the code it jumps over has been
+//! emitted. So the jumps that don't fit are recorded with their target, and when the function has been compiled they
+//! are widened to the long variants of their instructions. Widening moves the code after them, which can make other
+//! jumps too far as well, so the layout is recomputed until every short jump fits.
+//!
+//! The code is laid out again from its instructions, with the offsets their jumps go to, which `peephole` also uses
+//! to remove and change instructions.
+
+use crate::{
+    chunk::{push_run, Chunk},
+    common::Opcode,
+    fun::Fun,
+    scanner::Span,
+};
+use std::collections::HashMap;
+
+/// An instruction, with the offsets its jumps go to. None is an offset of 0 of a handler, which it uses for no block.
+pub(crate) struct Instruction {
+    pub(crate) start: usize, // Offset in the code it was decoded from
+    pub(crate) opcode: Opcode,
+    pub(crate) targets: Vec<Option<usize>>,
+    pub(crate) long: bool,    // Whether it is widened to the long variant of its opcode
+    pub(crate) removed: bool, // Removed instructions take no space, and jumps to them go to the next instruction
+}
+
+impl Instruction {
+    fn size(&self) -> usize {
+        match (self.removed, self.long) {
+            (true, _) => 0,
+            (false, true) => self.opcode.long().unwrap_or(self.opcode).size(),
+            (false, false) => self.opcode.size(),
+        }
+    }
+}
+
+/// The instructions of the chunk, with the jumps whose offset is at a key of `far_jumps` going to its value instead
+pub(crate) fn decode(chunk: &Chunk, far_jumps: &HashMap<usize, usize>) -> Vec<Instruction> {
+    chunk
+        .instruction_starts()
+        .into_iter()
+        .map(|start| {
+            let opcode = Opcode::try_from(chunk.code[start]).expect("Instructions start with an opcode");
+            let width = if opcode.is_long() { 3 } else { 2 };
+            let targets = opcode
+                .jump_operands()
+                .iter()
+                .zip(chunk.jump_targets(start))
+                .map(|(at, target)| match far_jumps.get(&(start + at)) {
+                    Some(&target) => Some(target),
+                    None => (opcode.short() != Opcode::PushHandler || target != start + at + width).then_some(target),
+                })
+                .collect();
+            let long = opcode.jump_operands().iter().any(|at| far_jumps.contains_key(&(start + at)));
+            Instruction {
+                start,
+                opcode,
+                targets,
+                long,
+                removed: false,
+            }
+        })
+        .collect()
+}
+
+/// Widen the jumps in `far_jumps`, given as where their offset is and the offset they go to, and the ones that have to
+/// be widened because of them. Returns false if a jump is too far even for a long jump.
+pub(crate) fn widen(fun: &mut Fun, far_jumps: &[(usize, usize)]) -> bool {
+    let far_jumps: HashMap<usize, usize> = far_jumps.iter().copied().collect();
+    let mut instructions = decode(&fun.chunk, &far_jumps);
+    encode(fun, &mut instructions)
+}
+
+/// Lay out the instructions, decoded from the chunk of the function, and replace its code with them. Jumps that don't
+/// fit are widened. Returns false if a jump is too far even for a long jump.
+pub(crate) fn encode(fun: &mut Fun, instructions: &mut [Instruction]) -> bool {
+    let chunk = &fun.chunk;
+
+    // Index of the instruction at each offset, and the end of the code, which is where jumps out of the function go
+    let mut index_of = vec![None; chunk.code.len() + 1];
+    for (index, instruction) in instructions.iter().enumerate() {
+        index_of[instruction.start] = Some(index);
+    }
+    index_of[chunk.code.len()] = Some(instructions.len());
+    let index_of = |target: usize| index_of[target].expect("Jumps go to where an instruction starts");
+
+    let starts = loop {
+        let mut starts = Vec::with_capacity(instructions.len() + 1);
+        let mut offset = 0;
+        for instruction in instructions.iter() {
+            starts.push(offset);
+            offset += instruction.size();
+        }
+        starts.push(offset);
+
+        let mut widened = false;
+        for (index, instruction) in instructions.iter_mut().enumerate() {
+            let fits = instruction
+                .targets
+                .iter()
+                .zip(instruction.opcode.jump_operands())
+                .all(|(target, &at)| {
+                    let end = starts[index] + at + 2;
+                    target.is_none_or(|target| starts[index_of(target)].abs_diff(end) <= u16::MAX as usize)
+                });
+            if !instruction.long && !fits {
+                instruction.long = true;
+                widened = true;
+            }
+        }
+        if !widened {
+            break starts;
+        }
+    };
+
+    let old_lines: Vec<usize> = chunk
+        .lines
+        .iter()
+        .flat_map(|&(line, length)| std::iter::repeat_n(line, length))
+        .collect();
+    let old_spans: Vec<Option<Span>> = chunk
+        .spans
+        .iter()
+        .flat_map(|&(span, length)| std::iter::repeat_n(span, length))
+        .collect();
+    let mut code = Vec::with_capacity(starts[instructions.len()]);
+    let mut lines: Vec<(usize, usize)> = Vec::new();
+    let mut spans = Vec::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.removed {
+            continue;
+        }
+        let old = &chunk.code[instruction.start..instruction.start + instruction.opcode.size()];
+        let opcode = match instruction.long {
+            true => instruction.opcode.long().unwrap_or(instruction.opcode),
+            false => instruction.opcode,
+        };
+        // Operands other than the jump offsets come before them
+        let jumps_at = opcode.jump_operands();
+        let copied = instruction.opcode.jump_operands().first().copied().unwrap_or(old.len());
+        code.push(opcode as u8);
+        code.extend_from_slice(&old[1..copied]);
+
+        let width = if opcode.is_long() { 3 } else { 2 };
+        for (target, &at) in instruction.targets.iter().zip(jumps_at) {
+            let end = starts[index] + at + width;
+            let jump = target.map_or(0, |target| starts[index_of(target)].abs_diff(end));
+            if jump >= 1 << (8 * width) {
+                return false;
+            }
+            code.extend(jump.to_be_bytes()[size_of::<usize>() - width..].iter());
+        }
+
+        let length = starts[index + 1] - starts[index];
+        push_run(&mut lines, old_lines[instruction.start], length);
+        push_run(&mut spans, old_spans.get(instruction.start).copied().flatten(), length);
+    }
+
+    // The scopes of local variables are from and to where instructions start, or the end
+    let moved = |offset: usize| if offset == usize::MAX { offset } else { starts[index_of(offset)] };
+    for local in &mut fun.locals {
+        local.start = moved(local.start);
+        local.end = moved(local.end);
+    }
+    fun.chunk.code = code;
+    fun.chunk.lines = lines;
+    fun.chunk.spans = spans;
+    true
+}
@@ -0,0 +1,623 @@
+//! Language Server Protocol server, so that editors like VS Code show the problems of programs as they are written, and
+//! can show what a name is when hovering over it, go to where it is declared, and list the declarations of a program.
+//! Messages are read from stdin and written to stdout, and documents are sent whole on each change.
+//!
+//! Names are resolved with the syntax tree of a document, which only exists for code that compiles, so while a document
+//! has errors, hovering and going to declarations find nothing in it and it has no symbols.
+
+use std::{collections::HashMap, io, iter};
+
+use crate::{
+    ast::{self, ClassMember, Expr, ExprKind, Function, FunctionBody, MethodKind, Pattern, Program, Stmt, StmtKind},
+    compiler::analyze,
+    diagnostic::{Diagnostic, Severity},
+    interner::Interner,
+    module::{ModuleRegistry, NoModules},
+    protocol::{object, read_message, send, Json},
+    scanner::{tokenize, Span, TokenType},
+    vm::Vm,
+    INTERNER_DEFAULT_CAP,
+};
+
+/// Error code of JSON-RPC for a request the server doesn't know
+const METHOD_NOT_FOUND: f64 = -32601.0;
+
+/// `SymbolKind`s of the protocol
+const SYMBOL_MODULE: usize = 2;
+const SYMBOL_CLASS: usize = 5;
+const SYMBOL_METHOD: usize = 6;
+const SYMBOL_PROPERTY: usize = 7;
+const SYMBOL_FIELD: usize = 8;
+const SYMBOL_ENUM: usize = 10;
+const SYMBOL_FUNCTION: usize = 12;
+const SYMBOL_VARIABLE: usize = 13;
+const SYMBOL_CONSTANT: usize = 14;
+const SYMBOL_ENUM_MEMBER: usize = 22;
+const SYMBOL_STRUCT: usize = 23;
+
+/// An open document, with what is known about its code
+struct Document {
+    text: String,
+    program: Option<Program>,       // None while it has errors
+    declarations: Vec<Declaration>, // Of the names in the program
+}
+
+/// A name declared in a program, and where it can be used
+struct Declaration {
+    name: String,
+    span: Span,  // Of the name
+    scope: Span, // Of the code that can use the name
+    detail: String,
+    arity: Option<Arity>,
+}
+
+/// How many arguments a function takes: at least `min`, up to `max` if there is one
+#[derive(Debug, Clone, Copy)]
+struct Arity {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Arity {
+    fn describe(&self) -> String {
+        let plural = |count: usize| if count == 1 { "argument" } else { "arguments" };
+        match self.max {
+            Some(0) => "Takes no arguments".to_string(),
+            Some(max) if max == self.min => format!("Takes {max} {}", plural(max)),
+            Some(max) => format!("Takes {} to {max} {}", self.min, plural(max)),
+            None => format!("Takes {} or more {}", self.min, plural(self.min)),
+        }
+    }
+}
+
+/// Lines of a document, to convert between offsets in it and positions of the protocol, whose characters are counted in
+/// UTF-16 code units
+struct Lines<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+}
+
+impl Lines<'_> {
+    fn new(text: &str) -> Lines<'_> {
+        let starts = iter::once(0)
+            .chain(text.match_indices('\n').map(|(newline, _)| newline + 1))
+            .collect();
+        Lines { text, starts }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let offset = offset.min(self.text.len());
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let character = self.text[self.starts[line]..offset].encode_utf16().count();
+        object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn range(&self, span: Span) -> Json {
+        object([("start", self.position(span.start)), ("end", self.position(span.end))])
+    }
+
+    /// Offset of a position, which is at the end of its line if it is past it
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let start = *self.starts.get(position.get("line").as_usize()?)?;
+        let character = position.get("character").as_usize()?;
+        let line = self.text[start..].split('\n').next().unwrap_or_default();
+        let mut units = 0;
+        for (idx, c) in line.char_indices() {
+            if units >= character {
+                return Some(start + idx);
+            }
+            units += c.len_utf16();
+        }
+        Some(start + line.len())
+    }
+}
+
+/// Serve one editor over stdin and stdout, until it exits
+pub fn serve() -> io::Result<()> {
+    let mut input = io::stdin().lock();
+    let mut server = Server {
+        documents: HashMap::new(),
+        natives: builtin_natives(),
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        let id = message.get("id");
+        if method == "exit" {
+            break;
+        }
+        // Requests have an id and get a response, notifications don't
+        if *id == Json::Null {
+            server.notification(method, params);
+            continue;
+        }
+        match server.request(method, params) {
+            Some(result) => send(object([("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)])),
+            None => {
+                let error = object([
+                    ("code", Json::Number(METHOD_NOT_FOUND)),
+                    ("message", format!("Unknown method {method}").into()),
+                ]);
+                send(object([("jsonrpc", "2.0".into()), ("id", id.clone()), ("error", error)]));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Natives of the VM by name, with how many arguments they take
+fn builtin_natives() -> HashMap<String, Arity> {
+    async fn no_input(_prompt: String) -> String {
+        String::new()
+    }
+    let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let modules = ModuleRegistry::new(Box::new(NoModules));
+    let vm = Vm::new(&mut interner, Vec::new(), modules.modules, no_input);
+    let natives = vm.builtin_natives().into_iter().map(|(name, arity, variadic)| {
+        let max = (!variadic).then_some(arity);
+        (name, Arity { min: arity, max })
+    });
+    natives.collect()
+}
+
+fn capabilities() -> Json {
+    let capabilities = object([
+        ("textDocumentSync", 1.into()), // Whole documents
+        ("hoverProvider", true.into()),
+        ("definitionProvider", true.into()),
+        ("documentSymbolProvider", true.into()),
+    ]);
+    object([("capabilities", capabilities)])
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    natives: HashMap<String, Arity>,
+}
+
+impl Server {
+    fn notification(&mut self, method: &str, params: &Json) {
+        let document = params.get("textDocument");
+        let uri = document.get("uri").as_str().unwrap_or_default();
+        match method {
+            "textDocument/didOpen" => self.update(uri, document.get("text").as_str().unwrap_or_default()),
+            "textDocument/didChange" => {
+                // The whole document is the last change
+                if let Json::Array(changes) = params.get("contentChanges") {
+                    if let Some(text) = changes.last().and_then(|change| change.get("text").as_str()) {
+                        self.update(uri, text);
+                    }
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                publish_diagnostics(uri, Vec::new());
+            }
+            _ => {}
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Option<Json> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or_default();
+        let document = self.documents.get(uri);
+        let position = params.get("position");
+        let result = match method {
+            "initialize" => capabilities(),
+            "shutdown" => Json::Null,
+            "textDocument/hover" => document
+                .and_then(|document| document.hover(position, &self.natives))
+                .unwrap_or(Json::Null),
+            "textDocument/definition" => document
+                .and_then(|document| document.definition(uri, position))
+                .unwrap_or(Json::Null),
+            "textDocument/documentSymbol" => document.map_or(Json::Array(Vec::new()), Document::symbols),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// Check the new text of the document, and send its problems to the editor
+    fn update(&mut self, uri: &str, text: &str) {
+        let (diagnostics, program) = analyze(text);
+        let lines = Lines::new(text);
+        publish_diagnostics(
+            uri,
+            diagnostics.iter().map(|diagnostic| diagnostic_json(diagnostic, &lines)).collect(),
+        );
+
+        let mut declarations = Vec::new();
+        if let Some(program) = &program {
+            let whole = Span { start: 0, end: text.len() };
+            Resolver {
+                text,
+                declarations: &mut declarations,
+            }
+            .statements(&program.statements, whole);
+        }
+        let document = Document {
+            text: text.to_string(),
+            program,
+            declarations,
+        };
+        self.documents.insert(uri.to_string(), document);
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) {
+    let params = object([("uri", uri.into()), ("diagnostics", diagnostics.into())]);
+    send(object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        ("params", params),
+    ]));
+}
+
+fn diagnostic_json(diagnostic: &Diagnostic, lines: &Lines) -> Json {
+    let mut message = diagnostic.message.clone();
+    for note in &diagnostic.notes {
+        message.push_str(&format!("\nnote: {note}"));
+    }
+    if let Some(hint) = diagnostic.code.hint() {
+        message.push_str(&format!("\nhelp: {hint}"));
+    }
+    let severity: usize = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    object([
+        ("range", lines.range(diagnostic.span)),
+        ("severity", severity.into()),
+        ("code", diagnostic.code.to_string().into()),
+        ("source", "lox".into()),
+        ("message", message.into()),
+    ])
+}
+
+impl Document {
+    /// The name at the position, if it is a variable, with where it is. Properties, which are after a `.`, aren't
+    /// variables.
+    fn name_at(&self, position: &Json) -> Option<(String, Span)> {
+        let lines = Lines::new(&self.text);
+        let offset = lines.offset(position)?;
+        let tokens = tokenize(&self.text);
+        let idx = tokens
+            .iter()
+            .position(|token| token.typ == TokenType::Identifier && token.span.start <= offset && offset <= token.span.end)?;
+        if idx > 0 && matches!(tokens[idx - 1].typ, TokenType::Dot | TokenType::QuestionDot) {
+            return None;
+        }
+        Some((tokens[idx].source.to_string(), tokens[idx].span))
+    }
+
+    /// The declaration of the name used at the offset: the one in the innermost scope, and in it the last one before
+    /// the offset. Names in the whole program can be used before they are declared, by functions that run later.
+    fn resolve(&self, name: &str, offset: usize) -> Option<&Declaration> {
+        let candidates = self.declarations.iter().filter(|declaration| {
+            let scope = declaration.scope;
+            let global = scope.start == 0 && scope.end == self.text.len();
+            declaration.name == name && scope.start <= offset && offset <= scope.end && (global || declaration.span.start <= offset)
+        });
+        candidates.min_by_key(|declaration| {
+            let scope = declaration.scope.end - declaration.scope.start;
+            (scope, declaration.span.start > offset, usize::MAX - declaration.span.start)
+        })
+    }
+
+    /// What the name at the position is, which is one of the natives if the program doesn't declare it
+    fn hover(&self, position: &Json, natives: &HashMap<String, Arity>) -> Option<Json> {
+        self.program.as_ref()?;
+        let (name, span) = self.name_at(position)?;
+        let (detail, arity) = match self.resolve(&name, span.start) {
+            Some(declaration) => (declaration.detail.clone(), declaration.arity),
+            None => (format!("native {name}"), Some(*natives.get(&name)?)),
+        };
+        let mut value = format!("```lox\n{detail}\n```");
+        if let Some(arity) = arity {
+            value.push_str(&format!("\n\n{}", arity.describe()));
+        }
+        let contents = object([("kind", "markdown".into()), ("value", value.into())]);
+        Some(object([("contents", contents), ("range", Lines::new(&self.text).range(span))]))
+    }
+
+    fn definition(&self, uri: &str, position: &Json) -> Option<Json> {
+        self.program.as_ref()?;
+        let (name, span) = self.name_at(position)?;
+        let declaration = self.resolve(&name, span.start)?;
+        Some(object([
+            ("uri", uri.into()),
+            ("range", Lines::new(&self.text).range(declaration.span)),
+        ]))
+    }
+
+    /// `DocumentSymbol`s of the declarations at the top level of the program, with the members of classes, enums and
+    /// records in them
+    fn symbols(&self) -> Json {
+        let Some(program) = &self.program else {
+            return Json::Array(Vec::new());
+        };
+        let lines = Lines::new(&self.text);
+        Json::Array(
+            program
+                .statements
+                .iter()
+                .filter_map(|stmt| statement_symbol(stmt, &lines))
+                .collect(),
+        )
+    }
+}
+
+fn symbol(name: &str, kind: usize, range: Span, selection: Span, children: Vec<Json>, lines: &Lines) -> Json {
+    object([
+        ("name", name.into()),
+        ("kind", kind.into()),
+        ("range", lines.range(range)),
+        ("selectionRange", lines.range(selection)),
+        ("children", children.into()),
+    ])
+}
+
+fn statement_symbol(stmt: &Stmt, lines: &Lines) -> Option<Json> {
+    let leaf = |name: &ast::Name, kind| symbol(&name.name, kind, name.span, name.span, Vec::new(), lines);
+    let symbol = match &stmt.kind {
+        StmtKind::Var { name, is_const, .. } => {
+            let kind = if *is_const { SYMBOL_CONSTANT } else { SYMBOL_VARIABLE };
+            symbol(&name.name, kind, stmt.span, name.span, Vec::new(), lines)
+        }
+        StmtKind::Function(Function { name: Some(name), .. }) => {
+            symbol(&name.name, SYMBOL_FUNCTION, stmt.span, name.span, Vec::new(), lines)
+        }
+        StmtKind::Class { name, members } => {
+            let members = members.iter().filter_map(|member| match member {
+                ClassMember::Method { function, .. } => {
+                    let name = function.name.as_ref()?;
+                    Some(symbol(&name.name, SYMBOL_METHOD, function.span, name.span, Vec::new(), lines))
+                }
+                ClassMember::StaticField { name, .. } => Some(leaf(name, SYMBOL_PROPERTY)),
+            });
+            symbol(&name.name, SYMBOL_CLASS, stmt.span, name.span, members.collect(), lines)
+        }
+        StmtKind::Enum { name, members } => {
+            let members = members.iter().map(|member| leaf(member, SYMBOL_ENUM_MEMBER)).collect();
+            symbol(&name.name, SYMBOL_ENUM, stmt.span, name.span, members, lines)
+        }
+        StmtKind::Record { name, fields } => {
+            let fields = fields.iter().map(|field| leaf(field, SYMBOL_FIELD)).collect();
+            symbol(&name.name, SYMBOL_STRUCT, stmt.span, name.span, fields, lines)
+        }
+        StmtKind::Import { name, .. } => symbol(&name.name, SYMBOL_MODULE, stmt.span, name.span, Vec::new(), lines),
+        StmtKind::Test { name, .. } => symbol(
+            &format!("test \"{name}\""),
+            SYMBOL_FUNCTION,
+            stmt.span,
+            stmt.span,
+            Vec::new(),
+            lines,
+        ),
+        StmtKind::Export(stmt) => return statement_symbol(stmt, lines),
+        _ => return None,
+    };
+    Some(symbol)
+}
+
+/// Finds the declarations of a program, with the scopes they are in
+struct Resolver<'a> {
+    text: &'a str,
+    declarations: &'a mut Vec<Declaration>,
+}
+
+impl Resolver<'_> {
+    fn declare(&mut self, name: &ast::Name, scope: Span, detail: String, arity: Option<Arity>) {
+        self.declarations.push(Declaration {
+            name: name.name.clone(),
+            span: name.span,
+            scope,
+            detail,
+            arity,
+        });
+    }
+
+    fn source(&self, span: Span) -> &str {
+        &self.text[span.start..span.end]
+    }
+
+    fn statements(&mut self, stmts: &[Stmt], scope: Span) {
+        for stmt in stmts {
+            self.statement(stmt, scope);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt, scope: Span) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) | StmtKind::Print(expr) | StmtKind::Throw(expr) => self.expr(expr),
+            StmtKind::Return(expr) | StmtKind::Yield(expr) => expr.iter().for_each(|expr| self.expr(expr)),
+            StmtKind::Var {
+                name,
+                size,
+                value,
+                is_const,
+            } => {
+                size.iter().chain(value).for_each(|expr| self.expr(expr));
+                let keyword = if *is_const { "const" } else { "var" };
+                // Values are shown if they are known without running the program
+                let detail = match value {
+                    Some(
+                        value @ Expr {
+                            kind: ExprKind::Number(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::Nil,
+                            ..
+                        },
+                    ) => format!("{keyword} {} = {}", name.name, self.source(value.span)),
+                    _ => format!("{keyword} {}", name.name),
+                };
+                self.declare(name, scope, detail, None);
+            }
+            StmtKind::Destructure { pattern, value, is_const } => {
+                self.expr(value);
+                let keyword = if *is_const { "const" } else { "var" };
+                let names: Vec<&ast::Name> = match pattern {
+                    Pattern::Array(names) => names.iter().collect(),
+                    Pattern::Map(entries) => entries.iter().map(|(_, name)| name).collect(),
+                };
+                for name in names {
+                    self.declare(name, scope, format!("{keyword} {}", name.name), None);
+                }
+            }
+            StmtKind::Function(function) => {
+                if let Some(name) = &function.name {
+                    let (signature, arity) = self.signature("function", function);
+                    self.declare(name, scope, signature, Some(arity));
+                }
+                self.function(function);
+            }
+            StmtKind::Class { name, members } => {
+                let mut methods = Vec::new();
+                let mut arity = Some(Arity { min: 0, max: Some(0) });
+                for member in members {
+                    match member {
+                        ClassMember::Method { kind, function } => {
+                            let method = function.name.as_ref().map_or("", |name| name.name.as_str());
+                            if *kind == MethodKind::Method && method == crate::compiler::INIT_METHOD {
+                                arity = Some(self.signature("", function).1);
+                            }
+                            methods.push(method.to_string());
+                            self.function(function);
+                        }
+                        ClassMember::StaticField { value, .. } => value.iter().for_each(|expr| self.expr(expr)),
+                    }
+                }
+                let mut detail = format!("class {}", name.name);
+                if !methods.is_empty() {
+                    detail.push_str(&format!(" {{ {} }}", methods.join(", ")));
+                }
+                self.declare(name, scope, detail, arity);
+            }
+            StmtKind::Enum { name, members } => {
+                let members: Vec<&str> = members.iter().map(|member| member.name.as_str()).collect();
+                self.declare(name, scope, format!("enum {} {{ {} }}", name.name, members.join(", ")), None);
+            }
+            StmtKind::Record { name, fields } => {
+                let fields: Vec<&str> = fields.iter().map(|field| field.name.as_str()).collect();
+                let arity = Arity {
+                    min: fields.len(),
+                    max: Some(fields.len()),
+                };
+                self.declare(name, scope, format!("record {}({})", name.name, fields.join(", ")), Some(arity));
+            }
+            StmtKind::Import { path, name } => self.declare(name, scope, format!("import \"{path}\" as {}", name.name), None),
+            StmtKind::Export(stmt) => self.statement(stmt, scope),
+            StmtKind::Test { body, .. } | StmtKind::Block(body) => self.statements(body, stmt.span),
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition);
+                self.statement(then_branch, scope);
+                else_branch.iter().for_each(|stmt| self.statement(stmt, scope));
+            }
+            StmtKind::While { condition, body } => {
+                self.expr(condition);
+                self.statement(body, scope);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                initializer.iter().for_each(|initializer| self.statement(initializer, stmt.span));
+                condition.iter().chain(increment).for_each(|expr| self.expr(expr));
+                self.statement(body, stmt.span);
+            }
+            StmtKind::ForIn { variable, iterable, body } => {
+                self.expr(iterable);
+                self.declare(variable, stmt.span, format!("var {}", variable.name), None);
+                self.statement(body, stmt.span);
+            }
+            StmtKind::Switch { subject, cases, default } => {
+                self.expr(subject);
+                for case in cases {
+                    case.values.iter().for_each(|value| self.expr(value));
+                    self.statements(&case.body, stmt.span);
+                }
+                default.iter().for_each(|body| self.statements(body, stmt.span));
+            }
+            StmtKind::Try { body, catch, finally } => {
+                self.statements(body, stmt.span);
+                if let Some(catch) = catch {
+                    if let Some(variable) = &catch.variable {
+                        self.declare(variable, stmt.span, format!("catch ({})", variable.name), None);
+                    }
+                    self.statements(&catch.body, stmt.span);
+                }
+                finally.iter().for_each(|body| self.statements(body, stmt.span));
+            }
+        }
+    }
+
+    /// Signature of the function, like `function add(a, b = 1)`, and its arity
+    fn signature(&self, keyword: &str, function: &Function) -> (String, Arity) {
+        let name = function.name.as_ref().map_or("", |name| name.name.as_str());
+        let mut arity = Arity { min: 0, max: Some(0) };
+        let params: Vec<String> = function
+            .params
+            .iter()
+            .map(|param| {
+                if param.is_rest {
+                    arity.max = None;
+                    return format!("...{}", param.name.name);
+                }
+                arity.max = arity.max.map(|max| max + 1);
+                match &param.default {
+                    Some(default) => format!("{} = {}", param.name.name, self.source(default.span)),
+                    None => {
+                        arity.min += 1;
+                        param.name.name.clone()
+                    }
+                }
+            })
+            .collect();
+        (format!("{keyword} {name}({})", params.join(", ")).trim_start().to_string(), arity)
+    }
+
+    fn function(&mut self, function: &Function) {
+        for param in &function.params {
+            param.default.iter().for_each(|default| self.expr(default));
+            self.declare(&param.name, function.span, format!("parameter {}", param.name.name), None);
+        }
+        match &function.body {
+            FunctionBody::Block(body) => self.statements(body, function.span),
+            FunctionBody::Expression(expr) => self.expr(expr),
+        }
+    }
+
+    /// Expressions only declare names in the functions in them
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Function(function) => self.function(function),
+            ExprKind::Grouping(operand) | ExprKind::Unary { operand, .. } | ExprKind::Spread(operand) => self.expr(operand),
+            ExprKind::Binary { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Assign { target, value } => {
+                self.expr(target);
+                self.expr(value);
+            }
+            ExprKind::Call { callee, arguments } => {
+                self.expr(callee);
+                arguments.iter().for_each(|argument| self.expr(&argument.value));
+            }
+            ExprKind::Property { object, .. } => self.expr(object),
+            ExprKind::Index { object, index, .. } => {
+                self.expr(object);
+                self.expr(index);
+            }
+            ExprKind::Array(items) | ExprKind::Tuple(items) => items.iter().for_each(|item| self.expr(item)),
+            ExprKind::Map(entries) => entries.iter().for_each(|(key, value)| {
+                self.expr(key);
+                self.expr(value);
+            }),
+            ExprKind::Number(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::Nil | ExprKind::This | ExprKind::Variable(_) => {}
+        }
+    }
+}
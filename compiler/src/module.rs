@@ -0,0 +1,60 @@
+use crate::{interner::StrId, testing::TestCase};
+use anyhow::{bail, Result};
+
+/// Implemented by the host to provide the source code of imported modules.
+/// For example, natively this reads from the file system, and on the web from a map of virtual files.
+pub trait ModuleLoader {
+    /// Returns the source code of the module at the given path
+    fn load(&self, path: &str) -> Result<String>;
+}
+
+/// Loader for hosts that do not support modules
+pub struct NoModules;
+
+impl ModuleLoader for NoModules {
+    fn load(&self, path: &str) -> Result<String> {
+        bail!("Importing modules is not supported here, can't import '{path}'")
+    }
+}
+
+#[derive(Debug)]
+pub struct Module {
+    pub path: String,
+    pub exports: Vec<StrId>,
+    pub constants: Vec<StrId>, // Globals declared with `const`
+    pub script: Option<usize>, // Index of the top-level function. None while the module is being compiled.
+    pub tests: Vec<TestCase>,
+}
+
+/// All modules of a program. Module 0 is the main script.
+pub struct ModuleRegistry {
+    pub modules: Vec<Module>,
+    loader: Box<dyn ModuleLoader>,
+}
+
+pub const MAIN_MODULE: usize = 0;
+/// Path of the main module in tracebacks
+pub const MAIN_MODULE_PATH: &str = "<script>";
+
+impl ModuleRegistry {
+    pub fn new(loader: Box<dyn ModuleLoader>) -> ModuleRegistry {
+        ModuleRegistry {
+            modules: vec![Module {
+                path: String::from(MAIN_MODULE_PATH),
+                exports: Vec::new(),
+                constants: Vec::new(),
+                script: None,
+                tests: Vec::new(),
+            }],
+            loader,
+        }
+    }
+
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.modules.iter().position(|module| module.path == path)
+    }
+
+    pub fn load(&self, path: &str) -> Result<String> {
+        self.loader.load(path)
+    }
+}
@@ -0,0 +1,112 @@
+//! Named arguments of calls to functions that are known when the call is compiled, like `area(height: 2, width: 3)`
+//! where `area` is declared with `function`. These are compiled to a plain `Call` with the arguments in the order of the
+//! parameters, so the VM doesn't have to match the names. Other calls use `CallNamed`, which matches them when it runs.
+//!
+//! A function declaration is only known if the module doesn't declare or assign its name anywhere else, which
+//! `rebound_names` finds from the tokens of the whole module before it is compiled. A constant that holds a function
+//! literal can't be changed, so it is always known.
+
+use crate::{
+    fun::Fun,
+    interner::{Interner, StrId},
+    scanner::{Scanner, TokenType},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/// Functions that names of the module always hold, shared by the compilers of its functions
+#[derive(Default)]
+pub(crate) struct KnownFunctions {
+    pub(crate) globals: HashMap<StrId, usize>, // Index of the function of each global that holds one
+    // Names that the module binds more than once. None if the module can get more code later, like the snippets of a
+    // session, so that only constants are known.
+    pub(crate) rebound: Option<HashSet<StrId>>,
+}
+
+impl KnownFunctions {
+    pub(crate) fn for_module(source: &Rc<str>, interner: &mut Interner) -> KnownFunctions {
+        KnownFunctions {
+            globals: HashMap::new(),
+            rebound: Some(rebound_names(source, interner)),
+        }
+    }
+
+    /// Whether a function declaration of the name always holds its function
+    pub(crate) fn keeps_declaration(&self, name: StrId) -> bool {
+        self.rebound.as_ref().is_some_and(|rebound| !rebound.contains(&name))
+    }
+}
+
+/// Names that the source declares more than once or assigns to, in any scope, so a name that is only shadowed by a
+/// declaration in a block is in the set too. Parameters are left out, since the calls they shadow a name in find them
+/// first, as locals that hold no known function.
+pub(crate) fn rebound_names(source: &Rc<str>, interner: &mut Interner) -> HashSet<StrId> {
+    let mut scanner = Scanner::new(source.clone());
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        if token.typ == TokenType::EOF {
+            break;
+        }
+        tokens.push(token);
+    }
+
+    let mut declared = HashSet::new();
+    let mut rebound = HashSet::new();
+    let mut pattern_depth = 0; // Brackets open in a destructuring pattern, whose names are declarations
+    for (index, token) in tokens.iter().enumerate() {
+        let previous = index.checked_sub(1).map(|index| tokens[index].typ);
+        let next = tokens.get(index + 1).map(|token| token.typ);
+        match token.typ {
+            TokenType::LeftBracket | TokenType::LeftBrace if pattern_depth > 0 => pattern_depth += 1,
+            TokenType::LeftBracket | TokenType::LeftBrace if matches!(previous, Some(TokenType::Var | TokenType::Const)) => {
+                pattern_depth = 1;
+            }
+            TokenType::RightBracket | TokenType::RightBrace if pattern_depth > 0 => pattern_depth -= 1,
+            TokenType::Identifier => {
+                let name = interner.intern(token.source.as_ref());
+                let declares = pattern_depth > 0
+                    || matches!(
+                        previous,
+                        Some(
+                            TokenType::Var
+                                | TokenType::Const
+                                | TokenType::Fun
+                                | TokenType::Class
+                                | TokenType::Enum
+                                | TokenType::Record
+                                | TokenType::As
+                        )
+                    );
+                if (declares && !declared.insert(name)) || (!declares && next == Some(TokenType::Equal)) {
+                    rebound.insert(name);
+                }
+            }
+            _ => {}
+        }
+    }
+    rebound
+}
+
+/// Parameter of each named argument of a call of `fun` with `arg_count` arguments, the last of which are named `names`,
+/// if the call can be a plain `Call` with the arguments in the order of the parameters. They have to fill the
+/// parameters after the positional ones without a gap, since those after them are left to their defaults, and the call
+/// has to be one that `CallNamed` makes without an error.
+pub(crate) fn parameters(fun: &Fun, arg_count: usize, names: &[StrId]) -> Option<Vec<usize>> {
+    let fixed_arity = if fun.is_variadic { fun.arity - 1 } else { fun.arity };
+    if arg_count > fixed_arity || arg_count < fun.min_arity {
+        return None;
+    }
+
+    // The names are different, so if each one is a parameter in the range, they fill it
+    let positional = arg_count - names.len();
+    names
+        .iter()
+        .map(|name| {
+            let param = fun.param_names[..fixed_arity].iter().position(|param| param == name)?;
+            (positional..arg_count).contains(&param).then_some(param)
+        })
+        .collect()
+}
@@ -1,21 +1,170 @@
 #![allow(unused_variables)]
 
 use crate::{
-    interner::{Interner, StrId},
-    value::{print_value, value_as_string, Value},
+    clock::{host_clock, DateTime, HostClock},
+    format::format,
+    gc,
+    interner::Interner,
+    json,
+    regex::{replace_all, Captures, Regex},
+    value::{print_value, sorted_keys, value_as_string, MapKey, SlotMap, Value, ValueSet},
     vm::ERR_STRING,
     xprintln,
 };
-use rustc_hash::FxHashMap;
-use std::fmt::Debug;
-use web_time::SystemTime;
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+};
 
-type Globals = FxHashMap<StrId, Value>;
+pub(crate) type Globals = SlotMap;
 
 pub trait Callable: Debug {
     fn arity(&self) -> usize;
     fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value;
     fn name(&self) -> &str;
+
+    /// Variadic natives take any number of arguments after the first `arity`
+    fn is_variadic(&self) -> bool {
+        false
+    }
+
+    /// Value the native holds on to, like the receiver of a method, which the garbage collector has to keep alive
+    fn receiver(&self) -> Option<&Value> {
+        None
+    }
+
+    /// Called by the VM. Errors are thrown as runtime errors, rather than reported through `errString` like `call` does.
+    fn call_or_throw(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Result<Value, String> {
+        Ok(self.call(interner, globals, args))
+    }
+
+    /// Natives that change which task is running, which the VM runs itself instead of calling `call`
+    fn task_operation(&self) -> Option<TaskOperation> {
+        None
+    }
+
+    /// Natives that wait for the host, like timers and network requests, return a future that the VM awaits instead of `call`
+    fn call_async(&self, interner: &mut Interner, args: &[Value]) -> Option<NativeFuture> {
+        None
+    }
+}
+
+/// Operations of the scheduler that runs tasks, see `Vm::run`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskOperation {
+    Spawn,   // Start a task that calls the function with the rest of the arguments
+    Receive, // Take the oldest value of a channel, waiting for another task to send one if it is empty
+}
+
+/// What an async native resolves to. The future can't borrow the interner, so strings are interned once it resolves.
+#[derive(Debug)]
+pub enum AsyncValue {
+    Value(Value),
+    Str(String),
+}
+
+impl From<Value> for AsyncValue {
+    fn from(value: Value) -> AsyncValue {
+        AsyncValue::Value(value)
+    }
+}
+
+impl From<String> for AsyncValue {
+    fn from(string: String) -> AsyncValue {
+        AsyncValue::Str(string)
+    }
+}
+
+impl AsyncValue {
+    pub fn into_value(self, interner: &mut Interner) -> Value {
+        match self {
+            AsyncValue::Value(value) => value,
+            AsyncValue::Str(string) => Value::Str(interner.intern(&string)),
+        }
+    }
+}
+
+/// Future returned by an async native, like a JS promise on wasm
+pub type NativeFuture = Pin<Box<dyn Future<Output = anyhow::Result<AsyncValue>>>>;
+
+/// Signature of async natives defined by the host with `Vm::define_async_native`.
+/// The arguments are only borrowed until the future is created, so it has to copy what it needs.
+pub type AsyncHostFn = dyn Fn(&mut Interner, &[Value]) -> NativeFuture;
+
+/// Signature of natives defined by the host with `Vm::define_native`
+pub type HostFn = dyn Fn(&mut Interner, &[Value]) -> anyhow::Result<Value>;
+
+/// Native function defined by the host. Errors are reported through `errString`, like the builtin natives.
+pub(crate) struct HostNative {
+    pub name: String,
+    pub arity: usize,
+    pub fun: Box<HostFn>,
+}
+
+impl Debug for HostNative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HostNative({})", self.name)
+    }
+}
+
+impl Callable for HostNative {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        match (self.fun)(interner, args) {
+            Ok(value) => value,
+            Err(err) => {
+                set_global_error(interner, globals, &err.to_string());
+                Value::Nil
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Async native function defined by the host. The program waits for it to resolve, while the host keeps running.
+pub(crate) struct AsyncHostNative {
+    pub name: String,
+    pub arity: usize,
+    pub fun: Box<AsyncHostFn>,
+}
+
+impl Debug for AsyncHostNative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AsyncHostNative({})", self.name)
+    }
+}
+
+impl Callable for AsyncHostNative {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Only the VM can wait for the result, natives calling it directly get an error
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        set_global_error(
+            interner,
+            globals,
+            &format!("{} is async, and can only be called by the program", self.name),
+        );
+        Value::Nil
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn call_async(&self, interner: &mut Interner, args: &[Value]) -> Option<NativeFuture> {
+        Some((self.fun)(interner, args))
+    }
 }
 
 pub fn set_global_error(interner: &mut Interner, globals: &mut Globals, message: &str) {
@@ -43,14 +192,162 @@ macro_rules! callable_struct {
     };
 }
 
-callable_struct!(Clock, 0, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
-    let epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-    Value::Number(epoch.as_millis() as f64)
+/// Like `callable_struct`, for natives that throw a runtime error when their body returns an error
+macro_rules! throwing_callable {
+    ($struct_name:ident, $arity:expr, $interner:ident: &mut Interner, $globals:ident: &mut Globals, $args:ident: &[Value], $body:block) => {
+        #[derive(Debug, Default)]
+        pub struct $struct_name;
+
+        impl Callable for $struct_name {
+            fn arity(&self) -> usize {
+                $arity
+            }
+
+            fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+                match self.call_or_throw(interner, globals, args) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        set_global_error(interner, globals, &err);
+                        Value::Nil
+                    }
+                }
+            }
+
+            fn call_or_throw(&self, $interner: &mut Interner, $globals: &mut Globals, $args: &[Value]) -> Result<Value, String> {
+                $body
+            }
+
+            fn name(&self) -> &str {
+                stringify!($struct_name)
+            }
+        }
+    };
+}
+
+/// Parse a number like a literal in the source: an integer if it has no fractional part, otherwise a float
+fn parse_number(input: &str) -> Result<Value, std::num::ParseFloatError> {
+    match input.parse::<i64>() {
+        Ok(i) => Ok(Value::Int(i)),
+        Err(_) => input.parse::<f64>().map(Value::Number),
+    }
+}
+
+/// Nanoseconds that each instruction takes on the virtual clock of a deterministic run
+const VIRTUAL_INSTRUCTION_NANOS: u64 = 1000;
+
+/// Time on the virtual clock when a deterministic run starts: 2000-01-01 00:00 UTC
+const VIRTUAL_EPOCH_MILLIS: i64 = 946_684_800_000;
+
+/// Whether the VM runs with `VmOptions::deterministic`, shared with the natives that behave differently then.
+/// Its clock counts instructions, so the time natives give the same results on every run.
+#[derive(Debug, Default)]
+pub(crate) struct Determinism {
+    pub active: Cell<bool>,
+    instructions: Cell<u64>,
+}
+
+impl Determinism {
+    pub fn reset(&self, active: bool) {
+        self.active.set(active);
+        self.instructions.set(0);
+    }
+
+    pub fn tick(&self) {
+        self.instructions.set(self.instructions.get() + 1);
+    }
+
+    /// The virtual clock in a deterministic run, otherwise the clock of the host
+    fn clock(self: &Rc<Self>) -> Rc<dyn HostClock> {
+        match self.active.get() {
+            true => self.clone(),
+            false => host_clock(),
+        }
+    }
+}
+
+impl HostClock for Determinism {
+    fn monotonic_seconds(&self) -> f64 {
+        (self.instructions.get() * VIRTUAL_INSTRUCTION_NANOS) as f64 / 1e9
+    }
+
+    fn epoch_millis(&self) -> i64 {
+        VIRTUAL_EPOCH_MILLIS + (self.instructions.get() * VIRTUAL_INSTRUCTION_NANOS / 1_000_000) as i64
+    }
+}
+
+pub(crate) type SharedDeterminism = Rc<Determinism>;
+
+/// Like `callable_struct!`, for natives that behave differently in a deterministic run
+macro_rules! determinism_callable {
+    ($struct_name:ident, $arity:expr, $determinism:ident, $interner:ident: &mut Interner, $globals:ident: &mut Globals, $args:ident: &[Value], $body:block) => {
+        #[derive(Debug)]
+        pub struct $struct_name {
+            pub(crate) determinism: SharedDeterminism,
+        }
+
+        impl Callable for $struct_name {
+            fn arity(&self) -> usize {
+                $arity
+            }
+
+            fn call(&self, $interner: &mut Interner, $globals: &mut Globals, $args: &[Value]) -> Value {
+                let $determinism = &self.determinism;
+                $body
+            }
+
+            fn name(&self) -> &str {
+                stringify!($struct_name)
+            }
+        }
+    };
+}
+
+determinism_callable!(Clock, 0, determinism, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    Value::Number(determinism.clock().monotonic_seconds())
+});
+
+determinism_callable!(Now, 0, determinism, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    Value::Int(determinism.clock().epoch_millis())
+});
+
+// The parts of a time given in epoch milliseconds, in UTC
+callable_struct!(Date, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    let Value::Int(millis) = args[0] else {
+        set_global_error(interner, globals, "Expected epoch milliseconds as argument to date");
+        return Value::Nil;
+    };
+
+    let date = DateTime::from_epoch_millis(millis);
+    let parts = [
+        ("year", date.year),
+        ("month", date.month),
+        ("day", date.day),
+        ("hour", date.hour),
+        ("minute", date.minute),
+        ("second", date.second),
+        ("millisecond", date.millisecond),
+        ("weekday", date.weekday),
+    ];
+    let map = parts.iter().map(|(name, value)| (MapKey::Str(interner.intern(name)), Value::Int(*value))).collect();
+    Value::Map(gc::new_map(map))
+});
+
+callable_struct!(FormatDate, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Int(millis), Value::Str(format)) => {
+            let formatted = DateTime::from_epoch_millis(*millis).format(interner.lookup(format));
+            Value::Str(interner.intern(&formatted))
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected epoch milliseconds and format string as arguments to formatdate");
+            Value::Nil
+        }
+    }
 });
 
 callable_struct!(Sleep, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
-    if let Some(Value::Number(n)) = args.first() {
-        std::thread::sleep(std::time::Duration::from_millis(*n as u64));
+    if let Some(n) = args.first().and_then(Value::as_number) {
+        std::thread::sleep(std::time::Duration::from_millis(n as u64));
         Value::Nil
     } else {
         set_global_error(interner, globals, "Expected number as argument to sleep");
@@ -77,13 +374,24 @@ callable_struct!(ReadString, 1, interner: &mut Interner, globals: &mut Globals,
     }
 });
 
+// Arg is what the user gave, like ReadString
+callable_struct!(Input, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Str(_) => args[0].clone(),
+        _ => {
+            set_global_error(interner, globals, "Expected string as the prompt to Input");
+            Value::Nil
+        }
+    }
+});
+
 // Arg is what the user gave
 callable_struct!(ReadNumber, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match &args[0] {
         Value::Str(user_input) => {
             let input = interner.lookup(user_input);
-            match input.parse::<f64>() {
-                Ok(n) => Value::Number(n),
+            match parse_number(input) {
+                Ok(n) => n,
                 Err(err) => {
                     set_global_error(interner, globals, &format!("Failed to parse number: {}", err));
                     Value::Nil
@@ -116,18 +424,234 @@ callable_struct!(TypeOf, 1, interner: &mut Interner, globals: &mut Globals, args
     Value::Str(interner.intern(&format!("{}", args[0])))
 });
 
+callable_struct!(Type, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    Value::Str(interner.intern(args[0].type_name()))
+});
+
+/// `Format(format, ...args)`, which takes any number of arguments to fill the placeholders with
+#[derive(Debug, Default)]
+pub struct Format;
+
+impl Callable for Format {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        let Value::Str(id) = &args[0] else {
+            set_global_error(interner, globals, "Expected string as the first argument to format");
+            return Value::Nil;
+        };
+
+        match format(interner.lookup(id), &args[1..], interner) {
+            Ok(s) => Value::Str(interner.intern(&s)),
+            Err(err) => {
+                set_global_error(interner, globals, &err);
+                Value::Nil
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "Format"
+    }
+
+    fn is_variadic(&self) -> bool {
+        true
+    }
+}
+
+callable_struct!(JsonParse, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    let Value::Str(id) = &args[0] else {
+        set_global_error(interner, globals, "Expected string as argument to jsonparse");
+        return Value::Nil;
+    };
+
+    let text = interner.lookup(id).to_string();
+    match json::parse(&text, interner) {
+        Ok(value) => value,
+        Err(err) => {
+            set_global_error(interner, globals, &err);
+            Value::Nil
+        }
+    }
+});
+
+/// `JsonStringify(value, indent?)`, where the indent is a number of spaces or a string to indent with
+#[derive(Debug, Default)]
+pub struct JsonStringify;
+
+impl Callable for JsonStringify {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        let indent = match args.get(1..) {
+            Some([]) | Some([Value::Nil]) => None,
+            Some([Value::Int(spaces)]) => Some(" ".repeat((*spaces).clamp(0, 10) as usize)),
+            Some([Value::Str(indent)]) => Some(interner.lookup(indent).to_string()),
+            _ => {
+                set_global_error(
+                    interner,
+                    globals,
+                    "Expected a number of spaces or a string as the indent of jsonstringify",
+                );
+                return Value::Nil;
+            }
+        };
+
+        match json::stringify(&args[0], indent.as_deref(), interner) {
+            Ok(s) => Value::Str(interner.intern(&s)),
+            Err(err) => {
+                set_global_error(interner, globals, &err);
+                Value::Nil
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "JsonStringify"
+    }
+
+    fn is_variadic(&self) -> bool {
+        true
+    }
+}
+
+/// Start of the message of failed assertions, which test runners use to tell them apart from other errors
+pub const ASSERTION_FAILED: &str = "Assertion failed";
+
+throwing_callable!(Assert, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    if args[0].is_falsey() {
+        return Err(format!("{ASSERTION_FAILED}: {}", value_as_string(&args[1], interner)));
+    }
+    Ok(Value::Nil)
+});
+
+throwing_callable!(AssertEq, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    if !args[0].deep_eq(&args[1]) {
+        return Err(format!(
+            "{ASSERTION_FAILED}: {} != {}",
+            value_as_string(&args[0], interner),
+            value_as_string(&args[1], interner)
+        ));
+    }
+    Ok(Value::Nil)
+});
+
+/// Compile the pattern and split the text into chars, or report why not through errString
+fn regex_args(interner: &mut Interner, globals: &mut Globals, pattern: &Value, text: &Value) -> Option<(Regex, Vec<char>)> {
+    let (Value::Str(pattern), Value::Str(text)) = (pattern, text) else {
+        set_global_error(interner, globals, "Expected a pattern string and a string to match");
+        return None;
+    };
+
+    match Regex::new(interner.lookup(pattern)) {
+        Ok(regex) => Some((regex, interner.lookup(text).chars().collect())),
+        Err(err) => {
+            set_global_error(interner, globals, &err);
+            None
+        }
+    }
+}
+
+/// The text of each group, or Nil for groups that did not take part in the match
+fn captures_to_array(interner: &mut Interner, text: &[char], captures: &Captures) -> Value {
+    let groups = captures
+        .iter()
+        .map(|span| match span {
+            Some((start, end)) => Value::Str(interner.intern(&text[*start..*end].iter().collect::<String>())),
+            None => Value::Nil,
+        })
+        .collect();
+    Value::Array(gc::new_array(groups))
+}
+
+callable_struct!(RegexTest, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match regex_args(interner, globals, &args[0], &args[1]) {
+        Some((regex, text)) => Value::Bool(regex.is_match(&text)),
+        None => Value::Nil,
+    }
+});
+
+// The first match as an array of the whole match followed by the groups, or Nil if there is none
+callable_struct!(RegexMatch, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    let Some((regex, text)) = regex_args(interner, globals, &args[0], &args[1]) else {
+        return Value::Nil;
+    };
+
+    match regex.captures_at(&text, 0) {
+        Some(captures) => captures_to_array(interner, &text, &captures),
+        None => Value::Nil,
+    }
+});
+
+// The text of every match
+callable_struct!(RegexFindAll, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    let Some((regex, text)) = regex_args(interner, globals, &args[0], &args[1]) else {
+        return Value::Nil;
+    };
+
+    let matches = regex
+        .captures_all(&text)
+        .iter()
+        .map(|captures| {
+            let (start, end) = captures[0].unwrap();
+            Value::Str(interner.intern(&text[start..end].iter().collect::<String>()))
+        })
+        .collect();
+    Value::Array(gc::new_array(matches))
+});
+
+callable_struct!(RegexReplace, 3, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    let Value::Str(replacement) = &args[2] else {
+        set_global_error(interner, globals, "Expected string as the replacement");
+        return Value::Nil;
+    };
+    let Some((regex, text)) = regex_args(interner, globals, &args[0], &args[1]) else {
+        return Value::Nil;
+    };
+
+    let replaced = replace_all(&regex, &text, interner.lookup(replacement));
+    Value::Str(interner.intern(&replaced))
+});
+
+/// Natives that check whether their argument has one of the given type names
+macro_rules! type_check {
+    ($struct_name:ident, $($type_name:literal)|+) => {
+        callable_struct!($struct_name, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+            Value::Bool(matches!(args[0].type_name(), $($type_name)|+))
+        });
+    };
+}
+
+type_check!(IsNil, "nil");
+type_check!(IsBool, "bool");
+type_check!(IsNumber, "number");
+type_check!(IsString, "string");
+type_check!(IsList, "list");
+type_check!(IsMap, "map");
+type_check!(IsSet, "set");
+type_check!(IsTuple, "tuple");
+type_check!(IsFunction, "function");
+type_check!(IsCallable, "function" | "class" | "record type");
+type_check!(IsInstance, "instance" | "record");
+
 callable_struct!(ToString, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     Value::Str(interner.intern(&value_as_string(&args[0], interner)))
 });
 
 callable_struct!(ToNumber, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match args[0] {
-        Value::Number(n) => Value::Number(n),
-        Value::Bool(b) => Value::Number(b as i8 as f64),
+        Value::Number(_) | Value::Int(_) => args[0].clone(),
+        #[cfg(feature = "bigint")]
+        Value::BigInt(ref n) => n.to_i64().map(Value::Int).unwrap_or(Value::Number(n.to_f64())),
+        Value::Bool(b) => Value::Int(b as i64),
         Value::Str(s) => {
             let str = interner.lookup(&s);
-            match str.parse::<f64>() {
-                Ok(n) => Value::Number(n),
+            match parse_number(str) {
+                Ok(n) => n,
                 Err(err) => {
                     set_global_error(interner, globals, &format!("Failed to parse number: {}", err));
                     Value::Nil
@@ -141,11 +665,35 @@ callable_struct!(ToNumber, 1, interner: &mut Interner, globals: &mut Globals, ar
     }
 });
 
+#[cfg(feature = "bigint")]
+callable_struct!(ToBigInt, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    use crate::bigint::BigInt;
+
+    let n = match &args[0] {
+        Value::BigInt(n) => Some((**n).clone()),
+        Value::Int(i) => Some(BigInt::from_i64(*i)),
+        Value::Number(n) => match Value::from_f64(*n) {
+            Value::Int(i) => Some(BigInt::from_i64(i)),
+            _ => None,
+        },
+        Value::Str(s) => BigInt::parse(interner.lookup(s).trim()),
+        _ => None,
+    };
+
+    match n {
+        Some(n) => Value::BigInt(Rc::new(n)),
+        None => {
+            set_global_error(interner, globals, "Expected integer, whole number, or string of digits as argument to tobigint");
+            Value::Nil
+        }
+    }
+});
+
 callable_struct!(StringAt, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match (&args[0], &args[1]) {
-        (Value::Str(s), Value::Number(n)) => {
+        (Value::Str(s), n @ (Value::Number(_) | Value::Int(_))) => {
             let str = interner.lookup(s);
-            let index = *n as usize;
+            let index = n.as_number().unwrap() as usize;
             if index < str.len() {
                 let c = str.chars().nth(index).unwrap();
                 Value::Str(interner.intern(&c.to_string()))
@@ -165,7 +713,7 @@ callable_struct!(StrLen, 1, interner: &mut Interner, globals: &mut Globals, args
     match &args[0] {
         Value::Str(s) => {
             let str = interner.lookup(s);
-            Value::Number(str.len() as f64)
+            Value::Int(str.chars().count() as i64)
         }
         _ => {
             set_global_error(interner, globals, "Expected string as argument to strlen");
@@ -174,76 +722,350 @@ callable_struct!(StrLen, 1, interner: &mut Interner, globals: &mut Globals, args
     }
 });
 
-callable_struct!(ArrLen, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+callable_struct!(StrUpper, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match &args[0] {
-        Value::Array(arr) => Value::Number(arr.borrow().len() as f64),
+        Value::Str(s) => {
+            let upper = interner.lookup(s).to_uppercase();
+            Value::Str(interner.intern(&upper))
+        }
         _ => {
-            set_global_error(interner, globals, "Expected array as argument to arrlen");
+            set_global_error(interner, globals, "Expected string as argument to strupper");
             Value::Nil
         }
     }
 });
 
-callable_struct!(Ceil, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+callable_struct!(StrLower, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match &args[0] {
-        Value::Number(n) => Value::Number(n.ceil()),
+        Value::Str(s) => {
+            let lower = interner.lookup(s).to_lowercase();
+            Value::Str(interner.intern(&lower))
+        }
         _ => {
-            set_global_error(interner, globals, "Expected number as argument to ceil");
+            set_global_error(interner, globals, "Expected string as argument to strlower");
             Value::Nil
         }
     }
 });
 
-callable_struct!(Floor, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+callable_struct!(StrTrim, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match &args[0] {
-        Value::Number(n) => Value::Number(n.floor()),
+        Value::Str(s) => {
+            let trimmed = interner.lookup(s).trim().to_string();
+            Value::Str(interner.intern(&trimmed))
+        }
         _ => {
-            set_global_error(interner, globals, "Expected number as argument to floor");
+            set_global_error(interner, globals, "Expected string as argument to strtrim");
             Value::Nil
         }
     }
 });
 
-callable_struct!(Abs, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
-    match &args[0] {
-        Value::Number(n) => Value::Number(n.abs()),
+callable_struct!(StrSplit, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Str(s), Value::Str(separator)) => {
+            let parts: Vec<String> = interner.lookup(s).split(interner.lookup(separator)).map(String::from).collect();
+            let parts = parts.iter().map(|part| Value::Str(interner.intern(part))).collect();
+            Value::Array(gc::new_array(parts))
+        }
         _ => {
-            set_global_error(interner, globals, "Expected number as argument to abs");
+            set_global_error(interner, globals, "Expected string and separator string as arguments to strsplit");
             Value::Nil
         }
     }
 });
 
-callable_struct!(Sort, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
-    match &args[0] {
-        Value::Array(arr) => {
-            let mut arr = arr.borrow_mut();
-            arr.sort_by(|a, b| match (a, b) {
-                (Value::Number(a), Value::Number(b)) => a.partial_cmp(b).unwrap(),
-                _ => {
-                    set_global_error(interner, globals, "Expected array of numbers");
-                    std::cmp::Ordering::Equal
-                }
-            });
-            Value::Nil
+callable_struct!(StrReplace, 3, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1], &args[2]) {
+        (Value::Str(s), Value::Str(from), Value::Str(to)) => {
+            let replaced = interner.lookup(s).replace(interner.lookup(from), interner.lookup(to));
+            Value::Str(interner.intern(&replaced))
         }
         _ => {
-            set_global_error(interner, globals, "Expected array as argument to sort");
+            set_global_error(interner, globals, "Expected three strings as arguments to strreplace");
             Value::Nil
         }
     }
 });
 
-callable_struct!(IndexOf, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+callable_struct!(StrContains, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match (&args[0], &args[1]) {
-        (Value::Array(arr), value) => {
-            let arr = arr.borrow();
-            for (i, v) in arr.iter().enumerate() {
-                if v == value {
-                    return Value::Number(i as f64);
+        (Value::Str(s), Value::Str(substring)) => Value::Bool(interner.lookup(s).contains(interner.lookup(substring))),
+        _ => {
+            set_global_error(interner, globals, "Expected two strings as arguments to strcontains");
+            Value::Nil
+        }
+    }
+});
+
+// Index in characters of the first occurrence of the substring, or -1 if it is not found
+callable_struct!(StrIndexOf, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Str(s), Value::Str(substring)) => {
+            let s = interner.lookup(s);
+            match s.find(interner.lookup(substring)) {
+                Some(byte_index) => Value::Int(s[..byte_index].chars().count() as i64),
+                None => Value::Int(-1),
+            }
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected two strings as arguments to strindexof");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(ArrLen, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Array(arr) => Value::Int(arr.borrow().len() as i64),
+        _ => {
+            set_global_error(interner, globals, "Expected array as argument to arrlen");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(ArrPush, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Array(arr) => {
+            arr.borrow_mut().push(args[1].clone());
+            Value::Nil
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected array as first argument to arrpush");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(ArrPop, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Array(arr) => match arr.borrow_mut().pop() {
+            Some(value) => value,
+            None => {
+                set_global_error(interner, globals, "Can't pop from an empty array");
+                Value::Nil
+            }
+        },
+        _ => {
+            set_global_error(interner, globals, "Expected array as argument to arrpop");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(ArrInsert, 3, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Array(arr), Value::Int(index)) if *index >= 0 && *index as usize <= arr.borrow().len() => {
+            arr.borrow_mut().insert(*index as usize, args[2].clone());
+            Value::Nil
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected array, index in bounds and value as arguments to arrinsert");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(ArrRemove, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Array(arr), Value::Int(index)) if *index >= 0 && (*index as usize) < arr.borrow().len() => {
+            arr.borrow_mut().remove(*index as usize)
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected array and index in bounds as arguments to arrremove");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(MapLen, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Map(map) => Value::Int(map.borrow().len() as i64),
+        _ => {
+            set_global_error(interner, globals, "Expected map as argument to maplen");
+            Value::Nil
+        }
+    }
+});
+
+determinism_callable!(MapKeys, 1, determinism, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Map(map) => {
+            let keys = match determinism.active.get() {
+                true => sorted_keys(map.borrow().keys(), interner).into_iter().map(MapKey::to_value).collect(),
+                false => map.borrow().keys().map(|key| key.to_value()).collect(),
+            };
+            Value::Array(gc::new_array(keys))
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected map as argument to mapkeys");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(MapRemove, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], MapKey::from_value(&args[1])) {
+        (Value::Map(map), Some(key)) => map.borrow_mut().remove(&key).unwrap_or(Value::Nil),
+        _ => {
+            set_global_error(interner, globals, "Expected map and string or number key as arguments to mapremove");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(RangeLen, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Range(range) => Value::Int(range.len() as i64),
+        _ => {
+            set_global_error(interner, globals, "Expected range as argument to rangelen");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(RangeContains, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Range(range), n @ (Value::Number(_) | Value::Int(_))) => Value::Bool(range.contains(n.as_number().unwrap())),
+        (Value::Range(_), _) => Value::Bool(false),
+        _ => {
+            set_global_error(interner, globals, "Expected range as first argument to rangecontains");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Ceil, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Number(n) => Value::from_f64(n.ceil()),
+        Value::Int(i) => Value::Int(*i),
+        _ => {
+            set_global_error(interner, globals, "Expected number as argument to ceil");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Floor, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Number(n) => Value::from_f64(n.floor()),
+        Value::Int(i) => Value::Int(*i),
+        _ => {
+            set_global_error(interner, globals, "Expected number as argument to floor");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Abs, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Number(n) => Value::Number(n.abs()),
+        Value::Int(i) => i.checked_abs().map(Value::Int).unwrap_or(Value::Number((*i as f64).abs())),
+        _ => {
+            set_global_error(interner, globals, "Expected number as argument to abs");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Sqrt, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match args[0].as_number() {
+        Some(n) => Value::Number(n.sqrt()),
+        None => {
+            set_global_error(interner, globals, "Expected number as argument to sqrt");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Sin, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match args[0].as_number() {
+        Some(n) => Value::Number(n.sin()),
+        None => {
+            set_global_error(interner, globals, "Expected number as argument to sin");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Cos, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match args[0].as_number() {
+        Some(n) => Value::Number(n.cos()),
+        None => {
+            set_global_error(interner, globals, "Expected number as argument to cos");
+            Value::Nil
+        }
+    }
+});
+
+// Integers raised to small non-negative integer powers stay integers
+callable_struct!(Pow, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    if let (Value::Int(base), Value::Int(exponent)) = (&args[0], &args[1]) {
+        if let Some(result) = u32::try_from(*exponent).ok().and_then(|exponent| base.checked_pow(exponent)) {
+            return Value::Int(result);
+        }
+    }
+
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(base), Some(exponent)) => Value::Number(base.powf(exponent)),
+        _ => {
+            set_global_error(interner, globals, "Expected numbers as arguments to pow");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Min, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(a), Some(b)) => if b < a { args[1].clone() } else { args[0].clone() },
+        _ => {
+            set_global_error(interner, globals, "Expected numbers as arguments to min");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Max, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(a), Some(b)) => if b > a { args[1].clone() } else { args[0].clone() },
+        _ => {
+            set_global_error(interner, globals, "Expected numbers as arguments to max");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Sort, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Array(arr) => {
+            let mut arr = arr.borrow_mut();
+            arr.sort_by(|a, b| match (a.as_number(), b.as_number()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b).unwrap(),
+                _ => {
+                    set_global_error(interner, globals, "Expected array of numbers");
+                    std::cmp::Ordering::Equal
+                }
+            });
+            Value::Nil
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected array as argument to sort");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(IndexOf, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Array(arr), value) => {
+            let arr = arr.borrow();
+            for (i, v) in arr.iter().enumerate() {
+                if v == value {
+                    return Value::Int(i as i64);
                 }
             }
-            Value::Number(arr.len() as f64)
+            Value::Int(arr.len() as i64)
         }
         _ => {
             set_global_error(interner, globals, "Expected array and value as arguments to find");
@@ -252,7 +1074,300 @@ callable_struct!(IndexOf, 2, interner: &mut Interner, globals: &mut Globals, arg
     }
 });
 
-callable_struct!(Rand, 0, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
-    let num: u32 = rand::random();
-    Value::Number(num as f64)
+/// Small pseudo-random number generator (SplitMix64) owned by the VM, so that seeded programs give the same
+/// results on every host
+#[derive(Debug)]
+pub struct Prng {
+    state: u64,
+}
+
+impl Prng {
+    pub fn new(seed: u64) -> Prng {
+        Prng { state: seed }
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub(crate) type SharedPrng = Rc<RefCell<Prng>>;
+
+/// Like `callable_struct!`, for natives that use the random number generator of the VM
+macro_rules! prng_callable {
+    ($struct_name:ident, $arity:expr, $prng:ident, $interner:ident: &mut Interner, $globals:ident: &mut Globals, $args:ident: &[Value], $body:block) => {
+        #[derive(Debug)]
+        pub struct $struct_name {
+            pub prng: SharedPrng,
+        }
+
+        impl Callable for $struct_name {
+            fn arity(&self) -> usize {
+                $arity
+            }
+
+            fn call(&self, $interner: &mut Interner, $globals: &mut Globals, $args: &[Value]) -> Value {
+                let mut $prng = self.prng.borrow_mut();
+                $body
+            }
+
+            fn name(&self) -> &str {
+                stringify!($struct_name)
+            }
+        }
+    };
+}
+
+prng_callable!(Rand, 0, prng, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    Value::Int((prng.next_u64() >> 32) as i64)
+});
+
+prng_callable!(Random, 0, prng, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    Value::Number(prng.next_f64())
+});
+
+// Between `lo` and `hi`, both included
+prng_callable!(RandInt, 2, prng, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], &args[1]) {
+        (Value::Int(lo), Value::Int(hi)) if lo <= hi => {
+            let span = (*hi as i128 - *lo as i128 + 1) as u128;
+            Value::Int((*lo as i128 + (prng.next_u64() as u128 % span) as i128) as i64)
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected two integers, the first not larger than the second, as arguments to randint");
+            Value::Nil
+        }
+    }
+});
+
+prng_callable!(Seed, 1, prng, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Int(seed) => {
+            prng.seed(*seed as u64);
+            Value::Nil
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected integer as argument to seed");
+            Value::Nil
+        }
+    }
+});
+
+/// Collect the elements of a collection into a set. Returns None if an element can't be stored in a set.
+fn collect_set(value: &Value) -> Option<ValueSet> {
+    match value {
+        Value::Array(arr) => arr.borrow().iter().map(MapKey::from_value).collect(),
+        Value::Tuple(elements) => elements.iter().map(MapKey::from_value).collect(),
+        Value::Range(range) => Some(
+            (0..range.len())
+                .filter_map(|index| range.get(index))
+                .map(|n| MapKey::from_value(&Value::Number(n)).unwrap())
+                .collect(),
+        ),
+        Value::Set(set) => Some(set.borrow().clone()),
+        _ => None,
+    }
+}
+
+callable_struct!(Set, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match collect_set(&args[0]) {
+        Some(set) => Value::Set(Rc::new(RefCell::new(set))),
+        None => {
+            set_global_error(interner, globals, "Expected array, tuple, range or set of strings and numbers as argument to set");
+            Value::Nil
+        }
+    }
 });
+
+callable_struct!(SetLen, 1, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Set(set) => Value::Int(set.borrow().len() as i64),
+        _ => {
+            set_global_error(interner, globals, "Expected set as argument to setlen");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(Channel, 0, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    Value::Channel(gc::new_channel())
+});
+
+callable_struct!(Send, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match &args[0] {
+        Value::Channel(queue) => {
+            queue.borrow_mut().push_back(args[1].clone());
+            Value::Nil
+        }
+        _ => {
+            set_global_error(interner, globals, "Expected channel as first argument to send");
+            Value::Nil
+        }
+    }
+});
+
+/// `Spawn(function, ...args)` runs the function in a new task, taking turns with the others
+#[derive(Debug, Default)]
+pub struct Spawn;
+
+impl Callable for Spawn {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        set_global_error(interner, globals, "Spawn can only be called by the program");
+        Value::Nil
+    }
+
+    fn name(&self) -> &str {
+        "Spawn"
+    }
+
+    fn is_variadic(&self) -> bool {
+        true
+    }
+
+    fn task_operation(&self) -> Option<TaskOperation> {
+        Some(TaskOperation::Spawn)
+    }
+}
+
+/// `Receive(channel)` takes the oldest value sent to the channel, letting other tasks run until there is one
+#[derive(Debug, Default)]
+pub struct Receive;
+
+impl Callable for Receive {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        set_global_error(interner, globals, "Receive can only be called by the program");
+        Value::Nil
+    }
+
+    fn name(&self) -> &str {
+        "Receive"
+    }
+
+    fn task_operation(&self) -> Option<TaskOperation> {
+        Some(TaskOperation::Receive)
+    }
+}
+
+callable_struct!(SetAdd, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], MapKey::from_value(&args[1])) {
+        (Value::Set(set), Some(element)) => Value::Bool(set.borrow_mut().insert(element)),
+        _ => {
+            set_global_error(interner, globals, "Expected set and string or number as arguments to setadd");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(SetHas, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], MapKey::from_value(&args[1])) {
+        (Value::Set(set), Some(element)) => Value::Bool(set.borrow().contains(&element)),
+        (Value::Set(_), None) => Value::Bool(false),
+        _ => {
+            set_global_error(interner, globals, "Expected set as first argument to sethas");
+            Value::Nil
+        }
+    }
+});
+
+callable_struct!(SetRemove, 2, interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    match (&args[0], MapKey::from_value(&args[1])) {
+        (Value::Set(set), Some(element)) => Value::Bool(set.borrow_mut().remove(&element)),
+        (Value::Set(_), None) => Value::Bool(false),
+        _ => {
+            set_global_error(interner, globals, "Expected set as first argument to setremove");
+            Value::Nil
+        }
+    }
+});
+
+/// A native function with its first argument bound to a value, used for methods of built-in types like `set.add(1)`
+#[derive(Debug)]
+pub struct BoundNative {
+    pub receiver: Value,
+    pub native: Rc<dyn Callable>,
+}
+
+impl Callable for BoundNative {
+    fn arity(&self) -> usize {
+        self.native.arity() - 1
+    }
+
+    fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
+        let mut all_args = Vec::with_capacity(args.len() + 1);
+        all_args.push(self.receiver.clone());
+        all_args.extend_from_slice(args);
+        self.native.call(interner, globals, &all_args)
+    }
+
+    fn name(&self) -> &str {
+        self.native.name()
+    }
+
+    fn is_variadic(&self) -> bool {
+        self.native.is_variadic()
+    }
+
+    fn receiver(&self) -> Option<&Value> {
+        Some(&self.receiver)
+    }
+
+    fn call_or_throw(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Result<Value, String> {
+        let mut all_args = Vec::with_capacity(args.len() + 1);
+        all_args.push(self.receiver.clone());
+        all_args.extend_from_slice(args);
+        self.native.call_or_throw(interner, globals, &all_args)
+    }
+}
+
+/// Look up a method of a built-in type. The receiver is passed to the native as its first argument.
+pub fn builtin_method(receiver: &Value, name: &str) -> Option<Value> {
+    let native: Rc<dyn Callable> = match (receiver, name) {
+        (Value::Set(_), "add") => Rc::new(SetAdd),
+        (Value::Set(_), "has") => Rc::new(SetHas),
+        (Value::Set(_), "remove") => Rc::new(SetRemove),
+        (Value::Set(_), "length") => Rc::new(SetLen),
+        (Value::Array(_), "length") => Rc::new(ArrLen),
+        (Value::Array(_), "push") => Rc::new(ArrPush),
+        (Value::Array(_), "pop") => Rc::new(ArrPop),
+        (Value::Array(_), "insert") => Rc::new(ArrInsert),
+        (Value::Array(_), "remove") => Rc::new(ArrRemove),
+        (Value::Str(_), "format") => Rc::new(Format),
+        (Value::Str(_), "length") => Rc::new(StrLen),
+        (Value::Str(_), "upper") => Rc::new(StrUpper),
+        (Value::Str(_), "lower") => Rc::new(StrLower),
+        (Value::Str(_), "trim") => Rc::new(StrTrim),
+        (Value::Str(_), "split") => Rc::new(StrSplit),
+        (Value::Str(_), "replace") => Rc::new(StrReplace),
+        (Value::Str(_), "contains") => Rc::new(StrContains),
+        (Value::Str(_), "indexOf") => Rc::new(StrIndexOf),
+        (Value::Range(_), "length") => Rc::new(RangeLen),
+        (Value::Range(_), "contains") => Rc::new(RangeContains),
+        _ => return None,
+    };
+
+    Some(Value::NativeFunction(Rc::new(BoundNative {
+        receiver: receiver.clone(),
+        native,
+    })))
+}
@@ -0,0 +1,69 @@
+//! Options of a run, both how the program is compiled and how the VM runs it, so that a host can choose them when it
+//! runs a program, like the modes of the web playground, instead of with a build for each.
+
+use crate::{
+    compiler::CompilerOptions,
+    interner::Interner,
+    json,
+    lint::{Level, Lints},
+    register::Backend,
+    value::{MapKey, Value},
+    vm::VmOptions,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub compiler: CompilerOptions,
+    pub vm: VmOptions,
+}
+
+impl RunOptions {
+    /// Options from a JSON object, like `{"printCode":true,"optLevel":1,"maxInstructions":1000000}`. Fields that are
+    /// left out keep their default. They are:
+    /// - `optLevel`, and `registers` for the register backend, `lint` for all lints as warnings, and `warnRemoved` to
+    ///   warn about code removed by optimizations, which are how the program is compiled
+    /// - `printCode`, `traceExecution`, `coverage`, `profile`, `collectStats` and `inlineCaches`, which are on or off
+    /// - `maxInstructions`, `maxHeapBytes`, `maxCallDepth`, and `deterministic` with the seed, which are numbers
+    pub fn from_json(text: &str) -> Result<RunOptions, String> {
+        let mut interner = Interner::with_capacity(32);
+        let Value::Map(fields) = json::parse(text, &mut interner)? else {
+            return Err("Options are not a JSON object".to_string());
+        };
+
+        let mut options = RunOptions::default();
+        for (key, value) in fields.borrow().iter() {
+            let MapKey::Str(name) = key else {
+                unreachable!("Keys of JSON objects are strings");
+            };
+            let name = interner.lookup(name);
+            let flag = || match value {
+                Value::Bool(flag) => Ok(*flag),
+                _ => Err(format!("Option '{name}' is not true or false")),
+            };
+            let number = || match value.as_number() {
+                Some(number) if number >= 0.0 && number.fract() == 0.0 => Ok(number as u64),
+                _ => Err(format!("Option '{name}' is not a whole number that is 0 or more")),
+            };
+            let compiler = &mut options.compiler;
+            let vm = &mut options.vm;
+            match name {
+                "optLevel" => compiler.opt_level = number()?.min(u8::MAX as u64) as u8,
+                "registers" => compiler.backend = if flag()? { Backend::Register } else { Backend::Stack },
+                "lint" => compiler.lints = Lints::all(if flag()? { Level::Warn } else { Level::Allow }),
+                "warnRemoved" => compiler.warnings.removed_code = flag()?,
+                "printCode" => vm.print_code = flag()?,
+                "traceExecution" => vm.trace_execution = flag()?,
+                "coverage" => vm.coverage = flag()?,
+                "profile" => vm.profile = flag()?,
+                "collectStats" => vm.collect_stats = flag()?,
+                "inlineCaches" => vm.inline_caches = flag()?,
+                "maxInstructions" => vm.max_instructions = Some(number()?),
+                "maxHeapBytes" => vm.max_heap_bytes = Some(number()? as usize),
+                "maxCallDepth" => vm.max_call_depth = number()? as usize,
+                "deterministic" => vm.deterministic = Some(number()?),
+                _ => return Err(format!("Unknown option '{name}'")),
+            }
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,148 @@
+//! Peephole optimizations, which replace short sequences of instructions with shorter ones that do the same. They run
+//! when a function has been compiled, with `CompilerOptions::opt_level` 1 or more:
+//! - a constant that is pushed and popped right away is removed, and so is a local variable that is read and popped
+//! - two `Not` after an instruction that pushes a bool are removed, like the ones after the `Less` of `!(a >= b)`
+//! - jumps to an unconditional jump go where it goes, and jumps to the next instruction are removed
+//!
+//! A sequence is only removed if nothing jumps into it after its first instruction, since jumping to the start of code
+//! that does nothing is the same as jumping after it. The code is then laid out again with `long_jump::encode`.
+
+use crate::{
+    common::Opcode,
+    fun::Fun,
+    long_jump::{decode, encode, Instruction},
+    value::Value,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Optimize the code of the function. Returns false if a jump is too far even for a long jump.
+pub(crate) fn optimize(fun: &mut Fun) -> bool {
+    let mut instructions = decode(&fun.chunk, &HashMap::new());
+    // Threaded first, since jumps to the next instruction are removed, and then nothing jumps to it
+    thread_jumps(&mut instructions, fun.chunk.code.len());
+    let targets: HashSet<usize> = instructions
+        .iter()
+        .filter(|instruction| !instruction.removed)
+        .flat_map(|instruction| instruction.targets.iter().flatten().copied())
+        .collect();
+
+    remove_pops(fun, &mut instructions, &targets);
+    remove_double_nots(&mut instructions, &targets);
+    encode(fun, &mut instructions)
+}
+
+/// Index of the instruction before `index` that is not removed
+fn previous(instructions: &[Instruction], index: usize) -> Option<usize> {
+    (0..index).rev().find(|&previous| !instructions[previous].removed)
+}
+
+/// Whether nothing jumps to the instructions after `first` up to and including `last`, removed ones included
+fn is_straight(instructions: &[Instruction], targets: &HashSet<usize>, first: usize, last: usize) -> bool {
+    instructions[first + 1..=last]
+        .iter()
+        .all(|instruction| !targets.contains(&instruction.start))
+}
+
+fn remove(instructions: &mut [Instruction], first: usize, last: usize) {
+    for instruction in &mut instructions[first..=last] {
+        instruction.removed = true;
+    }
+}
+
+/// Remove constants and local variables that are popped right after they are pushed
+fn remove_pops(fun: &Fun, instructions: &mut [Instruction], targets: &HashSet<usize>) {
+    let pushes_nil = |instruction: &Instruction| match instruction.opcode {
+        Opcode::Nil => true,
+        Opcode::Constant | Opcode::ConstantLong => {
+            let index = fun.chunk.constant_index(instruction.start + 1, instruction.opcode.is_long());
+            fun.chunk.constants[index] == Value::Nil
+        }
+        _ => false,
+    };
+
+    for index in 0..instructions.len() {
+        if instructions[index].opcode != Opcode::Pop {
+            continue;
+        }
+        let Some(push) = previous(instructions, index) else {
+            continue;
+        };
+        let first = match instructions[push].opcode {
+            Opcode::Constant | Opcode::ConstantLong | Opcode::Nil | Opcode::True | Opcode::False => Some(push),
+            // Reading a local variable pops the index before it, which is nil if it isn't an element
+            Opcode::GetLocal => previous(instructions, push).filter(|&index| pushes_nil(&instructions[index])),
+            _ => None,
+        };
+        if let Some(first) = first.filter(|&first| is_straight(instructions, targets, first, index)) {
+            remove(instructions, first, index);
+        }
+    }
+}
+
+/// Remove two `Not` in a row after an instruction that pushes a bool, which they give back
+fn remove_double_nots(instructions: &mut [Instruction], targets: &HashSet<usize>) {
+    for index in 0..instructions.len() {
+        if instructions[index].removed || instructions[index].opcode != Opcode::Not {
+            continue;
+        }
+        let Some(first) = previous(instructions, index).filter(|&first| instructions[first].opcode == Opcode::Not) else {
+            continue;
+        };
+        let Some(push) = previous(instructions, first) else {
+            continue;
+        };
+        let pushes_bool = matches!(
+            instructions[push].opcode,
+            Opcode::Equal | Opcode::Less | Opcode::Greater | Opcode::Not | Opcode::In | Opcode::True | Opcode::False
+        );
+        if pushes_bool && is_straight(instructions, targets, push, index) {
+            remove(instructions, first, index);
+        }
+    }
+}
+
+/// Make jumps to an unconditional jump go where it goes, and remove jumps to the next instruction
+fn thread_jumps(instructions: &mut [Instruction], code_len: usize) {
+    let index_of: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.start, index))
+        .collect();
+    // The instruction that runs when a jump goes to the offset, which is the next one if the one there is removed
+    let landing = |instructions: &[Instruction], target: usize| {
+        let index = if target == code_len {
+            instructions.len()
+        } else {
+            index_of[&target]
+        };
+        (index..instructions.len()).find(|&index| !instructions[index].removed)
+    };
+    let is_jump = |opcode: Opcode| {
+        matches!(
+            opcode.short(),
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfNil | Opcode::JumpIfNotNil
+        )
+    };
+
+    for index in 0..instructions.len() {
+        if instructions[index].removed || !is_jump(instructions[index].opcode) {
+            continue;
+        }
+        let Some(mut target) = instructions[index].targets[0] else {
+            continue;
+        };
+        // Only forward jumps are followed, so chains end
+        while let Some(next) = landing(instructions, target).map(|landing| &instructions[landing]) {
+            match next.targets.first() {
+                Some(&Some(next_target)) if next.opcode.short() == Opcode::Jump && next_target > next.start => target = next_target,
+                _ => break,
+            }
+        }
+        instructions[index].targets[0] = Some(target);
+
+        let next = (index + 1..instructions.len()).find(|&next| !instructions[next].removed);
+        if landing(instructions, target) == next {
+            instructions[index].removed = true;
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{fmt, rc::Rc, time::Duration};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    clock::{host_clock, HostClock},
+    module::MAIN_MODULE_PATH,
+};
+
+/// Instructions between samples of the running functions
+pub(crate) const PROFILE_SAMPLE_INTERVAL: u64 = 256;
+
+/// A stack of calls, in the tree of the stacks that were sampled
+#[derive(Debug)]
+struct Node {
+    fun_idx: usize,
+    parent: usize,
+    time: Duration, // With this stack running
+}
+
+/// Samples the stack of calls while a program runs, for `VmOptions::profile`.
+/// The time since the last sample goes to the calls that are running, so time spent in natives goes to their caller.
+pub(crate) struct Profiler {
+    clock: Rc<dyn HostClock>,
+    last_sample: f64,
+    calls: FxHashMap<usize, u64>,
+    nodes: Vec<Node>, // The first is the root, which has no function
+    children: FxHashMap<(usize, usize), usize>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        let clock = host_clock();
+        Profiler {
+            last_sample: clock.monotonic_seconds(),
+            clock,
+            calls: FxHashMap::default(),
+            nodes: vec![Node {
+                fun_idx: usize::MAX,
+                parent: 0,
+                time: Duration::ZERO,
+            }],
+            children: FxHashMap::default(),
+        }
+    }
+
+    pub fn count_call(&mut self, fun_idx: usize) {
+        *self.calls.entry(fun_idx).or_default() += 1;
+    }
+
+    /// Give the time since the last sample to the stack of calls, outermost first
+    pub fn sample(&mut self, stack: impl Iterator<Item = usize>) {
+        let mut node = 0;
+        for fun_idx in stack {
+            node = match self.children.get(&(node, fun_idx)) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(Node {
+                        fun_idx,
+                        parent: node,
+                        time: Duration::ZERO,
+                    });
+                    self.children.insert((node, fun_idx), self.nodes.len() - 1);
+                    self.nodes.len() - 1
+                }
+            };
+        }
+
+        let now = self.clock.monotonic_seconds();
+        self.nodes[node].time += Duration::from_secs_f64((now - self.last_sample).max(0.0));
+        self.last_sample = now;
+    }
+
+    /// Report with the names that `describe` gives to functions, as their name and module
+    pub fn report(&self, describe: impl Fn(usize) -> (String, String)) -> ProfileReport {
+        let mut functions: FxHashMap<usize, FunctionProfile> = FxHashMap::default();
+        for &fun_idx in self.calls.keys().chain(self.nodes.iter().skip(1).map(|node| &node.fun_idx)) {
+            functions.entry(fun_idx).or_insert_with(|| {
+                let (function, module) = describe(fun_idx);
+                FunctionProfile {
+                    function,
+                    module,
+                    calls: self.calls.get(&fun_idx).copied().unwrap_or(0),
+                    self_time: Duration::ZERO,
+                    total_time: Duration::ZERO,
+                }
+            });
+        }
+
+        let mut stacks = Vec::new();
+        for (idx, node) in self.nodes.iter().enumerate().skip(1).filter(|(_, node)| !node.time.is_zero()) {
+            functions.get_mut(&node.fun_idx).unwrap().self_time += node.time;
+
+            let mut stack = Vec::new();
+            let mut current = idx;
+            while current != 0 {
+                stack.push(self.nodes[current].fun_idx);
+                current = self.nodes[current].parent;
+            }
+            stack.reverse();
+
+            // Recursive functions are in the stack more than once, but the time only counts once
+            let mut seen = Vec::new();
+            for &fun_idx in &stack {
+                if !seen.contains(&fun_idx) {
+                    seen.push(fun_idx);
+                    functions.get_mut(&fun_idx).unwrap().total_time += node.time;
+                }
+            }
+            let names = stack.iter().map(|fun_idx| frame_name(&functions[fun_idx])).collect();
+            stacks.push((names, node.time));
+        }
+
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        functions.sort_by(|a, b| b.self_time.cmp(&a.self_time).then(b.calls.cmp(&a.calls)));
+        ProfileReport { functions, stacks }
+    }
+}
+
+/// Name of a function in the stacks, with the module if it is not in the main one
+fn frame_name(profile: &FunctionProfile) -> String {
+    match profile.module.as_str() {
+        MAIN_MODULE_PATH => profile.function.clone(),
+        module => format!("{module}:{}", profile.function),
+    }
+}
+
+/// Calls and time of a function in a `ProfileReport`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    pub function: String, // `<script>` for the top level of a module
+    pub module: String,
+    pub calls: u64,
+    pub self_time: Duration,  // Running its own instructions, and the natives it called
+    pub total_time: Duration, // Including the functions it called
+}
+
+/// Where a program spent its time, returned by `Vm::profile_report` after a run with `VmOptions::profile`.
+/// Times come from samples, so functions that run for less than a sample interval might get none.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProfileReport {
+    pub functions: Vec<FunctionProfile>,      // Most self time first
+    pub stacks: Vec<(Vec<String>, Duration)>, // Time with each stack of calls running, outermost call first
+}
+
+impl ProfileReport {
+    /// Stacks in the collapsed format that flamegraph tools read: the calls separated by semicolons, and the microseconds
+    pub fn collapsed_stacks(&self) -> String {
+        self.stacks
+            .iter()
+            .map(|(stack, time)| format!("{} {}\n", stack.join(";"), time.as_micros()))
+            .collect()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>10} {:>10} {:>10}  function", "calls", "self ms", "total ms")?;
+        for profile in &self.functions {
+            write!(
+                f,
+                "\n{:>10} {:>10.3} {:>10.3}  {}",
+                profile.calls,
+                profile.self_time.as_secs_f64() * 1000.0,
+                profile.total_time.as_secs_f64() * 1000.0,
+                frame_name(profile)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,519 @@
+//! A small regular expression engine, so the regex natives work the same natively and on the web.
+//!
+//! Supports literals, `.`, classes like `[a-z]` and `[^0-9]`, the escapes `\d \w \s \D \W \S \b \B`, anchors `^` and `$`,
+//! capturing `(...)` and non-capturing `(?:...)` groups, `|`, and the greedy and lazy quantifiers `* + ? {n} {n,} {n,m}`.
+//! Patterns are compiled to a program that is run by backtracking. Each (instruction, position) pair is explored at
+//! most once, which keeps matching linear in the length of the text.
+
+/// Larger counts in `{n,m}` would make the program too big
+const MAX_REPEAT: usize = 1000;
+
+#[derive(Debug, Clone)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn of(ranges: &[(char, char)], negated: bool) -> Class {
+        Class {
+            ranges: ranges.to_vec(),
+            negated,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != self.negated
+    }
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    WordBoundary(bool),
+    Group(Box<Node>, Option<usize>), // Index of the capture, if it captures
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+#[derive(Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Split(usize, usize), // Try the first, then the second
+    Jump(usize),
+    Save(usize),
+    Start,
+    End,
+    WordBoundary(bool),
+    Match,
+}
+
+/// A compiled regular expression
+#[derive(Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+    groups: usize, // Including the whole match as group 0
+}
+
+/// Span of each group of a match in chars, or None for groups that did not take part
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+struct Parser {
+    chars: Vec<char>,
+    current: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid regex at position {}: {message}", self.current)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.current).copied()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.eat('|') {
+            branches.push(self.concatenation()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.current;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.current += 1;
+        }
+        self.chars[start..self.current].iter().collect::<String>().parse().ok()
+    }
+
+    fn quantifier(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let start = self.current;
+                self.current += 1;
+                let Some(min) = self.number() else {
+                    // Not a repetition, so the brace is a literal
+                    self.current = start;
+                    return Ok(node);
+                };
+                let max = if self.eat(',') { self.number() } else { Some(min) };
+                if self.peek() != Some('}') {
+                    return Err(self.error("Expected '}' after repetition"));
+                }
+                if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                    return Err(self.error("Invalid repetition count"));
+                }
+                (min, max)
+            }
+            _ => return Ok(node),
+        };
+        self.current += 1;
+
+        if matches!(node, Node::Start | Node::End | Node::WordBoundary(_)) {
+            return Err(self.error("Nothing to repeat"));
+        }
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.current += 1;
+        match c {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '(' => {
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err(self.error("Expected ':' after '(?'"));
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("Expected ')'"));
+                }
+                Ok(Node::Group(Box::new(inner), index))
+            }
+            '[' => self.class(),
+            '\\' => self.escape(false),
+            '*' | '+' | '?' => Err(self.error("Nothing to repeat")),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    /// The part after a backslash. In classes, `\b` is a backspace rather than a word boundary.
+    fn escape(&mut self, in_class: bool) -> Result<Node, String> {
+        let Some(c) = self.peek() else {
+            return Err(self.error("Pattern ends with a backslash"));
+        };
+        self.current += 1;
+        Ok(match c {
+            'd' => Node::Class(Class::of(DIGIT, false)),
+            'D' => Node::Class(Class::of(DIGIT, true)),
+            'w' => Node::Class(Class::of(WORD, false)),
+            'W' => Node::Class(Class::of(WORD, true)),
+            's' => Node::Class(Class::of(SPACE, false)),
+            'S' => Node::Class(Class::of(SPACE, true)),
+            'b' if in_class => Node::Char('\u{8}'),
+            'b' => Node::WordBoundary(true),
+            'B' if !in_class => Node::WordBoundary(false),
+            'n' => Node::Char('\n'),
+            'r' => Node::Char('\r'),
+            't' => Node::Char('\t'),
+            c if c.is_ascii_alphanumeric() => return Err(self.error(&format!("Unknown escape '\\{c}'"))),
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("Unterminated character class"));
+            };
+            self.current += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            let low = match c {
+                '\\' => match self.escape(true)? {
+                    Node::Char(c) => c,
+                    Node::Class(class) if !class.negated => {
+                        ranges.extend(class.ranges);
+                        continue;
+                    }
+                    _ => return Err(self.error("Negated escapes are not supported in classes")),
+                },
+                c => c,
+            };
+
+            // A range like `a-z`, unless the '-' is last
+            if self.peek() == Some('-') && self.chars.get(self.current + 1).is_some_and(|c| *c != ']') {
+                self.current += 1;
+                let high = match self.peek() {
+                    Some('\\') => {
+                        self.current += 1;
+                        match self.escape(true)? {
+                            Node::Char(c) => c,
+                            _ => return Err(self.error("Invalid end of range")),
+                        }
+                    }
+                    Some(c) => {
+                        self.current += 1;
+                        c
+                    }
+                    None => return Err(self.error("Unterminated character class")),
+                };
+                if high < low {
+                    return Err(self.error("Range out of order in character class"));
+                }
+                ranges.push((low, high));
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Node::Class(Class { ranges, negated }))
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Empty => {}
+        Node::Char(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::WordBoundary(expected) => program.push(Inst::WordBoundary(*expected)),
+        Node::Group(inner, None) => compile(inner, program),
+        Node::Group(inner, Some(index)) => {
+            program.push(Inst::Save(index * 2));
+            compile(inner, program);
+            program.push(Inst::Save(index * 2 + 1));
+        }
+        Node::Concat(nodes) => nodes.iter().for_each(|node| compile(node, program)),
+        Node::Alternate(branches) => {
+            // split L1, next; L1: branch; jump end; next: split L2, ...
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 < branches.len() {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(branch, program);
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    let next = program.len();
+                    program[split] = Inst::Split(split + 1, next);
+                } else {
+                    compile(branch, program);
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat { node, min, max, greedy } => {
+            let split = |this: usize, other: usize| {
+                if *greedy {
+                    Inst::Split(this, other)
+                } else {
+                    Inst::Split(other, this)
+                }
+            };
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                None => {
+                    // loop: split body, end; body; jump loop
+                    let start = program.len();
+                    program.push(Inst::Match);
+                    compile(node, program);
+                    program.push(Inst::Jump(start));
+                    program[start] = split(start + 1, program.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Match);
+                        compile(node, program);
+                    }
+                    let end = program.len();
+                    for at in splits {
+                        program[at] = split(at + 1, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Job {
+    Explore(usize, usize),
+    Restore(usize, Option<usize>),
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            current: 0,
+            groups: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.current < parser.chars.len() {
+            return Err(parser.error("Unmatched ')'"));
+        }
+
+        let mut program = vec![Inst::Save(0)];
+        compile(&node, &mut program);
+        program.push(Inst::Save(1));
+        program.push(Inst::Match);
+        Ok(Regex {
+            program,
+            groups: parser.groups + 1,
+        })
+    }
+
+    /// The leftmost match that starts at or after `start`, with the span of each group
+    pub fn captures_at(&self, text: &[char], start: usize) -> Option<Captures> {
+        let mut visited = vec![false; self.program.len() * (text.len() + 1)];
+        let mut slots = vec![None; self.groups * 2];
+
+        // States that failed for an earlier start fail for later ones too, so `visited` is shared
+        for position in start..=text.len() {
+            if self.backtrack(text, position, &mut visited, &mut slots) {
+                let captures = (0..self.groups)
+                    .map(|group| match (slots[group * 2], slots[group * 2 + 1]) {
+                        (Some(start), Some(end)) => Some((start, end)),
+                        _ => None,
+                    })
+                    .collect();
+                return Some(captures);
+            }
+        }
+        None
+    }
+
+    /// Whether the text contains a match
+    pub fn is_match(&self, text: &[char]) -> bool {
+        self.captures_at(text, 0).is_some()
+    }
+
+    /// All matches that do not overlap, from left to right
+    pub fn captures_all(&self, text: &[char]) -> Vec<Captures> {
+        let mut matches = Vec::new();
+        let mut position = 0;
+        while position <= text.len() {
+            let Some(captures) = self.captures_at(text, position) else {
+                break;
+            };
+            let (start, end) = captures[0].unwrap();
+            // Step past empty matches so the search moves forward
+            position = if end == start { end + 1 } else { end };
+            matches.push(captures);
+        }
+        matches
+    }
+
+    fn backtrack(&self, text: &[char], start: usize, visited: &mut [bool], slots: &mut [Option<usize>]) -> bool {
+        let is_word = |position: usize| text.get(position).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_');
+        let mut stack = vec![Job::Explore(0, start)];
+
+        while let Some(job) = stack.pop() {
+            let (mut pc, mut position) = match job {
+                Job::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+                Job::Explore(pc, position) => (pc, position),
+            };
+
+            loop {
+                let state = pc * (text.len() + 1) + position;
+                if visited[state] {
+                    break;
+                }
+                visited[state] = true;
+
+                match &self.program[pc] {
+                    Inst::Char(c) if text.get(position) == Some(c) => position += 1,
+                    Inst::Any if text.get(position).is_some_and(|c| *c != '\n') => position += 1,
+                    Inst::Class(class) if text.get(position).is_some_and(|c| class.matches(*c)) => position += 1,
+                    Inst::Char(_) | Inst::Any | Inst::Class(_) => break,
+                    Inst::Split(first, second) => {
+                        stack.push(Job::Explore(*second, position));
+                        pc = *first;
+                        continue;
+                    }
+                    Inst::Jump(target) => {
+                        pc = *target;
+                        continue;
+                    }
+                    Inst::Save(slot) => {
+                        stack.push(Job::Restore(*slot, slots[*slot]));
+                        slots[*slot] = Some(position);
+                    }
+                    Inst::Start if position == 0 => {}
+                    Inst::End if position == text.len() => {}
+                    Inst::Start | Inst::End => break,
+                    Inst::WordBoundary(expected) => {
+                        let at_boundary = position > 0 && is_word(position - 1) != is_word(position) || position == 0 && is_word(0);
+                        if at_boundary != *expected {
+                            break;
+                        }
+                    }
+                    Inst::Match => return true,
+                }
+                pc += 1;
+            }
+        }
+        false
+    }
+}
+
+/// Replace every match in the text. In the replacement, `$0` is the whole match, `$1` to `$9` are groups, and `$$` is
+/// a dollar sign.
+pub fn replace_all(regex: &Regex, text: &[char], replacement: &str) -> String {
+    let replacement: Vec<char> = replacement.chars().collect();
+    let mut result = String::new();
+    let mut last = 0;
+
+    for captures in regex.captures_all(text) {
+        let (start, end) = captures[0].unwrap();
+        result.extend(&text[last..start]);
+
+        let mut i = 0;
+        while i < replacement.len() {
+            match (replacement[i], replacement.get(i + 1)) {
+                ('$', Some('$')) => {
+                    result.push('$');
+                    i += 2;
+                }
+                ('$', Some(digit)) if digit.is_ascii_digit() => {
+                    let group = digit.to_digit(10).unwrap() as usize;
+                    if let Some(Some((group_start, group_end))) = captures.get(group) {
+                        result.extend(&text[*group_start..*group_end]);
+                    }
+                    i += 2;
+                }
+                (c, _) => {
+                    result.push(c);
+                    i += 1;
+                }
+            }
+        }
+        last = end;
+    }
+
+    result.extend(&text[last..]);
+    result
+}
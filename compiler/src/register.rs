@@ -0,0 +1,522 @@
+//! Register backend, selected with `CompilerOptions::backend`.
+//!
+//! On the stack backend every value goes through the stack, so `a + b` pushes copies of both local variables and pops
+//! them again. Functions that only compute with their parameters and local variables can also be lowered to
+//! instructions that name the registers they read and write. Each position of the stack starts out as a register, so
+//! reading a local variable becomes a move, and then
+//! 1. copy propagation makes the instructions read the local variable instead of its copy,
+//! 2. loads and moves into registers that are not read afterwards are removed, and
+//! 3. registers that are never live at the same time share one, by coloring the graph of the ones that are.
+//!
+//! The VM runs a register function in the slots of its frame, with the parameters in the first registers. Functions
+//! that do anything else, like reading globals or calling functions, only run on the stack backend.
+
+use crate::{
+    chunk::Chunk,
+    common::Opcode,
+    fun::{Fun, FunType},
+    interner::Interner,
+    value::{value_as_string, Value},
+    xprintln,
+};
+
+/// How the functions of a program run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Stack,
+    Register, // Functions that can be lowered to register instructions run as those
+}
+
+pub type Register = usize;
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Load {
+        dst: Register,
+        value: Value,
+    },
+    Move {
+        dst: Register,
+        src: Register,
+    },
+    Unary {
+        op: Opcode,
+        dst: Register,
+        src: Register,
+    }, // `Not` or `Negate`
+    Binary {
+        op: Opcode,
+        dst: Register,
+        a: Register,
+        b: Register,
+    }, // Arithmetic, comparison or `Equal`
+    Jump {
+        target: usize,
+    }, // Index of the instruction to continue at
+    JumpIfFalse {
+        src: Register,
+        target: usize,
+    },
+    Return {
+        src: Register,
+    },
+}
+
+impl Instruction {
+    /// Register the instruction writes
+    fn def(&self) -> Option<Register> {
+        match *self {
+            Instruction::Load { dst, .. }
+            | Instruction::Move { dst, .. }
+            | Instruction::Unary { dst, .. }
+            | Instruction::Binary { dst, .. } => Some(dst),
+            _ => None,
+        }
+    }
+
+    /// Registers the instruction reads
+    fn uses(&self) -> impl Iterator<Item = Register> {
+        let uses = match *self {
+            Instruction::Move { src, .. }
+            | Instruction::Unary { src, .. }
+            | Instruction::JumpIfFalse { src, .. }
+            | Instruction::Return { src } => [Some(src), None],
+            Instruction::Binary { a, b, .. } => [Some(a), Some(b)],
+            _ => [None, None],
+        };
+        uses.into_iter().flatten()
+    }
+
+    fn map_uses(&mut self, f: impl Fn(Register) -> Register) {
+        match self {
+            Instruction::Move { src, .. }
+            | Instruction::Unary { src, .. }
+            | Instruction::JumpIfFalse { src, .. }
+            | Instruction::Return { src } => *src = f(*src),
+            Instruction::Binary { a, b, .. } => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            _ => {}
+        }
+    }
+
+    fn map_def(&mut self, f: impl Fn(Register) -> Register) {
+        match self {
+            Instruction::Load { dst, .. }
+            | Instruction::Move { dst, .. }
+            | Instruction::Unary { dst, .. }
+            | Instruction::Binary { dst, .. } => *dst = f(*dst),
+            _ => {}
+        }
+    }
+
+    /// Indices of the instructions that can run after this one, at `index`
+    fn successors(&self, index: usize) -> [Option<usize>; 2] {
+        match *self {
+            Instruction::Jump { target } => [Some(target), None],
+            Instruction::JumpIfFalse { target, .. } => [Some(index + 1), Some(target)],
+            Instruction::Return { .. } => [None, None],
+            _ => [Some(index + 1), None],
+        }
+    }
+}
+
+/// A function lowered to register instructions
+#[derive(Debug)]
+pub struct RegisterFunction {
+    pub code: Vec<Instruction>,
+    pub offsets: Vec<usize>, // Offset of the bytecode instruction each instruction was lowered from, for tracebacks
+    pub registers: usize,    // Including the parameters, which are the first ones
+}
+
+impl RegisterFunction {
+    pub fn disassemble(&self, name: &str, chunk: &Chunk, interner: &Interner) {
+        xprintln!("== {name} ({} registers) ==", self.registers);
+        for (index, instruction) in self.code.iter().enumerate() {
+            let line = chunk.line_at(self.offsets[index]);
+            let text = match instruction {
+                Instruction::Load { dst, value } => format!("r{dst} = {}", value_as_string(value, interner)),
+                Instruction::Move { dst, src } => format!("r{dst} = r{src}"),
+                Instruction::Unary { op, dst, src } => format!("r{dst} = {op} r{src}"),
+                Instruction::Binary { op, dst, a, b } => format!("r{dst} = r{a} {op} r{b}"),
+                Instruction::Jump { target } => format!("Jump -> {target:04}"),
+                Instruction::JumpIfFalse { src, target } => format!("JumpIfFalse r{src} -> {target:04}"),
+                Instruction::Return { src } => format!("Return r{src}"),
+            };
+            xprintln!("{index:04} {line:4} {text}");
+        }
+    }
+}
+
+/// Lower a function to register instructions, if it only uses what they support.
+/// Has to run before the superinstructions are fused.
+pub(crate) fn lower(fun: &Fun, typ: &FunType) -> Option<RegisterFunction> {
+    if fun.is_generator || !matches!(typ, FunType::Function | FunType::Method | FunType::StaticMethod) {
+        return None;
+    }
+    let params = fun.arity + fun.is_method as usize;
+    let stacks = stacks(&fun.chunk, params)?;
+    let (mut code, mut offsets) = translate(&fun.chunk, &stacks);
+
+    propagate_copies(&mut code);
+    let count = register_count(&code, params);
+    remove_dead_code(&mut code, &mut offsets, count);
+    let registers = allocate_registers(&mut code, params)?;
+    // Moves between registers that now are the same one
+    remove_dead_code(&mut code, &mut offsets, registers);
+
+    Some(RegisterFunction { code, offsets, registers })
+}
+
+fn is_binary(instruction: Opcode) -> bool {
+    matches!(
+        instruction,
+        Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Divide
+            | Opcode::Modulo
+            | Opcode::Equal
+            | Opcode::Greater
+            | Opcode::Less
+    )
+}
+
+/// Offsets that can run after the instruction at `offset`
+fn next_offsets(code: &[u8], offset: usize, instruction: Opcode) -> [Option<usize>; 2] {
+    let next = offset + instruction.size();
+    let jump = || (code[offset + 1] as usize) << 8 | code[offset + 2] as usize;
+    match instruction {
+        Opcode::Jump => [Some(next + jump()), None],
+        Opcode::Loop => [next.checked_sub(jump()), None],
+        Opcode::JumpIfFalse => [Some(next), Some(next + jump())],
+        Opcode::Return => [None, None],
+        _ => [Some(next), None],
+    }
+}
+
+/// The stack after an instruction, or None if it is not supported. Each position is whether it holds nil, which
+/// reading or assigning a local variable pushes first when it is not indexed.
+fn stack_after(chunk: &Chunk, offset: usize, instruction: Opcode, stack: &[bool]) -> Option<Vec<bool>> {
+    let mut stack = stack.to_vec();
+    let operand = || chunk.code[offset + 1] as usize;
+    match instruction {
+        Opcode::Constant | Opcode::ConstantLong => {
+            let index = chunk.constant_index(offset + 1, instruction.is_long());
+            stack.push(chunk.constants[index] == Value::Nil);
+        }
+        Opcode::Nil => stack.push(true),
+        Opcode::True | Opcode::False => stack.push(false),
+        Opcode::GetLocal => {
+            if !stack.pop()? || operand() >= stack.len() {
+                return None;
+            }
+            stack.push(false);
+        }
+        Opcode::SetLocal => {
+            stack.pop()?;
+            if !stack.pop()? || operand() >= stack.len() {
+                return None;
+            }
+            stack.push(false);
+        }
+        Opcode::Pop | Opcode::Return => {
+            stack.pop()?;
+        }
+        Opcode::Not | Opcode::Negate => *stack.last_mut()? = false,
+        Opcode::JumpIfFalse => {
+            stack.last()?;
+        }
+        Opcode::Jump | Opcode::Loop => {}
+        instruction if is_binary(instruction) => {
+            stack.pop()?;
+            *stack.last_mut()? = false;
+        }
+        _ => return None,
+    }
+    Some(stack)
+}
+
+/// The stack before each instruction that can run, by offset. None if an instruction is not supported, or if the
+/// stack doesn't have the same height on every path to an instruction.
+fn stacks(chunk: &Chunk, params: usize) -> Option<Vec<Option<Vec<bool>>>> {
+    let code = &chunk.code;
+    let mut stacks: Vec<Option<Vec<bool>>> = vec![None; code.len()];
+    let mut work = vec![(0, vec![false; params])];
+
+    while let Some((offset, stack)) = work.pop() {
+        let stack = match stacks.get(offset)? {
+            None => stack,
+            Some(known) if known.len() != stack.len() => return None,
+            Some(known) => {
+                let merged: Vec<bool> = known.iter().zip(&stack).map(|(a, b)| *a && *b).collect();
+                if merged == *known {
+                    continue;
+                }
+                merged
+            }
+        };
+        let instruction = Opcode::try_from(code[offset]).ok()?;
+        let after = stack_after(chunk, offset, instruction, &stack)?;
+        stacks[offset] = Some(stack);
+        for next in next_offsets(code, offset, instruction).into_iter().flatten() {
+            work.push((next, after.clone()));
+        }
+    }
+    Some(stacks)
+}
+
+/// Lower the instructions that can run, with a register for each stack position.
+/// Returns them with the offset each one was lowered from.
+fn translate(chunk: &Chunk, stacks: &[Option<Vec<bool>>]) -> (Vec<Instruction>, Vec<usize>) {
+    let code = &chunk.code;
+    let mut instructions = Vec::new();
+    let mut offsets = Vec::new();
+    let mut indices = vec![0; code.len()]; // Index of the first instruction lowered from each offset
+
+    for (offset, stack) in stacks.iter().enumerate() {
+        let Some(stack) = stack else {
+            continue;
+        };
+        indices[offset] = instructions.len();
+
+        let top = stack.len(); // The register a push writes
+        let instruction = Opcode::try_from(code[offset]).expect("Only supported instructions have a stack");
+        let operand = || code[offset + 1] as usize;
+        let [next, target] = next_offsets(code, offset, instruction);
+        let lowered = match instruction {
+            Opcode::Constant | Opcode::ConstantLong => vec![Instruction::Load {
+                dst: top,
+                value: chunk.constants[chunk.constant_index(offset + 1, instruction.is_long())].clone(),
+            }],
+            Opcode::Nil => vec![Instruction::Load {
+                dst: top,
+                value: Value::Nil,
+            }],
+            Opcode::True | Opcode::False => vec![Instruction::Load {
+                dst: top,
+                value: Value::Bool(instruction == Opcode::True),
+            }],
+            Opcode::GetLocal => vec![Instruction::Move {
+                dst: top - 1,
+                src: operand(),
+            }],
+            // The assigned value stays on the stack, in place of the nil before it
+            Opcode::SetLocal => vec![
+                Instruction::Move {
+                    dst: operand(),
+                    src: top - 1,
+                },
+                Instruction::Move {
+                    dst: top - 2,
+                    src: top - 1,
+                },
+            ],
+            Opcode::Pop => vec![],
+            Opcode::Not | Opcode::Negate => vec![Instruction::Unary {
+                op: instruction,
+                dst: top - 1,
+                src: top - 1,
+            }],
+            Opcode::Jump | Opcode::Loop => vec![Instruction::Jump { target: next.unwrap() }],
+            Opcode::JumpIfFalse => vec![Instruction::JumpIfFalse {
+                src: top - 1,
+                target: target.unwrap(),
+            }],
+            Opcode::Return => vec![Instruction::Return { src: top - 1 }],
+            _ => vec![Instruction::Binary {
+                op: instruction,
+                dst: top - 2,
+                a: top - 2,
+                b: top - 1,
+            }],
+        };
+        offsets.extend(std::iter::repeat_n(offset, lowered.len()));
+        instructions.extend(lowered);
+    }
+
+    // Jumps were lowered with the offset they jump to
+    for instruction in &mut instructions {
+        if let Instruction::Jump { target } | Instruction::JumpIfFalse { target, .. } = instruction {
+            *target = indices[*target];
+        }
+    }
+    (instructions, offsets)
+}
+
+/// Whether each instruction starts a basic block, which only runs from its start to its end
+fn block_starts(code: &[Instruction]) -> Vec<bool> {
+    let mut starts = vec![false; code.len() + 1];
+    starts[0] = true;
+    for (index, instruction) in code.iter().enumerate() {
+        match *instruction {
+            Instruction::Jump { target } | Instruction::JumpIfFalse { target, .. } => {
+                starts[target] = true;
+                starts[index + 1] = true;
+            }
+            Instruction::Return { .. } => starts[index + 1] = true,
+            _ => {}
+        }
+    }
+    starts
+}
+
+/// Make instructions read the register a value was moved from, rather than the one it was moved to, as long as
+/// neither was written since. Only within basic blocks.
+fn propagate_copies(code: &mut [Instruction]) {
+    let starts = block_starts(code);
+    let mut copies: Vec<(Register, Register)> = Vec::new(); // Copy, and the register it is a copy of
+
+    for (index, instruction) in code.iter_mut().enumerate() {
+        if starts[index] {
+            copies.clear();
+        }
+        instruction.map_uses(|register| {
+            copies
+                .iter()
+                .find(|(copy, _)| *copy == register)
+                .map_or(register, |(_, original)| *original)
+        });
+        if let Some(def) = instruction.def() {
+            copies.retain(|&(copy, original)| copy != def && original != def);
+            if let Instruction::Move { dst, src } = *instruction {
+                if dst != src {
+                    copies.push((dst, src));
+                }
+            }
+        }
+    }
+}
+
+/// One more than the highest register the code or the parameters use
+fn register_count(code: &[Instruction], params: usize) -> usize {
+    code.iter()
+        .flat_map(|instruction| instruction.def().into_iter().chain(instruction.uses()))
+        .map(|register| register + 1)
+        .max()
+        .unwrap_or(0)
+        .max(params)
+}
+
+/// Registers that are live after the instruction at `index`, that is, read before being written on some path
+fn live_out(code: &[Instruction], live_in: &[Vec<bool>], index: usize, registers: usize) -> Vec<bool> {
+    let mut live = vec![false; registers];
+    for next in code[index].successors(index).into_iter().flatten() {
+        for (register, &next_live) in live.iter_mut().zip(&live_in[next]) {
+            *register |= next_live;
+        }
+    }
+    live
+}
+
+/// Registers that are live before each instruction
+fn liveness(code: &[Instruction], registers: usize) -> Vec<Vec<bool>> {
+    let mut live_in = vec![vec![false; registers]; code.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..code.len()).rev() {
+            let mut live = live_out(code, &live_in, index, registers);
+            if let Some(def) = code[index].def() {
+                live[def] = false;
+            }
+            for register in code[index].uses() {
+                live[register] = true;
+            }
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// Remove the loads and moves into registers that are not live afterwards, until there are none.
+/// Other instructions stay even if what they write is not read, since they can throw.
+fn remove_dead_code(code: &mut Vec<Instruction>, offsets: &mut Vec<usize>, registers: usize) {
+    loop {
+        let live_in = liveness(code, registers);
+        let keep: Vec<bool> = code
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| match *instruction {
+                Instruction::Move { dst, src } if dst == src => false,
+                Instruction::Load { dst, .. } | Instruction::Move { dst, .. } => live_out(code, &live_in, index, registers)[dst],
+                _ => true,
+            })
+            .collect();
+        if keep.iter().all(|&kept| kept) {
+            return;
+        }
+
+        // Jumps to a removed instruction continue at the next one that is kept
+        let mut indices = Vec::with_capacity(code.len() + 1);
+        let mut kept = 0;
+        for &keep in &keep {
+            indices.push(kept);
+            kept += keep as usize;
+        }
+        indices.push(kept);
+
+        let mut index = 0;
+        code.retain_mut(|instruction| {
+            if let Instruction::Jump { target } | Instruction::JumpIfFalse { target, .. } = instruction {
+                *target = indices[*target];
+            }
+            index += 1;
+            keep[index - 1]
+        });
+        let mut index = 0;
+        offsets.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+    }
+}
+
+/// Give registers that are never live at the same time the same register, by coloring the graph of the ones that are.
+/// Parameters keep theirs. Returns the number of registers, or None if a register can be read before it is written.
+fn allocate_registers(code: &mut [Instruction], params: usize) -> Option<usize> {
+    let count = register_count(code, params);
+    let live_in = liveness(code, count);
+    // Only the parameters have values when the function starts
+    if live_in.first().is_some_and(|live| live[params..].contains(&true)) {
+        return None;
+    }
+
+    let mut interferes = vec![vec![false; count]; count];
+    for index in 0..code.len() {
+        let Some(def) = code[index].def() else {
+            continue;
+        };
+        // A move doesn't need a register other than its source, since both have the same value afterwards
+        let source = match code[index] {
+            Instruction::Move { src, .. } => Some(src),
+            _ => None,
+        };
+        for (register, live) in live_out(code, &live_in, index, count).into_iter().enumerate() {
+            if live && register != def && Some(register) != source {
+                interferes[def][register] = true;
+                interferes[register][def] = true;
+            }
+        }
+    }
+
+    let mut colors: Vec<usize> = (0..count).collect();
+    for register in params..count {
+        let taken: Vec<usize> = (0..register)
+            .filter(|&other| interferes[register][other])
+            .map(|other| colors[other])
+            .collect();
+        colors[register] = (0..).find(|color| !taken.contains(color)).unwrap();
+    }
+
+    for instruction in code.iter_mut() {
+        instruction.map_uses(|register| colors[register]);
+        instruction.map_def(|register| colors[register]);
+    }
+    Some(colors.into_iter().map(|color| color + 1).max().unwrap_or(0).max(params))
+}
@@ -1,10 +1,19 @@
 use std::{collections::HashMap, rc::Rc, sync::OnceLock};
 
+#[derive(Clone)]
 pub struct Scanner {
-    start: usize,
-    current: usize,
+    start: usize,   // Offset of the first byte of the token being scanned
+    current: usize, // Offset of the next byte, which is always at the start of a character
     source: Rc<str>,
     pub line: usize,
+    line_start: usize, // Offset where the current line starts
+}
+
+/// Range of bytes of the source, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -12,6 +21,8 @@ pub struct Token {
     pub typ: TokenType,
     pub source: Rc<str>,
     pub line: usize,
+    pub column: usize, // Starting at 1, like lines
+    pub span: Span,
 }
 
 impl Default for Token {
@@ -26,6 +37,8 @@ impl Token {
             typ: TokenType::Error,
             source: Rc::from(""),
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
@@ -37,6 +50,7 @@ impl Scanner {
             current: 0,
             source,
             line: 1,
+            line_start: 0,
         }
     }
 
@@ -53,12 +67,29 @@ impl Scanner {
             m.insert("print", TokenType::Print);
             m.insert("return", TokenType::Return);
             m.insert("super", TokenType::Super);
+            m.insert("this", TokenType::This);
             m.insert("var", TokenType::Var);
+            m.insert("const", TokenType::Const);
+            m.insert("static", TokenType::Static);
+            m.insert("enum", TokenType::Enum);
+            m.insert("record", TokenType::Record);
+            m.insert("in", TokenType::In);
             m.insert("while", TokenType::While);
             m.insert("for", TokenType::For);
             m.insert("true", TokenType::True);
             m.insert("false", TokenType::False);
             m.insert("function", TokenType::Fun);
+            m.insert("switch", TokenType::Switch);
+            m.insert("case", TokenType::Case);
+            m.insert("default", TokenType::Default);
+            m.insert("try", TokenType::Try);
+            m.insert("catch", TokenType::Catch);
+            m.insert("finally", TokenType::Finally);
+            m.insert("throw", TokenType::Throw);
+            m.insert("yield", TokenType::Yield);
+            m.insert("import", TokenType::Import);
+            m.insert("export", TokenType::Export);
+            m.insert("as", TokenType::As);
             m
         })
     }
@@ -79,12 +110,38 @@ impl Scanner {
             '[' => self.make_token(TokenType::LeftBracket),
             ']' => self.make_token(TokenType::RightBracket),
             ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
+            '.' => {
+                if self.peek() == '.' && self.peek2() == '.' {
+                    self.advance();
+                    self.advance();
+                    self.make_token(TokenType::Ellipsis)
+                } else if self.match_char('.') {
+                    if self.match_char('=') {
+                        self.make_token(TokenType::DotDotEqual)
+                    } else {
+                        self.make_token(TokenType::DotDot)
+                    }
+                } else {
+                    self.make_token(TokenType::Dot)
+                }
+            }
+            ':' => self.make_token(TokenType::Colon),
+            '?' => {
+                if self.match_char('.') {
+                    self.make_token(TokenType::QuestionDot)
+                } else if self.match_char('?') {
+                    self.make_token(TokenType::QuestionQuestion)
+                } else {
+                    self.error_token("Expect '.' or '?' after '?'".to_string())
+                }
+            }
             '-' => {
                 if self.match_char('=') {
                     self.error_token("-= is not supported".to_string())
                 } else if self.match_char('-') {
                     self.error_token("-- is not supported".to_string())
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::Arrow)
                 } else {
                     self.make_token(TokenType::Minus)
                 }
@@ -101,6 +158,8 @@ impl Scanner {
             ';' => self.make_token(TokenType::Semicolon),
             '*' => self.make_token(TokenType::Star),
             '%' => self.make_token(TokenType::Modulo),
+            '|' => self.make_token(TokenType::Pipe),
+            '&' => self.make_token(TokenType::Ampersand),
             '!' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::BangEqual)
@@ -143,7 +202,7 @@ impl Scanner {
             c => {
                 if c.is_ascii_digit() {
                     self.number()
-                } else if c.is_alphabetic() {
+                } else if c.is_alphabetic() || c == '_' {
                     self.identifier()
                 } else {
                     self.error_token(format!("Unexpected character '{}' at position {}", c, self.start))
@@ -157,43 +216,31 @@ impl Scanner {
             return false;
         }
 
-        if self.source.chars().nth(self.current) != Some(expected) {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
         true
     }
 
+    /// The next character, or `'\0'` at the end
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-
-        self.source
-            .chars()
-            .nth(self.current)
-            .unwrap_or_else(|| panic!("Could not get {}th  character", self.current))
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
+    /// The character after the next one, or `'\0'` if there is none
     fn peek2(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-
-        self.source
-            .chars()
-            .nth(self.current + 1)
-            .unwrap_or_else(|| panic!("Could not get {}th  character", self.current + 1))
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
+    /// Move past the next character, which may take more than one byte
     pub fn advance(&mut self) -> char {
-        self.current += 1;
-        return self
-            .source
-            .chars()
-            .nth(self.current - 1)
-            .unwrap_or_else(|| panic!("Could not get {}th  character", self.current - 1));
+        let Some(c) = self.source[self.current..].chars().next() else {
+            return '\0';
+        };
+        self.current += c.len_utf8();
+        c
     }
 
     fn is_at_end(&self) -> bool {
@@ -203,29 +250,74 @@ impl Scanner {
     fn skip_whitespace(&mut self) {
         while let ' ' | '\r' | '\t' | '\n' = self.peek() {
             if self.peek() == '\n' {
-                self.line += 1;
+                self.newline();
             }
 
             self.advance();
         }
     }
 
+    /// Count the newline at the next character
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+    }
+
+    /// Column of the start of the token in characters, on the line it starts on, which is earlier than `line` for
+    /// strings that span lines
+    fn column(&self) -> usize {
+        let line_start = match self.start >= self.line_start {
+            true => self.line_start,
+            false => self.source[..self.start].rfind('\n').map_or(0, |newline| newline + 1),
+        };
+        self.source[line_start..self.start].chars().count() + 1
+    }
+
+    /// Column of the first character of the line that isn't whitespace, and the span of the line without it
+    pub(crate) fn line_span(&self, line: usize) -> (usize, Span) {
+        let start = match line {
+            1 => 0,
+            _ => self
+                .source
+                .match_indices('\n')
+                .nth(line - 2)
+                .map_or(self.source.len(), |(newline, _)| newline + 1),
+        };
+        let end = self.source[start..].find('\n').map_or(self.source.len(), |newline| start + newline);
+        let text = &self.source[start..end];
+        let indent = text.len() - text.trim_start().len();
+        let span = Span {
+            start: start + indent,
+            end: start + text.trim_end().len().max(indent),
+        };
+        (indent + 1, span)
+    }
+
     pub fn make_token(&self, typ: TokenType) -> Token {
         Token {
             typ,
             source: self.source[self.start..self.current].into(),
             line: self.line,
+            column: self.column(),
+            span: Span {
+                start: self.start,
+                end: self.current,
+            },
         }
     }
 
+    /// Token of a mistake in the source, whose source is the message the parser reports
     fn error_token(&self, msg: String) -> Token {
-        panic!("[Line {}] {}", self.line, msg);
+        Token {
+            source: msg.into(),
+            ..self.make_token(TokenType::Error)
+        }
     }
 
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
-                self.line += 1;
+                self.newline();
             }
             self.advance();
         }
@@ -251,6 +343,9 @@ impl Scanner {
             while self.peek().is_ascii_digit() {
                 self.advance();
             }
+        } else if self.peek() == 'n' && !self.peek2().is_alphanumeric() && self.peek2() != '_' {
+            // Big integer suffix, like `123n`
+            self.advance();
         }
 
         self.make_token(TokenType::Number)
@@ -270,6 +365,23 @@ impl Scanner {
     }
 }
 
+/// The tokens of the source, up to but not including the `TokenType::EOF` at its end. Comments and whitespace are
+/// skipped, and a mistake, like an unterminated string, is a `TokenType::Error` token whose source is the message.
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.scan_token();
+        (token.typ != TokenType::EOF).then_some(token)
+    }
+}
+
+/// All the tokens of the source, with the error tokens, see `Scanner`'s `Iterator`. The text of a token in the source
+/// is `&source[token.span.start..token.span.end]`, which is also the text of error tokens.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    Scanner::new(Rc::from(source)).collect()
+}
+
 #[derive(strum_macros::Display, PartialEq, Eq, Debug, Copy, Clone, Hash)]
 pub enum TokenType {
     // Single char
@@ -281,6 +393,13 @@ pub enum TokenType {
     RightBracket,
     Comma,
     Dot,
+    Ellipsis,
+    DotDot,
+    DotDotEqual,
+    QuestionDot,
+    QuestionQuestion,
+    Colon,
+    Arrow,
     Minus,
     MinusEqual,
     Plus,
@@ -289,6 +408,8 @@ pub enum TokenType {
     Slash,
     Star,
     Modulo,
+    Pipe,
+    Ampersand,
 
     // One or two chars
     Bang,
@@ -322,6 +443,22 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Switch,
+    Case,
+    Default,
+    Try,
+    Catch,
+    Finally,
+    Throw,
+    Yield,
+    Const,
+    Static,
+    Enum,
+    Record,
+    In,
+    Import,
+    Export,
+    As,
     Error,
     EOF,
 }
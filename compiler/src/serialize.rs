@@ -0,0 +1,467 @@
+//! A binary format of compiled chunks, so that programs can be cached, like in `localStorage`, or shipped compiled.
+//!
+//! A chunk is written as:
+//! - the magic bytes `LOXB` and the version of the format, as a u16
+//! - the strings its constants use, each written once, since string ids are only valid in the interner they came from
+//! - its constants, each a tag byte and what the value holds, with strings as their index in the strings
+//! - its code, and the line of each run of its bytes, and the source of the statement of each run of its bytes, as a
+//!   byte that is 1 if it has one, and its start and end
+//!
+//! Numbers are little endian, and counts and lengths are u32. Functions and modules are kept as their index, which is
+//! only valid with the function list and the modules of the program the chunk was compiled in. Chunks are checked with
+//! `verify::verify_chunk` when they are read.
+//!
+//! A whole program, with `serialize_program`, is written as the magic bytes `LOXP` and the version, then each of its
+//! functions, with its chunk in the format above, and then each of its modules.
+
+use crate::{
+    chunk::Chunk,
+    fun::{Fun, LocalVariable},
+    interner::{Interner, StrId},
+    module::Module,
+    scanner::Span,
+    testing::TestCase,
+    value::{Enum, EnumMember, RecordType, Value},
+    verify::{verify_chunk, verify_function},
+};
+use anyhow::{bail, Context, Result};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+const MAGIC: &[u8; 4] = b"LOXB";
+const PROGRAM_MAGIC: &[u8; 4] = b"LOXP";
+/// Increased when the format or the opcodes change, since older chunks can't be read then
+const VERSION: u16 = 2;
+
+#[repr(u8)]
+#[derive(IntoPrimitive, TryFromPrimitive)]
+enum Tag {
+    Nil,
+    Bool,
+    Int,
+    Number,
+    Str,
+    Identifier,
+    Function,
+    Module,
+    Array,
+    Enum,
+    RecordType,
+    BigInt,
+}
+
+impl Chunk {
+    /// The chunk in the binary format, with the strings of its constants looked up in `interner`
+    pub fn serialize(&self, interner: &Interner) -> Vec<u8> {
+        let mut writer = Writer::default();
+        for constant in &self.constants {
+            writer.constant(constant, interner);
+        }
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        write_len(&mut out, writer.strings.len());
+        for string in &writer.strings {
+            write_len(&mut out, string.len());
+            out.extend_from_slice(string.as_bytes());
+        }
+        write_len(&mut out, self.constants.len());
+        out.extend_from_slice(&writer.constants);
+        write_len(&mut out, self.code.len());
+        out.extend_from_slice(&self.code);
+        write_len(&mut out, self.lines.len());
+        for &(line, length) in &self.lines {
+            write_len(&mut out, line);
+            write_len(&mut out, length);
+        }
+        write_len(&mut out, self.spans.len());
+        for &(span, length) in &self.spans {
+            out.push(span.is_some() as u8);
+            let span = span.unwrap_or_default();
+            write_len(&mut out, span.start);
+            write_len(&mut out, span.end);
+            write_len(&mut out, length);
+        }
+        out
+    }
+
+    /// Read a chunk written by `serialize`, interning the strings of its constants in `interner`
+    pub fn deserialize(bytes: &[u8], interner: &mut Interner) -> Result<Chunk> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("Not a compiled chunk");
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != VERSION {
+            bail!("Compiled chunk has version {version}, but only version {VERSION} can be read");
+        }
+
+        let mut strings = Vec::new();
+        for _ in 0..reader.len()? {
+            let len = reader.len()?;
+            let string = std::str::from_utf8(reader.take(len)?).context("String of a compiled chunk is not UTF-8")?;
+            strings.push(interner.intern(string));
+        }
+
+        let mut chunk = Chunk::default();
+        for _ in 0..reader.len()? {
+            let constant = reader.constant(&strings)?;
+            chunk.push_constant(constant);
+        }
+        let len = reader.len()?;
+        chunk.code = reader.take(len)?.to_vec();
+        for _ in 0..reader.len()? {
+            chunk.lines.push((reader.len()?, reader.len()?));
+        }
+        if chunk.lines.iter().map(|&(_, length)| length).sum::<usize>() != chunk.code.len() {
+            bail!("Line table of a compiled chunk doesn't match its code");
+        }
+        for _ in 0..reader.len()? {
+            let [has_span] = reader.array()?;
+            let span = Span {
+                start: reader.len()?,
+                end: reader.len()?,
+            };
+            chunk.spans.push(((has_span != 0).then_some(span), reader.len()?));
+        }
+        if !chunk.spans.is_empty() && chunk.spans.iter().map(|&(_, length)| length).sum::<usize>() != chunk.code.len() {
+            bail!("Span table of a compiled chunk doesn't match its code");
+        }
+        if reader.offset != bytes.len() {
+            bail!("Compiled chunk has bytes after its end");
+        }
+        verify_chunk(&chunk)?;
+        Ok(chunk)
+    }
+}
+
+/// The functions and modules of a program in the binary format. Functions lowered by the register backend are written
+/// without what it lowered them to, so they run on the stack when they are read back.
+pub fn serialize_program(functions: &[Fun], modules: &[Module], interner: &Interner) -> Vec<u8> {
+    let mut out = PROGRAM_MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    let write_name = |out: &mut Vec<u8>, name: &StrId| write_str(out, interner.lookup(name));
+    let write_flag = |out: &mut Vec<u8>, flag: bool| out.push(flag as u8);
+
+    write_len(&mut out, functions.len());
+    for fun in functions {
+        write_len(&mut out, fun.arity);
+        write_len(&mut out, fun.min_arity);
+        write_flag(&mut out, fun.is_variadic);
+        write_len(&mut out, fun.param_names.len());
+        fun.param_names.iter().for_each(|name| write_name(&mut out, name));
+        write_flag(&mut out, fun.name.is_some());
+        fun.name.iter().for_each(|name| write_name(&mut out, name));
+        write_len(&mut out, fun.module);
+        write_flag(&mut out, fun.is_method);
+        write_flag(&mut out, fun.is_generator);
+        write_len(&mut out, fun.locals.len());
+        for local in &fun.locals {
+            write_name(&mut out, &local.name);
+            write_len(&mut out, local.slot);
+            write_len(&mut out, local.start);
+            // Scopes that last until the function returns end at usize::MAX
+            write_len(&mut out, local.end.min(u32::MAX as usize));
+        }
+        let chunk = fun.chunk.serialize(interner);
+        write_len(&mut out, chunk.len());
+        out.extend_from_slice(&chunk);
+    }
+
+    write_len(&mut out, modules.len());
+    for module in modules {
+        write_str(&mut out, &module.path);
+        write_len(&mut out, module.exports.len());
+        module.exports.iter().for_each(|name| write_name(&mut out, name));
+        write_len(&mut out, module.constants.len());
+        module.constants.iter().for_each(|name| write_name(&mut out, name));
+        write_flag(&mut out, module.script.is_some());
+        module.script.iter().for_each(|&script| write_len(&mut out, script));
+        write_len(&mut out, module.tests.len());
+        for test in &module.tests {
+            write_str(&mut out, &test.name);
+            write_len(&mut out, test.fun);
+            write_len(&mut out, test.line);
+        }
+    }
+    out
+}
+
+/// Read a program written by `serialize_program`, interning its strings in `interner`. Its functions are checked with
+/// `verify::verify_function`.
+pub fn deserialize_program(bytes: &[u8], interner: &mut Interner) -> Result<(Vec<Fun>, Vec<Module>)> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(PROGRAM_MAGIC.len())? != PROGRAM_MAGIC {
+        bail!("Not a compiled program");
+    }
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        bail!("Compiled program has version {version}, but only version {VERSION} can be read");
+    }
+
+    let mut functions = Vec::new();
+    for _ in 0..reader.len()? {
+        let mut fun = Fun::new();
+        fun.arity = reader.len()?;
+        fun.min_arity = reader.len()?;
+        fun.is_variadic = reader.flag()?;
+        for _ in 0..reader.len()? {
+            fun.param_names.push(interner.intern(reader.str()?));
+        }
+        if reader.flag()? {
+            fun.name = Some(interner.intern(reader.str()?));
+        }
+        fun.module = reader.len()?;
+        fun.is_method = reader.flag()?;
+        fun.is_generator = reader.flag()?;
+        for _ in 0..reader.len()? {
+            let name = interner.intern(reader.str()?);
+            let (slot, start, end) = (reader.len()?, reader.len()?, reader.len()?);
+            let end = if end == u32::MAX as usize { usize::MAX } else { end };
+            fun.locals.push(LocalVariable { name, slot, start, end });
+        }
+        let len = reader.len()?;
+        fun.chunk = Chunk::deserialize(reader.take(len)?, interner)?;
+        functions.push(fun);
+    }
+
+    let mut modules = Vec::new();
+    for _ in 0..reader.len()? {
+        let path = reader.str()?.to_string();
+        let mut names =
+            |reader: &mut Reader| -> Result<Vec<StrId>> { (0..reader.len()?).map(|_| Ok(interner.intern(reader.str()?))).collect() };
+        let exports = names(&mut reader)?;
+        let constants = names(&mut reader)?;
+        let script = match reader.flag()? {
+            true => Some(reader.len()?),
+            false => None,
+        };
+        let mut tests = Vec::new();
+        for _ in 0..reader.len()? {
+            let name = reader.str()?.to_string();
+            tests.push(TestCase {
+                name,
+                fun: reader.len()?,
+                line: reader.len()?,
+            });
+        }
+        modules.push(Module {
+            path,
+            exports,
+            constants,
+            script,
+            tests,
+        });
+    }
+    if reader.offset != bytes.len() {
+        bail!("Compiled program has bytes after its end");
+    }
+
+    for fun in &functions {
+        verify_function(fun, functions.len(), modules.len())?;
+    }
+    let scripts = modules
+        .iter()
+        .flat_map(|module| module.script.into_iter().chain(module.tests.iter().map(|test| test.fun)));
+    if let Some(index) = scripts.into_iter().find(|&index| index >= functions.len()) {
+        bail!("Module of a compiled program has function {index}, which is out of bounds");
+    }
+    Ok((functions, modules))
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    let len = u32::try_from(len).expect("Chunks are smaller than 4 GiB");
+    out.extend_from_slice(&len.to_le_bytes());
+}
+
+/// Constants, and the strings they use
+#[derive(Default)]
+struct Writer<'a> {
+    strings: Vec<&'a str>,
+    string_indices: HashMap<StrId, usize>,
+    constants: Vec<u8>,
+}
+
+impl<'a> Writer<'a> {
+    fn string(&mut self, id: StrId, interner: &'a Interner) {
+        let index = *self.string_indices.entry(id).or_insert_with(|| {
+            self.strings.push(interner.lookup(&id));
+            self.strings.len() - 1
+        });
+        write_len(&mut self.constants, index);
+    }
+
+    fn tag(&mut self, tag: Tag) {
+        self.constants.push(tag.into());
+    }
+
+    fn constant(&mut self, value: &Value, interner: &'a Interner) {
+        match value {
+            Value::Nil => self.tag(Tag::Nil),
+            Value::Bool(bool) => {
+                self.tag(Tag::Bool);
+                self.constants.push(*bool as u8);
+            }
+            Value::Int(int) => {
+                self.tag(Tag::Int);
+                self.constants.extend_from_slice(&int.to_le_bytes());
+            }
+            Value::Number(number) => {
+                self.tag(Tag::Number);
+                self.constants.extend_from_slice(&number.to_bits().to_le_bytes());
+            }
+            Value::Str(id) => {
+                self.tag(Tag::Str);
+                self.string(*id, interner);
+            }
+            Value::Identifier(id) => {
+                self.tag(Tag::Identifier);
+                self.string(*id, interner);
+            }
+            Value::Function(index) => {
+                self.tag(Tag::Function);
+                write_len(&mut self.constants, *index);
+            }
+            Value::Module(index) => {
+                self.tag(Tag::Module);
+                write_len(&mut self.constants, *index);
+            }
+            Value::Array(array) => {
+                self.tag(Tag::Array);
+                let array = array.borrow();
+                write_len(&mut self.constants, array.len());
+                for element in array.iter() {
+                    self.constant(element, interner);
+                }
+            }
+            Value::Enum(enum_) => {
+                self.tag(Tag::Enum);
+                self.string(enum_.name, interner);
+                write_len(&mut self.constants, enum_.members.len());
+                for member in &enum_.members {
+                    let Value::EnumMember(member) = member else {
+                        unreachable!("Members of an enum are enum members");
+                    };
+                    self.string(member.name, interner);
+                }
+            }
+            Value::RecordType(record) => {
+                self.tag(Tag::RecordType);
+                self.string(record.name, interner);
+                write_len(&mut self.constants, record.fields.len());
+                for &field in &record.fields {
+                    self.string(field, interner);
+                }
+            }
+            #[cfg(feature = "bigint")]
+            Value::BigInt(bigint) => {
+                self.tag(Tag::BigInt);
+                let digits = bigint.to_string();
+                write_len(&mut self.constants, digits.len());
+                self.constants.extend_from_slice(digits.as_bytes());
+            }
+            other => unreachable!("{other:?} is not a constant"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.bytes.get(self.offset..self.offset + len) else {
+            bail!("Compiled chunk ends early");
+        };
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("Took N bytes"))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn flag(&mut self) -> Result<bool> {
+        Ok(self.array::<1>()?[0] != 0)
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).context("String of a compiled program is not UTF-8")
+    }
+
+    fn string(&mut self, strings: &[StrId]) -> Result<StrId> {
+        let index = self.len()?;
+        strings
+            .get(index)
+            .copied()
+            .context("Constant of a compiled chunk uses a missing string")
+    }
+
+    fn constant(&mut self, strings: &[StrId]) -> Result<Value> {
+        let [tag] = self.array()?;
+        let Ok(tag) = Tag::try_from(tag) else {
+            bail!("Unknown constant tag {tag} in compiled chunk");
+        };
+        let value = match tag {
+            Tag::Nil => Value::Nil,
+            Tag::Bool => Value::Bool(self.array::<1>()?[0] != 0),
+            Tag::Int => Value::Int(i64::from_le_bytes(self.array()?)),
+            Tag::Number => Value::Number(f64::from_bits(u64::from_le_bytes(self.array()?))),
+            Tag::Str => Value::Str(self.string(strings)?),
+            Tag::Identifier => Value::Identifier(self.string(strings)?),
+            Tag::Function => Value::Function(self.len()?),
+            Tag::Module => Value::Module(self.len()?),
+            Tag::Array => {
+                let mut array = Vec::new();
+                for _ in 0..self.len()? {
+                    array.push(self.constant(strings)?);
+                }
+                Value::Array(Rc::new(RefCell::new(array)))
+            }
+            Tag::Enum => {
+                let name = self.string(strings)?;
+                let mut members = Vec::new();
+                for ordinal in 0..self.len()? {
+                    members.push(Value::EnumMember(Rc::new(EnumMember {
+                        enum_name: name,
+                        name: self.string(strings)?,
+                        ordinal,
+                    })));
+                }
+                Value::Enum(Rc::new(Enum { name, members }))
+            }
+            Tag::RecordType => {
+                let name = self.string(strings)?;
+                let mut fields = Vec::new();
+                for _ in 0..self.len()? {
+                    fields.push(self.string(strings)?);
+                }
+                Value::RecordType(Rc::new(RecordType { name, fields }))
+            }
+            #[cfg(feature = "bigint")]
+            Tag::BigInt => {
+                let len = self.len()?;
+                let digits = std::str::from_utf8(self.take(len)?).ok();
+                let bigint = digits
+                    .and_then(crate::bigint::BigInt::parse)
+                    .context("Invalid big integer in compiled chunk")?;
+                Value::BigInt(Rc::new(bigint))
+            }
+            #[cfg(not(feature = "bigint"))]
+            Tag::BigInt => bail!("Compiled chunk has a big integer, but the compiler was built without the bigint feature"),
+        };
+        Ok(value)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{
+    compiler::{Compiler, CompilerOptions},
+    diagnostic::CompileError,
+    fun::Fun,
+    interner::Interner,
+    module::{ModuleLoader, ModuleRegistry},
+    native::NativeFuture,
+    value::{value_as_string, Value},
+    vm::{Vm, VmOptions, VmState},
+};
+use anyhow::{Context, Result};
+use std::{future::Future, rc::Rc};
+
+/// Runs snippets of code one after another, like the cells of a notebook or the lines of a REPL.
+/// Globals, imported modules and natives defined by the host are shared by all the snippets.
+pub struct Session<F, Fut>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    interner: Interner,
+    modules: ModuleRegistry, // Has no modules of its own, they are kept in the state between snippets
+    state: Option<VmState>,  // None while a snippet is running
+    read_async: Option<F>,
+}
+
+impl<F, Fut> Session<F, Fut>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    pub fn new(loader: impl ModuleLoader + 'static, read_async: F) -> Session<F, Fut> {
+        let mut interner = Interner::with_capacity(crate::INTERNER_DEFAULT_CAP);
+        let mut modules = ModuleRegistry::new(Box::new(loader));
+        let vm = Vm::new(&mut interner, Vec::new(), std::mem::take(&mut modules.modules), read_async);
+        let (state, read_async) = vm.into_state();
+        Session {
+            interner,
+            modules,
+            state: Some(state),
+            read_async: Some(read_async),
+        }
+    }
+
+    /// Run something with a VM for the state of the session
+    fn with_vm<T>(&mut self, f: impl FnOnce(&mut Vm<F, Fut>) -> T) -> Result<T> {
+        let (state, read_async) = self.take()?;
+        let mut vm = Vm::from_state(&mut self.interner, state, read_async);
+        let result = f(&mut vm);
+        let parts = vm.into_state();
+        self.restore(parts);
+        Ok(result)
+    }
+
+    fn take(&mut self) -> Result<(VmState, F)> {
+        let state = self
+            .state
+            .take()
+            .context("A snippet is still running, or one was stopped before it finished")?;
+        Ok((state, self.read_async.take().unwrap()))
+    }
+
+    fn restore(&mut self, (state, read_async): (VmState, F)) {
+        self.state = Some(state);
+        self.read_async = Some(read_async);
+    }
+
+    pub fn configure(&mut self, options: VmOptions) -> Result<()> {
+        self.with_vm(|vm| vm.configure(options))
+    }
+
+    /// Make a native function available to the snippets, like `Vm::define_native`
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        fun: impl Fn(&mut Interner, &[Value]) -> Result<Value> + 'static,
+    ) -> Result<()> {
+        self.with_vm(|vm| vm.define_native(name, arity, fun))
+    }
+
+    /// Make an async native function available to the snippets, like `Vm::define_async_native`
+    pub fn define_async_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        fun: impl Fn(&mut Interner, &[Value]) -> NativeFuture + 'static,
+    ) -> Result<()> {
+        self.with_vm(|vm| vm.define_async_native(name, arity, fun))
+    }
+
+    /// Compile and run a snippet, and return the value it returns.
+    /// A snippet that ends with an expression statement returns the value of the expression, see `Compiler::compile_snippet`.
+    /// Errors are either a `CompileError` or a `RuntimeError`.
+    pub async fn eval(&mut self, source: &str) -> Result<Value> {
+        self.run(|interner, functions, modules| Compiler::compile_snippet(Rc::from(source), interner, functions, modules))
+            .await
+    }
+
+    /// Compile and run a single expression, like a calculator, and return its value, see `Compiler::compile_expression`.
+    /// Statements and declarations are a `CompileError`, and errors are like those of `eval`.
+    pub async fn eval_expression(&mut self, source: &str) -> Result<Value> {
+        self.run(|interner, functions, modules| {
+            let compiled = Compiler::compile_expression(Rc::from(source), interner, functions, modules, &CompilerOptions::default());
+            compiled.map(|expression| expression.script)
+        })
+        .await
+    }
+
+    /// Compile a script with the globals and modules of the session, and run it
+    async fn run(
+        &mut self,
+        compile: impl FnOnce(&mut Interner, &mut Vec<Fun>, &mut ModuleRegistry) -> Result<Fun, CompileError>,
+    ) -> Result<Value> {
+        let (mut state, read_async) = self.take()?;
+
+        self.modules.modules = std::mem::take(&mut state.modules);
+        let compiled = compile(&mut self.interner, &mut state.functions, &mut self.modules);
+        state.modules = std::mem::take(&mut self.modules.modules);
+
+        let mut vm = Vm::from_state(&mut self.interner, state, read_async);
+        let result = match compiled {
+            Ok(fun) => {
+                vm.load_script(fun);
+                vm.interpret().await.map_err(anyhow::Error::from)
+            }
+            Err(err) => Err(err.into()),
+        };
+        let parts = vm.into_state();
+        self.restore(parts);
+        result
+    }
+
+    /// Text of a value, like `print` writes it
+    pub fn format_value(&self, value: &Value) -> String {
+        value_as_string(value, &self.interner)
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+}
+
+/// Whether a snippet is unfinished, because it has an unterminated string or more opening brackets than closing ones.
+/// A REPL uses it to read more lines before running the snippet.
+pub fn needs_more_input(source: &str) -> bool {
+    let mut depth = 0isize;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if !chars.by_ref().any(|c| c == '"') => return true,
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().find(|&c| c == '\n');
+            }
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
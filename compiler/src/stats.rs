@@ -0,0 +1,146 @@
+use std::fmt;
+
+use rustc_hash::FxHashMap;
+
+use crate::{common::Opcode, json::write_string};
+
+/// Constants whose value is shown in full in `VmStats`, longer ones are shortened
+const MAX_CONSTANT_TEXT: usize = 40;
+
+/// Counts of what a program ran, for `VmOptions::collect_stats`
+pub(crate) struct StatsCounter {
+    opcodes: Vec<u64>,                         // Indexed by opcode
+    constants: FxHashMap<(usize, usize), u64>, // By function and index in its constant pool
+}
+
+impl StatsCounter {
+    pub fn new() -> StatsCounter {
+        StatsCounter {
+            opcodes: vec![0; u8::MAX as usize + 1],
+            constants: FxHashMap::default(),
+        }
+    }
+
+    pub fn count_opcode(&mut self, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+    }
+
+    pub fn count_constant(&mut self, fun_idx: usize, index: usize) {
+        *self.constants.entry((fun_idx, index)).or_default() += 1;
+    }
+
+    /// Statistics with the text that `describe` gives to a constant, as the name of its function and its value
+    pub fn stats(&self, describe: impl Fn(usize, usize) -> (String, String)) -> VmStats {
+        let mut opcodes: Vec<(String, u64)> = (0..=u8::MAX)
+            .filter(|&opcode| self.opcodes[opcode as usize] > 0)
+            .filter_map(|opcode| Some((Opcode::try_from(opcode).ok()?.to_string(), self.opcodes[opcode as usize])))
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut constants: Vec<ConstantStats> = self
+            .constants
+            .iter()
+            .map(|(&(fun_idx, index), &loads)| {
+                let (function, mut value) = describe(fun_idx, index);
+                if let Some((cut, _)) = value.char_indices().nth(MAX_CONSTANT_TEXT) {
+                    value.truncate(cut);
+                    value.push_str("...");
+                }
+                ConstantStats {
+                    function,
+                    index,
+                    value,
+                    loads,
+                }
+            })
+            .collect();
+        constants.sort_by(|a, b| {
+            b.loads
+                .cmp(&a.loads)
+                .then_with(|| a.function.cmp(&b.function))
+                .then(a.index.cmp(&b.index))
+        });
+
+        VmStats {
+            instructions: self.opcodes.iter().sum(),
+            constant_loads: self.constants.values().sum(),
+            opcodes,
+            constants,
+        }
+    }
+}
+
+/// How often an instruction read a constant
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstantStats {
+    pub function: String, // `<script>` for the top level of a module
+    pub index: usize,     // In the constant pool of the function
+    pub value: String,
+    pub loads: u64,
+}
+
+/// What the last run executed, returned by `Vm::stats` after a run with `VmOptions::collect_stats`.
+/// Instructions that natives ran in callbacks, like for `map`, are included.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmStats {
+    pub instructions: u64,
+    pub constant_loads: u64,         // Instructions that read a constant, like `Constant` or `GetGlobal` for the name
+    pub opcodes: Vec<(String, u64)>, // Opcodes that ran, most often first
+    pub constants: Vec<ConstantStats>, // Constants that were read, most often first
+}
+
+impl VmStats {
+    /// Fraction of the instructions that read a constant
+    pub fn constant_hit_rate(&self) -> f64 {
+        match self.instructions {
+            0 => 0.0,
+            instructions => self.constant_loads as f64 / instructions as f64,
+        }
+    }
+
+    /// The statistics as a JSON object, with the same fields
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"instructions\":{},\"constant_loads\":{},\"constant_hit_rate\":{},\"opcodes\":{{",
+            self.instructions,
+            self.constant_loads,
+            self.constant_hit_rate()
+        );
+        for (idx, (opcode, count)) in self.opcodes.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            write_string(&mut out, opcode);
+            out.push_str(&format!(":{count}"));
+        }
+        out.push_str("},\"constants\":[");
+        for (idx, constant) in self.constants.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"function\":");
+            write_string(&mut out, &constant.function);
+            out.push_str(&format!(",\"index\":{},\"value\":", constant.index));
+            write_string(&mut out, &constant.value);
+            out.push_str(&format!(",\"loads\":{}}}", constant.loads));
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl fmt::Display for VmStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} instructions, {} read a constant ({:.1}%)",
+            self.instructions,
+            self.constant_loads,
+            self.constant_hit_rate() * 100.0
+        )?;
+        for (opcode, count) in &self.opcodes {
+            write!(f, "\n{count:>12}  {opcode}")?;
+        }
+        Ok(())
+    }
+}
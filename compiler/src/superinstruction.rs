@@ -0,0 +1,82 @@
+//! Superinstructions, which do the work of a few common instructions in a row with a single dispatch.
+//!
+//! They are fused when a function has been compiled, in place: the first opcode of the sequence is replaced by the
+//! superinstruction, and the rest of the bytes are kept for it to read its operands from. So no offset changes and
+//! jumps stay valid, as long as nothing jumps into the middle of the sequence, which is checked.
+
+use crate::{chunk::Chunk, common::Opcode, value::Value};
+
+/// Whether the byte is an operator that a superinstruction can end with
+pub(crate) fn is_fusable_operator(byte: u8) -> bool {
+    matches!(
+        Opcode::try_from(byte),
+        Ok(Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Divide
+            | Opcode::Modulo
+            | Opcode::Equal
+            | Opcode::Greater
+            | Opcode::Less)
+    )
+}
+
+/// Offsets that execution can continue at other than by falling through: targets of jumps, loops, exception handlers
+/// and loops over iterables
+fn jump_targets(chunk: &Chunk, starts: &[usize]) -> Vec<bool> {
+    let mut targets = vec![false; chunk.code.len() + 1];
+    for &offset in starts {
+        for target in chunk.jump_targets(offset) {
+            if let Some(target) = targets.get_mut(target) {
+                *target = true;
+            }
+        }
+    }
+    targets
+}
+
+/// Replace the sequences of instructions that have a superinstruction with it
+pub(crate) fn fuse(chunk: &mut Chunk) {
+    let starts = chunk.instruction_starts();
+    let targets = jump_targets(chunk, &starts);
+
+    let code = &chunk.code;
+    let is = |index: usize, opcode: Opcode| starts.get(index).is_some_and(|&offset| code[offset] == opcode as u8);
+    let is_operator = |index: usize| starts.get(index).is_some_and(|&offset| is_fusable_operator(code[offset]));
+    // Reading a local variable pushes nil first, which means it is not indexed
+    let is_nil_constant = |index: usize| is(index, Opcode::Constant) && chunk.constants[code[starts[index] + 1] as usize] == Value::Nil;
+
+    let mut fused = Vec::new();
+    let mut index = 0;
+    while index < starts.len() {
+        let local = is_nil_constant(index) && is(index + 1, Opcode::GetLocal);
+        // Longest first, by number of instructions
+        let candidates = [
+            (
+                Opcode::LocalsOperation,
+                5,
+                local && is_nil_constant(index + 2) && is(index + 3, Opcode::GetLocal) && is_operator(index + 4),
+            ),
+            (
+                Opcode::LocalConstantOperation,
+                4,
+                local && is(index + 2, Opcode::Constant) && is_operator(index + 3),
+            ),
+            (Opcode::GetLocalPlain, 2, local),
+            (Opcode::ConstantOperation, 2, is(index, Opcode::Constant) && is_operator(index + 1)),
+        ];
+        let jumped_into = |length: usize| starts[index + 1..index + length].iter().any(|&offset| targets[offset]);
+
+        match candidates.into_iter().find(|&(_, length, matches)| matches && !jumped_into(length)) {
+            Some((superinstruction, length, _)) => {
+                fused.push((starts[index], superinstruction));
+                index += length;
+            }
+            None => index += 1,
+        }
+    }
+
+    for (offset, superinstruction) in fused {
+        chunk.code[offset] = superinstruction as u8;
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// A `test "name" { ... }` declaration, whose body is compiled as a function without parameters
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub fun: usize, // Index of the body in the function list
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    /// An assertion failed. The line is where the assertion was, if it is known.
+    Failed {
+        message: String,
+        line: Option<usize>,
+    },
+    /// Anything else that was thrown and not caught by the test
+    Errored {
+        message: String,
+        line: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub module: String, // Path of the module that declared the test
+    pub line: usize,    // Line of the declaration
+    pub outcome: TestOutcome,
+}
+
+/// Results of `Vm::run_tests`, in the order the tests were declared
+#[derive(Debug, Clone, Default)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, TestOutcome::Passed))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, TestOutcome::Failed { .. }))
+    }
+
+    pub fn errored(&self) -> usize {
+        self.count(|outcome| matches!(outcome, TestOutcome::Errored { .. }))
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.passed() == self.results.len()
+    }
+
+    fn count(&self, predicate: impl Fn(&TestOutcome) -> bool) -> usize {
+        self.results.iter().filter(|result| predicate(&result.outcome)).count()
+    }
+}
+
+/// Split the `[line N] ` prefix that runtime errors from natives have off the message
+pub(crate) fn split_line(message: &str) -> (Option<usize>, &str) {
+    let parsed = message
+        .strip_prefix("[line ")
+        .and_then(|rest| rest.split_once("] "))
+        .and_then(|(line, rest)| Some((line.parse().ok()?, rest)));
+    match parsed {
+        Some((line, rest)) => (Some(line), rest),
+        None => (None, message),
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            let location = format!("{}:{}", result.module, result.line);
+            match &result.outcome {
+                TestOutcome::Passed => writeln!(f, "PASS  {} ({location})", result.name)?,
+                TestOutcome::Failed { message, line } | TestOutcome::Errored { message, line } => {
+                    let label = if matches!(result.outcome, TestOutcome::Failed { .. }) {
+                        "FAIL "
+                    } else {
+                        "ERROR"
+                    };
+                    writeln!(f, "{label} {} ({location})", result.name)?;
+                    match line {
+                        Some(line) => writeln!(f, "      [line {line}] {message}")?,
+                        None => writeln!(f, "      {message}")?,
+                    }
+                }
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} errored, {} total",
+            self.passed(),
+            self.failed(),
+            self.errored(),
+            self.results.len()
+        )
+    }
+}
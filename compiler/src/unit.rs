@@ -0,0 +1,156 @@
+//! A program made of several named sources, like the tabs of a playground project, compiled together:
+//!
+//! ```ignore
+//! let mut unit = CompilationUnit::new();
+//! unit.add("main.lox", "import \"shapes.lox\" as shapes; print shapes.area(2);");
+//! unit.add("shapes.lox", "export function area(r) { return 3.14 * r * r; }");
+//! match unit.compile(&CompilerOptions::default()) {
+//!     Ok(program) => { /* run program.functions with program.modules */ }
+//!     Err(error) => println!("{}", unit.render_error(&error)),
+//! }
+//! ```
+//!
+//! The first source is the main script, and the others are modules it can import by their name. Sources that nothing
+//! imports are compiled too, on their own, so that problems in them are reported. Every diagnostic has the name of
+//! the source it is in as its `file`.
+
+use crate::{
+    compiler::{Compiler, CompilerOptions},
+    diagnostic::{CompileError, Diagnostic, Severity},
+    fun::Fun,
+    interner::Interner,
+    module::{Module, ModuleLoader, ModuleRegistry, MAIN_MODULE},
+    INTERNER_DEFAULT_CAP,
+};
+use anyhow::{anyhow, Result};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Default)]
+pub struct CompilationUnit {
+    sources: Vec<(String, Rc<str>)>, // Name and code of each source, the main script first
+}
+
+/// A compiled unit, ready to run with `vm::Vm::new`
+pub struct CompiledUnit {
+    pub interner: Interner,
+    pub functions: Vec<Fun>,
+    pub modules: Vec<Module>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl CompilationUnit {
+    pub fn new() -> CompilationUnit {
+        CompilationUnit::default()
+    }
+
+    /// Add a source, or replace the code of the one with the same name. The first one added is the main script.
+    pub fn add(&mut self, name: impl Into<String>, code: impl AsRef<str>) -> &mut Self {
+        let name = name.into();
+        let code = Rc::from(code.as_ref());
+        match self.sources.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => *existing = code,
+            None => self.sources.push((name, code)),
+        }
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.sources.retain(|(existing, _)| existing != name);
+    }
+
+    /// Code of the source with the name
+    pub fn source(&self, name: &str) -> Option<&str> {
+        self.sources
+            .iter()
+            .find(|(existing, _)| existing == name)
+            .map(|(_, code)| code.as_ref())
+    }
+
+    /// Names of the sources, the main script first
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sources.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Compile the main script with the modules it imports, and check the sources that aren't imported. The error has
+    /// the problems of all of the sources.
+    pub fn compile(&self, options: &CompilerOptions) -> Result<CompiledUnit, CompileError> {
+        // A unit without sources is an empty program
+        let (main, code) = self.sources.first().cloned().unwrap_or_else(|| (String::new(), Rc::from("")));
+        let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+        let mut functions = Vec::new();
+        let mut modules = ModuleRegistry::new(Box::new(self.loader()));
+        let (script, mut diagnostics) = match Compiler::compile_program(code, &mut interner, &mut functions, &mut modules, options) {
+            Ok(program) => (Some(program.script), program.warnings),
+            Err(error) => (None, error.diagnostics),
+        };
+        for diagnostic in &mut diagnostics {
+            diagnostic.file.get_or_insert_with(|| main.clone());
+        }
+
+        // Problems in sources that aren't imported, which are left out of the program
+        for (name, code) in self.sources.iter().skip(1) {
+            if modules.find(name).is_some() {
+                continue;
+            }
+            let mut interner = Interner::with_capacity(INTERNER_DEFAULT_CAP);
+            let mut functions = Vec::new();
+            let mut registry = ModuleRegistry::new(Box::new(self.loader()));
+            let found = match Compiler::compile_program(code.clone(), &mut interner, &mut functions, &mut registry, options) {
+                Ok(program) => program.warnings,
+                Err(error) => error.diagnostics,
+            };
+            // Problems in the modules it imports are reported for those modules
+            diagnostics.extend(
+                found
+                    .into_iter()
+                    .filter(|diagnostic| diagnostic.file.is_none())
+                    .map(|mut diagnostic| {
+                        diagnostic.file = Some(name.clone());
+                        diagnostic
+                    }),
+            );
+        }
+
+        match script {
+            Some(script) if diagnostics.iter().all(|diagnostic| diagnostic.severity != Severity::Error) => {
+                functions.push(script);
+                modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+                Ok(CompiledUnit {
+                    interner,
+                    functions,
+                    modules: modules.modules,
+                    warnings: diagnostics,
+                })
+            }
+            _ => Err(CompileError { diagnostics }),
+        }
+    }
+
+    /// The diagnostic rendered like `Diagnostic::render`, with the line of the source it is in
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        diagnostic.render_in(diagnostic.file.as_deref().and_then(|file| self.source(file)))
+    }
+
+    /// The diagnostics of the error rendered with `render`, separated by blank lines
+    pub fn render_error(&self, error: &CompileError) -> String {
+        let rendered: Vec<String> = error.diagnostics.iter().map(|diagnostic| self.render(diagnostic)).collect();
+        rendered.join("\n")
+    }
+
+    fn loader(&self) -> UnitLoader {
+        UnitLoader(self.sources.clone())
+    }
+}
+
+/// Loads the sources of a unit by their name
+struct UnitLoader(Vec<(String, Rc<str>)>);
+
+impl ModuleLoader for UnitLoader {
+    fn load(&self, path: &str) -> Result<String> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == path)
+            .map(|(_, code)| code.to_string())
+            .ok_or_else(|| anyhow!("No source is named '{path}'"))
+    }
+}
@@ -1,24 +1,348 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::rc::Rc;
 
 use crate::interner::Interner;
 use crate::native::Callable;
+use crate::vm::SuspendedFrame;
 use crate::{interner::StrId, xprint};
+use indexmap::IndexMap;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use std::hash::BuildHasherDefault;
 use strum_macros::Display;
 
 #[derive(Debug, Display, Clone)]
 pub enum Value {
     Bool(bool),
     Number(f64),
+    Int(i64),
+    #[cfg(feature = "bigint")]
+    BigInt(Rc<crate::bigint::BigInt>), // Written like `123n`
     Str(StrId),
     Identifier(StrId),
     Array(Rc<RefCell<ValueArray>>),
+    Map(Rc<RefCell<ValueMap>>),
+    Set(Rc<RefCell<ValueSet>>),
     Function(usize),
     NativeFunction(Rc<dyn Callable>),
+    Module(usize),
+    Range(Range),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    ListCallback(Rc<ListCallback>), // `list.map` and other list methods that call back into the program
+    Enum(Rc<Enum>),
+    EnumMember(Rc<EnumMember>),
+    RecordType(Rc<RecordType>),
+    Record(Rc<Record>),
+    Generator(Rc<RefCell<Generator>>), // Returned by calling a function that contains `yield`
+    Channel(Rc<RefCell<ValueQueue>>),  // Values sent from one task to another, received in the order they were sent
+    Tuple(Rc<[Value]>),                // Immutable, compared by value
+    Spread(Rc<ValueArray>),            // Values of `...expr`, expanded by calls and array literals
     Nil,
 }
 
 pub type ValueArray = Vec<Value>;
+/// Fields of an instance or globals of a module. They keep their index while no entry is removed, which inline caches rely on.
+pub type SlotMap = IndexMap<StrId, Value, BuildHasherDefault<FxHasher>>;
+pub type ValueMap = FxHashMap<MapKey, Value>;
+pub type ValueSet = FxHashSet<MapKey>;
+pub type ValueQueue = VecDeque<Value>;
+
+/// Hashable key of a map or element of a set. Only strings and numbers can be used as keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Str(StrId),
+    Int(i64),    // Also used for whole floats, so that `m[1]` and `m[1.0]` are the same entry
+    Number(u64), // Bits of the f64
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> Option<MapKey> {
+        match value {
+            Value::Str(id) => Some(MapKey::Str(*id)),
+            Value::Int(i) => Some(MapKey::Int(*i)),
+            Value::Number(n) => match Value::from_f64(*n) {
+                Value::Int(i) => Some(MapKey::Int(i)),
+                _ => Some(MapKey::Number(n.to_bits())),
+            },
+            _ => None,
+        }
+    }
+
+    pub fn to_value(self) -> Value {
+        match self {
+            MapKey::Str(id) => Value::Str(id),
+            MapKey::Int(i) => Value::Int(i),
+            MapKey::Number(bits) => Value::Number(f64::from_bits(bits)),
+        }
+    }
+
+    /// Numbers by their value, then strings by their text
+    pub(crate) fn cmp_by_value(&self, other: &MapKey, interner: &Interner) -> Ordering {
+        match (self, other) {
+            (MapKey::Str(a), MapKey::Str(b)) => interner.lookup(a).cmp(interner.lookup(b)),
+            (MapKey::Str(_), _) => Ordering::Greater,
+            (_, MapKey::Str(_)) => Ordering::Less,
+            (MapKey::Int(a), MapKey::Int(b)) => a.cmp(b),
+            (a, b) => a
+                .to_value()
+                .as_number()
+                .unwrap_or(0.0)
+                .total_cmp(&b.to_value().as_number().unwrap_or(0.0)),
+        }
+    }
+}
+
+/// Keys of a map or elements of a set, in the order of `MapKey::cmp_by_value` rather than the order of the hash table
+pub(crate) fn sorted_keys<'a>(keys: impl Iterator<Item = &'a MapKey>, interner: &Interner) -> Vec<MapKey> {
+    let mut keys: Vec<MapKey> = keys.copied().collect();
+    keys.sort_by(|a, b| a.cmp_by_value(b, interner));
+    keys
+}
+
+impl Value {
+    /// The value of an `Int` or `Number` as a float
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Int(i) => Some(*i as f64),
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => Some(n.to_f64()),
+            _ => None,
+        }
+    }
+
+    /// Nil, false, zero and empty collections are falsey
+    pub fn is_falsey(&self) -> bool {
+        match self {
+            Value::Nil => true,
+            Value::Bool(b) => !b,
+            Value::Number(n) => (*n - 0.0).abs() < f64::EPSILON,
+            Value::Int(i) => *i == 0,
+            #[cfg(feature = "bigint")]
+            Value::BigInt(n) => n.is_zero(),
+            Value::Array(arr) => arr.borrow().is_empty(),
+            Value::Map(map) => map.borrow().is_empty(),
+            Value::Set(set) => set.borrow().is_empty(),
+            Value::Range(range) => range.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Like `==`, but arrays and maps are compared by their contents rather than by identity
+    pub fn deep_eq(&self, other: &Value) -> bool {
+        fn deep_eq(a: &Value, b: &Value, depth: usize) -> bool {
+            // Containers can hold themselves, so give up on cycles rather than recursing forever
+            if depth > 512 {
+                return false;
+            }
+            match (a, b) {
+                (Value::Array(a), Value::Array(b)) => {
+                    let (a, b) = (a.borrow(), b.borrow());
+                    a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| deep_eq(a, b, depth + 1))
+                }
+                (Value::Map(a), Value::Map(b)) => {
+                    let (a, b) = (a.borrow(), b.borrow());
+                    a.len() == b.len() && a.iter().all(|(key, a)| b.get(key).is_some_and(|b| deep_eq(a, b, depth + 1)))
+                }
+                (Value::Tuple(a), Value::Tuple(b)) => a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| deep_eq(a, b, depth + 1)),
+                (a, b) => a == b,
+            }
+        }
+        deep_eq(self, other, 0)
+    }
+
+    /// Name of the type as seen by programs. Unlike the variant name, all the kinds of numbers are "number" and all the
+    /// kinds of functions are "function".
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Number(_) | Value::Int(_) => "number",
+            #[cfg(feature = "bigint")]
+            Value::BigInt(_) => "number",
+            Value::Str(_) | Value::Identifier(_) => "string",
+            Value::Array(_) | Value::Spread(_) => "list",
+            Value::Map(_) => "map",
+            Value::Set(_) => "set",
+            Value::Tuple(_) => "tuple",
+            Value::Range(_) => "range",
+            Value::Function(_) | Value::NativeFunction(_) | Value::BoundMethod(_) | Value::ListCallback(_) => "function",
+            Value::Module(_) => "module",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::Enum(_) => "enum",
+            Value::EnumMember(_) => "enum member",
+            Value::RecordType(_) => "record type",
+            Value::Record(_) => "record",
+            Value::Generator(_) => "generator",
+            Value::Channel(_) => "channel",
+            Value::Nil => "nil",
+        }
+    }
+
+    /// Whole floats that fit in an `Int` become one, anything else stays a `Number`
+    pub fn from_f64(n: f64) -> Value {
+        if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+            Value::Int(n as i64)
+        } else {
+            Value::Number(n)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: StrId,
+    pub methods: RefCell<FxHashMap<StrId, usize>>, // Index of each method in the function list
+    pub getters: RefCell<FxHashMap<StrId, usize>>, // Methods run when reading a property
+    pub setters: RefCell<FxHashMap<StrId, usize>>, // Methods run when assigning to a property
+    pub statics: RefCell<FxHashMap<StrId, Value>>, // Static methods and fields
+}
+
+impl Class {
+    pub fn find_method(&self, name: StrId) -> Option<usize> {
+        self.methods.borrow().get(&name).copied()
+    }
+
+    pub fn find_getter(&self, name: StrId) -> Option<usize> {
+        self.getters.borrow().get(&name).copied()
+    }
+
+    pub fn find_setter(&self, name: StrId) -> Option<usize> {
+        self.setters.borrow().get(&name).copied()
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: SlotMap,
+}
+
+/// A method along with the instance it was accessed on
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: usize,
+}
+
+#[derive(Debug)]
+pub struct Enum {
+    pub name: StrId,
+    pub members: Vec<Value>, // EnumMember values, in declaration order
+}
+
+/// Members of an enum are only equal to themselves
+#[derive(Debug)]
+pub struct EnumMember {
+    pub enum_name: StrId,
+    pub name: StrId,
+    pub ordinal: usize,
+}
+
+/// List methods that take a function to call, so they are run by the VM rather than as natives
+#[derive(Debug, Clone, Copy, PartialEq, strum_macros::Display)]
+pub enum ListCallbackKind {
+    Map,
+    Filter,
+    Reduce,
+    Sort,
+}
+
+impl ListCallbackKind {
+    pub fn from_name(name: &str) -> Option<ListCallbackKind> {
+        match name {
+            "map" => Some(ListCallbackKind::Map),
+            "filter" => Some(ListCallbackKind::Filter),
+            "reduce" => Some(ListCallbackKind::Reduce),
+            "sort" => Some(ListCallbackKind::Sort),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ListCallback {
+    pub list: Rc<RefCell<ValueArray>>,
+    pub kind: ListCallbackKind,
+}
+
+/// Declared with `record Name(field, ...)`. Calling it creates a record with a value for each field.
+#[derive(Debug)]
+pub struct RecordType {
+    pub name: StrId,
+    pub fields: Vec<StrId>,
+}
+
+/// Immutable instance of a record type, compared by value
+#[derive(Debug)]
+pub struct Record {
+    pub typ: Rc<RecordType>,
+    pub values: Vec<Value>, // In the order of the fields of the type
+}
+
+impl Record {
+    pub fn get(&self, field: StrId) -> Option<&Value> {
+        let index = self.typ.fields.iter().position(|name| *name == field)?;
+        self.values.get(index)
+    }
+}
+
+/// Frame of a call to a function that contains `yield`, kept on the heap between the values it produces.
+/// A for-in loop resumes it each time it needs a value, and it runs until the next `yield`.
+#[derive(Debug)]
+pub struct Generator {
+    pub fun_idx: usize,
+    pub(crate) state: GeneratorState,
+}
+
+#[derive(Debug)]
+pub(crate) enum GeneratorState {
+    Suspended(SuspendedFrame),  // Not started yet, or stopped at a `yield`
+    Running { exit_ip: usize }, // Where the loop that resumed it continues once it finishes
+    Done,
+}
+
+/// Numbers from `start` up to `end`, in steps of 1. Created with `start..end` or `start..=end`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+
+impl Range {
+    pub fn len(&self) -> usize {
+        let span = self.end - self.start;
+        if span < 0.0 || (span == 0.0 && !self.inclusive) {
+            0
+        } else if self.inclusive {
+            span.floor() as usize + 1
+        } else {
+            span.ceil() as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number at the given position in the range
+    pub fn get(&self, index: usize) -> Option<f64> {
+        if index < self.len() {
+            Some(self.start + index as f64)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, number: f64) -> bool {
+        let offset = number - self.start;
+        offset >= 0.0 && offset.fract() == 0.0 && (offset as usize) < self.len()
+    }
+}
 
 pub fn print_value(value: &Value, interner: &Interner) {
     xprint!("{}", value_as_string(value, interner));
@@ -27,6 +351,9 @@ pub fn print_value(value: &Value, interner: &Interner) {
 pub fn value_as_string(value: &Value, interner: &Interner) -> String {
     match value {
         Value::Number(num) => format!("{num}"),
+        Value::Int(i) => format!("{i}"),
+        #[cfg(feature = "bigint")]
+        Value::BigInt(n) => format!("{n}"),
         Value::Bool(b) => format!("{b}"),
         Value::Nil => "Nil".to_string(),
         Value::Str(s) => interner.lookup(s).to_string(),
@@ -50,12 +377,105 @@ pub fn value_as_string(value: &Value, interner: &Interner) -> String {
             s.push_str("]>");
             s
         }
+        Value::Map(map) => {
+            let map = map.borrow();
+            let mut s = format!("Map<{} entries {{", map.len());
+            for (i, k) in sorted_keys(map.keys(), interner).into_iter().enumerate() {
+                if i != 0 {
+                    s.push_str(", ");
+                }
+
+                if i >= 10 {
+                    s.push_str("...");
+                    break;
+                }
+
+                s.push_str(&value_as_string(&k.to_value(), interner));
+                s.push_str(": ");
+                s.push_str(&value_as_string(&map[&k], interner));
+            }
+            s.push_str("}>");
+            s
+        }
+        Value::Set(set) => {
+            let set = set.borrow();
+            let mut s = format!("Set<{} elements {{", set.len());
+            for (i, element) in sorted_keys(set.iter(), interner).into_iter().enumerate() {
+                if i != 0 {
+                    s.push_str(", ");
+                }
+
+                if i >= 10 {
+                    s.push_str("...");
+                    break;
+                }
+
+                s.push_str(&value_as_string(&element.to_value(), interner));
+            }
+            s.push_str("}>");
+            s
+        }
         Value::Function(idx) => {
             format!("<Function {idx}>")
         }
         Value::NativeFunction(fun) => {
             format!("<Native Function {}>", fun.as_ref().name())
         }
+        Value::Module(idx) => {
+            format!("<Module {idx}>")
+        }
+        Value::Class(class) => {
+            format!("<Class {}>", interner.lookup(&class.name))
+        }
+        Value::Instance(instance) => {
+            format!("<{} instance>", interner.lookup(&instance.borrow().class.name))
+        }
+        Value::ListCallback(method) => {
+            format!("<Method {}>", method.kind.to_string().to_lowercase())
+        }
+        Value::BoundMethod(bound) => {
+            format!("<Method {}>", bound.method)
+        }
+        Value::Enum(enm) => {
+            format!("<Enum {}>", interner.lookup(&enm.name))
+        }
+        Value::EnumMember(member) => {
+            format!("{}.{}", interner.lookup(&member.enum_name), interner.lookup(&member.name))
+        }
+        Value::RecordType(typ) => {
+            format!("<Record {}>", interner.lookup(&typ.name))
+        }
+        Value::Record(record) => {
+            let fields: Vec<String> = record
+                .typ
+                .fields
+                .iter()
+                .zip(&record.values)
+                .map(|(field, value)| format!("{}: {}", interner.lookup(field), value_as_string(value, interner)))
+                .collect();
+            format!("{}({})", interner.lookup(&record.typ.name), fields.join(", "))
+        }
+        Value::Tuple(elements) => {
+            let elements: Vec<String> = elements.iter().map(|element| value_as_string(element, interner)).collect();
+            if elements.len() == 1 {
+                format!("({},)", elements[0])
+            } else {
+                format!("({})", elements.join(", "))
+            }
+        }
+        Value::Generator(generator) => {
+            format!("<Generator {}>", generator.borrow().fun_idx)
+        }
+        Value::Channel(queue) => {
+            format!("<Channel with {} values>", queue.borrow().len())
+        }
+        Value::Spread(values) => {
+            format!("<Spread of {} values>", values.len())
+        }
+        Value::Range(range) => {
+            let operator = if range.inclusive { "..=" } else { ".." };
+            format!("{}{operator}{}", range.start, range.end)
+        }
     }
 }
 
@@ -65,10 +485,32 @@ impl PartialEq<Value> for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
             (Number(a), Number(b)) => (a - b).abs() < f64::EPSILON,
+            (Int(a), Int(b)) => a == b,
+            (Int(a), Number(b)) | (Number(b), Int(a)) => (*a as f64 - b).abs() < f64::EPSILON,
+            #[cfg(feature = "bigint")]
+            (BigInt(a), BigInt(b)) => a == b,
+            #[cfg(feature = "bigint")]
+            (BigInt(a), Int(b)) | (Int(b), BigInt(a)) => a.to_i64() == Some(*b),
+            #[cfg(feature = "bigint")]
+            (BigInt(a), Number(b)) | (Number(b), BigInt(a)) => a.to_f64() == *b,
             (Bool(a), Bool(b)) => a == b,
             (Str(a), Str(b)) => a == b,
             (Nil, Nil) => true,
             (Array(a), Array(b)) => Rc::ptr_eq(a, b),
+            (Map(a), Map(b)) => Rc::ptr_eq(a, b),
+            (Set(a), Set(b)) => Rc::ptr_eq(a, b) || *a.borrow() == *b.borrow(),
+            (Module(a), Module(b)) => a == b,
+            (Range(a), Range(b)) => a == b,
+            (Tuple(a), Tuple(b)) => a == b,
+            (Class(a), Class(b)) => Rc::ptr_eq(a, b),
+            (Instance(a), Instance(b)) => Rc::ptr_eq(a, b),
+            (Enum(a), Enum(b)) => Rc::ptr_eq(a, b),
+            (EnumMember(a), EnumMember(b)) => Rc::ptr_eq(a, b),
+            (RecordType(a), RecordType(b)) => Rc::ptr_eq(a, b),
+            (Record(a), Record(b)) => Rc::ptr_eq(&a.typ, &b.typ) && a.values == b.values,
+            (Generator(a), Generator(b)) => Rc::ptr_eq(a, b),
+            (Channel(a), Channel(b)) => Rc::ptr_eq(a, b),
+            (BoundMethod(a), BoundMethod(b)) => a.method == b.method && a.receiver == b.receiver,
             _ => false,
         }
     }
@@ -0,0 +1,321 @@
+//! Verification of bytecode, so that a corrupted or hand-written chunk is reported as an error instead of making the VM
+//! panic or read out of bounds. The VM trusts its code, since the compiler only writes valid code.
+//!
+//! `verify_chunk` checks what the chunk alone tells: that each instruction is a valid opcode with its operands inside
+//! the code, that constant indices are in bounds and of the kind the instruction needs, and that jumps go to where
+//! an instruction starts. `verify_function` also follows the code from its start to check that the stack has the
+//! same height on every path to an instruction, that instructions don't pop more than it has, that local variables
+//! are in it, and that the code doesn't run past its end. It checks the parameters of the function too, which the VM
+//! relies on when calling it. The kinds of the values on the stack aren't followed, so the VM reports an instruction
+//! given the wrong kind of value as a runtime error.
+
+use crate::{chunk::Chunk, common::Opcode, fun::Fun, superinstruction::is_fusable_operator, value::Value};
+use std::{collections::HashMap, fmt};
+
+/// Problem that makes code unsafe to run
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub offset: usize, // Offset of the instruction with the problem
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid bytecode at offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+fn error<T>(offset: usize, message: impl Into<String>) -> Result<T, VerifyError> {
+    Err(VerifyError {
+        offset,
+        message: message.into(),
+    })
+}
+
+/// Check the instructions of the chunk, and return them with where they start
+pub fn verify_chunk(chunk: &Chunk) -> Result<Vec<(usize, Opcode)>, VerifyError> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let Ok(instruction) = Opcode::try_from(chunk.code[offset]) else {
+            return error(offset, format!("{} is not an opcode", chunk.code[offset]));
+        };
+        if offset + instruction.size() > chunk.code.len() {
+            return error(offset, format!("{instruction} ends after the code"));
+        }
+        instructions.push((offset, instruction));
+        offset += instruction.size();
+    }
+
+    let starts: HashMap<usize, Opcode> = instructions.iter().copied().collect();
+    for &(offset, instruction) in &instructions {
+        check_operands(chunk, offset, instruction)?;
+        for target in jump_targets(chunk, offset, instruction)? {
+            if !starts.contains_key(&target) {
+                return error(offset, format!("{instruction} jumps to {target}, where no instruction starts"));
+            }
+        }
+    }
+    Ok(instructions)
+}
+
+/// Check the code of the function, in a program with `functions` functions and `modules` modules
+pub fn verify_function(fun: &Fun, functions: usize, modules: usize) -> Result<(), VerifyError> {
+    let chunk = &fun.chunk;
+    let instructions = verify_chunk(chunk)?;
+    if fun.module >= modules {
+        return error(0, format!("Function is in module {}, which is out of bounds", fun.module));
+    }
+    check_parameters(fun)?;
+    for constant in &chunk.constants {
+        match *constant {
+            Value::Function(index) if index >= functions => return error(0, format!("Constant of function {index} is out of bounds")),
+            Value::Module(index) if index >= modules => return error(0, format!("Constant of module {index} is out of bounds")),
+            _ => {}
+        }
+    }
+    if instructions.is_empty() {
+        return error(0, "Function has no code");
+    }
+
+    let index_of: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, &(offset, _))| (offset, index))
+        .collect();
+    let mut heights: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut pending = vec![(0, fun.arity + fun.is_method as usize)];
+    while let Some((index, height)) = pending.pop() {
+        let (offset, instruction) = instructions[index];
+        match heights[index] {
+            Some(known) if known == height => continue,
+            Some(known) => {
+                return error(
+                    offset,
+                    format!("Stack has {known} values on one path to {instruction} and {height} on another"),
+                )
+            }
+            None => heights[index] = Some(height),
+        }
+
+        let (pops, pushes) = stack_effect(chunk, offset, instruction);
+        if pops > height {
+            return error(offset, format!("{instruction} pops {pops} values from a stack of {height}"));
+        }
+        for slot in local_slots(chunk, offset, instruction) {
+            // Reading a local variable pops the index before it
+            let locals = if matches!(instruction, Opcode::GetLocal | Opcode::SetLocal) {
+                height - 1
+            } else {
+                height
+            };
+            if slot >= locals {
+                return error(offset, format!("{instruction} uses local slot {slot} of a stack of {height}"));
+            }
+        }
+        let after = height - pops + pushes;
+
+        let targets = jump_targets(chunk, offset, instruction)?;
+        let next = match instruction.short() {
+            Opcode::Jump | Opcode::Loop | Opcode::Return | Opcode::Throw => None,
+            _ => Some(index + 1),
+        };
+        let target_heights: Vec<usize> = match instruction.short() {
+            // An exception goes to the catch block on the stack of the try block, and finally blocks also get how they
+            // were entered
+            Opcode::PushHandler => handler_targets(chunk, offset, instruction)
+                .map(|(_, extra)| height + extra)
+                .collect(),
+            // The loop ends without pushing the next element
+            Opcode::IterNext => vec![height],
+            _ => vec![after; targets.len()],
+        };
+        for (target, target_height) in targets.into_iter().zip(target_heights) {
+            pending.push((index_of[&target], target_height));
+        }
+        if let Some(next) = next {
+            if next == instructions.len() {
+                return error(offset, "Code runs past its end");
+            }
+            pending.push((next, after));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the function has at most 255 parameters, with a name for each, and that those without a default value
+/// and a rest parameter are among them
+fn check_parameters(fun: &Fun) -> Result<(), VerifyError> {
+    if fun.arity > u8::MAX as usize {
+        return error(0, format!("Function has {} parameters, but at most 255 can be passed", fun.arity));
+    }
+    if fun.min_arity > fun.arity {
+        let (min_arity, arity) = (fun.min_arity, fun.arity);
+        return error(0, format!("Function needs {min_arity} arguments, but has {arity} parameters"));
+    }
+    if fun.is_variadic && fun.arity == 0 {
+        return error(0, "Function has a rest parameter, but no parameters");
+    }
+    if fun.param_names.len() != fun.arity {
+        let (arity, names) = (fun.arity, fun.param_names.len());
+        return error(0, format!("Function has {arity} parameters, but {names} parameter names"));
+    }
+    Ok(())
+}
+
+/// Values the instruction pops, and then pushes. Superinstructions count as the instructions they replace.
+fn stack_effect(chunk: &Chunk, offset: usize, instruction: Opcode) -> (usize, usize) {
+    let count = || chunk.code[offset + 1] as usize;
+    match instruction.short() {
+        Opcode::Constant | Opcode::Nil | Opcode::True | Opcode::False | Opcode::Class => (0, 1),
+        Opcode::GetLocalPlain | Opcode::LocalConstantOperation | Opcode::LocalsOperation => (0, 1),
+        Opcode::Not
+        | Opcode::Negate
+        | Opcode::Spread
+        | Opcode::IterStart
+        | Opcode::DeclareArray
+        | Opcode::GetProperty
+        | Opcode::Import
+        | Opcode::GetLocal
+        | Opcode::GetGlobal
+        | Opcode::JumpIfFalse
+        | Opcode::JumpIfNil
+        | Opcode::JumpIfNotNil
+        | Opcode::ConstantOperation => (1, 1),
+        Opcode::Add
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Modulo
+        | Opcode::Divide
+        | Opcode::Equal
+        | Opcode::Greater
+        | Opcode::Less
+        | Opcode::In
+        | Opcode::Union
+        | Opcode::Intersection
+        | Opcode::Range
+        | Opcode::RangeInclusive
+        | Opcode::GetIndex
+        | Opcode::SetLocal
+        | Opcode::SetGlobal
+        | Opcode::SetProperty
+        | Opcode::StaticMember
+        | Opcode::Method
+        | Opcode::Getter
+        | Opcode::Setter => (2, 1),
+        Opcode::SetIndex => (3, 1),
+        Opcode::Print | Opcode::Pop | Opcode::DefineGlobal | Opcode::Yield | Opcode::Return | Opcode::Throw => (1, 0),
+        Opcode::EndFinally => (2, 0),
+        Opcode::IterNext => (0, 1),
+        Opcode::Call | Opcode::TailCall | Opcode::CallSpread | Opcode::CallNamed => (count() + 1, 1),
+        Opcode::BuildArray | Opcode::BuildTuple => (count(), 1),
+        Opcode::BuildMap => (2 * count(), 1),
+        Opcode::Jump | Opcode::Loop | Opcode::JumpIfArgPassed | Opcode::PushHandler | Opcode::PopHandler => (0, 0),
+        long => unreachable!("{long} is a long instruction"),
+    }
+}
+
+/// Slots of the local variables the instruction uses, relative to the frame
+fn local_slots(chunk: &Chunk, offset: usize, instruction: Opcode) -> Vec<usize> {
+    let code = &chunk.code[offset..offset + instruction.size()];
+    match instruction.short() {
+        Opcode::GetLocal | Opcode::SetLocal => vec![code[1] as usize],
+        // The index of the loop is in the slot after the iterable
+        Opcode::IterNext => vec![code[1] as usize + 1],
+        Opcode::GetLocalPlain | Opcode::LocalConstantOperation => vec![code[3] as usize],
+        Opcode::LocalsOperation => vec![code[3] as usize, code[7] as usize],
+        _ => Vec::new(),
+    }
+}
+
+/// Offsets the instruction can jump to, which have to be inside the code
+fn jump_targets(chunk: &Chunk, offset: usize, instruction: Opcode) -> Result<Vec<usize>, VerifyError> {
+    let targets: Vec<usize> = match instruction.short() {
+        Opcode::PushHandler => handler_targets(chunk, offset, instruction).map(|(target, _)| target).collect(),
+        _ => chunk.jump_targets(offset),
+    };
+    // Only loops jump backward, and the ones that would go before the start have no target
+    if instruction.short() == Opcode::Loop && targets.is_empty() {
+        return error(offset, format!("{instruction} jumps before the start of the code"));
+    }
+    match targets.iter().find(|&&target| target >= chunk.code.len()) {
+        Some(target) => error(offset, format!("{instruction} jumps to {target}, after the code")),
+        None => Ok(targets),
+    }
+}
+
+/// Catch and finally blocks of a handler, with how many values are pushed when they are entered. An offset of 0 is no
+/// block.
+fn handler_targets(chunk: &Chunk, offset: usize, instruction: Opcode) -> impl Iterator<Item = (usize, usize)> {
+    let width = if instruction.is_long() { 3 } else { 2 };
+    let jumps = instruction.jump_operands().iter().zip(chunk.jump_targets(offset));
+    jumps
+        .zip([1, 2])
+        .filter(move |&((&at, target), _)| target != offset + at + width)
+        .map(|((_, target), extra)| (target, extra))
+}
+
+/// Check that constant indices are in bounds and of the kind the instruction needs, and that superinstructions end with
+/// an operator
+fn check_operands(chunk: &Chunk, offset: usize, instruction: Opcode) -> Result<(), VerifyError> {
+    let code = &chunk.code[offset..offset + instruction.size()];
+    let constant = |at: usize, long: bool| {
+        let index = chunk.constant_index(offset + at, long);
+        match chunk.constants.get(index) {
+            Some(constant) => Ok(constant),
+            None => error(
+                offset,
+                format!("{instruction} uses constant {index}, but the chunk has {}", chunk.constants.len()),
+            ),
+        }
+    };
+    let long = instruction.is_long();
+    match instruction.short() {
+        Opcode::Constant => {
+            constant(1, long)?;
+        }
+        Opcode::DefineGlobal
+        | Opcode::GetGlobal
+        | Opcode::SetGlobal
+        | Opcode::GetProperty
+        | Opcode::SetProperty
+        | Opcode::Class
+        | Opcode::Method
+        | Opcode::Getter
+        | Opcode::Setter
+        | Opcode::StaticMember => {
+            let name = constant(1, long)?;
+            if !matches!(name, Value::Str(_) | Value::Identifier(_)) {
+                return error(offset, format!("{instruction} needs a name constant"));
+            }
+        }
+        Opcode::CallNamed => {
+            let Value::Array(names) = constant(2, long)? else {
+                return error(offset, format!("{instruction} needs an array of names"));
+            };
+            if names.borrow().iter().any(|name| !matches!(name, Value::Str(_))) {
+                return error(offset, format!("{instruction} needs an array of names"));
+            }
+        }
+        Opcode::ConstantOperation => {
+            constant(1, false)?;
+            check_operator(offset, code[2])?;
+        }
+        Opcode::LocalConstantOperation => {
+            constant(5, false)?;
+            check_operator(offset, code[6])?;
+        }
+        Opcode::LocalsOperation => check_operator(offset, code[8])?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_operator(offset: usize, byte: u8) -> Result<(), VerifyError> {
+    match is_fusable_operator(byte) {
+        true => Ok(()),
+        false => error(offset, format!("Superinstruction ends with {byte}, which is not an operator")),
+    }
+}
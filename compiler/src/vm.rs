@@ -1,61 +1,393 @@
-use std::rc::Rc;
-use std::{cell::RefCell, future::Future};
+use std::rc::{Rc, Weak};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
+    chunk::InlineCache,
     common::Opcode,
-    dbgln,
+    compiler::INIT_METHOD,
+    coverage::{Coverage, CoverageCounter},
     debug::disassemble_instruction,
+    debugger::{DebugEvent, Debugger, StepMode, Variable},
+    error::{RuntimeError, RuntimeErrorKind, TraceFrame},
     fun::Fun,
+    gc,
+    heap::{HeapSnapshot, SnapshotBuilder},
     interner::{Interner, StrId},
+    module::{Module, MAIN_MODULE},
     native::*,
+    profile::{ProfileReport, Profiler, PROFILE_SAMPLE_INTERVAL},
+    register::{Instruction, RegisterFunction},
+    scanner::Span,
+    stats::{StatsCounter, VmStats},
+    testing::{split_line, TestOutcome, TestReport, TestResult},
     value::{
-        print_value,
+        print_value, sorted_keys, value_as_string, Generator, GeneratorState, ListCallback, ListCallbackKind, MapKey,
         Value::{self, *},
+        ValueArray, ValueMap, ValueQueue,
     },
+    verify::verify_function,
 };
-use anyhow::*;
-use rustc_hash::FxHashMap;
+use anyhow::{bail, Context, Error, Result};
 
 #[allow(unused_imports)]
 use crate::{xprint, xprintln};
 
-#[derive(Debug, Default, Clone, Copy)]
+/// Set of parameters that the caller passed an argument for (functions have at most 256 parameters)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ArgSet([u64; 4]);
+
+impl ArgSet {
+    fn first(count: usize) -> ArgSet {
+        let mut set = ArgSet::default();
+        for param in 0..count {
+            set.insert(param);
+        }
+        set
+    }
+
+    fn insert(&mut self, param: usize) {
+        self.0[param / 64] |= 1 << (param % 64);
+    }
+
+    fn contains(&self, param: usize) -> bool {
+        self.0[param / 64] & (1 << (param % 64)) != 0
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 struct CallFrame {
     pub fun_idx: usize,
     pub ip: usize,
-    pub start_len: usize,   // Length of the stack before this frame
-    pub slot_offset: usize, // Offset of this call-frame from the base of the stack
+    pub start_len: usize,    // Length of the stack before this frame
+    pub slot_offset: usize,  // Offset of this call-frame from the base of the stack
+    pub passed_args: ArgSet, // Parameters without an argument get their default value
+    pub module: usize,       // Module whose globals this frame uses
+}
+
+/// An exception handler, pushed by a `try` block
+#[derive(Debug, Clone, Copy)]
+struct Handler {
+    frame_count: usize, // Number of call frames when the handler was pushed
+    stack_len: usize,   // Length of the stack when the handler was pushed
+    catch_ip: Option<usize>,
+    finally_ip: Option<usize>,
+}
+
+/// Frame of a generator that is not running, with the values in its slots and the handlers of its `try` blocks.
+/// Handlers keep the height of the stack relative to the first slot, since the frame is resumed higher or lower.
+#[derive(Debug)]
+pub(crate) struct SuspendedFrame {
+    frame: CallFrame,
+    pub slots: Vec<Value>,
+    handlers: Vec<Handler>,
+}
+
+/// Change of a vector from one checkpoint of the history to the next: the elements after the first `kept`,
+/// as they were at the earlier checkpoint
+#[derive(Debug)]
+struct Delta<T> {
+    kept: usize,
+    elements: Vec<T>,
+}
+
+impl<T> Default for Delta<T> {
+    fn default() -> Delta<T> {
+        Delta {
+            kept: 0,
+            elements: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> Delta<T> {
+    /// Delta from the `recorded` vector to the current one, which is recorded in its place
+    fn record(recorded: &mut Vec<T>, current: &[T]) -> Delta<T> {
+        let kept = recorded.iter().zip(current).take_while(|(a, b)| a == b).count();
+        let elements = recorded.split_off(kept);
+        recorded.extend_from_slice(&current[kept..]);
+        Delta { kept, elements }
+    }
+
+    fn undo(self, current: &mut Vec<T>) {
+        current.truncate(self.kept);
+        current.extend(self.elements);
+    }
+}
+
+/// State of the program where a line started, which the debugger can step back to.
+/// The deltas and the assigned globals are filled in when the next line starts.
+#[derive(Debug)]
+struct Checkpoint {
+    handlers: Vec<Handler>,
+    frames: Delta<CallFrame>,
+    stack: Delta<Value>,
+    globals: Vec<(usize, StrId, Option<Value>)>, // Globals assigned before the next line, with their earlier values
+}
+
+/// Checkpoints of the lines that ran last, most recent last, so the debugger can step back
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    checkpoints: VecDeque<Checkpoint>,
+    limit: usize,           // Checkpoints kept, 0 when not recording
+    frames: Vec<CallFrame>, // Frames at the last checkpoint
+    stack: Vec<Value>,      // Stack at the last checkpoint
+}
+
+impl History {
+    /// Forget the checkpoints, like when tasks switch or generators move frames off the stack, which deltas can't undo
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+        self.frames.clear();
+        self.stack.clear();
+    }
+
+    /// Values the checkpoints keep, which are roots for the collector since stepping back brings them back
+    fn values(&self) -> impl Iterator<Item = &Value> {
+        let checkpoints = self.checkpoints.iter().flat_map(|checkpoint| {
+            let globals = checkpoint.globals.iter().filter_map(|(_, _, value)| value.as_ref());
+            checkpoint.stack.elements.iter().chain(globals)
+        });
+        self.stack.iter().chain(checkpoints)
+    }
+}
+
+/// A task that is waiting for its turn, with its own stack, call frames and exception handlers
+struct Task {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    is_main: bool,                              // Runs the main script, rather than a function passed to `Spawn`
+    receiving: Option<Rc<RefCell<ValueQueue>>>, // Channel it waits for, with the call to `Receive` still on its stack
 }
 
+impl Task {
+    fn can_run(&self) -> bool {
+        self.receiving.as_ref().is_none_or(|queue| !queue.borrow().is_empty())
+    }
+}
+
+/// The error is the value being thrown. For runtime errors, this is the error message.
+type ThrowResult<T> = Result<T, Value>;
+
+// How a `finally` block was entered. Stored on the stack, below the value being thrown or returned.
+pub(crate) const COMPLETION_NORMAL: f64 = 0.0;
+pub(crate) const COMPLETION_THROW: f64 = 1.0;
+pub(crate) const COMPLETION_RETURN: f64 = 2.0;
+
 pub const ERR_STRING: &str = "errString";
 
+// Special methods that classes can define to overload operators
+const ADD_METHOD: &str = "__add";
+const SUB_METHOD: &str = "__sub";
+const MUL_METHOD: &str = "__mul";
+const DIV_METHOD: &str = "__div";
+const MOD_METHOD: &str = "__mod";
+const EQ_METHOD: &str = "__eq";
+const LT_METHOD: &str = "__lt";
+const GT_METHOD: &str = "__gt";
+const NEG_METHOD: &str = "__neg";
+const GET_METHOD: &str = "__get"; // instance[index]
+const SET_METHOD: &str = "__set"; // instance[index] = value
+
+/// Calls that can be nested before a stack overflow error, unless `VmOptions::max_call_depth` says otherwise
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Loop iterations and calls a task runs before the next task takes its turn
+const TASK_TURN: u32 = 1000;
+
+/// Calls from natives back into the program, like `map` callbacks, that can be nested.
+/// Each uses tens of kilobytes of host stack in debug builds, and wasm only has one megabyte.
+const MAX_NESTED_CALLS: usize = 128;
+
+/// Options for how the VM runs programs, set with `Vm::configure`
+#[derive(Debug, Clone)]
+pub struct VmOptions {
+    /// Collect garbage after every instruction that allocated a container, instead of once enough have been allocated.
+    /// This is slow, but makes a container the collector misses get cleared right away.
+    pub gc_stress: bool,
+    /// Before each collection, check that no container cleared by an earlier one is reachable, and panic if one is.
+    pub gc_verify: bool,
+    /// Stop with `RuntimeErrorKind::ExecutionLimitExceeded` after running this many instructions, for programs that might not finish
+    pub max_instructions: Option<u64>,
+    /// Stop with `RuntimeErrorKind::Cancelled` once the token is cancelled, which is checked every `CANCEL_CHECK_INTERVAL` instructions
+    pub cancel_token: Option<CancellationToken>,
+    /// Throw an out of memory runtime error when interned strings and containers take more than this many bytes.
+    /// The size of containers is estimated when garbage is collected, which happens more often with a limit.
+    pub max_heap_bytes: Option<usize>,
+    /// Throw a stack overflow runtime error when a call would nest more deeply than this
+    pub max_call_depth: usize,
+    /// Count the calls of each function and sample which ones are running, for `Vm::profile_report`
+    pub profile: bool,
+    /// Count the instructions that run and the constants they read, for `Vm::stats`
+    pub collect_stats: bool,
+    /// Disassemble the bytecode of every function before the main script runs
+    pub print_code: bool,
+    /// Print the stack and each instruction before it runs
+    pub trace_execution: bool,
+    /// Mark the lines whose instructions run, for `Vm::coverage`
+    pub coverage: bool,
+    /// Make runs reproducible: seed the random natives with this, run the time natives on a clock that advances with
+    /// each instruction, and iterate over maps and sets in the order of their keys
+    pub deterministic: Option<u64>,
+    /// Remember where each `GetGlobal`, `SetGlobal` and `GetProperty` found its variable, so it can skip the lookup next time
+    pub inline_caches: bool,
+}
+
+impl Default for VmOptions {
+    fn default() -> VmOptions {
+        VmOptions {
+            gc_stress: false,
+            gc_verify: false,
+            max_instructions: None,
+            cancel_token: None,
+            max_heap_bytes: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            profile: false,
+            collect_stats: false,
+            print_code: false,
+            trace_execution: false,
+            coverage: false,
+            deterministic: None,
+            inline_caches: true,
+        }
+    }
+}
+
+/// How many instructions run between checks of `VmOptions::cancel_token`. A power of two.
+pub const CANCEL_CHECK_INTERVAL: u64 = 1024;
+
+/// With `VmOptions::max_heap_bytes`, the fewest instructions between collections that measure the heap.
+/// Collections also wait for as many instructions as there are containers, so measuring takes constant time on average.
+const HEAP_CHECK_INTERVAL: u64 = 4096;
+
+/// Shared flag that the host sets to stop a running program, for example from a "Stop" button or another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What a VM keeps between runs, so that a `Session` can run more code with the same globals and modules
+pub(crate) struct VmState {
+    pub functions: Vec<Fun>,
+    pub modules: Vec<Module>,
+    builtins: Globals,
+    globals: Vec<Option<Globals>>,
+    prng: SharedPrng,
+    determinism: SharedDeterminism,
+    options: VmOptions,
+    gc_id: usize,
+    verified: usize,
+}
+
+impl VmState {
+    pub fn new(functions: Vec<Fun>, modules: Vec<Module>) -> VmState {
+        VmState {
+            functions,
+            modules,
+            builtins: Default::default(),
+            globals: Vec::new(),
+            prng: Rc::new(RefCell::new(Prng::new(rand::random()))),
+            determinism: Rc::default(),
+            options: VmOptions::default(),
+            gc_id: gc::new_vm_id(),
+            verified: 0,
+        }
+    }
+}
+
 pub struct Vm<'src, F, Fut>
 where
     F: Fn(String) -> Fut,
     Fut: Future<Output = String>,
 {
     frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
     functions: Vec<Fun>,
     stack: Vec<Value>,
     interner: &'src mut Interner,
-    globals: FxHashMap<StrId, Value>,
-    global_error_id: StrId, // StrId of global error variable
+    modules: Vec<Module>,
+    builtins: Globals,              // Native functions, available in every module
+    globals: Vec<Option<Globals>>,  // Globals of each module, None until the module is imported
+    global_error_id: StrId,         // StrId of global error variable
+    init_name: StrId,               // StrId of the initializer method name
+    prng: SharedPrng,               // Random numbers for the natives, which can be seeded
+    determinism: SharedDeterminism, // Virtual clock of deterministic runs
     read_async: F,
+    options: VmOptions,
+    fuel: u64,                                // Instructions left before `max_instructions` is reached
+    cancelled: bool,                          // Whether the fuel ran out because the program was cancelled
+    container_bytes: usize,                   // Size of the live containers at the last collection
+    collected_at: u64,                        // Fuel left at the last collection
+    gc_id: usize,                             // Owner of the containers this VM creates, for the garbage collector
+    thrown: Option<(Value, Vec<TraceFrame>)>, // Exception being unwound, with the frames from where it was thrown
+    nested_calls: usize,                      // Depth of `call_and_wait`, during which natives hold values the collector can't see
+    tasks: VecDeque<Task>,                    // Tasks other than the running one, in the order they take turns
+    task_is_main: bool,                       // Whether the running task runs the main script
+    turn_left: u32,                           // Loop iterations and calls before the running task lets the next one run
+    main_result: Option<Value>,               // What the main script returned, once it finished before other tasks
+    debugger: Debugger,                       // Breakpoints and stepping, see `start_debugging`
+    profiler: Option<Profiler>,               // Samples of the last run, with `VmOptions::profile`
+    stats: Option<StatsCounter>,              // Counts of the last run, with `VmOptions::collect_stats`
+    coverage: Option<CoverageCounter>,        // Instructions that ran in the last run, with `VmOptions::coverage`
+    verified: usize,                          // Functions whose code has been verified, see `verify_functions`
 }
 
 macro_rules! binop {
-    ($vm: ident, $typ: tt, $op: tt) => {
-        {
+    ($vm: ident, $op: expr, $method: expr) => {
+        if !$vm.call_special_method($method, 1)? {
             let b = $vm.pop_unchecked();
             let a = $vm.pop_unchecked();
-            match (a, b) {
-                (Number(a), Number(b)) => {
-                    let result = a $op b;
-                    $vm.stack.push($typ(result));
-                },
-                (first, second)=> { $vm.runtime_error(&format!("Operands must be numbers, but got {first} and {second}")); }
+            match arithmetic($op, &a, &b) {
+                Some(result) => $vm.stack.push(result),
+                None => {
+                    return Err($vm.runtime_error(&format!("Operands must be numbers, but got {a} and {b}")));
+                }
             }
+        }
+    };
+}
 
+/// Like `binop!`, but strings are also compared, lexicographically
+macro_rules! comparison {
+    ($vm: ident, $op: tt, $method: expr) => {
+        if !$vm.call_special_method($method, 1)? {
+            let b = $vm.pop_unchecked();
+            let a = $vm.pop_unchecked();
+            match (a, b) {
+                (Int(a), Int(b)) => $vm.stack.push(Bool(a $op b)),
+                #[cfg(feature = "bigint")]
+                (a @ (BigInt(_) | Int(_)), b @ (BigInt(_) | Int(_))) => $vm.stack.push(Bool(to_bigint(&a).unwrap() $op to_bigint(&b).unwrap())),
+                #[cfg(feature = "bigint")]
+                (a @ (BigInt(_) | Number(_)), b @ (BigInt(_) | Number(_))) => $vm.stack.push(Bool(a.as_number() $op b.as_number())),
+                (a @ (Int(_) | Number(_)), b @ (Int(_) | Number(_))) => $vm.stack.push(Bool(a.as_number() $op b.as_number())),
+                (Str(a), Str(b)) => {
+                    let result = $vm.interner.lookup(&a) $op $vm.interner.lookup(&b);
+                    $vm.stack.push(Bool(result));
+                }
+                (first, second) => {
+                    return Err($vm.runtime_error(&format!("Can only compare two numbers or two strings, but got {first} and {second}")));
+                }
+            }
         }
     };
 }
@@ -75,13 +407,141 @@ macro_rules! frame_mut {
 macro_rules! register_native {
     ($vm: ident, $name: ident) => {
         let name = $vm.interner.intern(stringify!($name));
-        dbgln!("Registering native function {}", stringify!($name));
-        $vm.globals.insert(name, Value::NativeFunction(Rc::new($name)));
+        $vm.builtins.insert(name, Value::NativeFunction(Rc::new($name)));
+    };
+    ($vm: ident, $name: ident { $($field: ident: $value: expr),* }) => {
+        let name = $vm.interner.intern(stringify!($name));
+        $vm.builtins.insert(name, Value::NativeFunction(Rc::new($name { $($field: $value),* })));
+    };
+}
+
+/// Whether the instruction is run by `Vm::run_call_instruction`
+fn awaits(instruction: &Opcode) -> bool {
+    matches!(instruction, Opcode::Call | Opcode::TailCall | Opcode::CallSpread | Opcode::Import)
+}
+
+/// Arithmetic on numbers. Operations on two integers give an integer, unless the result overflows, in which case it is
+/// promoted to a float. Mixing integers and floats gives a float.
+/// Dividing two integers gives an integer only if the division is exact, so `6 / 3` is `2` but `7 / 2` is `3.5`.
+/// Integer division and remainder by zero are done on floats, giving infinity or NaN.
+/// With the `bigint` feature, overflowing integers are promoted to big integers instead.
+pub(crate) fn arithmetic(op: Opcode, a: &Value, b: &Value) -> Option<Value> {
+    #[cfg(feature = "bigint")]
+    if let Some(result) = bigint_arithmetic(&op, a, b) {
+        return Some(result);
+    }
+
+    if let (Int(a), Int(b)) = (a, b) {
+        let (a, b) = (*a, *b);
+        let result = match op {
+            Opcode::Add => a.checked_add(b),
+            Opcode::Subtract => a.checked_sub(b),
+            Opcode::Multiply => a.checked_mul(b),
+            Opcode::Divide if a.checked_rem(b) == Some(0) => a.checked_div(b),
+            Opcode::Divide => None,
+            Opcode::Modulo => a.checked_rem(b),
+            _ => unreachable!("{op} is not an arithmetic operation"),
+        };
+
+        if let Some(result) = result {
+            return Some(Int(result));
+        }
+
+        #[cfg(feature = "bigint")]
+        return bigint_arithmetic(&op, &BigInt(Rc::new(crate::bigint::BigInt::from_i64(a))), &Int(b));
+    }
+
+    let (a, b) = (a.as_number()?, b.as_number()?);
+    let result = match op {
+        Opcode::Add => a + b,
+        Opcode::Subtract => a - b,
+        Opcode::Multiply => a * b,
+        Opcode::Divide => a / b,
+        Opcode::Modulo => a % b,
+        _ => unreachable!("{op} is not an arithmetic operation"),
+    };
+    Some(Number(result))
+}
+
+/// The value of an `Int` or `BigInt` as a big integer
+#[cfg(feature = "bigint")]
+fn to_bigint(value: &Value) -> Option<crate::bigint::BigInt> {
+    match value {
+        BigInt(n) => Some((**n).clone()),
+        Int(i) => Some(crate::bigint::BigInt::from_i64(*i)),
+        _ => None,
+    }
+}
+
+/// Arithmetic where at least one operand is a big integer and the other is an integer, with the same
+/// division semantics as integers. Returns None for other operands.
+#[cfg(feature = "bigint")]
+fn bigint_arithmetic(op: &Opcode, a: &Value, b: &Value) -> Option<Value> {
+    if !matches!(a, BigInt(_)) && !matches!(b, BigInt(_)) {
+        return None;
+    }
+
+    let (a, b) = (to_bigint(a)?, to_bigint(b)?);
+    let result = match op {
+        Opcode::Add => &a + &b,
+        Opcode::Subtract => &a - &b,
+        Opcode::Multiply => &a * &b,
+        Opcode::Divide => match a.div_rem(&b) {
+            Some((quotient, remainder)) if remainder.is_zero() => quotient,
+            _ => return Some(Number(a.to_f64() / b.to_f64())),
+        },
+        Opcode::Modulo => match a.div_rem(&b) {
+            Some((_, remainder)) => remainder,
+            None => return Some(Number(f64::NAN)),
+        },
+        _ => unreachable!("{op} is not an arithmetic operation"),
+    };
+    Some(BigInt(Rc::new(result)))
+}
+
+/// Convert a string index to a character position
+fn char_index(index: f64, len: usize) -> Result<usize> {
+    if index < 0.0 || index.fract() != 0.0 {
+        bail!("String index must be a non-negative integer, got {index}");
+    }
+    if index as usize > len {
+        bail!("String index out of range: {index} (length {len})");
+    }
+    Ok(index as usize)
+}
+
+/// Index a string by character (not byte), or slice it with a range
+fn string_index(string: &str, index: &Value) -> Result<String> {
+    let len = string.chars().count();
+    let (start, end) = match index {
+        Value::Number(index) => {
+            let start = char_index(*index, len)?;
+            if start == len {
+                bail!("String index out of range: {index} (length {len})");
+            }
+            (start, start + 1)
+        }
+        Value::Range(range) => {
+            let start = char_index(range.start, len)?;
+            let end = char_index(if range.inclusive { range.end + 1.0 } else { range.end }, len)?;
+            (start, end.max(start))
+        }
+        other => bail!("Strings can only be indexed with numbers and ranges, got {other}"),
     };
+
+    Ok(string.chars().skip(start).take(end - start).collect())
+}
+
+/// Integer indices are handled like whole floats
+fn index_as_number(index: &Value) -> Value {
+    match index {
+        Value::Int(i) => Value::Number(*i as f64),
+        other => other.clone(),
+    }
 }
 
-fn get_array(arr: &Value, index: &Value) -> anyhow::Result<Value, Error> {
-    match (arr, index) {
+fn get_index(container: &Value, index: &Value, interner: &mut Interner) -> anyhow::Result<Value, Error> {
+    match (container, &index_as_number(index)) {
         (Value::Array(array), Value::Number(index)) => {
             let index = *index as usize;
             if index < array.borrow().len() {
@@ -90,14 +550,28 @@ fn get_array(arr: &Value, index: &Value) -> anyhow::Result<Value, Error> {
                 bail!("Index out of bounds: {index}")
             }
         }
-        (arr, index) => {
-            bail!(format!("Tried to index value of type {arr} with index {index}"));
+        (Value::Map(map), key) => {
+            let Some(key) = MapKey::from_value(key) else {
+                bail!("Map keys must be strings or numbers, got {key}");
+            };
+            Ok(map.borrow().get(&key).cloned().unwrap_or(Value::Nil))
+        }
+        (Value::Tuple(elements), Value::Number(index)) => match elements.get(*index as usize) {
+            Some(element) if index.fract() == 0.0 && *index >= 0.0 => Ok(element.clone()),
+            _ => bail!("Tuple index out of bounds: {index}"),
+        },
+        (Value::Str(id), index) => {
+            let substring = string_index(interner.lookup(id), index)?;
+            Ok(Value::Str(interner.intern(&substring)))
+        }
+        (container, index) => {
+            bail!(format!("Tried to index value of type {container} with index {index}"));
         }
     }
 }
 
-fn set_array(arr: &mut Value, index: &Value, new_value: Value) -> anyhow::Result<(), Error> {
-    match (arr, index) {
+fn set_index(container: &mut Value, index: &Value, new_value: Value) -> anyhow::Result<(), Error> {
+    match (container, &index_as_number(index)) {
         (Value::Array(array), Value::Number(index)) => {
             let index = *index as usize;
             if index < array.borrow().len() {
@@ -107,8 +581,16 @@ fn set_array(arr: &mut Value, index: &Value, new_value: Value) -> anyhow::Result
                 bail!("Index out of bounds: {index}")
             }
         }
-        (arr, index) => {
-            bail!(format!("Tried to index value of type {arr} with index {index}"));
+        (Value::Map(map), key) => {
+            let Some(key) = MapKey::from_value(key) else {
+                bail!("Map keys must be strings or numbers, got {key}");
+            };
+            map.borrow_mut().insert(key, new_value);
+            Ok(())
+        }
+        (Value::Tuple(_), _) => bail!("Tuples are immutable"),
+        (container, index) => {
+            bail!(format!("Tried to index value of type {container} with index {index}"));
         }
     }
 }
@@ -118,26 +600,236 @@ where
     F: Fn(String) -> Fut,
     Fut: Future<Output = String>,
 {
-    pub fn new(interner: &'src mut Interner, functions: Vec<Fun>, read_async: F) -> Vm<F, Fut> {
+    /// Create a VM for the program whose main script is the last of the functions
+    pub fn new(interner: &'src mut Interner, functions: Vec<Fun>, modules: Vec<Module>, read_async: F) -> Vm<'src, F, Fut> {
+        let mut vm = Vm::from_state(interner, VmState::new(functions, modules), read_async);
+        vm.register_builtins();
+        vm
+    }
+
+    /// Create a VM that continues with the state another one left with `into_state`
+    pub(crate) fn from_state(interner: &'src mut Interner, state: VmState, read_async: F) -> Vm<'src, F, Fut> {
         let global_error_id = interner.intern(ERR_STRING);
+        let init_name = interner.intern(INIT_METHOD);
 
-        let mut frames: Vec<CallFrame> = Vec::with_capacity(10240);
-        frames.push(CallFrame {
-            fun_idx: functions.len() - 1,
-            ip: 0,
-            start_len: 0,
-            slot_offset: 0,
-        });
+        // Modules compiled since the last run have no globals yet
+        let mut globals = state.globals;
+        globals.resize_with(state.modules.len(), || None);
 
         Vm {
-            frames,
-            functions,
+            frames: Vec::with_capacity(10240),
+            handlers: Vec::new(),
+            functions: state.functions,
             stack: Vec::with_capacity(10240),
             interner,
-            globals: Default::default(),
+            globals,
+            modules: state.modules,
+            builtins: state.builtins,
             global_error_id,
+            init_name,
+            prng: state.prng,
+            determinism: state.determinism,
             read_async,
+            fuel: state.options.max_instructions.unwrap_or(u64::MAX),
+            options: state.options,
+            cancelled: false,
+            container_bytes: 0,
+            collected_at: u64::MAX,
+            gc_id: state.gc_id,
+            thrown: None,
+            nested_calls: 0,
+            tasks: VecDeque::new(),
+            task_is_main: true,
+            turn_left: TASK_TURN,
+            main_result: None,
+            debugger: Debugger::default(),
+            profiler: None,
+            stats: None,
+            coverage: None,
+            verified: state.verified,
+        }
+    }
+
+    /// Make the function the main script, which `interpret` runs
+    pub(crate) fn load_script(&mut self, fun: Fun) {
+        self.functions.push(fun);
+        self.modules[MAIN_MODULE].script = Some(self.functions.len() - 1);
+    }
+
+    /// Stop using the VM, and keep what the next one needs to continue
+    pub(crate) fn into_state(self) -> (VmState, F) {
+        let state = VmState {
+            functions: self.functions,
+            modules: self.modules,
+            builtins: self.builtins,
+            globals: self.globals,
+            prng: self.prng,
+            determinism: self.determinism,
+            options: self.options,
+            gc_id: self.gc_id,
+            verified: self.verified,
+        };
+        (state, self.read_async)
+    }
+
+    pub fn configure(&mut self, options: VmOptions) {
+        self.fuel = options.max_instructions.unwrap_or(u64::MAX);
+        if let Some(seed) = options.deterministic {
+            self.prng.borrow_mut().seed(seed);
+        }
+        self.determinism.reset(options.deterministic.is_some());
+        self.options = options;
+    }
+
+    /// Make a native function available to every module under the given name.
+    /// If the function returns an error, the call evaluates to nil and `errString` is set to the error message.
+    pub fn define_native(&mut self, name: &str, arity: usize, fun: impl Fn(&mut Interner, &[Value]) -> Result<Value> + 'static) {
+        let native = Value::NativeFunction(Rc::new(HostNative {
+            name: name.to_string(),
+            arity,
+            fun: Box::new(fun),
+        }));
+        self.define_global(name, native);
+    }
+
+    /// Like `define_native`, for functions that wait for the host, like timers, network requests and input.
+    /// The program is suspended until the future resolves, and `interpret` only finishes after that, so a browser is not
+    /// blocked while it waits. If the future resolves to an error, the call evaluates to nil and `errString` is set.
+    pub fn define_async_native(&mut self, name: &str, arity: usize, fun: impl Fn(&mut Interner, &[Value]) -> NativeFuture + 'static) {
+        let native = Value::NativeFunction(Rc::new(AsyncHostNative {
+            name: name.to_string(),
+            arity,
+            fun: Box::new(fun),
+        }));
+        self.define_global(name, native);
+    }
+
+    /// Add a builtin, which every module can use
+    fn define_global(&mut self, name: &str, value: Value) {
+        let name = self.interner.intern(name);
+        // Modules that already ran have their own copy of the builtins
+        for globals in self.globals.iter_mut().flatten() {
+            globals.insert(name, value.clone());
+        }
+        self.builtins.insert(name, value);
+    }
+
+    /// The native functions that every module can call, with their names, how many arguments they take, and whether
+    /// they take any number after those, for editors
+    pub fn builtin_natives(&self) -> Vec<(String, usize, bool)> {
+        let natives = self.builtins.iter().filter_map(|(name, value)| match value {
+            Value::NativeFunction(native) => Some((self.interner.lookup(name).to_string(), native.arity(), native.is_variadic())),
+            _ => None,
+        });
+        natives.collect()
+    }
+
+    fn register_builtins(&mut self) {
+        self.builtins.insert(self.global_error_id, Value::Nil);
+
+        register_native!(
+            self,
+            Clock {
+                determinism: self.determinism.clone()
+            }
+        );
+        register_native!(
+            self,
+            Now {
+                determinism: self.determinism.clone()
+            }
+        );
+        register_native!(self, Date);
+        register_native!(self, FormatDate);
+        register_native!(self, Sleep);
+        register_native!(self, TypeOf);
+        register_native!(self, Type);
+        register_native!(self, IsNil);
+        register_native!(self, IsBool);
+        register_native!(self, IsNumber);
+        register_native!(self, IsString);
+        register_native!(self, IsList);
+        register_native!(self, IsMap);
+        register_native!(self, IsSet);
+        register_native!(self, IsTuple);
+        register_native!(self, IsFunction);
+        register_native!(self, IsCallable);
+        register_native!(self, IsInstance);
+        register_native!(self, Print);
+        register_native!(self, ReadNumber);
+        register_native!(self, ReadString);
+        register_native!(self, ReadBool);
+        register_native!(self, Input);
+        register_native!(self, ToString);
+        register_native!(self, Format);
+        register_native!(self, JsonParse);
+        register_native!(self, JsonStringify);
+        register_native!(self, RegexTest);
+        register_native!(self, RegexMatch);
+        register_native!(self, RegexFindAll);
+        register_native!(self, RegexReplace);
+        register_native!(self, Assert);
+        register_native!(self, AssertEq);
+        register_native!(self, ToNumber);
+        #[cfg(feature = "bigint")]
+        {
+            register_native!(self, ToBigInt);
         }
+        register_native!(self, StringAt);
+        register_native!(self, StrLen);
+        register_native!(self, StrUpper);
+        register_native!(self, StrLower);
+        register_native!(self, StrTrim);
+        register_native!(self, StrSplit);
+        register_native!(self, StrReplace);
+        register_native!(self, StrContains);
+        register_native!(self, StrIndexOf);
+        register_native!(self, ArrLen);
+        register_native!(self, ArrPush);
+        register_native!(self, ArrPop);
+        register_native!(self, ArrInsert);
+        register_native!(self, ArrRemove);
+        register_native!(self, MapLen);
+        register_native!(
+            self,
+            MapKeys {
+                determinism: self.determinism.clone()
+            }
+        );
+        register_native!(self, MapRemove);
+        register_native!(self, RangeLen);
+        register_native!(self, RangeContains);
+        // `Set` also names a variant of Value, so it is registered by path
+        let name = self.interner.intern("Set");
+        self.builtins.insert(name, Value::NativeFunction(Rc::new(crate::native::Set)));
+        register_native!(self, SetLen);
+        register_native!(self, SetAdd);
+        register_native!(self, SetHas);
+        register_native!(self, SetRemove);
+        // `Channel` is also a variant of `Value`, so the native is named by its path
+        let channel_name = self.interner.intern("Channel");
+        self.builtins
+            .insert(channel_name, Value::NativeFunction(Rc::new(crate::native::Channel)));
+        register_native!(self, Send);
+        register_native!(self, Spawn);
+        register_native!(self, Receive);
+        register_native!(self, Ceil);
+        register_native!(self, Floor);
+        register_native!(self, Abs);
+        register_native!(self, Sqrt);
+        register_native!(self, Sin);
+        register_native!(self, Cos);
+        register_native!(self, Pow);
+        register_native!(self, Min);
+        register_native!(self, Max);
+        let pi = self.interner.intern("PI");
+        self.builtins.insert(pi, Value::Number(std::f64::consts::PI));
+        register_native!(self, Sort);
+        register_native!(self, IndexOf);
+        register_native!(self, Rand { prng: self.prng.clone() });
+        register_native!(self, Random { prng: self.prng.clone() });
+        register_native!(self, RandInt { prng: self.prng.clone() });
+        register_native!(self, Seed { prng: self.prng.clone() });
     }
 
     fn code(&self, offset: usize) -> u8 {
@@ -157,13 +849,20 @@ where
         value
     }
 
-    fn read_constant(&mut self) -> &Value {
-        let index: usize = self.read_byte() as usize;
-        return self.constant(index);
+    /// Read a constant index, which takes 3 bytes for the long variants of instructions, and return the constant
+    fn read_constant(&mut self, long: bool) -> &Value {
+        let index = match long {
+            true => (self.read_byte() as usize) << 16 | (self.read_byte() as usize) << 8 | self.read_byte() as usize,
+            false => self.read_byte() as usize,
+        };
+        if let Some(stats) = &mut self.stats {
+            stats.count_constant(frame!(self).fun_idx, index);
+        }
+        self.constant(index)
     }
 
-    #[cfg(feature = "tracing")]
-    fn stack_trace(&self) {
+    /// Print the stack and the instruction that runs next, for `VmOptions::trace_execution`
+    fn trace_instruction(&self) {
         xprint!("Stack values: ");
         xprint!("[ ");
         for value in &self.stack {
@@ -173,18 +872,30 @@ where
         xprint!("]");
 
         xprintln!("");
+        disassemble_instruction(&self.functions[frame!(self).fun_idx].chunk, frame!(self).ip, self.interner);
     }
 
-    fn is_falsey(&self, value: &Value) -> bool {
-        match value {
-            Nil => true,
-            Bool(b) => !b,
-            Number(n) => (*n - 0.0).abs() < f64::EPSILON,
-            Array(arr) => arr.borrow().is_empty(),
-            _ => false,
+    /// Print the bytecode of every function, for `VmOptions::print_code`
+    fn print_code(&self) {
+        for fun in &self.functions {
+            let name = fun.display_name(self.interner, &self.modules);
+            fun.chunk.disassemble(name, self.interner);
+            if let Some(register) = &fun.register {
+                register.disassemble(name, &fun.chunk, self.interner);
+            }
         }
     }
 
+    fn is_falsey(&self, value: &Value) -> bool {
+        value.is_falsey()
+    }
+
+    /// Line of the instruction being run
+    fn current_line(&self) -> usize {
+        let frame = frame!(self);
+        self.functions[frame.fun_idx].chunk.line_at(frame.ip - 1)
+    }
+
     fn read_u16(&mut self) -> u16 {
         frame_mut!(self).ip += 2;
 
@@ -193,26 +904,74 @@ where
         (high_byte << 8) | low_byte
     }
 
-    #[cfg(not(feature = "tracing"))]
-    fn stack_trace(&self) {}
+    /// Read a jump offset, which takes 3 bytes for the long variants of jumps
+    fn read_jump(&mut self, long: bool) -> usize {
+        match long {
+            true => (self.read_byte() as usize) << 16 | self.read_u16() as usize,
+            false => self.read_u16() as usize,
+        }
+    }
+
+    /// Create the value that is thrown for a runtime error
+    fn runtime_error(&mut self, msg: &str) -> Value {
+        Value::Str(self.interner.intern(msg))
+    }
+
+    /// Convert the error of a fallible helper into a runtime error
+    fn check<T>(&mut self, result: Result<T>, context: &str) -> ThrowResult<T> {
+        result.map_err(|err| self.runtime_error(&format!("{context}: {err}")))
+    }
 
-    fn runtime_error(&self, msg: &str) -> ! {
-        xprintln!("Runtime error: {msg}");
-        xprintln!("Traceback (most recent call first):");
-        let mut idx = (self.frames.len() - 1) as isize;
+    /// Error that stops the program, with the frames that are running. `offset` is where the instruction that failed
+    /// starts, the other frames are at the call they are waiting on.
+    fn error_at(&self, kind: RuntimeErrorKind, message: String, offset: usize) -> RuntimeError {
+        let stack = self.trace(offset);
+        let line = stack.first().map_or(0, |frame| frame.line);
+        RuntimeError {
+            kind,
+            message,
+            line,
+            offset,
+            stack,
+        }
+    }
 
-        while idx >= 0 {
-            let frame = &self.frames[idx as usize];
-            let fun: &Fun = &self.functions[frame.fun_idx];
-            let fun_name = match fun.name {
-                Some(name) => self.interner.lookup(&name),
-                None => "<script>",
-            };
-            xprintln!("[line {:3}] in {}", fun.chunk.lines[&frame.ip], fun_name);
-            idx -= 1;
+    /// The frames that are running, most recent first. `offset` is where the instruction of the last frame starts.
+    fn trace(&self, offset: usize) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(idx, frame)| {
+                let fun: &Fun = &self.functions[frame.fun_idx];
+                let offset = if idx == 0 { offset } else { frame.ip.saturating_sub(1) };
+                TraceFrame {
+                    function: fun.name.map_or("<script>", |name| self.interner.lookup(&name)).to_string(),
+                    module: self.modules[frame.module].path.clone(),
+                    line: fun.chunk.line_at(offset),
+                    offset,
+                }
+            })
+            .collect()
+    }
+
+    /// Error for an exception that nothing caught. If frames were unwound while it was thrown, their traceback is used.
+    fn uncaught(&mut self, exception: &Value, offset: usize) -> RuntimeError {
+        let mut error = self.error_at(RuntimeErrorKind::Uncaught, value_as_string(exception, self.interner), offset);
+        if let Some((_, stack)) = self.thrown.take().filter(|(thrown, _)| thrown == exception) {
+            error.line = stack[0].line;
+            error.offset = stack[0].offset;
+            error.stack = stack;
         }
+        error
+    }
 
-        panic!("Exiting due to runtime error");
+    /// Remember the frames that are running, before the exception unwinds some of them
+    fn capture_trace(&mut self, exception: &Value, offset: usize) {
+        if self.thrown.as_ref().is_some_and(|(thrown, _)| thrown == exception) || self.frames.is_empty() {
+            return;
+        }
+        self.thrown = Some((exception.clone(), self.trace(offset)));
     }
 
     fn pop(&mut self) -> Result<Value> {
@@ -223,8 +982,8 @@ where
         unsafe { self.stack.pop().unwrap_unchecked() }
     }
 
-    fn read_string_or_id(&mut self) -> StrId {
-        let value = self.read_constant();
+    fn read_string_or_id(&mut self, long: bool) -> StrId {
+        let value = self.read_constant(long);
         match value {
             Value::Str(id) => *id,
             Value::Identifier(id) => *id,
@@ -232,286 +991,2343 @@ where
         }
     }
 
-    fn reset_err_string(&mut self) {
-        self.globals.insert(self.global_error_id, Value::Nil);
+    /// Globals of the module the current function belongs to
+    fn globals(&mut self) -> &mut Globals {
+        let module = frame!(self).module;
+        unsafe { self.globals.get_unchecked_mut(module).as_mut().unwrap_unchecked() }
     }
 
-    async fn call_value(&mut self, arg_count: u8) -> bool {
-        let callee = self.peek(arg_count as usize);
-        match callee {
-            Function(idx) => {
-                let fun = &self.functions[*idx];
+    /// Index of the global named by the operand of the instruction at `offset`, with the name if the inline cache missed,
+    /// or Err with the name if the global is not defined. A global keeps its index until `step_back` removes one and
+    /// empties the caches, so a hit is used without reading the name. Constants are not cached, so a hit is a variable.
+    fn global_slot(&mut self, offset: usize, long: bool) -> Result<(usize, Option<StrId>), StrId> {
+        let fun_idx = frame!(self).fun_idx;
+        if let Some(&InlineCache::Slot(slot)) = self.functions[fun_idx].chunk.caches.get(offset) {
+            if self.stats.is_some() {
+                self.read_constant(long);
+            } else {
+                frame_mut!(self).ip += if long { 3 } else { 1 };
+            }
+            return Ok((slot, None));
+        }
 
-                if arg_count as usize != fun.arity {
-                    self.runtime_error(&format!("Expected {} arguments but got {} instead", fun.arity, arg_count));
-                }
+        let name = self.read_string_or_id(long);
+        let slot = self.globals().get_index_of(&name).ok_or(name)?;
+        if !self.modules[frame!(self).module].constants.contains(&name) {
+            self.cache(offset, InlineCache::Slot(slot));
+        }
+        Ok((slot, Some(name)))
+    }
 
-                let new_frame_offset = self.stack.len() - arg_count as usize;
-                let orig_len = self.stack.len() - 1 - fun.arity;
-                let frame: CallFrame = CallFrame {
-                    fun_idx: *idx,
-                    ip: 0,
-                    start_len: orig_len,
-                    slot_offset: new_frame_offset, // -1 here in book ?
-                };
-                self.frames.push(frame);
-                true
+    /// Name of the global at `slot` of the current module
+    fn global_name(&mut self, slot: usize) -> StrId {
+        *self.globals().get_index(slot).expect("Global slot is in bounds").0
+    }
+
+    /// Field or method of an instance, found with the inline cache of the instruction at `offset`
+    fn cached_property(&self, offset: usize, instance: &Rc<RefCell<crate::value::Instance>>, name: StrId) -> Option<Value> {
+        let object = instance.borrow();
+        match self.functions[frame!(self).fun_idx].chunk.caches.get(offset)? {
+            InlineCache::Slot(slot) => match object.fields.get_index(*slot) {
+                Some((key, value)) if *key == name => Some(value.clone()),
+                _ => None,
+            },
+            // Fields hide methods with the same name, and any instance can have one
+            InlineCache::Method { class, method }
+                if Weak::as_ptr(class) == Rc::as_ptr(&object.class) && !object.fields.contains_key(&name) =>
+            {
+                Some(BoundMethod(Rc::new(crate::value::BoundMethod {
+                    receiver: Instance(instance.clone()),
+                    method: *method,
+                })))
             }
-            NativeFunction(fun) => {
-                if arg_count as usize != fun.arity() {
-                    self.runtime_error(&format!("Expected {} arguments but got {} instead", fun.arity(), arg_count));
-                }
+            _ => None,
+        }
+    }
 
-                let function = fun.clone();
+    fn cache(&mut self, offset: usize, cache: InlineCache) {
+        if self.options.inline_caches {
+            self.functions[frame!(self).fun_idx].chunk.set_cache(offset, cache);
+        }
+    }
 
-                // Speacial read input functions - take the prompt, and convert it the the user response
-                if function.name() == "ReadString" || function.name() == "ReadNumber" || function.name() == "ReadBool" {
-                    let len = self.stack.len();
-                    let first_arg = &mut self.stack[len - arg_count as usize];
-                    match first_arg {
-                        Value::Str(id) => {
-                            let prompt = self.interner.lookup(id);
-                            let input = (self.read_async)(prompt.to_string()).await;
-                            *first_arg = Value::Str(self.interner.intern(&input));
-                        }
-                        _ => *first_arg = Value::Nil,
-                    }
-                };
+    fn reset_err_string(&mut self) {
+        let global_error_id = self.global_error_id;
+        self.globals().insert(global_error_id, Value::Nil);
+    }
 
-                let args = &self.stack[self.stack.len() - arg_count as usize..];
-                self.globals.insert(self.global_error_id, Value::Nil); // Reset error string
+    /// Call a special method of the instance below the arguments on the stack.
+    /// Returns false if the value is not an instance, or its class does not define the method.
+    fn call_special_method(&mut self, method: &str, arg_count: usize) -> ThrowResult<bool> {
+        let Instance(instance) = self.peek(arg_count) else {
+            return Ok(false);
+        };
 
-                let result = function.call(self.interner, &mut self.globals, args);
+        let class = instance.borrow().class.clone();
+        let Some(method) = class.find_method(self.interner.intern(method)) else {
+            return Ok(false);
+        };
 
-                dbgln!("Truncating to length {}", self.stack.len() - 1 - arg_count as usize);
-                self.stack.truncate(self.stack.len() - 1 - arg_count as usize);
-                self.stack.push(result);
+        self.call_function(method, arg_count)?;
+        Ok(true)
+    }
 
-                true
-            }
-            other => {
-                self.runtime_error(&format!("Can only call functions, got {other}"));
-            }
+    /// Index into an instance with its `__get` or `__set` method
+    fn index_instance(&mut self, instance: Value, method: &str, args: &[Value]) -> ThrowResult<()> {
+        self.stack.push(instance);
+        self.stack.extend_from_slice(args);
+
+        if self.call_special_method(method, args.len())? {
+            Ok(())
+        } else {
+            let instance = self.stack[self.stack.len() - 1 - args.len()].clone();
+            Err(self.runtime_error(&format!("Tried to index {instance} without a {method} method")))
         }
     }
 
-    async fn run(&mut self) -> Result<()> {
-        loop {
-            self.stack_trace();
-            disassemble_instruction(&self.functions[frame!(self).fun_idx].chunk, frame!(self).ip, self.interner);
-            let instruction = unsafe { Opcode::try_from(self.read_byte()).unwrap_unchecked() };
-            match instruction {
-                Opcode::Print => {
-                    print_value(&self.pop_unchecked(), self.interner);
-                    xprintln!("");
-                }
-                Opcode::JumpIfFalse => {
-                    let offset: u16 = self.read_u16();
-                    if self.is_falsey(self.peek(0)) {
-                        frame_mut!(self).ip += offset as usize;
-                    }
-                }
-                Opcode::Loop => {
-                    let offset = self.read_u16();
-                    frame_mut!(self).ip -= offset as usize;
-                }
-                Opcode::Jump => {
-                    let offset: u16 = self.read_u16();
-                    frame_mut!(self).ip += offset as usize;
-                }
-                Opcode::Call => {
-                    let arg_count = self.read_byte();
-                    if !self.call_value(arg_count).await {
-                        self.runtime_error("Could not call value");
-                    }
-                }
-                Opcode::Return => {
-                    let value = self.pop().expect("Nothing to return");
-                    let orig_len = frame!(self).start_len;
-                    self.frames.pop();
+    /// Call a value with the given arguments and run it to completion, for builtins that call back into the program.
+    /// Exceptions that are not caught inside the call are returned, after unwinding the frames of the call.
+    async fn call_and_wait(&mut self, callee: Value, args: &[Value]) -> ThrowResult<Value> {
+        // Each nested call also nests on the host stack, which is much smaller than the frame limit allows for
+        if self.nested_calls >= MAX_NESTED_CALLS {
+            return Err(self.runtime_error(&format!("Stack overflow: more than {MAX_NESTED_CALLS} nested callbacks")));
+        }
+        self.nested_calls += 1;
+        let result = self.run_nested_call(callee, args).await;
+        self.nested_calls -= 1;
+        result
+    }
 
-                    if self.frames.is_empty() {
-                        return Ok(());
-                    }
+    async fn run_nested_call(&mut self, callee: Value, args: &[Value]) -> ThrowResult<Value> {
+        let frame_count = self.frames.len();
+        let stack_len = self.stack.len();
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
 
-                    self.stack_trace();
-                    dbgln!("Truncating to length {}", orig_len,);
-                    self.stack.truncate(orig_len);
-                    self.stack.push(value);
-                }
-                Opcode::Constant => {
-                    let constant = self.read_constant().clone();
-                    self.stack.push(constant);
-                }
-                Opcode::Negate => {
-                    let value = self.pop_unchecked();
-                    match value {
-                        Number(num) => self.stack.push(Value::Number(-num)),
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                        }
-                    }
-                }
-                Opcode::True => self.stack.push(Bool(true)),
-                Opcode::False => self.stack.push(Bool(false)),
-                Opcode::Pop => {
-                    self.pop_unchecked();
-                }
-                Opcode::GetLocal => {
-                    let array_index = self.pop_unchecked();
-                    let slot = self.read_byte() as usize;
-                    let value = &self.stack[frame!(self).slot_offset + slot];
+        // Natives and other builtins finish immediately, functions push a frame that is run below
+        if let Err(exception) = Box::pin(self.call_value(args.len())).await {
+            self.stack.truncate(stack_len);
+            return Err(exception);
+        }
 
-                    if array_index == Value::Nil {
-                        self.stack.push(value.clone());
-                    } else {
-                        self.stack.push(get_array(value, &array_index).unwrap_or_else(|err| {
-                            self.runtime_error(&format!("Error getting array: {err}"));
-                        }));
-                    }
-                }
-                Opcode::GetGlobal => {
-                    let name = self.read_string_or_id();
-                    let array_index = self.pop_unchecked();
-
-                    if let Some(value) = self.globals.get(&name) {
-                        if array_index == Value::Nil {
-                            self.stack.push(value.clone());
-                        } else {
-                            self.stack.push(get_array(value, &array_index).unwrap_or_else(|err| {
-                                self.runtime_error(&format!("Error getting array: {err}"));
-                            }));
-                        }
-                    } else {
-                        self.runtime_error(&format!("Undefined variable {}", self.interner.lookup(&name)));
-                    }
+        while self.frames.len() > frame_count {
+            let offset = frame!(self).ip;
+            // Thrown so the frames unwind, but the fuel stays empty, so the program stops even if it catches this
+            if !self.consume_fuel() {
+                let message = self.interrupted(frame!(self).ip).message;
+                let exception = self.runtime_error(&message);
+                self.unwind_frames(frame_count);
+                self.stack.truncate(stack_len);
+                return Err(exception);
+            }
+            if self.options.trace_execution {
+                self.trace_instruction();
+            }
+            let instruction = self.read_instruction();
+            let result = if awaits(&instruction) {
+                Box::pin(self.run_call_instruction(instruction)).await
+            } else {
+                self.run_instruction(instruction)
+            };
+            if let Err(exception) = result {
+                if self.handlers.last().is_some_and(|handler| handler.frame_count > frame_count) {
+                    let caught = self.throw_value(exception, offset);
+                    debug_assert!(caught.is_ok(), "The handler is inside the call");
+                } else {
+                    self.capture_trace(&exception, offset);
+                    self.unwind_frames(frame_count);
+                    self.stack.truncate(stack_len);
+                    return Err(exception);
                 }
-                Opcode::SetLocal => {
-                    let slot: usize = self.read_byte() as usize;
-                    let new_value = self.pop_unchecked();
-                    let array_index = self.pop_unchecked();
-                    self.stack.push(new_value.clone());
-                    let value_to_be_modified = &mut self.stack[frame!(self).slot_offset + slot];
+            }
+        }
 
-                    if array_index == Value::Nil {
-                        *value_to_be_modified = new_value;
-                    } else {
-                        set_array(value_to_be_modified, &array_index, new_value).unwrap_or_else(|err| {
-                            self.runtime_error(&format!("Error setting array: {err}"));
-                        });
-                    }
-                }
-                Opcode::SetGlobal => {
-                    let name = self.read_string_or_id();
+        Ok(self.pop_unchecked())
+    }
 
-                    if !self.globals.contains_key(&name) {
-                        self.runtime_error(&format!("Undefined variable {}", self.interner.lookup(&name)));
-                    } else {
-                        let new_value = self.pop_unchecked();
-                        let array_index = self.pop_unchecked();
-                        self.stack.push(new_value.clone());
-                        let value_to_be_modified = self.globals.get_mut(&name).unwrap();
-
-                        if array_index == Value::Nil {
-                            *value_to_be_modified = new_value;
-                        } else {
-                            set_array(value_to_be_modified, &array_index, new_value).unwrap_or_else(|err| {
-                                self.runtime_error(&format!("Error setting array: {err}"));
-                            });
+    /// Free containers that are only reachable from each other, like an array that contains itself.
+    /// Returns how many containers were cleared. This runs automatically as containers are allocated.
+    pub fn collect_garbage(&mut self) -> usize {
+        if self.options.gc_verify {
+            if let Err(error) = self.verify_heap() {
+                panic!("{error}");
+            }
+        }
+        let collection = gc::collect(self.gc_id, self.roots(), self.options.gc_verify);
+        self.container_bytes = collection.live_bytes;
+        self.collected_at = self.fuel;
+        collection.cleared
+    }
+
+    /// Bytes used by interned strings, and by containers as of the last garbage collection
+    pub fn heap_bytes(&self) -> usize {
+        self.interner.allocated_bytes() + self.container_bytes
+    }
+
+    /// The objects reachable from the globals and the stacks, with their sizes and references
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        let mut builder = SnapshotBuilder::new(self.interner, &self.functions);
+        for (module, globals) in self.globals.iter().enumerate() {
+            let Some(globals) = globals else {
+                continue;
+            };
+            let mut globals: Vec<(String, &Value)> = globals
+                .iter()
+                .filter(|(name, value)| !self.is_builtin(name, value))
+                .map(|(name, value)| (self.interner.lookup(name).to_string(), value))
+                .collect();
+            globals.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, value) in globals {
+                match module {
+                    MAIN_MODULE => builder.root(name, value),
+                    _ => builder.root(format!("{}:{name}", self.modules[module].path), value),
+                }
+            }
+        }
+        for (slot, value) in self.stack.iter().enumerate() {
+            builder.root(format!("stack[{slot}]"), value);
+        }
+        for (task, value) in self.tasks.iter().enumerate() {
+            for (slot, value) in value.stack.iter().enumerate() {
+                builder.root(format!("task {task} stack[{slot}]"), value);
+            }
+        }
+        if let Some(result) = &self.main_result {
+            builder.root("result".to_string(), result);
+        }
+        builder.finish()
+    }
+
+    fn out_of_memory(&mut self, max: usize) -> Value {
+        self.runtime_error(&format!("Out of memory: the heap is larger than the limit of {max} bytes"))
+    }
+
+    /// Check that no container cleared by a collection while `VmOptions::gc_verify` was on is reachable
+    pub fn verify_heap(&self) -> Result<()> {
+        gc::verify(self.gc_id, self.roots()).map_err(Error::msg)
+    }
+
+    /// Values the garbage collector starts marking from
+    fn roots(&self) -> impl Iterator<Item = &Value> {
+        let constants = self.functions.iter().flat_map(|fun| fun.chunk.constants.iter());
+        let globals = self
+            .globals
+            .iter()
+            .flatten()
+            .chain(std::iter::once(&self.builtins))
+            .flat_map(|globals| globals.values());
+        let tasks = self.tasks.iter().flat_map(|task| task.stack.iter());
+        self.stack
+            .iter()
+            .chain(tasks)
+            .chain(&self.main_result)
+            .chain(globals)
+            .chain(constants)
+            .chain(self.debugger.history.values())
+    }
+
+    /// Collect garbage if enough containers have been allocated, at points where every live value is a root.
+    /// With a heap limit, also collect every so often to measure the heap, since containers grow without allocating.
+    fn maybe_collect_garbage(&mut self) -> ThrowResult<()> {
+        if self.nested_calls != 0 {
+            return Ok(());
+        }
+
+        let Some(max) = self.options.max_heap_bytes else {
+            if gc::collection_due(self.options.gc_stress) {
+                self.collect_garbage();
+            }
+            return Ok(());
+        };
+        let since_collection = self.collected_at.saturating_sub(self.fuel);
+        if gc::collection_due(self.options.gc_stress) || since_collection >= HEAP_CHECK_INTERVAL.max(gc::tracked_objects() as u64) {
+            self.collect_garbage();
+        }
+        // Checked every time, since strings can grow quickly, and the interner knows its size without a collection
+        if self.heap_bytes() > max {
+            return Err(self.out_of_memory(max));
+        }
+        Ok(())
+    }
+
+    /// Check that a string of the given length can be interned without going over `VmOptions::max_heap_bytes`
+    fn reserve_string(&mut self, len: usize) -> ThrowResult<()> {
+        match self.options.max_heap_bytes {
+            Some(max) if self.heap_bytes() + len > max => Err(self.out_of_memory(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// `map`, `filter`, `reduce` and `sort` on lists
+    async fn run_list_callback(&mut self, method: &ListCallback, args: Vec<Value>) -> ThrowResult<Value> {
+        let kind = method.kind;
+        let expected_args = match kind {
+            ListCallbackKind::Map | ListCallbackKind::Filter => 1..=1,
+            ListCallbackKind::Reduce => 1..=2,
+            ListCallbackKind::Sort => 0..=1,
+        };
+        if !expected_args.contains(&args.len()) {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {} instead",
+                expected_args.end(),
+                args.len()
+            )));
+        }
+
+        // The callback may change the list, so work on a copy of its elements
+        let elements = method.list.borrow().clone();
+        match kind {
+            ListCallbackKind::Map => {
+                let mut mapped = Vec::with_capacity(elements.len());
+                for element in elements {
+                    mapped.push(self.call_and_wait(args[0].clone(), &[element]).await?);
+                }
+                Ok(Array(gc::new_array(mapped)))
+            }
+            ListCallbackKind::Filter => {
+                let mut kept = Vec::new();
+                for element in elements {
+                    let keep = self.call_and_wait(args[0].clone(), std::slice::from_ref(&element)).await?;
+                    if !self.is_falsey(&keep) {
+                        kept.push(element);
+                    }
+                }
+                Ok(Array(gc::new_array(kept)))
+            }
+            ListCallbackKind::Reduce => {
+                let mut elements = elements.into_iter();
+                let mut accumulator = match args.get(1) {
+                    Some(initial) => initial.clone(),
+                    None => match elements.next() {
+                        Some(first) => first,
+                        None => return Err(self.runtime_error("Can't reduce an empty list without an initial value")),
+                    },
+                };
+                for element in elements {
+                    accumulator = self.call_and_wait(args[0].clone(), &[accumulator, element]).await?;
+                }
+                Ok(accumulator)
+            }
+            ListCallbackKind::Sort => {
+                let sorted = self.merge_sort(elements, args.first()).await?;
+                *method.list.borrow_mut() = sorted;
+                Ok(Array(method.list.clone()))
+            }
+        }
+    }
+
+    /// Stable sort. With a comparator, `comparator(a, b)` returns a negative number if `a` goes first, a positive number if
+    /// `b` goes first, and 0 if they are equal. Without one, numbers and strings are sorted in ascending order.
+    async fn merge_sort(&mut self, mut elements: ValueArray, comparator: Option<&Value>) -> ThrowResult<ValueArray> {
+        let mut width = 1;
+        while width < elements.len() {
+            let mut merged = Vec::with_capacity(elements.len());
+            for chunk in elements.chunks(2 * width) {
+                let (mut left, mut right) = (0, 0);
+                let (left_half, right_half) = chunk.split_at(width.min(chunk.len()));
+                while left < left_half.len() && right < right_half.len() {
+                    if self.sorts_before(&right_half[right], &left_half[left], comparator).await? {
+                        merged.push(right_half[right].clone());
+                        right += 1;
+                    } else {
+                        merged.push(left_half[left].clone());
+                        left += 1;
+                    }
+                }
+                merged.extend_from_slice(&left_half[left..]);
+                merged.extend_from_slice(&right_half[right..]);
+            }
+            elements = merged;
+            width *= 2;
+        }
+        Ok(elements)
+    }
+
+    async fn sorts_before(&mut self, a: &Value, b: &Value, comparator: Option<&Value>) -> ThrowResult<bool> {
+        let Some(comparator) = comparator else {
+            return match (a, b) {
+                (Str(a), Str(b)) => Ok(self.interner.lookup(a) < self.interner.lookup(b)),
+                _ => match (a.as_number(), b.as_number()) {
+                    (Some(a), Some(b)) => Ok(a < b),
+                    _ => Err(self.runtime_error(&format!("Can only sort numbers and strings without a comparator, got {a} and {b}"))),
+                },
+            };
+        };
+
+        let order = self.call_and_wait(comparator.clone(), &[a.clone(), b.clone()]).await?;
+        match order.as_number() {
+            Some(order) => Ok(order < 0.0),
+            None => Err(self.runtime_error(&format!("Sort comparator must return a number, got {order}"))),
+        }
+    }
+
+    /// Call a function declared in the program, whose arguments are on the stack
+    fn call_function(&mut self, idx: usize, arg_count: usize) -> ThrowResult<()> {
+        let fun = &self.functions[idx];
+        let arity = fun.arity;
+
+        // The rest parameter of variadic functions is not counted as a fixed parameter
+        let fixed_arity = if fun.is_variadic { arity - 1 } else { arity };
+
+        if arg_count < fun.min_arity || (!fun.is_variadic && arg_count > arity) {
+            if fun.is_variadic {
+                return Err(self.runtime_error(&format!(
+                    "Expected at least {} arguments but got {} instead",
+                    fun.min_arity, arg_count
+                )));
+            } else if fun.min_arity == arity {
+                return Err(self.runtime_error(&format!("Expected {} arguments but got {} instead", arity, arg_count)));
+            } else {
+                return Err(self.runtime_error(&format!(
+                    "Expected {} to {} arguments but got {} instead",
+                    fun.min_arity, arity, arg_count
+                )));
+            }
+        }
+
+        let rest = if fun.is_variadic && arg_count > fixed_arity {
+            self.stack.split_off(self.stack.len() - (arg_count - fixed_arity))
+        } else {
+            Vec::new()
+        };
+
+        // Missing arguments are filled in by the function prologue
+        for _ in arg_count.min(fixed_arity)..fixed_arity {
+            self.stack.push(Value::Nil);
+        }
+
+        if self.functions[idx].is_variadic {
+            self.stack.push(Value::Array(gc::new_array(rest)));
+        }
+
+        self.push_frame(idx, ArgSet::first(arg_count.min(fixed_arity)))?;
+        match self.register_function(idx) {
+            Some(function) => self.run_registers(&function),
+            None => Ok(()),
+        }
+    }
+
+    /// Register instructions of the function whose frame was just pushed, if it runs on the register backend. It doesn't
+    /// when something needs to see its bytecode run, when it is what a task starts with, or when a parameter is an
+    /// instance, whose operator methods only the stack backend calls.
+    fn register_function(&self, fun_idx: usize) -> Option<Rc<RegisterFunction>> {
+        let function = self.functions[fun_idx].register.as_ref()?;
+        if self.debugger.active || self.stats.is_some() || self.coverage.is_some() || self.options.trace_execution || self.frames.len() < 2
+        {
+            return None;
+        }
+        let params = &self.stack[frame!(self).slot_offset..];
+        if params.iter().any(|value| matches!(value, Instance(_))) {
+            return None;
+        }
+        Some(function.clone())
+    }
+
+    /// Run the function whose frame was just pushed on the register backend, in the slots of the frame, until it returns.
+    /// If the budget runs out, the frame is left at the instruction that was next, where the run loop stops.
+    fn run_registers(&mut self, function: &RegisterFunction) -> ThrowResult<()> {
+        let base = frame!(self).slot_offset;
+        self.stack.resize(base + function.registers, Nil);
+        let mut index = 0;
+        loop {
+            if !self.consume_fuel() {
+                frame_mut!(self).ip = function.offsets[index];
+                return Ok(());
+            }
+            index += 1;
+            let result = match &function.code[index - 1] {
+                Instruction::Load { dst, value } => {
+                    self.stack[base + dst] = value.clone();
+                    Ok(())
+                }
+                Instruction::Move { dst, src } => {
+                    self.stack[base + dst] = self.stack[base + src].clone();
+                    Ok(())
+                }
+                Instruction::Unary { op, dst, src } => {
+                    let result = match (op, &self.stack[base + src]) {
+                        (Opcode::Not, value) => Ok(Bool(value.is_falsey())),
+                        (_, Number(num)) => Ok(Number(-num)),
+                        (_, Int(i)) if *i != i64::MIN => Ok(Int(-i)),
+                        (_, value) => self.operate_on_stack(*op, [value.clone()]),
+                    };
+                    result.map(|value| self.stack[base + dst] = value)
+                }
+                Instruction::Binary { op, dst, a, b } => {
+                    let (a, b) = (&self.stack[base + a], &self.stack[base + b]);
+                    let fast = match (op, a, b) {
+                        (Opcode::Less, Int(a), Int(b)) => Some(Bool(a < b)),
+                        (Opcode::Greater, Int(a), Int(b)) => Some(Bool(a > b)),
+                        (Opcode::Less, Int(_) | Number(_), Int(_) | Number(_)) => Some(Bool(a.as_number() < b.as_number())),
+                        (Opcode::Greater, Int(_) | Number(_), Int(_) | Number(_)) => Some(Bool(a.as_number() > b.as_number())),
+                        (Opcode::Equal, Int(_) | Number(_), Int(_) | Number(_)) => Some(Bool(a == b)),
+                        (Opcode::Less | Opcode::Greater | Opcode::Equal, _, _) => None,
+                        _ => arithmetic(*op, a, b),
+                    };
+                    let result = match fast {
+                        Some(value) => Ok(value),
+                        None => self.operate_on_stack(*op, [a.clone(), b.clone()]),
+                    };
+                    result.map(|value| self.stack[base + dst] = value)
+                }
+                Instruction::Jump { target } => {
+                    index = *target;
+                    Ok(())
+                }
+                Instruction::JumpIfFalse { src, target } => {
+                    if self.stack[base + src].is_falsey() {
+                        index = *target;
+                    }
+                    Ok(())
+                }
+                Instruction::Return { src } => {
+                    let value = self.stack[base + src].clone();
+                    self.return_value(value);
+                    return Ok(());
+                }
+            };
+            if let Err(exception) = result {
+                self.capture_trace(&exception, function.offsets[index - 1]);
+                let start_len = frame!(self).start_len;
+                self.unwind_frames(self.frames.len() - 1);
+                self.stack.truncate(start_len);
+                return Err(exception);
+            }
+        }
+    }
+
+    /// Run an operator instruction of the stack backend on the operands, for the values the register backend doesn't
+    /// handle itself
+    fn operate_on_stack<const N: usize>(&mut self, instruction: Opcode, operands: [Value; N]) -> ThrowResult<Value> {
+        self.stack.extend(operands);
+        self.run_instruction(instruction)?;
+        Ok(self.pop_unchecked())
+    }
+
+    async fn call_value(&mut self, arg_count: usize) -> ThrowResult<()> {
+        let callee = self.peek(arg_count);
+        match callee {
+            Function(idx) => {
+                let idx = *idx;
+                self.call_function(idx, arg_count)
+            }
+            ListCallback(method) => {
+                let method = method.clone();
+                let args = self.stack.split_off(self.stack.len() - arg_count);
+                self.stack.pop(); // The method
+                let result = self.run_list_callback(&method, args).await?;
+                self.stack.push(result);
+                Ok(())
+            }
+            BoundMethod(bound) => {
+                let method = bound.method;
+                let callee_slot = self.stack.len() - 1 - arg_count;
+                self.stack[callee_slot] = bound.receiver.clone();
+                self.call_function(method, arg_count)
+            }
+            RecordType(typ) => {
+                if arg_count != typ.fields.len() {
+                    return Err(self.runtime_error(&format!("Expected {} arguments but got {} instead", typ.fields.len(), arg_count)));
+                }
+
+                let typ = typ.clone();
+                let values = self.stack.split_off(self.stack.len() - arg_count);
+                self.stack.pop(); // The record type
+                self.stack.push(Record(Rc::new(crate::value::Record { typ, values })));
+                Ok(())
+            }
+            Class(class) => {
+                let class = class.clone();
+                let init = class.find_method(self.init_name);
+                let callee_slot = self.stack.len() - 1 - arg_count;
+                self.stack[callee_slot] = Instance(gc::new_instance(crate::value::Instance {
+                    class,
+                    fields: Default::default(),
+                }));
+
+                match init {
+                    Some(init) => self.call_function(init, arg_count),
+                    None if arg_count == 0 => Ok(()),
+                    None => Err(self.runtime_error(&format!("Expected 0 arguments but got {arg_count} instead"))),
+                }
+            }
+            NativeFunction(fun) => {
+                if fun.is_variadic() && arg_count < fun.arity() {
+                    return Err(self.runtime_error(&format!(
+                        "Expected at least {} arguments but got {} instead",
+                        fun.arity(),
+                        arg_count
+                    )));
+                } else if !fun.is_variadic() && arg_count != fun.arity() {
+                    return Err(self.runtime_error(&format!("Expected {} arguments but got {} instead", fun.arity(), arg_count)));
+                }
+
+                let function = fun.clone();
+
+                // Speacial read input functions - take the prompt, and convert it the the user response
+                if matches!(function.name(), "ReadString" | "ReadNumber" | "ReadBool" | "Input") {
+                    let len = self.stack.len();
+                    let first_arg = &mut self.stack[len - arg_count];
+                    match first_arg {
+                        Value::Str(id) => {
+                            let prompt = self.interner.lookup(id);
+                            let input = (self.read_async)(prompt.to_string()).await;
+                            // Other VMs on the thread may have run while waiting
+                            gc::set_active_vm(self.gc_id);
+                            *first_arg = Value::Str(self.interner.intern(&input));
+                        }
+                        _ => *first_arg = Value::Nil,
+                    }
+                };
+
+                if let Some(operation) = function.task_operation() {
+                    return self.run_task_operation(operation, arg_count).await;
+                }
+
+                self.reset_err_string();
+                let args = &self.stack[self.stack.len() - arg_count..];
+                if let Some(future) = function.call_async(self.interner, args) {
+                    self.stack.truncate(self.stack.len() - 1 - arg_count);
+                    let result = future.await;
+                    // Other VMs on the thread may have run while waiting
+                    gc::set_active_vm(self.gc_id);
+                    let result = match result {
+                        Ok(result) => result.into_value(self.interner),
+                        Err(err) => {
+                            let (error_id, message) = (self.global_error_id, self.interner.intern(&err.to_string()));
+                            self.globals().insert(error_id, Value::Str(message));
+                            Value::Nil
+                        }
+                    };
+                    self.stack.push(result);
+                    return Ok(());
+                }
+
+                let module = frame!(self).module;
+                let globals = unsafe { self.globals.get_unchecked_mut(module).as_mut().unwrap_unchecked() };
+                let args = &self.stack[self.stack.len() - arg_count..];
+
+                let result = function.call_or_throw(self.interner, globals, args);
+                let result = match result {
+                    Ok(result) => result,
+                    Err(msg) => return Err(self.runtime_error(&format!("[line {}] {msg}", self.current_line()))),
+                };
+
+                self.stack.truncate(self.stack.len() - 1 - arg_count);
+                self.stack.push(result);
+
+                Ok(())
+            }
+            other => Err(self.runtime_error(&format!("Can only call functions, got {other}"))),
+        }
+    }
+
+    /// The `in` operator: keys of maps, elements of arrays, sets and ranges, members of enums, and substrings of strings
+    fn contains(&mut self, container: &Value, item: &Value) -> ThrowResult<bool> {
+        match (container, item) {
+            (Map(map), key) => Ok(MapKey::from_value(key).is_some_and(|key| map.borrow().contains_key(&key))),
+            (Value::Set(set), element) => Ok(MapKey::from_value(element).is_some_and(|element| set.borrow().contains(&element))),
+            (Array(array), item) => Ok(array.borrow().contains(item)),
+            (Tuple(elements), item) => Ok(elements.contains(item)),
+            (Range(range), item @ (Number(_) | Int(_))) => Ok(range.contains(item.as_number().unwrap())),
+            (Range(_), _) => Ok(false),
+            (Enum(enm), item) => Ok(enm.members.contains(item)),
+            (Str(string), Str(substring)) => Ok(self.interner.lookup(string).contains(self.interner.lookup(substring))),
+            (Str(_), item) => Err(self.runtime_error(&format!("Can only check if a string contains a string, got {item}"))),
+            (container, _) => Err(self.runtime_error(&format!("Can't check if {container} contains a value"))),
+        }
+    }
+
+    /// Union, intersection and difference of the two sets on top of the stack.
+    /// Returns false, leaving the stack untouched, if the operands are not both sets.
+    fn set_operation(&mut self, op: Opcode) -> bool {
+        let len = self.stack.len();
+        let (Value::Set(a), Value::Set(b)) = (&self.stack[len - 2], &self.stack[len - 1]) else {
+            return false;
+        };
+
+        let (a, b) = (a.borrow(), b.borrow());
+        let result = match op {
+            Opcode::Union => a.union(&b).copied().collect(),
+            Opcode::Intersection => a.intersection(&b).copied().collect(),
+            Opcode::Subtract => a.difference(&b).copied().collect(),
+            _ => unreachable!("{op} is not a set operation"),
+        };
+        drop((a, b));
+
+        self.stack.truncate(len - 2);
+        self.stack.push(Value::Set(Rc::new(RefCell::new(result))));
+        true
+    }
+
+    /// All the values a for-in loop over the given value would produce
+    fn elements(&mut self, value: Value) -> ThrowResult<ValueArray> {
+        match self.iterable(value)? {
+            Array(array) => Ok(array.borrow().clone()),
+            Range(range) => Ok((0..range.len()).filter_map(|index| range.get(index)).map(Value::from_f64).collect()),
+            Value::Generator(_) => Err(self.runtime_error("Generators can only be iterated over with a for-in loop")),
+            other => unreachable!("{other} is not iterable"),
+        }
+    }
+
+    /// Replace spread markers with the values they hold
+    fn expand_spread(values: Vec<Value>) -> Vec<Value> {
+        if !values.iter().any(|value| matches!(value, Spread(_))) {
+            return values;
+        }
+
+        let mut expanded = Vec::with_capacity(values.len());
+        for value in values {
+            match value {
+                Spread(elements) => expanded.extend(elements.iter().cloned()),
+                value => expanded.push(value),
+            }
+        }
+        expanded
+    }
+
+    /// Convert a value to something `IterNext` can step through.
+    /// Arrays and ranges are iterated over directly, maps by their keys, sets by their elements, strings by their characters,
+    /// and enums and tuples by their members.
+    fn iterable(&mut self, value: Value) -> ThrowResult<Value> {
+        match value {
+            Array(_) | Range(_) | Value::Generator(_) => Ok(value),
+            Enum(enm) => Ok(Array(gc::new_array(enm.members.clone()))),
+            Tuple(elements) => Ok(Array(gc::new_array(elements.to_vec()))),
+            Map(map) if self.options.deterministic.is_some() => {
+                let keys = sorted_keys(map.borrow().keys(), self.interner)
+                    .into_iter()
+                    .map(MapKey::to_value)
+                    .collect();
+                Ok(Array(gc::new_array(keys)))
+            }
+            Map(map) => {
+                let keys = map.borrow().keys().map(|key| key.to_value()).collect();
+                Ok(Array(gc::new_array(keys)))
+            }
+            Value::Set(set) if self.options.deterministic.is_some() => {
+                let elements = sorted_keys(set.borrow().iter(), self.interner)
+                    .into_iter()
+                    .map(MapKey::to_value)
+                    .collect();
+                Ok(Array(gc::new_array(elements)))
+            }
+            Value::Set(set) => {
+                let elements = set.borrow().iter().map(|element| element.to_value()).collect();
+                Ok(Array(gc::new_array(elements)))
+            }
+            Str(id) => {
+                let string = self.interner.lookup(&id).to_string();
+                let chars = string
+                    .chars()
+                    .map(|c| Str(self.interner.intern(c.encode_utf8(&mut [0; 4]))))
+                    .collect();
+                Ok(Array(gc::new_array(chars)))
+            }
+            other => Err(self.runtime_error(&format!("Can't iterate over {other}"))),
+        }
+    }
+
+    fn push_frame(&mut self, fun_idx: usize, passed_args: ArgSet) -> ThrowResult<()> {
+        if self.frames.len() >= self.options.max_call_depth {
+            let max = self.options.max_call_depth;
+            return Err(self.runtime_error(&format!("Stack overflow: more than {max} nested calls")));
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count_call(fun_idx);
+        }
+        let fun = &self.functions[fun_idx];
+        let arity = fun.arity;
+        // The receiver of a method takes the place of the callee
+        let new_frame_offset = self.stack.len() - arity - fun.is_method as usize;
+        let orig_len = self.stack.len() - 1 - arity;
+        let frame: CallFrame = CallFrame {
+            fun_idx,
+            ip: 0,
+            start_len: orig_len,
+            slot_offset: new_frame_offset, // -1 here in book ?
+            passed_args,
+            module: fun.module,
+        };
+        if fun.is_generator {
+            // The frame is suspended before it starts, and the generator takes the place of the callee
+            let slots = self.stack.split_off(new_frame_offset);
+            self.stack.truncate(orig_len);
+            let state = GeneratorState::Suspended(SuspendedFrame {
+                frame,
+                slots,
+                handlers: Vec::new(),
+            });
+            self.stack.push(Value::Generator(gc::new_generator(Generator { fun_idx, state })));
+        } else {
+            self.frames.push(frame);
+            self.turn_left = self.turn_left.saturating_sub(1);
+        }
+        self.maybe_collect_garbage()
+    }
+
+    /// Remove the frame below the one that was just pushed, for a tail call. The callee and arguments of the new frame
+    /// move down to where the old one started, so it returns straight to the caller of the old one.
+    fn replace_caller_frame(&mut self) {
+        let mut frame = unsafe { self.frames.pop().unwrap_unchecked() };
+        let caller = unsafe { self.frames.pop().unwrap_unchecked() };
+        let shift = frame.start_len - caller.start_len;
+        self.stack.drain(caller.start_len..frame.start_len);
+        frame.start_len -= shift;
+        frame.slot_offset -= shift;
+        self.frames.push(frame);
+    }
+
+    /// Continue running a generator from where it stopped, for a for-in loop that jumps to `exit_ip` once it finishes.
+    /// The generator goes on the stack below its slots, where the callee of a call would be.
+    fn resume_generator(&mut self, generator: Rc<RefCell<Generator>>, exit_ip: usize) -> ThrowResult<()> {
+        self.debugger.history.clear();
+        let state = std::mem::replace(&mut generator.borrow_mut().state, GeneratorState::Running { exit_ip });
+        let suspended = match state {
+            GeneratorState::Suspended(suspended) => suspended,
+            GeneratorState::Running { exit_ip } => {
+                generator.borrow_mut().state = GeneratorState::Running { exit_ip };
+                return Err(self.runtime_error("Generator is already running"));
+            }
+            GeneratorState::Done => {
+                generator.borrow_mut().state = GeneratorState::Done;
+                frame_mut!(self).ip = exit_ip;
+                return Ok(());
+            }
+        };
+        if self.frames.len() >= self.options.max_call_depth {
+            generator.borrow_mut().state = GeneratorState::Suspended(suspended);
+            let max = self.options.max_call_depth;
+            return Err(self.runtime_error(&format!("Stack overflow: more than {max} nested calls")));
+        }
+
+        let start_len = self.stack.len();
+        self.stack.push(Value::Generator(generator));
+        let slot_offset = self.stack.len();
+        self.stack.extend(suspended.slots);
+        let frame_count = self.frames.len() + 1;
+        self.handlers.extend(suspended.handlers.into_iter().map(|handler| Handler {
+            frame_count,
+            stack_len: slot_offset + handler.stack_len,
+            ..handler
+        }));
+        self.frames.push(CallFrame {
+            start_len,
+            slot_offset,
+            ..suspended.frame
+        });
+        Ok(())
+    }
+
+    /// Suspend the running generator at a `yield`, and give the value to the loop that resumed it
+    fn yield_value(&mut self, value: Value) {
+        self.debugger.history.clear();
+        let frame_count = self.frames.len();
+        let frame = unsafe { self.frames.pop().unwrap_unchecked() };
+        let handler_count = self
+            .handlers
+            .iter()
+            .rev()
+            .take_while(|handler| handler.frame_count == frame_count)
+            .count();
+        let handlers = self
+            .handlers
+            .split_off(self.handlers.len() - handler_count)
+            .into_iter()
+            .map(|handler| Handler {
+                stack_len: handler.stack_len - frame.slot_offset,
+                ..handler
+            })
+            .collect();
+        let slots = self.stack.split_off(frame.slot_offset);
+        let Some(Value::Generator(generator)) = self.stack.pop() else {
+            unreachable!("A generator frame is below its slots");
+        };
+        generator.borrow_mut().state = GeneratorState::Suspended(SuspendedFrame { frame, slots, handlers });
+        self.stack.push(value);
+    }
+
+    /// Drop the frames above `frame_count`, like when an exception unwinds them
+    fn unwind_frames(&mut self, frame_count: usize) {
+        let frames = self.frames.split_off(frame_count.min(self.frames.len()));
+        if frames.iter().any(|frame| self.functions[frame.fun_idx].is_generator) {
+            self.debugger.history.clear();
+        }
+        self.finish_generators(&frames, &self.stack);
+    }
+
+    /// Generators running in frames that are dropped can't be resumed, so they are finished
+    fn finish_generators(&self, frames: &[CallFrame], stack: &[Value]) {
+        for frame in frames {
+            if self.functions[frame.fun_idx].is_generator {
+                if let Some(Value::Generator(generator)) = stack.get(frame.slot_offset - 1) {
+                    generator.borrow_mut().state = GeneratorState::Done;
+                }
+            }
+        }
+    }
+
+    /// `Spawn` and `Receive`, which change which task is running
+    async fn run_task_operation(&mut self, operation: TaskOperation, arg_count: usize) -> ThrowResult<()> {
+        let callee_slot = self.stack.len() - 1 - arg_count;
+        match operation {
+            TaskOperation::Spawn => {
+                // Call the function in place of `Spawn`, then move the frame it pushed to a new task
+                self.stack.remove(callee_slot);
+                let frame_count = self.frames.len();
+                Box::pin(self.call_value(arg_count - 1)).await?;
+                if self.frames.len() > frame_count {
+                    let frame = unsafe { self.frames.pop().unwrap_unchecked() };
+                    let base = frame.start_len;
+                    self.tasks.push_back(Task {
+                        stack: self.stack.split_off(base),
+                        frames: vec![CallFrame {
+                            start_len: 0,
+                            slot_offset: frame.slot_offset - base,
+                            ..frame
+                        }],
+                        handlers: Vec::new(),
+                        is_main: false,
+                        receiving: None,
+                    });
+                }
+                // Builtins finish right away, and what they returned is dropped
+                self.stack.truncate(callee_slot);
+                self.stack.push(Nil);
+                Ok(())
+            }
+            TaskOperation::Receive => {
+                let Value::Channel(queue) = self.peek(0).clone() else {
+                    let arg = self.peek(0).clone();
+                    return Err(self.runtime_error(&format!("Expected channel as argument to receive, got {arg}")));
+                };
+                let value = queue.borrow_mut().pop_front();
+                if let Some(value) = value {
+                    self.stack.truncate(callee_slot);
+                    self.stack.push(value);
+                    return Ok(());
+                }
+
+                // A callback runs on the host stack, so its task can't be put aside
+                if self.nested_calls > 0 {
+                    return Err(self.runtime_error("Can't wait for a value from a channel inside a callback"));
+                }
+                let Some(next) = self.next_task() else {
+                    return Err(self.deadlock());
+                };
+                let mut waiting = self.enter_task(next);
+                waiting.receiving = Some(queue);
+                self.tasks.push_back(waiting);
+                Ok(())
+            }
+        }
+    }
+
+    fn deadlock(&mut self) -> Value {
+        self.runtime_error("Deadlock: every task is waiting for a value from a channel")
+    }
+
+    /// Take the next task that can run out of the queue
+    fn next_task(&mut self) -> Option<Task> {
+        let idx = self.tasks.iter().position(Task::can_run)?;
+        self.tasks.remove(idx)
+    }
+
+    /// Make the task the running one, and return the one that was running.
+    /// A task that was waiting for a channel gets the value it was waiting for.
+    fn enter_task(&mut self, task: Task) -> Task {
+        self.debugger.history.clear();
+        let previous = Task {
+            stack: std::mem::replace(&mut self.stack, task.stack),
+            frames: std::mem::replace(&mut self.frames, task.frames),
+            handlers: std::mem::replace(&mut self.handlers, task.handlers),
+            is_main: std::mem::replace(&mut self.task_is_main, task.is_main),
+            receiving: None,
+        };
+        if let Some(queue) = task.receiving {
+            let value = queue.borrow_mut().pop_front().unwrap_or(Nil);
+            self.stack.truncate(self.stack.len() - 2);
+            self.stack.push(value);
+        }
+        self.turn_left = TASK_TURN;
+        previous
+    }
+
+    /// Let the next task that can run take a turn, and put the running one at the back of the queue
+    fn take_turns(&mut self) {
+        self.turn_left = TASK_TURN;
+        if let Some(next) = self.next_task() {
+            let previous = self.enter_task(next);
+            self.tasks.push_back(previous);
+        }
+    }
+
+    /// Run the next task once the running one has returned.
+    /// When no task can run anymore, returns what the main script returned, or throws a deadlock error in it.
+    fn finish_task(&mut self) -> std::result::Result<Option<Value>, RuntimeError> {
+        let value = self.pop_unchecked();
+        if self.task_is_main {
+            self.main_result = Some(value);
+        }
+        if let Some(next) = self.next_task() {
+            self.enter_task(next);
+            return Ok(None);
+        }
+        // Tasks that are still waiting could never run again
+        if let Some(result) = self.main_result.take() {
+            self.drop_tasks();
+            return Ok(Some(result));
+        }
+
+        let main = self.tasks.iter().position(|task| task.is_main).expect("The main script is waiting");
+        let mut main = unsafe { self.tasks.remove(main).unwrap_unchecked() };
+        main.receiving = None;
+        self.enter_task(main);
+        let offset = frame!(self).ip.saturating_sub(1);
+        let exception = self.deadlock();
+        match self.throw_value(exception, offset) {
+            Ok(()) => Ok(None),
+            Err(exception) => Err(self.uncaught(&exception, offset)),
+        }
+    }
+
+    fn drop_tasks(&mut self) {
+        for task in std::mem::take(&mut self.tasks) {
+            self.finish_generators(&task.frames, &task.stack);
+        }
+    }
+
+    /// Call a function with named arguments, which follow the positional arguments on the stack.
+    /// Arguments are moved into the slots of the parameters they name.
+    fn call_named(&mut self, arg_count: usize, names: &[StrId]) -> ThrowResult<()> {
+        let callee = self.peek(arg_count).clone();
+        let callee_slot = self.stack.len() - 1 - arg_count;
+        let idx = match callee {
+            Function(idx) => idx,
+            BoundMethod(bound) => {
+                self.stack[callee_slot] = bound.receiver.clone();
+                bound.method
+            }
+            Class(class) if class.find_method(self.init_name).is_some() => {
+                let init = class.find_method(self.init_name).unwrap();
+                self.stack[callee_slot] = Instance(gc::new_instance(crate::value::Instance {
+                    class,
+                    fields: Default::default(),
+                }));
+                init
+            }
+            callee => {
+                return Err(self.runtime_error(&format!(
+                    "Only functions declared in the program accept named arguments, got {callee}"
+                )));
+            }
+        };
+
+        let fun = &self.functions[idx];
+        let (arity, min_arity, is_variadic) = (fun.arity, fun.min_arity, fun.is_variadic);
+        let fixed_arity = if is_variadic { arity - 1 } else { arity };
+        let positional_count = arg_count - names.len();
+
+        if !is_variadic && positional_count > arity {
+            return Err(self.runtime_error(&format!(
+                "Expected at most {arity} arguments but got {positional_count} positional arguments"
+            )));
+        }
+
+        let mut args = self.stack.split_off(self.stack.len() - arg_count);
+        let named = args.split_off(positional_count);
+        let rest = if args.len() > fixed_arity {
+            args.split_off(fixed_arity)
+        } else {
+            Vec::new()
+        };
+
+        let mut passed_args = ArgSet::first(args.len());
+        args.resize(fixed_arity, Value::Nil);
+
+        for (name, value) in names.iter().zip(named) {
+            let param_names = &self.functions[idx].param_names[..fixed_arity];
+            let Some(param) = param_names.iter().position(|param_name| param_name == name) else {
+                return Err(self.runtime_error(&format!("Unknown parameter name {}", self.interner.lookup(name))));
+            };
+
+            if passed_args.contains(param) {
+                return Err(self.runtime_error(&format!("Got multiple values for parameter {}", self.interner.lookup(name))));
+            }
+
+            args[param] = value;
+            passed_args.insert(param);
+        }
+
+        for param in 0..min_arity {
+            if !passed_args.contains(param) {
+                let name = self.functions[idx].param_names[param];
+                return Err(self.runtime_error(&format!("Missing argument for parameter {}", self.interner.lookup(&name))));
+            }
+        }
+
+        self.stack.extend(args);
+        if is_variadic {
+            self.stack.push(Value::Array(gc::new_array(rest)));
+        }
+
+        self.push_frame(idx, passed_args)
+    }
+
+    /// Unwind to the closest handler that can handle the exception.
+    /// If there is none, the exception is given back, and the frames are left as they were for the traceback.
+    /// `offset` is where the instruction that threw starts.
+    fn throw_value(&mut self, exception: Value, offset: usize) -> ThrowResult<()> {
+        while let Some(handler) = self.handlers.pop() {
+            if let Some(catch_ip) = handler.catch_ip {
+                self.thrown = None;
+                self.unwind_frames(handler.frame_count);
+                self.stack.truncate(handler.stack_len);
+
+                // The finally block still has to run if the catch block throws
+                self.handlers.push(Handler { catch_ip: None, ..handler });
+                self.stack.push(exception);
+                frame_mut!(self).ip = catch_ip;
+                return Ok(());
+            }
+
+            if let Some(finally_ip) = handler.finally_ip {
+                // The exception is thrown again after the finally block, from where the traceback would be lost
+                self.capture_trace(&exception, offset);
+                self.unwind_frames(handler.frame_count);
+                self.stack.truncate(handler.stack_len);
+                self.stack.push(exception);
+                self.stack.push(Number(COMPLETION_THROW));
+                frame_mut!(self).ip = finally_ip;
+                return Ok(());
+            }
+        }
+
+        Err(exception)
+    }
+
+    /// Return from the current function, after running any pending `finally` blocks in it.
+    /// Returns true if the script has finished running.
+    fn return_value(&mut self, value: Value) -> bool {
+        while self.handlers.last().is_some_and(|handler| handler.frame_count == self.frames.len()) {
+            let handler = unsafe { self.handlers.pop().unwrap_unchecked() };
+
+            if let Some(finally_ip) = handler.finally_ip {
+                self.stack.truncate(handler.stack_len);
+                self.stack.push(value);
+                self.stack.push(Number(COMPLETION_RETURN));
+                frame_mut!(self).ip = finally_ip;
+                return false;
+            }
+        }
+
+        // The time since the last sample goes to the script, before it finishes
+        if self.frames.len() == 1 && self.profiler.is_some() {
+            self.take_sample();
+        }
+
+        let orig_len = frame!(self).start_len;
+        let frame = unsafe { self.frames.pop().unwrap_unchecked() };
+
+        // A finished generator ends the loop that resumed it, and what it returned is dropped
+        if self.functions[frame.fun_idx].is_generator {
+            self.debugger.history.clear();
+            let Some(Value::Generator(generator)) = self.stack.get(frame.slot_offset - 1) else {
+                unreachable!("A generator frame is below its slots");
+            };
+            let state = std::mem::replace(&mut generator.borrow_mut().state, GeneratorState::Done);
+            let GeneratorState::Running { exit_ip } = state else {
+                unreachable!("A generator that returns is running");
+            };
+            self.stack.truncate(orig_len);
+            frame_mut!(self).ip = exit_ip;
+            return false;
+        }
+
+        self.stack.truncate(orig_len);
+        self.stack.push(value);
+        self.frames.is_empty()
+    }
+
+    /// Run until the script finishes, and return what it returned, or None if the debugger paused it
+    async fn run(&mut self) -> std::result::Result<Option<Value>, RuntimeError> {
+        gc::set_active_vm(self.gc_id);
+        loop {
+            if self.turn_left == 0 {
+                self.take_turns();
+            }
+            if self.debugger.active {
+                if let Some(line) = self.line_start() {
+                    if !std::mem::take(&mut self.debugger.resuming) {
+                        self.record_checkpoint();
+                        if self.should_pause(line) {
+                            self.debugger.resuming = true;
+                            return Ok(None);
                         }
                     }
                 }
-                Opcode::DefineGlobal => {
-                    let name = self.read_string_or_id();
-                    let value = self.pop_unchecked();
-                    self.globals.insert(name, value);
+            }
+            let offset = frame!(self).ip;
+            if !self.consume_fuel() {
+                return Err(self.interrupted(offset));
+            }
+            if self.options.trace_execution {
+                self.trace_instruction();
+            }
+            let instruction = self.read_instruction();
+            let result = if awaits(&instruction) {
+                self.run_call_instruction(instruction).await
+            } else {
+                self.run_instruction(instruction)
+            };
+            let result = match result {
+                Ok(true) => match self.finish_task()? {
+                    Some(result) => return Ok(Some(result)),
+                    None => Ok(()),
+                },
+                Ok(false) if self.options.gc_stress => self.maybe_collect_garbage(),
+                Ok(false) => Ok(()),
+                Err(exception) => Err(exception),
+            };
+            if let Err(exception) = result {
+                if let Err(exception) = self.throw_value(exception, offset) {
+                    return Err(self.uncaught(&exception, offset));
+                }
+            }
+        }
+    }
+
+    /// Use up one instruction of the budget. Returns false if there was none left.
+    /// Cancelling the program uses up the rest of the budget, so it stops the same way.
+    fn consume_fuel(&mut self) -> bool {
+        if self.fuel == 0 {
+            return false;
+        }
+        self.fuel -= 1;
+        if self.options.deterministic.is_some() {
+            self.determinism.tick();
+        }
+        if self.fuel & (PROFILE_SAMPLE_INTERVAL - 1) == 0 && self.profiler.is_some() {
+            self.take_sample();
+        }
+        if self.fuel & (CANCEL_CHECK_INTERVAL - 1) == 0 && self.options.cancel_token.as_ref().is_some_and(|token| token.is_cancelled()) {
+            self.cancelled = true;
+            self.fuel = 0;
+            return false;
+        }
+        true
+    }
+
+    fn take_sample(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.sample(self.frames.iter().map(|frame| frame.fun_idx));
+        }
+    }
+
+    /// Calls and time of each function in the last run with `VmOptions::profile`
+    pub fn profile_report(&self) -> Option<ProfileReport> {
+        let profiler = self.profiler.as_ref()?;
+        Some(profiler.report(|fun_idx| {
+            let fun = &self.functions[fun_idx];
+            let name = fun.name.map_or("<script>", |name| self.interner.lookup(&name)).to_string();
+            (name, self.modules[fun.module].path.clone())
+        }))
+    }
+
+    /// Instructions and constant reads of the last run with `VmOptions::collect_stats`
+    pub fn stats(&self) -> Option<VmStats> {
+        let stats = self.stats.as_ref()?;
+        Some(stats.stats(|fun_idx, index| {
+            let fun = &self.functions[fun_idx];
+            let name = fun.name.map_or("<script>", |name| self.interner.lookup(&name)).to_string();
+            (name, value_as_string(&fun.chunk.constants[index], self.interner))
+        }))
+    }
+
+    /// Lines of each module that ran in the last run with `VmOptions::coverage`, main module first
+    pub fn coverage(&self) -> Option<Vec<Coverage>> {
+        let coverage = self.coverage.as_ref()?;
+        let paths: Vec<String> = self.modules.iter().map(|module| module.path.clone()).collect();
+        Some(coverage.coverage(&self.functions, &paths))
+    }
+
+    /// Error for when the program was stopped because the fuel ran out
+    fn interrupted(&self, offset: usize) -> RuntimeError {
+        if self.cancelled {
+            return self.error_at(RuntimeErrorKind::Cancelled, "Execution cancelled".to_string(), offset);
+        }
+        let limit = self.options.max_instructions.unwrap_or(u64::MAX);
+        let message = format!("Execution limit of {limit} instructions exceeded");
+        self.error_at(RuntimeErrorKind::ExecutionLimitExceeded { limit }, message, offset)
+    }
+
+    /// Push the local variable in the slot that is read next
+    fn push_local(&mut self) {
+        let slot = self.read_byte() as usize;
+        let value = self.stack[frame!(self).slot_offset + slot].clone();
+        self.stack.push(value);
+    }
+
+    /// Read the operator a superinstruction ends with, and run it
+    fn read_operation(&mut self) -> ThrowResult<bool> {
+        let operator = unsafe { Opcode::try_from(self.read_byte()).unwrap_unchecked() };
+        self.binary_operation(operator)
+    }
+
+    /// Run an operator on the two values on top of the stack. Superinstructions that end with an operator run it here.
+    fn binary_operation(&mut self, instruction: Opcode) -> ThrowResult<bool> {
+        match instruction {
+            Opcode::Equal => {
+                if self.call_special_method(EQ_METHOD, 1)? {
+                    return Ok(false);
+                }
+
+                let a = self.pop_unchecked();
+                let b = self.pop_unchecked();
+                self.stack.push(Bool(a == b))
+            }
+            Opcode::Add => {
+                if self.call_special_method(ADD_METHOD, 1)? {
+                    return Ok(false);
+                }
+
+                let b = self.pop_unchecked();
+                let a = self.pop_unchecked();
+                if let Some(result) = arithmetic(Opcode::Add, &a, &b) {
+                    self.stack.push(result);
+                    return Ok(false);
+                }
+
+                match (b, a) {
+                    (Str(b), Str(a)) => {
+                        self.reserve_string(self.interner.lookup(&a).len() + self.interner.lookup(&b).len())?;
+                        let mut new_string = String::from(self.interner.lookup(&a));
+                        new_string.push_str(self.interner.lookup(&b));
+                        let id = self.interner.intern(&new_string);
+                        self.stack.push(Str(id));
+                    }
+                    (b @ (Number(_) | Int(_)), Str(a)) => {
+                        let mut new_string = String::from(self.interner.lookup(&a));
+                        new_string.push_str(&value_as_string(&b, self.interner));
+                        let id = self.interner.intern(&new_string);
+                        self.stack.push(Str(id));
+                    }
+                    (left, right) => {
+                        return Err(self.runtime_error(&format!("Operands must be numbers but got {left} {right}")));
+                    }
+                }
+            }
+            Opcode::Subtract => {
+                if !self.set_operation(Opcode::Subtract) {
+                    binop!(self, Opcode::Subtract, SUB_METHOD)
+                }
+            }
+            Opcode::Multiply => binop!(self, Opcode::Multiply, MUL_METHOD),
+            Opcode::Modulo => binop!(self, Opcode::Modulo, MOD_METHOD),
+            Opcode::Divide => binop!(self, Opcode::Divide, DIV_METHOD),
+            Opcode::Greater => comparison!(self, >, GT_METHOD),
+            Opcode::Less => comparison!(self, <, LT_METHOD),
+            _ => unreachable!("{instruction} is not a binary operation"),
+        }
+
+        Ok(false)
+    }
+
+    /// Read the opcode of the next instruction, and count it for `VmOptions::collect_stats` and `VmOptions::coverage`
+    fn read_instruction(&mut self) -> Opcode {
+        let frame = frame!(self);
+        let byte = self.functions[frame.fun_idx].chunk.code[frame.ip];
+        if let Some(stats) = &mut self.stats {
+            stats.count_opcode(byte);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(frame.fun_idx, frame.ip);
+        }
+        frame_mut!(self).ip += 1;
+        // The compiler only writes valid opcodes where instructions start
+        unsafe { Opcode::from_u8_unchecked(byte) }
+    }
+
+    /// Execute an instruction that calls a value or runs a module, which is async since builtins can be. The others are
+    /// run by `run_instruction`, so they don't pay for an async call. Returns true if the script has finished running.
+    async fn run_call_instruction(&mut self, instruction: Opcode) -> ThrowResult<bool> {
+        match instruction {
+            Opcode::Call => {
+                let arg_count = self.read_byte() as usize;
+                self.call_value(arg_count).await?;
+            }
+            Opcode::TailCall => {
+                let arg_count = self.read_byte() as usize;
+                // Frames with handlers still have `finally` blocks to run, and generators have to stay below their slots.
+                // Builtins return right away, so the `Return` after the call returns their result.
+                let frame_count = self.frames.len();
+                let reuse_frame = matches!(self.peek(arg_count), Function(_) | BoundMethod(_) | Class(_))
+                    && !self.functions[frame!(self).fun_idx].is_generator
+                    && self.handlers.last().is_none_or(|handler| handler.frame_count != frame_count);
+                self.call_value(arg_count).await?;
+                if reuse_frame && self.frames.len() > frame_count {
+                    self.replace_caller_frame();
+                }
+            }
+            Opcode::CallSpread => {
+                let arg_count = self.read_byte() as usize;
+                let args = self.stack.split_off(self.stack.len() - arg_count);
+                let args = Vm::<F, Fut>::expand_spread(args);
+                let arg_count = args.len();
+                self.stack.extend(args);
+                self.call_value(arg_count).await?;
+            }
+            Opcode::Import => {
+                let Module(module) = self.pop_unchecked() else {
+                    return Err(self.runtime_error("Can only import a module"));
+                };
+
+                if self.globals[module].is_some() {
+                    self.stack.push(Module(module));
+                } else {
+                    // First import - run the top-level code of the module, which returns the module
+                    let Some(script) = self.modules[module].script else {
+                        return Err(self.runtime_error(&format!("Module '{}' was not compiled", self.modules[module].path)));
+                    };
+                    self.globals[module] = Some(self.builtins.clone());
+                    self.stack.push(Function(script));
+                    self.call_value(0).await?;
+                }
+            }
+            _ => unreachable!("{instruction} is not async"),
+        }
+
+        Ok(false)
+    }
+
+    /// Execute an instruction that `run_call_instruction` does not. Returns true if the script has finished running.
+    /// Inlined into `run`, so that instructions that don't throw pay nothing for returning a `ThrowResult`.
+    #[inline(always)]
+    fn run_instruction(&mut self, instruction: Opcode) -> ThrowResult<bool> {
+        match instruction {
+            Opcode::Call | Opcode::TailCall | Opcode::CallSpread | Opcode::Import => {
+                unreachable!("{instruction} is run by `run_call_instruction`")
+            }
+            Opcode::Print => {
+                print_value(&self.pop_unchecked(), self.interner);
+                xprintln!("");
+            }
+            Opcode::JumpIfNil | Opcode::JumpIfNotNil | Opcode::JumpIfNilLong | Opcode::JumpIfNotNilLong => {
+                let offset = self.read_jump(instruction.is_long());
+                if (*self.peek(0) == Nil) == (instruction.short() == Opcode::JumpIfNil) {
+                    frame_mut!(self).ip += offset;
+                }
+            }
+            Opcode::GetIndex => {
+                let index = self.pop_unchecked();
+                let container = self.pop_unchecked();
+                if let Instance(_) = container {
+                    self.index_instance(container, GET_METHOD, &[index])?;
+                } else {
+                    let element = get_index(&container, &index, self.interner);
+                    let element = self.check(element, "Error getting index")?;
+                    self.stack.push(element);
+                }
+            }
+            Opcode::SetIndex => {
+                let value = self.pop_unchecked();
+                let index = self.pop_unchecked();
+                let mut container = self.pop_unchecked();
+                if let Instance(_) = container {
+                    self.index_instance(container, SET_METHOD, &[index, value])?;
+                } else {
+                    let result = set_index(&mut container, &index, value.clone());
+                    self.check(result, "Error setting index")?;
+                    self.stack.push(value);
+                }
+            }
+            Opcode::JumpIfFalse | Opcode::JumpIfFalseLong => {
+                let offset = self.read_jump(instruction.is_long());
+                if self.is_falsey(self.peek(0)) {
+                    frame_mut!(self).ip += offset;
+                }
+            }
+            Opcode::JumpIfArgPassed | Opcode::JumpIfArgPassedLong => {
+                let param = self.read_byte() as usize;
+                let offset = self.read_jump(instruction.is_long());
+                if frame!(self).passed_args.contains(param) {
+                    frame_mut!(self).ip += offset;
+                }
+            }
+            Opcode::Loop | Opcode::LoopLong => {
+                let offset = self.read_jump(instruction.is_long());
+                frame_mut!(self).ip -= offset;
+                self.turn_left = self.turn_left.saturating_sub(1);
+                self.maybe_collect_garbage()?;
+            }
+            Opcode::Jump | Opcode::JumpLong => {
+                let offset = self.read_jump(instruction.is_long());
+                frame_mut!(self).ip += offset;
+            }
+            Opcode::Spread => {
+                let value = self.pop_unchecked();
+                let elements = self.elements(value)?;
+                self.stack.push(Spread(Rc::new(elements)));
+            }
+            Opcode::BuildTuple => {
+                let count = self.read_byte() as usize;
+                let elements = self.stack.split_off(self.stack.len() - count);
+                self.stack.push(Tuple(Rc::from(elements)));
+            }
+            Opcode::BuildArray => {
+                let count = self.read_byte() as usize;
+                let elements = self.stack.split_off(self.stack.len() - count);
+                let elements = Vm::<F, Fut>::expand_spread(elements);
+                self.stack.push(Array(gc::new_array(elements)));
+            }
+            Opcode::CallNamed | Opcode::CallNamedLong => {
+                let arg_count = self.read_byte() as usize;
+                let names: Vec<StrId> = match self.read_constant(instruction.is_long()) {
+                    Value::Array(names) => names
+                        .borrow()
+                        .iter()
+                        .map(|name| match name {
+                            Value::Str(id) => *id,
+                            other => panic!("Found {other} instead"),
+                        })
+                        .collect(),
+                    other => panic!("Found {other} instead"),
+                };
+                self.call_named(arg_count, &names)?;
+            }
+            Opcode::Return => {
+                let value = self.pop().expect("Nothing to return");
+                if self.return_value(value) {
+                    return Ok(true);
+                }
+            }
+            Opcode::Yield => {
+                let value = self.pop_unchecked();
+                self.yield_value(value);
+            }
+            Opcode::PushHandler | Opcode::PushHandlerLong => {
+                let catch_offset = self.read_jump(instruction.is_long());
+                let catch_ip = frame!(self).ip + catch_offset;
+                let finally_offset = self.read_jump(instruction.is_long());
+                let finally_ip = frame!(self).ip + finally_offset;
+
+                self.handlers.push(Handler {
+                    frame_count: self.frames.len(),
+                    stack_len: self.stack.len(),
+                    catch_ip: (catch_offset != 0).then_some(catch_ip),
+                    finally_ip: (finally_offset != 0).then_some(finally_ip),
+                });
+            }
+            Opcode::PopHandler => {
+                self.handlers.pop();
+            }
+            Opcode::IterStart => {
+                let value = self.pop_unchecked();
+                let iterable = self.iterable(value)?;
+                self.stack.push(iterable);
+            }
+            Opcode::IterNext | Opcode::IterNextLong => {
+                let slot = frame!(self).slot_offset + self.read_byte() as usize;
+                let offset = self.read_jump(instruction.is_long());
+                if let Value::Generator(generator) = &self.stack[slot] {
+                    let exit_ip = frame!(self).ip + offset;
+                    self.resume_generator(generator.clone(), exit_ip)?;
+                    return Ok(false);
+                }
+                let Int(index) = self.stack[slot + 1] else {
+                    return Err(self.runtime_error("Index of a loop must be an integer"));
+                };
+
+                let next = match &self.stack[slot] {
+                    Array(array) => array.borrow().get(index as usize).cloned(),
+                    Range(range) => range.get(index as usize).map(Value::from_f64),
+                    other => {
+                        let message = format!("Can't iterate over {other}");
+                        return Err(self.runtime_error(&message));
+                    }
+                };
+
+                match next {
+                    Some(value) => {
+                        self.stack[slot + 1] = Int(index + 1);
+                        self.stack.push(value);
+                    }
+                    None => frame_mut!(self).ip += offset,
                 }
-                Opcode::DeclareArray => {
-                    let size_val = self.pop_unchecked();
-                    match size_val {
-                        Number(len) => {
-                            self.stack.push(Value::Array(Rc::new(RefCell::new(vec![Nil; len as usize]))));
+            }
+            Opcode::GetProperty | Opcode::GetPropertyLong => {
+                let offset = frame!(self).ip - 1;
+                let name = self.read_string_or_id(instruction.is_long());
+                let object = self.pop_unchecked();
+                match object {
+                    Module(module) => {
+                        if !self.modules[module].exports.contains(&name) {
+                            return Err(self.runtime_error(&format!(
+                                "Module '{}' does not export '{}'",
+                                self.modules[module].path,
+                                self.interner.lookup(&name)
+                            )));
                         }
+
+                        let value = self.globals[module].as_ref().and_then(|globals| globals.get(&name)).cloned();
+                        self.stack.push(value.unwrap_or(Nil));
+                    }
+                    Instance(instance) => {
+                        if let Some(value) = self.cached_property(offset, &instance, name) {
+                            self.stack.push(value);
+                            return Ok(false);
+                        }
+
+                        let field = instance
+                            .borrow()
+                            .fields
+                            .get_full(&name)
+                            .map(|(slot, _, value)| (slot, value.clone()));
+                        if let Some((slot, value)) = field {
+                            self.cache(offset, InlineCache::Slot(slot));
+                            self.stack.push(value);
+                            return Ok(false);
+                        }
+
+                        let class = instance.borrow().class.clone();
+                        if let Some(getter) = class.find_getter(name) {
+                            self.stack.push(Instance(instance));
+                            self.call_function(getter, 0)?;
+                            return Ok(false);
+                        }
+
+                        let Some(method) = class.find_method(name) else {
+                            return Err(self.runtime_error(&format!("Undefined property {}", self.interner.lookup(&name))));
+                        };
+                        let class = Rc::downgrade(&class);
+                        self.cache(offset, InlineCache::Method { class, method });
+
+                        self.stack.push(BoundMethod(Rc::new(crate::value::BoundMethod {
+                            receiver: Instance(instance),
+                            method,
+                        })));
+                    }
+                    Enum(enm) => {
+                        let member = enm
+                            .members
+                            .iter()
+                            .find(|member| matches!(member, EnumMember(member) if member.name == name));
+                        let Some(member) = member.cloned() else {
+                            return Err(self.runtime_error(&format!(
+                                "Enum {} has no member {}",
+                                self.interner.lookup(&enm.name),
+                                self.interner.lookup(&name)
+                            )));
+                        };
+                        self.stack.push(member);
+                    }
+                    EnumMember(member) => match self.interner.lookup(&name) {
+                        "name" => self.stack.push(Str(member.name)),
+                        "ordinal" => self.stack.push(Int(member.ordinal as i64)),
                         other => {
-                            self.runtime_error(&format!("Expected number, got {other}"));
+                            return Err(self.runtime_error(&format!("Enum members only have 'name' and 'ordinal' properties, got {other}")));
                         }
+                    },
+                    Class(class) => {
+                        let Some(value) = class.statics.borrow().get(&name).cloned() else {
+                            return Err(self.runtime_error(&format!(
+                                "Undefined static member {} of class {}",
+                                self.interner.lookup(&name),
+                                self.interner.lookup(&class.name)
+                            )));
+                        };
+                        self.stack.push(value);
+                    }
+                    Record(record) => {
+                        let Some(value) = record.get(name) else {
+                            return Err(self.runtime_error(&format!(
+                                "Record {} has no field {}",
+                                self.interner.lookup(&record.typ.name),
+                                self.interner.lookup(&name)
+                            )));
+                        };
+                        self.stack.push(value.clone());
+                    }
+                    Array(list) if ListCallbackKind::from_name(self.interner.lookup(&name)).is_some() => {
+                        let kind = ListCallbackKind::from_name(self.interner.lookup(&name)).unwrap();
+                        self.stack.push(ListCallback(Rc::new(crate::value::ListCallback { list, kind })));
+                    }
+                    other => {
+                        let Some(method) = builtin_method(&other, self.interner.lookup(&name)) else {
+                            return Err(self.runtime_error(&format!("Undefined property {} of {other}", self.interner.lookup(&name))));
+                        };
+                        self.stack.push(method);
                     }
                 }
-                Opcode::Equal => {
-                    let a = self.pop_unchecked();
-                    let b = self.pop_unchecked();
-                    self.stack.push(Bool(a == b))
+            }
+            Opcode::SetProperty | Opcode::SetPropertyLong => {
+                let name = self.read_string_or_id(instruction.is_long());
+                let value = self.pop_unchecked();
+                let object = self.pop_unchecked();
+                let instance = match object {
+                    Instance(instance) => instance,
+                    Class(class) => {
+                        class.statics.borrow_mut().insert(name, value.clone());
+                        self.stack.push(value);
+                        return Ok(false);
+                    }
+                    Record(_) => {
+                        return Err(self.runtime_error("Records are immutable"));
+                    }
+                    other => {
+                        return Err(self.runtime_error(&format!("Only classes and instances have fields, got {other}")));
+                    }
+                };
+
+                // The assignment evaluates to the return value of the setter
+                let setter = instance.borrow().class.find_setter(name);
+                if let Some(setter) = setter {
+                    self.stack.push(Instance(instance));
+                    self.stack.push(value);
+                    self.call_function(setter, 1)?;
+                    return Ok(false);
                 }
-                Opcode::Nil => self.stack.push(Nil),
-                Opcode::Add => {
-                    let b = self.pop_unchecked();
-                    let a = self.pop_unchecked();
-                    match (b, a) {
-                        (Number(a), Number(b)) => {
-                            self.stack.push(Number(a + b));
-                        }
-                        (Str(b), Str(a)) => {
-                            let mut new_string = String::from(self.interner.lookup(&a));
-                            new_string.push_str(self.interner.lookup(&b));
-                            let id = self.interner.intern(&new_string);
-                            self.stack.push(Str(id));
-                        }
-                        (Number(b), Str(a)) => {
-                            let mut new_string = String::from(self.interner.lookup(&a));
-                            new_string.push_str(&b.to_string());
-                            let id = self.interner.intern(&new_string);
-                            self.stack.push(Str(id));
-                        }
-                        (left, right) => {
-                            self.runtime_error(&format!("Operands must be numbers but got {left} {right}"));
+
+                instance.borrow_mut().fields.insert(name, value.clone());
+                self.stack.push(value);
+            }
+            Opcode::Class | Opcode::ClassLong => {
+                let name = self.read_string_or_id(instruction.is_long());
+                self.stack.push(Class(gc::new_class(crate::value::Class {
+                    name,
+                    methods: Default::default(),
+                    getters: Default::default(),
+                    setters: Default::default(),
+                    statics: Default::default(),
+                })));
+            }
+            Opcode::StaticMember | Opcode::StaticMemberLong => {
+                let name = self.read_string_or_id(instruction.is_long());
+                let value = self.pop_unchecked();
+                let Class(class) = self.peek(0) else {
+                    return Err(self.runtime_error("Static members can only be added to a class"));
+                };
+                class.statics.borrow_mut().insert(name, value);
+            }
+            Opcode::Method | Opcode::Getter | Opcode::Setter | Opcode::MethodLong | Opcode::GetterLong | Opcode::SetterLong => {
+                let name = self.read_string_or_id(instruction.is_long());
+                let Function(method) = self.pop_unchecked() else {
+                    return Err(self.runtime_error("Method must be a function"));
+                };
+                let Class(class) = self.peek(0) else {
+                    return Err(self.runtime_error("Methods can only be added to a class"));
+                };
+
+                let methods = match instruction.short() {
+                    Opcode::Getter => &class.getters,
+                    Opcode::Setter => &class.setters,
+                    _ => &class.methods,
+                };
+                methods.borrow_mut().insert(name, method);
+            }
+            Opcode::Throw => {
+                let exception = self.pop_unchecked();
+                return Err(exception);
+            }
+            Opcode::EndFinally => {
+                let completion = self.pop_unchecked();
+                let value = self.pop_unchecked();
+                match completion {
+                    Number(COMPLETION_THROW) => return Err(value),
+                    Number(COMPLETION_RETURN) => return Ok(self.return_value(value)),
+                    _ => {}
+                }
+            }
+            Opcode::Constant => {
+                let constant = self.read_constant(false).clone();
+                self.stack.push(constant);
+            }
+            Opcode::ConstantLong => {
+                let constant = self.read_constant(true).clone();
+                self.stack.push(constant);
+            }
+            Opcode::ConstantOperation => {
+                let constant = self.read_constant(false).clone();
+                self.stack.push(constant);
+                return self.read_operation();
+            }
+            Opcode::GetLocalPlain => {
+                frame_mut!(self).ip += 2; // The nil constant and the `GetLocal`
+                self.push_local();
+            }
+            Opcode::LocalConstantOperation => {
+                frame_mut!(self).ip += 2;
+                self.push_local();
+                frame_mut!(self).ip += 1;
+                let constant = self.read_constant(false).clone();
+                self.stack.push(constant);
+                return self.read_operation();
+            }
+            Opcode::LocalsOperation => {
+                frame_mut!(self).ip += 2;
+                self.push_local();
+                frame_mut!(self).ip += 3;
+                self.push_local();
+                return self.read_operation();
+            }
+            Opcode::Negate => {
+                if self.call_special_method(NEG_METHOD, 0)? {
+                    return Ok(false);
+                }
+
+                let value = self.pop_unchecked();
+                match value {
+                    Number(num) => self.stack.push(Value::Number(-num)),
+                    #[cfg(not(feature = "bigint"))]
+                    Int(i) => self.stack.push(i.checked_neg().map(Int).unwrap_or(Number(-(i as f64)))),
+                    #[cfg(feature = "bigint")]
+                    Int(i) => self.stack.push(
+                        i.checked_neg()
+                            .map(Int)
+                            .unwrap_or_else(|| BigInt(Rc::new(-&crate::bigint::BigInt::from_i64(i)))),
+                    ),
+                    #[cfg(feature = "bigint")]
+                    BigInt(n) => self.stack.push(BigInt(Rc::new(-&*n))),
+                    _ => {
+                        return Err(self.runtime_error("Operand must be a number"));
+                    }
+                }
+            }
+            Opcode::True => self.stack.push(Bool(true)),
+            Opcode::False => self.stack.push(Bool(false)),
+            Opcode::Pop => {
+                self.pop_unchecked();
+            }
+            Opcode::GetLocal => {
+                let array_index = self.pop_unchecked();
+                let slot = self.read_byte() as usize;
+                let value = &self.stack[frame!(self).slot_offset + slot];
+
+                if array_index == Value::Nil {
+                    self.stack.push(value.clone());
+                } else if let Instance(_) = value {
+                    self.index_instance(value.clone(), GET_METHOD, &[array_index])?;
+                } else {
+                    let element = get_index(value, &array_index, self.interner);
+                    let element = self.check(element, "Error getting index")?;
+                    self.stack.push(element);
+                }
+            }
+            Opcode::GetGlobal | Opcode::GetGlobalLong => {
+                let offset = frame!(self).ip - 1;
+                let slot = self.global_slot(offset, instruction.is_long());
+                let array_index = self.pop_unchecked();
+
+                if let Ok((slot, _)) = slot {
+                    let value = self.globals()[slot].clone();
+                    if array_index == Value::Nil {
+                        self.stack.push(value);
+                    } else if let Instance(_) = value {
+                        self.index_instance(value, GET_METHOD, &[array_index])?;
+                    } else {
+                        let element = get_index(&value, &array_index, self.interner);
+                        let element = self.check(element, "Error getting index")?;
+                        self.stack.push(element);
+                    }
+                } else if let Err(name) = slot {
+                    return Err(self.runtime_error(&format!("Undefined variable {}", self.interner.lookup(&name))));
+                }
+            }
+            Opcode::SetLocal => {
+                let slot: usize = self.read_byte() as usize;
+                let new_value = self.pop_unchecked();
+                let array_index = self.pop_unchecked();
+                let value_to_be_modified = &self.stack[frame!(self).slot_offset + slot];
+                if let (Instance(_), false) = (value_to_be_modified, array_index == Value::Nil) {
+                    return self
+                        .index_instance(value_to_be_modified.clone(), SET_METHOD, &[array_index, new_value])
+                        .map(|_| false);
+                }
+
+                self.stack.push(new_value.clone());
+                let value_to_be_modified = &mut self.stack[frame!(self).slot_offset + slot];
+
+                if array_index == Value::Nil {
+                    *value_to_be_modified = new_value;
+                } else {
+                    let result = set_index(value_to_be_modified, &array_index, new_value);
+                    self.check(result, "Error setting index")?;
+                }
+            }
+            Opcode::SetGlobal | Opcode::SetGlobalLong => {
+                let offset = frame!(self).ip - 1;
+                let (slot, name) = match self.global_slot(offset, instruction.is_long()) {
+                    Ok(found) => found,
+                    Err(name) => return Err(self.runtime_error(&format!("Undefined variable {}", self.interner.lookup(&name)))),
+                };
+                let new_value = self.pop_unchecked();
+                let array_index = self.pop_unchecked();
+                if let (Value::Nil, Some(name)) = (&array_index, name) {
+                    if self.modules[frame!(self).module].constants.contains(&name) {
+                        return Err(self.runtime_error(&format!("Cannot assign to constant {}", self.interner.lookup(&name))));
+                    }
+                }
+
+                let value_to_be_modified = &self.globals()[slot];
+                if let (Instance(_), false) = (value_to_be_modified, array_index == Value::Nil) {
+                    let instance = value_to_be_modified.clone();
+                    return self.index_instance(instance, SET_METHOD, &[array_index, new_value]).map(|_| false);
+                }
+
+                self.stack.push(new_value.clone());
+                if array_index == Value::Nil && !self.debugger.history.checkpoints.is_empty() {
+                    let name = name.unwrap_or_else(|| self.global_name(slot));
+                    self.record_global(name);
+                }
+                let value_to_be_modified = &mut self.globals()[slot];
+
+                if array_index == Value::Nil {
+                    *value_to_be_modified = new_value;
+                } else {
+                    let result = set_index(value_to_be_modified, &array_index, new_value);
+                    self.check(result, "Error setting index")?;
+                }
+            }
+            Opcode::DefineGlobal | Opcode::DefineGlobalLong => {
+                let name = self.read_string_or_id(instruction.is_long());
+                let value = self.pop_unchecked();
+                self.record_global(name);
+                self.globals().insert(name, value);
+            }
+            Opcode::DeclareArray => {
+                let size_val = self.pop_unchecked();
+                match size_val {
+                    Number(_) | Int(_) => {
+                        let len = size_val.as_number().unwrap();
+                        if let Some(max) = self.options.max_heap_bytes {
+                            if len * std::mem::size_of::<Value>() as f64 > max as f64 {
+                                return Err(self.out_of_memory(max));
+                            }
                         }
+                        self.stack.push(Value::Array(gc::new_array(vec![Nil; len as usize])));
+                    }
+                    other => {
+                        return Err(self.runtime_error(&format!("Expected number, got {other}")));
                     }
                 }
-                Opcode::Subtract => binop!(self, Number, -),
-                Opcode::Multiply => binop!(self, Number, *),
-                Opcode::Modulo => binop!(self, Number, %),
-                Opcode::Divide => binop!(self, Number, /),
-                Opcode::Not => {
-                    let val = self.pop_unchecked();
-                    self.stack.push(Bool(self.is_falsey(&val)))
+            }
+            Opcode::BuildMap => {
+                let entry_count = self.read_byte() as usize;
+                let entries = self.stack.split_off(self.stack.len() - 2 * entry_count);
+                let mut map = ValueMap::default();
+
+                for pair in entries.chunks_exact(2) {
+                    let Some(key) = MapKey::from_value(&pair[0]) else {
+                        return Err(self.runtime_error(&format!("Map keys must be strings or numbers, got {}", pair[0])));
+                    };
+                    map.insert(key, pair[1].clone());
+                }
+
+                self.stack.push(Value::Map(gc::new_map(map)));
+            }
+            Opcode::Nil => self.stack.push(Nil),
+            Opcode::Union | Opcode::Intersection => {
+                if !self.set_operation(instruction) {
+                    let b = self.pop_unchecked();
+                    let a = self.pop_unchecked();
+                    return Err(self.runtime_error(&format!("Operands must be sets, but got {a} and {b}")));
                 }
-                Opcode::Greater => binop!(self, Bool, >),
-                Opcode::Less => binop!(self, Bool, <),
             }
+            Opcode::Equal
+            | Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Modulo
+            | Opcode::Divide
+            | Opcode::Greater
+            | Opcode::Less => return self.binary_operation(instruction),
+            Opcode::Not => {
+                let val = self.pop_unchecked();
+                self.stack.push(Bool(self.is_falsey(&val)))
+            }
+            Opcode::In => {
+                let container = self.pop_unchecked();
+                let item = self.pop_unchecked();
+                let contains = self.contains(&container, &item)?;
+                self.stack.push(Bool(contains));
+            }
+            Opcode::Range | Opcode::RangeInclusive => {
+                let end = self.pop_unchecked();
+                let start = self.pop_unchecked();
+                let (Some(start), Some(end)) = (start.as_number(), end.as_number()) else {
+                    return Err(self.runtime_error(&format!("Range bounds must be numbers, but got {start} and {end}")));
+                };
+
+                self.stack.push(Range(crate::value::Range {
+                    start,
+                    end,
+                    inclusive: instruction == Opcode::RangeInclusive,
+                }));
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Run the main script, and return the value it returns.
+    /// Globals of the main module are kept from earlier runs, like the previous snippets of a `Session`.
+    pub async fn interpret(&mut self) -> std::result::Result<Value, RuntimeError> {
+        self.verify_functions()?;
+        self.start_main();
+        self.debugger.active = false;
+        self.run().await.map(|result| result.unwrap_or(Nil))
+    }
+
+    /// Verify the code of the functions added since the last run, so that invalid code is an error instead of running
+    fn verify_functions(&mut self) -> std::result::Result<(), RuntimeError> {
+        for fun in &self.functions[self.verified..] {
+            if let Err(error) = verify_function(fun, self.functions.len(), self.modules.len()) {
+                let frame = TraceFrame {
+                    function: fun.name.map_or("<script>", |name| self.interner.lookup(&name)).to_string(),
+                    module: self.modules.get(fun.module).map_or_else(String::new, |module| module.path.clone()),
+                    line: fun.chunk.line_at(error.offset),
+                    offset: error.offset,
+                };
+                return Err(RuntimeError {
+                    kind: RuntimeErrorKind::InvalidBytecode,
+                    message: error.to_string(),
+                    line: frame.line,
+                    offset: error.offset,
+                    stack: vec![frame],
+                });
+            }
+        }
+        self.verified = self.functions.len();
+        Ok(())
+    }
+
+    /// Reset the VM to run the main script from its first instruction
+    fn start_main(&mut self) {
+        let script = self.modules[MAIN_MODULE].script.unwrap_or(self.functions.len() - 1);
+        if self.options.print_code {
+            self.print_code();
+        }
+        self.profiler = self.options.profile.then(Profiler::new);
+        self.stats = self.options.collect_stats.then(StatsCounter::new);
+        self.coverage = self.options.coverage.then(CoverageCounter::default);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count_call(script);
+        }
+        // Frames and tasks left by an error in an earlier run of a session
+        self.unwind_frames(0);
+        self.drop_tasks();
+        self.task_is_main = true;
+        self.main_result = None;
+        self.stack.clear();
+        self.handlers.clear();
+        self.frames.push(CallFrame {
+            fun_idx: script,
+            ip: 0,
+            start_len: 0,
+            slot_offset: 0,
+            passed_args: ArgSet::default(),
+            module: MAIN_MODULE,
+        });
+        if self.globals[MAIN_MODULE].is_none() {
+            self.globals[MAIN_MODULE] = Some(self.builtins.clone());
         }
     }
 
-    pub async fn interpret(functions: Vec<Fun>, interner: &'src mut Interner, read_async: F) -> Result<()> {
-        dbgln!("== Interpreter VM ==");
-        let mut vm = Vm::new(interner, functions, read_async);
+    /// Run the main script, then every test declared in the modules it loaded, in order.
+    /// Tests that throw are reported rather than stopping the run.
+    pub async fn run_tests(&mut self) -> Result<TestReport> {
+        self.interpret().await?;
 
-        vm.reset_err_string();
+        let mut report = TestReport::default();
+        for module in 0..self.modules.len() {
+            // Modules that were never imported at runtime have no globals for their tests to use
+            if self.globals[module].is_none() {
+                continue;
+            }
 
-        register_native!(vm, Clock);
-        register_native!(vm, Sleep);
-        register_native!(vm, TypeOf);
-        register_native!(vm, Print);
-        register_native!(vm, ReadNumber);
-        register_native!(vm, ReadString);
-        register_native!(vm, ReadBool);
-        register_native!(vm, ToString);
-        register_native!(vm, ToNumber);
-        register_native!(vm, StringAt);
-        register_native!(vm, StrLen);
-        register_native!(vm, ArrLen);
-        register_native!(vm, Ceil);
-        register_native!(vm, Floor);
-        register_native!(vm, Sort);
-        register_native!(vm, IndexOf);
-        register_native!(vm, Rand);
-        dbgln!("Interpreting  code");
-        vm.run().await
+            for test in self.modules[module].tests.clone() {
+                let result = self.call_and_wait(Function(test.fun), &[]).await;
+                if result.is_err() && self.fuel == 0 {
+                    return Err(self.interrupted(0).into());
+                }
+                let outcome = match result {
+                    Ok(_) => TestOutcome::Passed,
+                    Err(exception) => {
+                        let message = value_as_string(&exception, self.interner);
+                        let (line, message) = split_line(&message);
+                        match message.strip_prefix(ASSERTION_FAILED) {
+                            Some(message) => TestOutcome::Failed {
+                                message: message.trim_start_matches(": ").to_string(),
+                                line,
+                            },
+                            None => TestOutcome::Errored {
+                                message: message.to_string(),
+                                line,
+                            },
+                        }
+                    }
+                };
+                report.results.push(TestResult {
+                    name: test.name,
+                    module: self.modules[module].path.clone(),
+                    line: test.line,
+                    outcome,
+                });
+            }
+        }
+        Ok(report)
     }
 
     fn peek(&self, distance: usize) -> &Value {
-        return self
-            .stack
+        self.stack
             .get(self.stack.len() - 1 - distance)
-            .unwrap_or_else(|| panic!("Failed to peek {distance} deep"));
+            .unwrap_or_else(|| panic!("Failed to peek {distance} deep"))
+    }
+}
+
+// Debugger related methods
+impl<'src, F, Fut> Vm<'src, F, Fut>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = String>,
+{
+    /// Set up the main script like `interpret`, paused before its first instruction.
+    /// Run it with `step_into`, `step_over` or `continue_execution`, which return when it pauses again or finishes.
+    /// Callbacks that natives call, like the function passed to `map`, run without pausing. Code that doesn't verify is an
+    /// error, like for `interpret`.
+    pub fn start_debugging(&mut self) -> std::result::Result<(), RuntimeError> {
+        self.verify_functions()?;
+        self.start_main();
+        self.debugger.history.clear();
+        self.debugger.active = true;
+        self.debugger.resuming = false;
+        Ok(())
+    }
+
+    /// Pause before the first instruction of the line whenever it runs. Lines are those of the main module.
+    pub fn set_breakpoint(&mut self, line: usize) {
+        self.debugger.breakpoints.insert(line);
+    }
+
+    pub fn clear_breakpoint(&mut self, line: usize) {
+        self.debugger.breakpoints.remove(&line);
+    }
+
+    /// Run until the next line, including lines of the functions that are called
+    pub async fn step_into(&mut self) -> std::result::Result<DebugEvent, RuntimeError> {
+        self.resume(StepMode::Into).await
+    }
+
+    /// Run until the next line of the current function, or of its caller once it returns
+    pub async fn step_over(&mut self) -> std::result::Result<DebugEvent, RuntimeError> {
+        let depth = self.frames.len();
+        self.resume(StepMode::Over(depth)).await
+    }
+
+    /// Run until the current function returns, and pause at the next line of its caller
+    pub async fn step_out(&mut self) -> std::result::Result<DebugEvent, RuntimeError> {
+        let depth = self.frames.len().saturating_sub(1);
+        self.resume(StepMode::Over(depth)).await
+    }
+
+    /// Run until a breakpoint, or until the program finishes
+    pub async fn continue_execution(&mut self) -> std::result::Result<DebugEvent, RuntimeError> {
+        self.resume(StepMode::Continue).await
+    }
+
+    /// Once the program finished or stopped with an error, there is nothing to run, and it is reported as finished with nil
+    async fn resume(&mut self, step: StepMode) -> std::result::Result<DebugEvent, RuntimeError> {
+        if !self.debugger.active {
+            return Ok(DebugEvent::Finished(Nil));
+        }
+        self.debugger.step = step;
+        match self.run().await {
+            Ok(Some(result)) => {
+                self.debugger.active = false;
+                Ok(DebugEvent::Finished(result))
+            }
+            Ok(None) => Ok(DebugEvent::Paused(self.call_stack().remove(0))),
+            Err(error) => {
+                self.debugger.active = false;
+                Err(error)
+            }
+        }
+    }
+
+    /// Line of the next instruction, if it is the first instruction of the line
+    fn line_start(&self) -> Option<usize> {
+        let frame = frame!(self);
+        let chunk = &self.functions[frame.fun_idx].chunk;
+        if frame.ip >= chunk.code.len() {
+            return None;
+        }
+        let line = chunk.line_at(frame.ip);
+        match frame.ip > 0 && chunk.line_at(frame.ip - 1) == line {
+            true => None,
+            false => Some(line),
+        }
+    }
+
+    /// Whether the debugger pauses before the first instruction of the line
+    fn should_pause(&self, line: usize) -> bool {
+        let frame = frame!(self);
+        let stepped = match self.debugger.step {
+            StepMode::Into => true,
+            StepMode::Over(depth) => self.frames.len() <= depth,
+            StepMode::Continue => false,
+        };
+        stepped || (frame.module == MAIN_MODULE && self.debugger.breakpoints.contains(&line))
+    }
+
+    /// Keep the last `steps` lines that run while debugging, so that `step_back` can return to them. 0 stops recording.
+    pub fn record_history(&mut self, steps: usize) {
+        let history = &mut self.debugger.history;
+        history.limit = steps;
+        if steps == 0 {
+            history.clear();
+        }
+        while history.checkpoints.len() > steps {
+            history.checkpoints.pop_front();
+        }
+    }
+
+    /// Go back to where the line that ran before the current one started, with the call frames, stack and globals it had then.
+    /// Only those are restored: changes to arrays, maps and instances, output, and globals that natives set are not undone.
+    /// Stepping forward again runs the lines again. Returns the frame that is paused then, or None if no earlier line was recorded.
+    pub fn step_back(&mut self) -> Option<TraceFrame> {
+        let history = &mut self.debugger.history;
+        if !self.debugger.active || history.checkpoints.len() < 2 {
+            return None;
+        }
+        history.checkpoints.pop_back();
+        let checkpoint = history.checkpoints.back_mut().unwrap();
+        let mut removed = false;
+        for (module, name, value) in checkpoint.globals.drain(..).rev() {
+            let globals = self.globals[module].as_mut().unwrap();
+            match value {
+                Some(value) => _ = globals.insert(name, value),
+                None => removed |= globals.swap_remove(&name).is_some(),
+            };
+        }
+        // Removing a global moves the last one into its slot, which the caches of globals would still point to
+        if removed {
+            for fun in &mut self.functions {
+                fun.chunk.caches.clear();
+            }
+        }
+        std::mem::take(&mut checkpoint.stack).undo(&mut self.stack);
+        std::mem::take(&mut checkpoint.frames).undo(&mut self.frames);
+        self.handlers.clone_from(&checkpoint.handlers);
+        history.stack.clone_from(&self.stack);
+        history.frames.clone_from(&self.frames);
+        self.thrown = None;
+        self.debugger.resuming = true;
+        self.call_stack().into_iter().next()
+    }
+
+    /// Record the state where a line starts, and finish the checkpoint of the line before it
+    fn record_checkpoint(&mut self) {
+        let history = &mut self.debugger.history;
+        if history.limit == 0 {
+            return;
+        }
+        let stack = Delta::record(&mut history.stack, &self.stack);
+        let frames = Delta::record(&mut history.frames, &self.frames);
+        if let Some(last) = history.checkpoints.back_mut() {
+            last.stack = stack;
+            last.frames = frames;
+        }
+        history.checkpoints.push_back(Checkpoint {
+            handlers: self.handlers.clone(),
+            frames: Delta::default(),
+            stack: Delta::default(),
+            globals: Vec::new(),
+        });
+        if history.checkpoints.len() > history.limit {
+            history.checkpoints.pop_front();
+        }
+    }
+
+    /// Keep the value of a global before it is assigned, for stepping back
+    fn record_global(&mut self, name: StrId) {
+        if self.debugger.history.checkpoints.is_empty() {
+            return;
+        }
+        let module = frame!(self).module;
+        let value = self.globals().get(&name).cloned();
+        if let Some(last) = self.debugger.history.checkpoints.back_mut() {
+            last.globals.push((module, name, value));
+        }
+    }
+
+    /// Frames of the running task, most recent first, with the line each one is at
+    pub fn call_stack(&self) -> Vec<TraceFrame> {
+        self.trace(self.frames.last().map_or(0, |frame| frame.ip))
+    }
+
+    /// Source of the statement a frame of `call_stack` is at, like to highlight it while stepping. It is in the source of
+    /// the module of the frame.
+    pub fn span(&self, frame: usize) -> Option<Span> {
+        let call = self.frames.len().checked_sub(frame + 1).map(|idx| &self.frames[idx])?;
+        let offset = if frame == 0 { call.ip } else { call.ip.saturating_sub(1) };
+        self.functions[call.fun_idx].chunk.span_at(offset)
+    }
+
+    /// Values on the stack of the running task, from the bottom
+    pub fn stack_values(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Local variables in scope in a frame of `call_stack`, in the order they were declared
+    pub fn locals(&self, frame: usize) -> Vec<Variable> {
+        let Some(call) = self.frames.len().checked_sub(frame + 1).map(|idx| &self.frames[idx]) else {
+            return Vec::new();
+        };
+        let offset = if frame == 0 { call.ip } else { call.ip.saturating_sub(1) };
+        self.functions[call.fun_idx]
+            .locals
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&offset))
+            .filter_map(|local| {
+                Some(Variable {
+                    name: self.interner.lookup(&local.name).to_string(),
+                    value: self.stack.get(call.slot_offset + local.slot)?.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Global variables of the module of a frame of `call_stack`, sorted by name.
+    /// Natives are left out, unless the program assigned something else to their name.
+    pub fn global_variables(&self, frame: usize) -> Vec<Variable> {
+        let Some(call) = self.frames.len().checked_sub(frame + 1).map(|idx| &self.frames[idx]) else {
+            return Vec::new();
+        };
+        let Some(globals) = &self.globals[call.module] else {
+            return Vec::new();
+        };
+        let mut variables: Vec<Variable> = globals
+            .iter()
+            .filter(|(name, value)| !self.is_builtin(name, value))
+            .map(|(name, value)| Variable {
+                name: self.interner.lookup(name).to_string(),
+                value: value.clone(),
+            })
+            .collect();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+        variables
+    }
+
+    fn is_builtin(&self, name: &StrId, value: &Value) -> bool {
+        match (self.builtins.get(name), value) {
+            (Some(NativeFunction(builtin)), NativeFunction(value)) => Rc::ptr_eq(builtin, value),
+            (Some(builtin), value) => builtin == value,
+            (None, _) => false,
+        }
+    }
+
+    /// Text of a value, like `print` writes it
+    pub fn format_value(&self, value: &Value) -> String {
+        value_as_string(value, self.interner)
     }
 }
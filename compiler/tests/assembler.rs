@@ -0,0 +1,182 @@
+//! The assembler, which reads back what the disassembler writes
+
+use compiler::{
+    assembler::assemble,
+    chunk::Chunk,
+    compiler::{Compiler, CompilerOptions},
+    fun::Fun,
+    interner::Interner,
+    module::{ModuleRegistry, NoModules},
+};
+use std::rc::Rc;
+
+const PROGRAM: &str = r#"
+enum Color { Red, Green }
+record Point(x, y);
+
+class Shape {
+    init(name) {
+        this.name = name;
+    }
+
+    get label() {
+        return this.name + "!";
+    }
+}
+
+function sum(items, start = 0) {
+    var total = start;
+    for (var item in items) {
+        if (item == nil) continue;
+        total = total + item * 2;
+    }
+    return total;
+}
+
+var names = ["a\n", "tab	", "é 😀", 1.5, -2, nil, false];
+var point = Point(1, 2);
+try {
+    print sum([1, 2, nil], start: 10);
+} catch (error) {
+    print error;
+} finally {
+    print Color.Green;
+}
+switch (point.x) {
+    case 1: print Shape("one").label;
+    default: print "other";
+}
+print sum(...[[1, 2]]);
+"#;
+
+fn functions(source: &str, opt_level: u8) -> (Vec<Fun>, Interner) {
+    let mut interner = Interner::with_capacity(64);
+    let mut functions = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(NoModules));
+    let options = CompilerOptions {
+        opt_level,
+        ..CompilerOptions::default()
+    };
+    let program = Compiler::compile_program(Rc::from(source), &mut interner, &mut functions, &mut modules, &options)
+        .unwrap_or_else(|error| panic!("{error}"));
+    functions.push(program.script);
+    (functions, interner)
+}
+
+fn assert_round_trips(chunk: &Chunk, interner: &mut Interner) {
+    let text = chunk.disassembly("chunk", interner);
+    let assembled = assemble(&text, interner).unwrap_or_else(|error| panic!("{error}\n{text}"));
+    assert_eq!(assembled.disassembly("chunk", interner), text);
+    assert_eq!(assembled.code, chunk.code);
+    assert_eq!(assembled.constants.len(), chunk.constants.len());
+    for offset in 0..chunk.code.len() {
+        assert_eq!(assembled.line_at(offset), chunk.line_at(offset));
+    }
+}
+
+#[test]
+fn disassembly_round_trip() {
+    for opt_level in [0, 1] {
+        let (functions, mut interner) = functions(PROGRAM, opt_level);
+        for fun in &functions {
+            assert_round_trips(&fun.chunk, &mut interner);
+        }
+    }
+}
+
+#[test]
+fn superinstructions_round_trip() {
+    let source = "function f(a, b) { var c = a + 1; return a * b + c; } print f(1, 2);";
+    let (functions, mut interner) = functions(source, 1);
+    let text = functions[0].chunk.disassembly("f", &interner);
+    assert!(text.contains("Operation"), "{text}");
+    assert_round_trips(&functions[0].chunk, &mut interner);
+}
+
+#[test]
+fn offsets_lines_and_distances_are_optional() {
+    let mut interner = Interner::with_capacity(8);
+    let full = "\
+== script ==
+0000    3 True
+0001    3 JumpIfFalse 4 -> 8
+0004    4 Constant Idx 0 \"yes\"
+0006    4 Print
+0007    4 Pop
+0008    5 Nil
+0009    5 Return
+";
+    let chunk = assemble(full, &mut interner).unwrap();
+    let short = "
+3 True          ; the condition
+JumpIfFalse -> 8
+4 Constant \"yes\"
+Print
+Pop
+
+5 Nil
+Return
+";
+    let other = assemble(short, &mut interner).unwrap();
+    assert_eq!(other.code, chunk.code);
+    assert_eq!(other.disassembly("script", &interner), chunk.disassembly("script", &interner));
+    assert_eq!((other.line_at(0), other.line_at(4), other.line_at(9)), (3, 4, 5));
+}
+
+#[test]
+fn constants_are_reused() {
+    let mut interner = Interner::with_capacity(8);
+    let chunk = assemble("Constant \"a\"\nConstant 1\nConstant \"a\"\nAdd\nAdd\nReturn", &mut interner).unwrap();
+    assert_eq!(chunk.constants.len(), 2);
+
+    // Indices that no instruction gives are nil
+    let chunk = assemble("Constant Idx 2 5\nReturn", &mut interner).unwrap();
+    assert_eq!(
+        chunk.disassembly("c", &interner),
+        "== c ==\n0000    1 Constant Idx 2 5\n0002    1 Return\n"
+    );
+    assert_eq!(chunk.constants.len(), 3);
+}
+
+#[test]
+fn errors() {
+    let cases = [
+        ("Frobnicate", "Line 1: Unknown instruction 'Frobnicate'"),
+        ("0001 1 Nil", "Line 1: Instruction is at offset 0, not 1"),
+        ("Nil\nConstant", "Line 2: Line ends early"),
+        ("Nil 1", "Line 1: Unexpected '1' after Nil"),
+        ("Constant \"open", "Line 1: Unterminated string"),
+        ("Constant Idx 0 1\nConstant Idx 0 2", "Line 2: Constant 0 is 1 elsewhere, not 2"),
+        ("== a ==\nNil\n== b ==\nNil", "Line 3: Only one chunk can be assembled at a time"),
+        ("Nil\nLoop -> 5\nNil\nReturn", "Line 2: Loop goes backward, to before where it ends"),
+        (
+            "Jump -> 0\nReturn",
+            "Line 1: Jump goes forward, to after where it ends, and only loops go back",
+        ),
+        ("ConstantOperation 1 Print", "Line 1: Expected an operator, found 'Print'"),
+        ("Constant <Thing 1>", "Line 1: Unknown constant '<Thing 1>'"),
+    ];
+    for (text, message) in cases {
+        let error = assemble(text, &mut Interner::with_capacity(8)).map(|_| ()).expect_err(text);
+        assert_eq!(error.to_string(), message, "{text}");
+    }
+}
+
+#[test]
+fn invalid_bytecode_is_rejected() {
+    // The chunk is verified, so code that would misbehave in the VM isn't assembled
+    let cases = [
+        (
+            "GetGlobal 1\nReturn",
+            "Invalid bytecode at offset 0: GetGlobal needs a name constant",
+        ),
+        (
+            "Jump -> 4\nConstant 1\nReturn",
+            "Invalid bytecode at offset 0: Jump jumps to 4, where no instruction starts",
+        ),
+    ];
+    for (text, message) in cases {
+        let error = assemble(text, &mut Interner::with_capacity(8)).map(|_| ()).unwrap_err();
+        assert_eq!(error.to_string(), message, "{text}");
+    }
+}
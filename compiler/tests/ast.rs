@@ -0,0 +1,88 @@
+//! The syntax tree that the compiler builds as it parses a program
+
+use compiler::{
+    ast::{parse_to_ast, Argument, BinaryOperator, ExprKind, FunctionBody, StmtKind},
+    scanner::Span,
+};
+
+#[test]
+fn tree_of_a_program() {
+    let source = "function add(a, b = 1) { return a + b; }\nprint add(2, b: 3);";
+    let program = parse_to_ast(source).unwrap();
+    let [declaration, print] = program.statements.as_slice() else {
+        panic!("{program:?}");
+    };
+
+    let StmtKind::Function(function) = &declaration.kind else {
+        panic!("{declaration:?}");
+    };
+    assert_eq!(function.name.as_ref().unwrap().name, "add");
+    assert_eq!(function.span, Span { start: 0, end: 40 });
+    let params: Vec<(&str, bool)> = function
+        .params
+        .iter()
+        .map(|param| (param.name.name.as_str(), param.default.is_some()))
+        .collect();
+    assert_eq!(params, [("a", false), ("b", true)]);
+    let FunctionBody::Block(body) = &function.body else {
+        panic!("{function:?}");
+    };
+    let StmtKind::Return(Some(value)) = &body[0].kind else {
+        panic!("{body:?}");
+    };
+    assert!(matches!(
+        value.kind,
+        ExprKind::Binary {
+            operator: BinaryOperator::Add,
+            ..
+        }
+    ));
+
+    assert_eq!((print.line, print.span), (2, Span { start: 41, end: 60 }));
+    let StmtKind::Print(call) = &print.kind else {
+        panic!("{print:?}");
+    };
+    let ExprKind::Call { callee, arguments } = &call.kind else {
+        panic!("{call:?}");
+    };
+    assert_eq!(callee.kind, ExprKind::Variable("add".to_string()));
+    let names: Vec<Option<&str>> = arguments
+        .iter()
+        .map(|Argument { name, .. }| name.as_ref().map(|name| name.name.as_str()))
+        .collect();
+    assert_eq!(names, [None, Some("b")]);
+}
+
+#[test]
+fn chains_and_assignments() {
+    let program = parse_to_ast("var a = {b: [1]};\na.b[0] = a?.b?.[0] ?? 2;").unwrap();
+    let StmtKind::Expression(assignment) = &program.statements[1].kind else {
+        panic!("{program:?}");
+    };
+    let ExprKind::Assign { target, value } = &assignment.kind else {
+        panic!("{assignment:?}");
+    };
+    assert!(matches!(target.kind, ExprKind::Index { optional: false, .. }));
+    let ExprKind::Binary {
+        operator: BinaryOperator::Coalesce,
+        left,
+        ..
+    } = &value.kind
+    else {
+        panic!("{value:?}");
+    };
+    let ExprKind::Index {
+        object, optional: true, ..
+    } = &left.kind
+    else {
+        panic!("{left:?}");
+    };
+    assert!(matches!(object.kind, ExprKind::Property { optional: true, .. }));
+    assert_eq!(assignment.span, Span { start: 18, end: 41 });
+}
+
+#[test]
+fn programs_with_errors_have_no_tree() {
+    let error = parse_to_ast("var x = ;").unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "Expect expression");
+}
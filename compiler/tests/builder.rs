@@ -0,0 +1,243 @@
+//! `ChunkBuilder`, checked against the assembler, which writes the same bytecode from text
+
+use compiler::{assembler::assemble, builder::ChunkBuilder, chunk::Chunk, common::Opcode, interner::Interner, value::Value};
+
+fn assert_same(built: &Chunk, text: &str, interner: &mut Interner) {
+    let assembled = assemble(text, interner).unwrap_or_else(|error| panic!("{error}"));
+    assert_eq!(built.disassembly("built", interner), assembled.disassembly("built", interner));
+    assert_eq!(built.code, assembled.code);
+}
+
+fn build_error(build: impl FnOnce(&mut ChunkBuilder)) -> String {
+    let mut builder = ChunkBuilder::new();
+    build(&mut builder);
+    builder.build().map(|_| ()).unwrap_err().to_string()
+}
+
+#[test]
+fn forward_jumps_and_lines() {
+    let mut interner = Interner::with_capacity(8);
+    let mut builder = ChunkBuilder::new();
+    let (otherwise, end) = (builder.label(), builder.label());
+    builder
+        .line(2)
+        .op(Opcode::True)
+        .jump_label(Opcode::JumpIfFalse, otherwise)
+        .op(Opcode::Pop);
+    builder
+        .line(3)
+        .constant(Value::Int(1))
+        .op(Opcode::Print)
+        .jump_label(Opcode::Jump, end);
+    builder.bind(otherwise).line(4).op(Opcode::Pop);
+    builder.bind(end).op(Opcode::Nil).op(Opcode::Return);
+    let chunk = builder.build().unwrap();
+
+    let text = "
+2 True
+JumpIfFalse -> 11
+Pop
+3 Constant 1
+Print
+Jump -> 12
+4 Pop
+Nil
+Return
+";
+    assert_same(&chunk, text, &mut interner);
+    assert_eq!((chunk.line_at(0), chunk.line_at(5), chunk.line_at(12)), (2, 3, 4));
+}
+
+#[test]
+fn loops_go_backward() {
+    let mut interner = Interner::with_capacity(8);
+    let i = interner.intern("i");
+    let mut builder = ChunkBuilder::new();
+    let (start, exit) = (builder.label(), builder.label());
+    builder
+        .constant(Value::Int(0))
+        .op_constant(Opcode::DefineGlobal, Value::Identifier(i));
+    builder.bind(start);
+    builder
+        .op_constant(Opcode::GetGlobal, Value::Identifier(i))
+        .constant(Value::Int(3))
+        .op(Opcode::Less);
+    builder.jump_label(Opcode::JumpIfFalse, exit).op(Opcode::Pop);
+    builder
+        .op_constant(Opcode::GetGlobal, Value::Identifier(i))
+        .constant(Value::Int(1))
+        .op(Opcode::Add);
+    builder.op_constant(Opcode::SetGlobal, Value::Identifier(i)).op(Opcode::Pop);
+    builder.jump_label(Opcode::Loop, start);
+    builder.bind(exit).op(Opcode::Pop).op(Opcode::Nil).op(Opcode::Return);
+    let chunk = builder.build().unwrap();
+
+    let text = "
+Constant 0
+DefineGlobal Identifier: i
+GetGlobal Identifier: i
+Constant 3
+Less
+JumpIfFalse -> 24
+Pop
+GetGlobal Identifier: i
+Constant 1
+Add
+SetGlobal Identifier: i
+Pop
+Loop -> 4
+Pop
+Nil
+Return
+";
+    assert_same(&chunk, text, &mut interner);
+}
+
+#[test]
+fn handlers_and_byte_jumps() {
+    let mut interner = Interner::with_capacity(8);
+    let mut builder = ChunkBuilder::new();
+    let (catch, end) = (builder.label(), builder.label());
+    builder.handler(Some(catch), None).constant(Value::Int(1)).op(Opcode::Throw);
+    builder.bind(catch).op(Opcode::Print).jump_label(Opcode::Jump, end);
+    builder.bind(end).op(Opcode::Nil).op(Opcode::Return);
+    let chunk = builder.build().unwrap();
+    // Without a finally block, its distance is 0
+    assert_same(
+        &chunk,
+        "PushHandler catch -> 8 finally -> 5\nConstant 1\nThrow\nPrint\nJump -> 12\nNil\nReturn",
+        &mut interner,
+    );
+
+    let mut builder = ChunkBuilder::new();
+    let passed = builder.label();
+    builder
+        .byte_jump_label(Opcode::JumpIfArgPassed, 0, passed)
+        .constant(Value::Int(2))
+        .op_byte(Opcode::SetLocal, 1);
+    builder.op(Opcode::Pop).bind(passed).op_byte(Opcode::GetLocal, 1).op(Opcode::Return);
+    let chunk = builder.build().unwrap();
+    assert_eq!(chunk.code[..4], [Opcode::JumpIfArgPassed as u8, 0, 0, 5]);
+}
+
+#[test]
+fn named_calls() {
+    let mut interner = Interner::with_capacity(8);
+    let (f, name) = (interner.intern("f"), interner.intern("name"));
+    let mut builder = ChunkBuilder::new();
+    builder
+        .op_constant(Opcode::GetGlobal, Value::Identifier(f))
+        .constant(Value::Int(1))
+        .constant(Value::Int(2));
+    builder.call_named(2, &[name]).op(Opcode::Return);
+    let chunk = builder.build().unwrap();
+    assert_same(
+        &chunk,
+        "GetGlobal Identifier: f\nConstant 1\nConstant 2\nCallNamed 2 [\"name\"]\nReturn",
+        &mut interner,
+    );
+}
+
+#[test]
+fn long_constants() {
+    let mut builder = ChunkBuilder::new();
+    for i in 0..300 {
+        builder.constant(Value::Int(i)).op(Opcode::Pop);
+    }
+    builder.op(Opcode::Nil).op(Opcode::Return);
+    let chunk = builder.build().unwrap();
+
+    assert_eq!(chunk.constants.len(), 300);
+    let instructions = chunk.disassembly("c", &Interner::with_capacity(8));
+    assert!(instructions.contains("Constant Idx 255 255"), "{instructions}");
+    assert!(instructions.contains("ConstantLong Idx 256 256"), "{instructions}");
+    // Constants that were added already are reused
+    let mut builder = ChunkBuilder::new();
+    builder
+        .constant(Value::Int(7))
+        .constant(Value::Int(7))
+        .op(Opcode::Add)
+        .op(Opcode::Return);
+    assert_eq!(builder.build().unwrap().constants.len(), 1);
+}
+
+#[test]
+fn mistakes_are_returned_by_build() {
+    assert_eq!(build_error(|b| _ = b.op(Opcode::Call)), "At offset 0: Call has operands");
+    assert_eq!(
+        build_error(|b| _ = b.op(Opcode::Nil).op_byte(Opcode::Add, 1)),
+        "At offset 1: Add doesn't take a byte operand"
+    );
+    assert_eq!(
+        build_error(|b| _ = b.op_constant(Opcode::Add, Value::Nil)),
+        "At offset 0: Add doesn't take a constant operand"
+    );
+    assert_eq!(
+        build_error(|b| {
+            let label = b.label();
+            b.jump_label(Opcode::Pop, label);
+        }),
+        "At offset 0: Pop is not a jump"
+    );
+    assert_eq!(
+        build_error(|b| {
+            let label = b.label();
+            b.byte_jump_label(Opcode::Jump, 0, label);
+        }),
+        "At offset 0: Jump is not a jump with a byte operand"
+    );
+    // The first mistake is kept
+    assert_eq!(
+        build_error(|b| _ = b.op(Opcode::Call).op(Opcode::GetLocal)),
+        "At offset 0: Call has operands"
+    );
+}
+
+#[test]
+fn label_mistakes() {
+    assert_eq!(
+        build_error(|b| {
+            let label = b.label();
+            b.bind(label).op(Opcode::Nil).bind(label);
+        }),
+        "At offset 1: Label 0 is bound twice"
+    );
+    assert_eq!(
+        build_error(|b| {
+            let label = ChunkBuilder::new().label();
+            b.bind(label);
+        }),
+        "At offset 0: Label 0 is from another builder"
+    );
+    assert_eq!(
+        build_error(|b| {
+            let label = b.label();
+            b.jump_label(Opcode::Jump, label).op(Opcode::Nil).op(Opcode::Return);
+        }),
+        "Label 0 is never bound"
+    );
+    assert_eq!(
+        build_error(|b| {
+            let label = b.label();
+            b.bind(label).op(Opcode::Nil).jump_label(Opcode::Jump, label);
+        }),
+        "Jump at offset 1 goes the wrong way to label 0"
+    );
+    assert_eq!(
+        build_error(|b| {
+            let label = b.label();
+            b.jump_label(Opcode::Jump, label);
+            for _ in 0..70_000 {
+                b.op(Opcode::Nil);
+            }
+            b.bind(label).op(Opcode::Return);
+        }),
+        "Label 0 is too far for Jump, which needs its long variant"
+    );
+}
+
+#[test]
+fn chunk_is_verified() {
+    let error = build_error(|b| _ = b.op_byte(Opcode::Call, 0).op_constant(Opcode::GetGlobal, Value::Int(1)));
+    assert_eq!(error, "Invalid bytecode at offset 2: GetGlobal needs a name constant");
+}
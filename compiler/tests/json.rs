@@ -0,0 +1,134 @@
+//! `JsonParse` and `JsonStringify`, run as the tests of Lox programs
+
+use compiler::{module::NoModules, run_tests, testing::TestOutcome};
+use futures::executor::block_on;
+
+/// Lox strings have no escapes, so `json` writes JSON text with `'` for `"`. Backslashes are kept as they are.
+const PRELUDE: &str = r#"
+function json(text) {
+    return text.replace("'", StringAt(JsonStringify(""), 0));
+}
+"#;
+
+/// Run the tests the program declares, and fail with the message of each one that didn't pass
+fn assert_passes(tests: &str) {
+    let source = format!("{PRELUDE}{tests}");
+    let report = block_on(run_tests(&source, NoModules, |_| async { String::new() })).unwrap();
+    let failures: Vec<String> = report
+        .results
+        .iter()
+        .filter_map(|result| match &result.outcome {
+            TestOutcome::Passed => None,
+            TestOutcome::Failed { message, .. } | TestOutcome::Errored { message, .. } => Some(format!("{}: {message}", result.name)),
+        })
+        .collect();
+    assert!(failures.is_empty(), "{failures:#?}");
+    assert!(!report.results.is_empty());
+}
+
+#[test]
+fn round_trip() {
+    assert_passes(
+        r#"
+        test "compact" {
+            var text = json("{'a':[1,2.5,true,null,'x'],'b':{},'c':-3}");
+            AssertEq(JsonStringify(JsonParse(text)), text);
+        }
+
+        test "values" {
+            var value = {"n": 1, "f": -0.5, "s": json("'quoted' \ and ñ"), "l": [[], [nil]], "t": false};
+            var parsed = JsonParse(JsonStringify(value));
+            AssertEq(parsed, value);
+            AssertEq(JsonStringify(parsed), JsonStringify(value));
+        }
+
+        test "keys are sorted" {
+            AssertEq(JsonStringify({"b": 1, "a": 2}), json("{'a':2,'b':1}"));
+        }
+
+        test "indent" {
+            AssertEq(JsonStringify([1, {"a": 2}], 2), json("[
+  1,
+  {
+    'a': 2
+  }
+]"));
+            AssertEq(JsonStringify([1], "--"), json("[
+--1
+]"));
+        }
+
+        test "escapes" {
+            AssertEq(JsonParse(json("'é😀\'\\'")), json("é😀'\"));
+        }
+        "#,
+    );
+}
+
+#[test]
+fn parse_errors() {
+    assert_passes(
+        r#"
+        test "invalid text" {
+            var texts = ["", "[1,", "{'a' 1}", "{1: 2}", "'open", "tru", "1 2", "[1,]", "'\x'", "'\ud83d'"];
+            for (var i = 0; i < texts.length(); i = i + 1) {
+                var result = JsonParse(json(texts[i]));
+                var error = errString;
+                AssertEq(result, nil);
+                Assert(error != nil, texts[i]);
+            }
+        }
+
+        test "not a string" {
+            var result = JsonParse(1);
+            var error = errString;
+            AssertEq(result, nil);
+            AssertEq(error, "Expected string as argument to jsonparse");
+        }
+
+        test "bad indent" {
+            var result = JsonStringify([1], true);
+            var error = errString;
+            AssertEq(result, nil);
+            AssertEq(error, "Expected a number of spaces or a string as the indent of jsonstringify");
+        }
+
+        test "error is cleared" {
+            JsonParse("[");
+            JsonParse("[]");
+            AssertEq(errString, nil);
+        }
+        "#,
+    );
+}
+
+#[test]
+fn cycles() {
+    assert_passes(
+        r#"
+        test "array containing itself" {
+            var a = [1];
+            a.push(a);
+            var result = JsonStringify(a);
+            var error = errString;
+            AssertEq(result, nil);
+            AssertEq(error, "Cannot convert a cyclic or too deeply nested value to JSON");
+        }
+
+        test "maps containing each other" {
+            var a = {};
+            var b = {"a": a};
+            a["b"] = b;
+            var result = JsonStringify(b);
+            var error = errString;
+            AssertEq(result, nil);
+            AssertEq(error, "Cannot convert a cyclic or too deeply nested value to JSON");
+        }
+
+        test "shared value is not a cycle" {
+            var shared = [1];
+            AssertEq(JsonStringify([shared, shared]), "[[1],[1]]");
+        }
+        "#,
+    );
+}
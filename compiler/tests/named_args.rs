@@ -0,0 +1,113 @@
+//! Named arguments of calls to functions known at compile time, which are put in the order of the parameters
+
+use compiler::{
+    compiler::{Compiler, CompilerOptions},
+    interner::Interner,
+    module::{ModuleRegistry, NoModules},
+    run_tests,
+    testing::TestOutcome,
+};
+use futures::executor::block_on;
+use std::rc::Rc;
+
+/// Instructions of the script that call functions, without their offsets and lines
+fn calls(source: &str) -> Vec<String> {
+    let mut interner = Interner::with_capacity(64);
+    let mut functions = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(NoModules));
+    let program = Compiler::compile_program(
+        Rc::from(source),
+        &mut interner,
+        &mut functions,
+        &mut modules,
+        &CompilerOptions::default(),
+    )
+    .unwrap_or_else(|error| panic!("{error}"));
+    program
+        .script
+        .chunk
+        .disassembly("script", &interner)
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .skip(2)
+                .collect::<Vec<_>>()
+                .join(" ")
+                .strip_prefix("Call")
+                .map(String::from)
+        })
+        .collect()
+}
+
+fn assert_passes(tests: &str) {
+    let report = block_on(run_tests(tests, NoModules, |_| async { String::new() })).unwrap();
+    for result in &report.results {
+        if let TestOutcome::Failed { message, .. } | TestOutcome::Errored { message, .. } = &result.outcome {
+            panic!("{}: {message}", result.name);
+        }
+    }
+    assert!(!report.results.is_empty());
+}
+
+#[test]
+fn known_functions_are_called_in_parameter_order() {
+    let source = r#"
+        function area(width, height = 2, depth = 1) { return width * height * depth; }
+        const twice = (a, b) -> a * 10 + b;
+        var minus = function(x, y) { return x - y; };
+        area(3, height: 4);
+        area(depth: 5, height: 4, width: 3);
+        twice(b: 1, a: 2);
+        minus(y: 1, x: 5);
+    "#;
+    assert_eq!(calls(source), [" 2", " 3", " 2", " 2"]);
+}
+
+#[test]
+fn other_calls_name_their_arguments() {
+    let source = r#"
+        function area(width, height = 2, depth = 1) { return width * height * depth; }
+        function later(p, q) { return p - q; }
+        later = (q, p) -> q * 100 + p;
+        function side(x) { return x; }
+        var h = 7;
+        later(q: 1, p: 2);
+        area(width: 2, depth: 3);
+        area(height: h, width: 1);
+        area(height: side(1), width: side(2));
+        (false or area)(height: 1, width: 2);
+    "#;
+    let calls = calls(source);
+    assert_eq!(calls.iter().filter(|call| call.starts_with("Named")).count(), 5, "{calls:?}");
+}
+
+#[test]
+fn results_are_the_same() {
+    assert_passes(
+        r#"
+        function area(width, height = 2, depth = 1) { return width * height * depth; }
+        function later(p, q) { return p - q; }
+        later = (q, p) -> q * 100 + p;
+        var order = "";
+        function side(x) { order = order + ToString(x); return x; }
+
+        test "known" {
+            AssertEq(area(3, height: 4), 12);
+            AssertEq(area(depth: 5, height: 4, width: 3), 60);
+            function inner(m, n) { return m / n; }
+            var k = 8;
+            AssertEq(inner(n: 2, m: k), 4);
+        }
+
+        test "dynamic" {
+            AssertEq(later(q: 1, p: 2), 102);
+            AssertEq(area(width: 2, depth: 3), 12);
+        }
+
+        test "arguments are evaluated in the order they are written" {
+            AssertEq(area(height: side(1), width: side(2)), 2);
+            AssertEq(order, "12");
+        }
+        "#,
+    );
+}
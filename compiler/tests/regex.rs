@@ -0,0 +1,112 @@
+//! The regex engine behind the regex natives
+
+use compiler::regex::{replace_all, Regex};
+
+fn chars(text: &str) -> Vec<char> {
+    text.chars().collect()
+}
+
+/// Text of the whole first match and of each group, or None if there is no match
+fn first(pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+    let text = chars(text);
+    let captures = Regex::new(pattern).unwrap().captures_at(&text, 0)?;
+    let groups = captures
+        .into_iter()
+        .map(|span| span.map(|(start, end)| text[start..end].iter().collect()))
+        .collect();
+    Some(groups)
+}
+
+/// Text of the whole first match
+fn matched(pattern: &str, text: &str) -> Option<String> {
+    first(pattern, text).map(|mut groups| groups.remove(0).unwrap())
+}
+
+/// Text of every match, from left to right
+fn all(pattern: &str, text: &str) -> Vec<String> {
+    let text = chars(text);
+    Regex::new(pattern)
+        .unwrap()
+        .captures_all(&text)
+        .into_iter()
+        .map(|captures| {
+            let (start, end) = captures[0].unwrap();
+            text[start..end].iter().collect()
+        })
+        .collect()
+}
+
+fn replace(pattern: &str, text: &str, replacement: &str) -> String {
+    replace_all(&Regex::new(pattern).unwrap(), &chars(text), replacement)
+}
+
+#[test]
+fn greedy_and_lazy_quantifiers() {
+    assert_eq!(matched("<.+>", "<a><b>").as_deref(), Some("<a><b>"));
+    assert_eq!(matched("<.+?>", "<a><b>").as_deref(), Some("<a>"));
+    assert_eq!(matched("a*", "aaab").as_deref(), Some("aaa"));
+    assert_eq!(matched("a*?", "aaab").as_deref(), Some(""));
+    assert_eq!(matched("a??b", "ab").as_deref(), Some("ab"));
+    assert_eq!(matched("a{2,3}", "aaaa").as_deref(), Some("aaa"));
+    assert_eq!(matched("a{2,3}?", "aaaa").as_deref(), Some("aa"));
+    assert_eq!(matched("a{2,}", "aaaaa").as_deref(), Some("aaaaa"));
+    assert_eq!(matched("a{2}", "a"), None);
+    // A lazy quantifier still takes as much as the rest of the pattern needs
+    assert_eq!(matched("a+?b", "aaab").as_deref(), Some("aaab"));
+}
+
+#[test]
+fn empty_matches() {
+    assert_eq!(matched("", "abc").as_deref(), Some(""));
+    assert_eq!(matched("x*", "abc").as_deref(), Some(""));
+    assert_eq!(matched("^$", "").as_deref(), Some(""));
+    assert_eq!(matched("^$", "a"), None);
+    // The search steps past empty matches, so each position is tried once
+    assert_eq!(all("x*", "ab"), ["", "", ""]);
+    assert_eq!(all("a*", "baab"), ["", "aa", "", ""]);
+    assert_eq!(replace("x*", "ab", "-"), "-a-b-");
+    assert_eq!(replace("", "", "-"), "-");
+}
+
+#[test]
+fn captures() {
+    assert_eq!(
+        first(r"(\d+)-(\d+)", "from 10-20"),
+        Some(vec![Some("10-20".into()), Some("10".into()), Some("20".into())])
+    );
+    // Groups that don't take part are None, and non-capturing groups are not counted
+    assert_eq!(first("(?:a)(b)|(c)", "c"), Some(vec![Some("c".into()), None, Some("c".into())]));
+    // A repeated group captures its last iteration
+    assert_eq!(first(r"(\w)+", "abc"), Some(vec![Some("abc".into()), Some("c".into())]));
+    assert_eq!(
+        first("((a)|b)+", "ab"),
+        Some(vec![Some("ab".into()), Some("b".into()), Some("a".into())])
+    );
+    assert_eq!(replace(r"(\w+)@(\w+)", "me@host", "$2 at $1, $$$0"), "host at me, $me@host");
+}
+
+#[test]
+fn classes_anchors_and_boundaries() {
+    assert_eq!(all(r"\b\w", "one two_three 4"), ["o", "t", "4"]);
+    assert_eq!(all("[^a-c ]+", "abxyc dz"), ["xy", "dz"]);
+    assert_eq!(all(r"[\d.]+", "1.5 and 20"), ["1.5", "20"]);
+    assert_eq!(matched(r"\Bb\B", "abc").as_deref(), Some("b"));
+    assert_eq!(matched("^b", "ab"), None);
+    assert_eq!(matched("a.c", "a\nc"), None);
+    assert_eq!(matched("é+", "cafééé").as_deref(), Some("ééé"));
+    assert_eq!(matched("a{x}", "a{x}").as_deref(), Some("a{x}"));
+}
+
+#[test]
+fn invalid_patterns() {
+    for pattern in ["(a", "a)", "*", "a{3,2}", "a{1001}", "[a", "(?a)", "^*", "a{2"] {
+        assert!(Regex::new(pattern).is_err(), "{pattern}");
+    }
+}
+
+#[test]
+fn nested_quantifiers_are_linear() {
+    let text = "a".repeat(5000) + "b";
+    assert_eq!(matched("(a*)*c", &text), None);
+    assert_eq!(matched("(a|aa)+$", &text), None);
+}
@@ -0,0 +1,237 @@
+//! The binary format of chunks and programs, read back after being written, and rejected when it is damaged
+
+use compiler::{
+    chunk::Chunk,
+    compiler::{Compiler, CompilerOptions},
+    fun::Fun,
+    interner::Interner,
+    module::{Module, ModuleRegistry, NoModules, MAIN_MODULE},
+    serialize::{deserialize_program, serialize_program},
+};
+use std::rc::Rc;
+
+const PROGRAM: &str = r#"
+enum Color { Red, Green }
+record Point(x, y);
+
+class Counter {
+    init(start) {
+        this.count = start;
+    }
+
+    next() {
+        this.count = this.count + 1;
+        return this.count;
+    }
+}
+
+function describe(point, label = "point", ...rest) {
+    var total = 0;
+    for (var i = 0; i < rest.length(); i = i + 1) {
+        total = total + rest[i];
+    }
+    return Format("{} {} {}", label, point.x + point.y, total);
+}
+
+function counter() {
+    var count = 0;
+    return () -> count = count + 1;
+}
+
+var values = [1, 2.5, "three", nil, true, Color.Green, Point(1, 2), {"key": 1}];
+try {
+    throw "oops";
+} catch (error) {
+    print error;
+}
+print describe(Point(1, 2), label: "sum");
+print Counter(1).next();
+print counter()();
+
+test "adds" {
+    AssertEq(1 + 1, 2);
+}
+"#;
+
+struct Program {
+    interner: Interner,
+    functions: Vec<Fun>,
+    modules: Vec<Module>,
+}
+
+fn compile(source: &str) -> Program {
+    let mut interner = Interner::with_capacity(64);
+    let mut functions = Vec::new();
+    let mut modules = ModuleRegistry::new(Box::new(NoModules));
+    let program = Compiler::compile_program(
+        Rc::from(source),
+        &mut interner,
+        &mut functions,
+        &mut modules,
+        &CompilerOptions::default(),
+    )
+    .unwrap_or_else(|error| panic!("{error}"));
+    functions.push(program.script);
+    modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+    Program {
+        interner,
+        functions,
+        modules: modules.modules,
+    }
+}
+
+fn disassembly(chunk: &Chunk, interner: &Interner) -> String {
+    chunk.disassembly("chunk", interner)
+}
+
+fn names(ids: &[compiler::interner::StrId], interner: &Interner) -> Vec<String> {
+    ids.iter().map(|id| interner.lookup(id).to_string()).collect()
+}
+
+#[test]
+fn chunk_round_trip() {
+    let program = compile(PROGRAM);
+    for fun in &program.functions {
+        // A fresh interner gives the strings other ids, so the chunks are compared by their disassembly
+        let mut interner = Interner::with_capacity(64);
+        let chunk = Chunk::deserialize(&fun.chunk.serialize(&program.interner), &mut interner).unwrap();
+        assert_eq!(disassembly(&chunk, &interner), disassembly(&fun.chunk, &program.interner));
+        assert_eq!(chunk.source_map(), fun.chunk.source_map());
+        assert_eq!(chunk.serialize(&interner), fun.chunk.serialize(&program.interner));
+    }
+}
+
+#[test]
+fn program_round_trip() {
+    let program = compile(PROGRAM);
+    let bytes = serialize_program(&program.functions, &program.modules, &program.interner);
+    let mut interner = Interner::with_capacity(64);
+    let (functions, modules) = deserialize_program(&bytes, &mut interner).unwrap();
+
+    assert_eq!(functions.len(), program.functions.len());
+    for (read, written) in functions.iter().zip(&program.functions) {
+        assert_eq!(
+            (
+                read.arity,
+                read.min_arity,
+                read.is_variadic,
+                read.module,
+                read.is_method,
+                read.is_generator
+            ),
+            (
+                written.arity,
+                written.min_arity,
+                written.is_variadic,
+                written.module,
+                written.is_method,
+                written.is_generator
+            )
+        );
+        assert_eq!(names(&read.param_names, &interner), names(&written.param_names, &program.interner));
+        assert_eq!(
+            read.name.map(|id| interner.lookup(&id).to_string()),
+            written.name.map(|id| program.interner.lookup(&id).to_string())
+        );
+        assert_eq!(read.locals.len(), written.locals.len());
+        assert_eq!(disassembly(&read.chunk, &interner), disassembly(&written.chunk, &program.interner));
+    }
+
+    assert_eq!(modules.len(), program.modules.len());
+    for (read, written) in modules.iter().zip(&program.modules) {
+        assert_eq!((&read.path, read.script), (&written.path, written.script));
+        assert_eq!(names(&read.exports, &interner), names(&written.exports, &program.interner));
+        assert_eq!(names(&read.constants, &interner), names(&written.constants, &program.interner));
+        let tests = |module: &Module| {
+            module
+                .tests
+                .iter()
+                .map(|test| (test.name.clone(), test.fun, test.line))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tests(read), tests(written));
+    }
+
+    // Writing what was read gives the same bytes
+    assert_eq!(serialize_program(&functions, &modules, &interner), bytes);
+}
+
+#[test]
+fn truncated_input_is_rejected() {
+    let program = compile(PROGRAM);
+    let bytes = serialize_program(&program.functions, &program.modules, &program.interner);
+    for len in 0..bytes.len() {
+        assert!(
+            deserialize_program(&bytes[..len], &mut Interner::with_capacity(64)).is_err(),
+            "{len} bytes"
+        );
+    }
+
+    let chunk = program.functions.last().unwrap().chunk.serialize(&program.interner);
+    for len in 0..chunk.len() {
+        assert!(
+            Chunk::deserialize(&chunk[..len], &mut Interner::with_capacity(64)).is_err(),
+            "{len} bytes"
+        );
+    }
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let program = compile(PROGRAM);
+    let mut bytes = serialize_program(&program.functions, &program.modules, &program.interner);
+    bytes.push(0);
+    assert!(deserialize_program(&bytes, &mut Interner::with_capacity(64)).is_err());
+}
+
+#[test]
+fn corrupted_header_is_rejected() {
+    let program = compile(PROGRAM);
+    let bytes = program.functions[0].chunk.serialize(&program.interner);
+
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    let error = Chunk::deserialize(&magic, &mut Interner::with_capacity(64)).unwrap_err();
+    assert_eq!(error.to_string(), "Not a compiled chunk");
+
+    let mut version = bytes.clone();
+    version[4] = version[4].wrapping_add(1);
+    let error = Chunk::deserialize(&version, &mut Interner::with_capacity(64)).unwrap_err();
+    assert!(error.to_string().starts_with("Compiled chunk has version"), "{error}");
+
+    // A chunk is not a program
+    assert!(deserialize_program(&bytes, &mut Interner::with_capacity(64)).is_err());
+}
+
+#[test]
+fn corrupted_code_is_rejected() {
+    let program = compile("var a = 1; print a + 2;");
+    let chunk = &program.functions[0].chunk;
+    let bytes = chunk.serialize(&program.interner);
+    // The code is written after its length
+    let mut written = (chunk.code.len() as u32).to_le_bytes().to_vec();
+    written.extend_from_slice(&chunk.code);
+    let code_start = bytes.windows(written.len()).position(|window| window == written).unwrap() + 4;
+
+    let mut unknown_opcode = bytes.clone();
+    unknown_opcode[code_start] = 0xff;
+    assert!(Chunk::deserialize(&unknown_opcode, &mut Interner::with_capacity(64)).is_err());
+
+    let mut missing_constant = bytes.clone();
+    missing_constant[code_start + 1] = 0xff;
+    assert!(Chunk::deserialize(&missing_constant, &mut Interner::with_capacity(64)).is_err());
+}
+
+#[test]
+fn corrupted_bytes_never_panic() {
+    let program = compile(PROGRAM);
+    let bytes = serialize_program(&program.functions, &program.modules, &program.interner);
+    for index in 0..bytes.len() {
+        for flip in [0x01, 0x80, 0xff] {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= flip;
+            // Some changes, like to the value of a number, still give a valid program
+            let _ = deserialize_program(&corrupted, &mut Interner::with_capacity(64));
+        }
+    }
+}
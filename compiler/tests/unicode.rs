@@ -0,0 +1,79 @@
+//! Tokens of sources with text and names outside of ASCII, whose columns are counted in characters rather than bytes.
+
+use compiler::scanner::{tokenize, TokenType};
+
+/// Type, text, line and column of each token of the source
+fn tokens(source: &str) -> Vec<(TokenType, String, usize, usize)> {
+    tokenize(source)
+        .into_iter()
+        .filter(|token| token.typ != TokenType::EOF)
+        .map(|token| (token.typ, token.source.to_string(), token.line, token.column))
+        .collect()
+}
+
+#[test]
+fn emoji_string() {
+    assert_eq!(
+        tokens("print \"🎉 done\" + \"👍\";"),
+        [
+            (TokenType::Print, "print".to_string(), 1, 1),
+            (TokenType::String, "\"🎉 done\"".to_string(), 1, 7),
+            (TokenType::Plus, "+".to_string(), 1, 16),
+            (TokenType::String, "\"👍\"".to_string(), 1, 18),
+            (TokenType::Semicolon, ";".to_string(), 1, 21),
+        ]
+    );
+}
+
+#[test]
+fn cyrillic_and_cjk_identifiers() {
+    assert_eq!(
+        tokens("var имя = 1;\n  print 名前 + имя;"),
+        [
+            (TokenType::Var, "var".to_string(), 1, 1),
+            (TokenType::Identifier, "имя".to_string(), 1, 5),
+            (TokenType::Equal, "=".to_string(), 1, 9),
+            (TokenType::Number, "1".to_string(), 1, 11),
+            (TokenType::Semicolon, ";".to_string(), 1, 12),
+            (TokenType::Print, "print".to_string(), 2, 3),
+            (TokenType::Identifier, "名前".to_string(), 2, 9),
+            (TokenType::Plus, "+".to_string(), 2, 12),
+            (TokenType::Identifier, "имя".to_string(), 2, 14),
+            (TokenType::Semicolon, ";".to_string(), 2, 17),
+        ]
+    );
+}
+
+#[test]
+fn columns_after_comment_with_emoji() {
+    assert_eq!(
+        tokens("// 😀 и 中\nвар;"),
+        [
+            (TokenType::Identifier, "вар".to_string(), 2, 1),
+            (TokenType::Semicolon, ";".to_string(), 2, 4),
+        ]
+    );
+}
+
+#[test]
+fn string_spanning_lines() {
+    // The line of a string is the one it ends on, and its column is on the line it starts on
+    assert_eq!(
+        tokens("var é = \"а\n😀б\"; é"),
+        [
+            (TokenType::Var, "var".to_string(), 1, 1),
+            (TokenType::Identifier, "é".to_string(), 1, 5),
+            (TokenType::Equal, "=".to_string(), 1, 7),
+            (TokenType::String, "\"а\n😀б\"".to_string(), 2, 9),
+            (TokenType::Semicolon, ";".to_string(), 2, 4),
+            (TokenType::Identifier, "é".to_string(), 2, 6),
+        ]
+    );
+}
+
+#[test]
+fn unexpected_character_after_unicode() {
+    let tokens = tokens("имя ⌘");
+    assert_eq!(tokens[1].0, TokenType::Error);
+    assert_eq!((tokens[1].2, tokens[1].3), (1, 5));
+}
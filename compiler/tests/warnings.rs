@@ -0,0 +1,30 @@
+//! Warnings are returned to the host with the result instead of being printed
+
+use compiler::{compile_code, compiler::CompilerOptions, diagnostic::result_json, module::NoModules, run_code, RunResult};
+use futures::executor::block_on;
+
+const SOURCE: &str = "function f() { var unused = 1; return 2; } f();";
+
+#[test]
+fn compile_code_returns_warnings() {
+    let program = compile_code(SOURCE, NoModules, &CompilerOptions::default()).unwrap();
+    let codes: Vec<String> = program.warnings.iter().map(|warning| warning.code.to_string()).collect();
+    assert_eq!(codes, ["W0001"]);
+}
+
+#[test]
+fn result_json_lists_warnings() {
+    let RunResult { warnings, result } = block_on(run_code(SOURCE, NoModules, |_| async { String::new() }));
+    assert!(result.is_ok());
+    let json = result_json(&result, &warnings);
+    assert!(
+        json.starts_with(r#"{"ok":true,"diagnostics":[{"severity":"warning","code":"W0001","#),
+        "{json}"
+    );
+
+    // A program that does not compile lists its errors
+    let RunResult { warnings, result } = block_on(run_code("var;", NoModules, |_| async { String::new() }));
+    assert!(warnings.is_empty());
+    let json = result_json(&result, &warnings);
+    assert!(json.starts_with(r#"{"ok":false,"diagnostics":[{"severity":"error""#), "{json}");
+}
@@ -103,6 +103,9 @@ function loadSamples() {
         }, {
             name: "Trace Back",
             code: "sample_programs/traceback.lox",
+        }, {
+            name: "Unicode",
+            code: "sample_programs/unicode.lox",
         }
     ]
 
@@ -1,5 +1,20 @@
-use compiler::{init, run_code};
+use compiler::{
+    ast::parse_to_ast,
+    compile_code,
+    compiler::CompilerOptions,
+    diagnostic::{render_error, result_json},
+    formatter::format_source,
+    init,
+    lint::{Level, Lints},
+    module::ModuleLoader,
+    register::Backend,
+    run_bytecode_with, run_program, run_tests,
+    vm::VmOptions,
+};
 use futures::executor;
+use std::path::{Path, PathBuf};
+
+mod repl;
 
 #[cfg(debug_assertions)]
 fn flush_if_debug() {
@@ -21,7 +36,29 @@ fn println(output: String) {
 }
 
 fn help(args: &[String]) {
-    println(format!("Usage: {} <FILE> \nInterpret the program in FILE", args[0]));
+    println(format!(
+        "Usage: {} [--test] [--print-code] [--trace] [--registers] [-O] [--warn-removed] [--lint] [--json] [--format] [--ast] [--assemble] [FILE] \nInterpret the program in FILE, or run the tests it declares with --test.\nWith --print-code, print the bytecode before running, and with --trace, each instruction as it runs.\nWith --registers, run the functions that can be on the register backend.\nWith -O, fold constant expressions, remove code that can never run and optimize the bytecode, and with --warn-removed, warn about the code it removes.\nWith --lint, warn about code that breaks the lints.\nWith --json, print the result as JSON after the output of the program, with the compile errors or the runtime error.\nWith --format, print the program in FILE formatted instead of running it.\nWith --ast, print its syntax tree as JSON instead of running it.\nWith --assemble, run FILE as textual bytecode, in the format --print-code prints.\nWithout a FILE, start an interactive session.",
+        args[0]
+    ));
+}
+
+/// Loads imported modules from files, relative to the directory of the main program
+struct FileLoader {
+    base_dir: PathBuf,
+}
+
+impl FileLoader {
+    fn for_program(path: &Path) -> FileLoader {
+        FileLoader {
+            base_dir: path.parent().map(PathBuf::from).unwrap_or_default(),
+        }
+    }
+}
+
+impl ModuleLoader for FileLoader {
+    fn load(&self, path: &str) -> anyhow::Result<String> {
+        Ok(std::fs::read_to_string(self.base_dir.join(path))?)
+    }
 }
 
 async fn read_async(prompt: String) -> String {
@@ -34,19 +71,130 @@ async fn read_async(prompt: String) -> String {
 }
 
 fn main() {
-    init(print, println);
-
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        help(&args);
-        std::process::exit(1);
+
+    // Talk the Debug Adapter Protocol with an editor over stdin and stdout
+    #[cfg(feature = "dap")]
+    if args.len() == 2 && args[1] == "--dap" {
+        init(compiler::dap::print, compiler::dap::println);
+        executor::block_on(compiler::dap::serve(FileLoader::for_program)).expect("Failed to talk to the editor");
+        return;
+    }
+
+    // Talk the Language Server Protocol with an editor over stdin and stdout
+    #[cfg(feature = "lsp")]
+    if args.len() == 2 && args[1] == "--lsp" {
+        compiler::lsp::serve().expect("Failed to talk to the editor");
+        return;
     }
 
-    if args[1] == "-h" || args[1] == "--help" {
+    init(print, println);
+    if args.len() == 2 && (args[1] == "-h" || args[1] == "--help") {
         help(&args);
         std::process::exit(0);
     }
 
-    let input = std::fs::read_to_string(&args[1]).expect("Failed to read file");
-    executor::block_on(run_code(&input, read_async));
+    let mut test = false;
+    let mut json = false;
+    let mut format = false;
+    let mut ast = false;
+    let mut assemble = false;
+    let mut options = VmOptions::default();
+    let mut compiler_options = CompilerOptions::default();
+    let mut paths = Vec::new();
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "--test" => test = true,
+            "--print-code" => options.print_code = true,
+            "--trace" => options.trace_execution = true,
+            "--registers" => compiler_options.backend = Backend::Register,
+            "-O" => compiler_options.opt_level = 1,
+            "--warn-removed" => compiler_options.warnings.removed_code = true,
+            "--lint" => compiler_options.lints = Lints::all(Level::Warn),
+            "--json" => json = true,
+            "--format" => format = true,
+            "--ast" => ast = true,
+            "--assemble" => assemble = true,
+            _ => paths.push(arg),
+        }
+    }
+    let path = match paths.as_slice() {
+        [] if args.len() == 1 => {
+            repl::run();
+            return;
+        }
+        [path] => path,
+        _ => {
+            help(&args);
+            std::process::exit(1);
+        }
+    };
+
+    let input = std::fs::read_to_string(path).expect("Failed to read file");
+    if format {
+        match format_source(&input) {
+            Ok(formatted) => print(formatted),
+            Err(error) => {
+                println(render_error(&error, &input));
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if ast {
+        match parse_to_ast(&input) {
+            Ok(program) => println(program.to_json()),
+            Err(error) => {
+                println(error.render(&input).trim_end().to_string());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let loader = FileLoader::for_program(Path::new(path));
+    if assemble {
+        let setup = |vm: &mut compiler::vm::Vm<_, _>| vm.configure(options);
+        if let Err(error) = executor::block_on(run_bytecode_with(&input, loader, read_async, setup)) {
+            println(error.to_string());
+            std::process::exit(1);
+        }
+        return;
+    }
+    if !test {
+        let setup = |vm: &mut compiler::vm::Vm<_, _>| vm.configure(options);
+        let (result, warnings) = match compile_code(&input, loader, &compiler_options) {
+            Ok(mut program) => {
+                let warnings = std::mem::take(&mut program.warnings);
+                if !json {
+                    for warning in &warnings {
+                        println(warning.render(&input).trim_end().to_string());
+                    }
+                }
+                (executor::block_on(run_program(program, read_async, setup)), warnings)
+            }
+            Err(error) => (Err(error.into()), Vec::new()),
+        };
+        if json {
+            println(result_json(&result, &warnings));
+        }
+        if let Err(error) = result {
+            if !json {
+                println(render_error(&error, &input));
+            }
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let report = match executor::block_on(run_tests(&input, loader, read_async)) {
+        Ok(report) => report,
+        Err(error) => {
+            println(render_error(&error, &input));
+            std::process::exit(1);
+        }
+    };
+    println(report.to_string());
+    if !report.all_passed() {
+        std::process::exit(1);
+    }
 }
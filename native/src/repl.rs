@@ -0,0 +1,66 @@
+use crate::{println, read_async, FileLoader};
+use compiler::{
+    diagnostic::render_error,
+    session::{needs_more_input, Session},
+    value::Value,
+};
+use futures::executor;
+use std::io::{BufRead, Write};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+fn prompt(text: &str) {
+    print!("{}", text);
+    std::io::stdout().flush().unwrap();
+}
+
+/// Read snippets from stdin and run them in one session, printing the values of expressions.
+/// Lines are collected until brackets and strings are closed. `:history` lists the snippets so far, `:quit` exits.
+pub fn run() {
+    let mut session = Session::new(
+        FileLoader {
+            base_dir: Default::default(),
+        },
+        read_async,
+    );
+    let mut history: Vec<String> = Vec::new();
+    let mut snippet = String::new();
+    let mut lines = std::io::stdin().lock().lines();
+
+    loop {
+        prompt(if snippet.is_empty() { PROMPT } else { CONTINUATION_PROMPT });
+        let Some(Ok(line)) = lines.next() else {
+            println(String::new());
+            break;
+        };
+
+        if snippet.is_empty() {
+            match line.trim() {
+                "" => continue,
+                ":quit" => break,
+                ":history" => {
+                    for (idx, entry) in history.iter().enumerate() {
+                        println(format!("{:4}  {}", idx + 1, entry.replace('\n', "\n      ")));
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        snippet.push_str(&line);
+        snippet.push('\n');
+        if needs_more_input(&snippet) {
+            continue;
+        }
+
+        let source = std::mem::take(&mut snippet);
+        history.push(source.trim_end().to_string());
+        match executor::block_on(session.eval(&source)) {
+            Ok(Value::Nil) => {}
+            Ok(value) => println(session.format_value(&value)),
+            Err(error) => println(render_error(&error, &source)),
+        }
+    }
+}
@@ -1,9 +1,46 @@
-use compiler::{init, run_code};
-use std::{panic, sync::atomic::AtomicBool};
+use compiler::{
+    ast,
+    cache::CompileCache,
+    clock::{HostClock, SystemClock},
+    compiler::{Compiler, CompilerOptions},
+    diagnostic::{render_error, result_json, Diagnostic},
+    disassemble_to_json, formatter,
+    fun::Fun,
+    highlight, init,
+    interner::Interner,
+    module::{Module, ModuleLoader, ModuleRegistry, MAIN_MODULE},
+    native::{AsyncValue, NativeFuture},
+    options::RunOptions,
+    run_bytecode_with, run_code_with, run_compiled,
+    value::Value,
+    vm::{Vm, VmOptions},
+    RunResult,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::{poll_fn, Future},
+    panic,
+    pin::pin,
+    rc::Rc,
+    sync::atomic::AtomicBool,
+};
 use wasm_bindgen::prelude::*;
 
 static COMPILER_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+thread_local! {
+    // Virtual files that programs can import, added from JS
+    static MODULES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+    // Programs run before, so that running one again doesn't compile it again
+    static COMPILE_CACHE: RefCell<CompileCache> = RefCell::new(CompileCache::new());
+    // Programs compiled with `Engine::compile`, by their handle, until they run
+    static PROGRAMS: RefCell<HashMap<u32, Program>> = RefCell::new(HashMap::new());
+    static NEXT_HANDLE: Cell<u32> = const { Cell::new(0) };
+    // Output of the program whose run is being polled, see `capture_output`
+    static CAPTURED: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
 // Called when the wasm module is instantiated
 #[wasm_bindgen(start)]
 fn main() -> Result<(), JsValue> {
@@ -24,18 +61,406 @@ extern "C" {
     pub fn end();
     pub async fn sleep(ms: u32);
     pub async fn readAsync(text: String) -> JsValue;
+    #[wasm_bindgen(catch)]
+    pub async fn fetchText(url: String) -> Result<JsValue, JsValue>;
 }
 
 async fn read_async(text: String) -> String {
     readAsync(text).await.as_string().unwrap_or_default()
 }
 
+/// `Sleep(ms)` waits on a JS timer, so the page stays responsive
+fn sleep_native(_: &mut compiler::interner::Interner, args: &[Value]) -> NativeFuture {
+    let ms = args[0].as_number();
+    Box::pin(async move {
+        let ms = ms.ok_or_else(|| anyhow::anyhow!("Expected number as argument to sleep"))?;
+        sleep(ms as u32).await;
+        Ok(AsyncValue::Value(Value::Nil))
+    })
+}
+
+/// `Fetch(url)` gets the body of the response as a string
+fn fetch_native(interner: &mut compiler::interner::Interner, args: &[Value]) -> NativeFuture {
+    let url = match &args[0] {
+        Value::Str(url) => Some(interner.lookup(url).to_string()),
+        _ => None,
+    };
+    Box::pin(async move {
+        let url = url.ok_or_else(|| anyhow::anyhow!("Expected string as argument to fetch"))?;
+        let body = fetchText(url)
+            .await
+            .map_err(|err| anyhow::anyhow!("{}", err.as_string().unwrap_or_default()))?;
+        Ok(AsyncValue::Str(body.as_string().unwrap_or_default()))
+    })
+}
+
+/// Loads imported modules from the virtual files added with `add_module`
+struct VirtualFileLoader;
+
+impl ModuleLoader for VirtualFileLoader {
+    fn load(&self, path: &str) -> anyhow::Result<String> {
+        MODULES
+            .with_borrow(|modules| modules.get(path).cloned())
+            .ok_or_else(|| anyhow::anyhow!("No such module"))
+    }
+}
+
+/// Make a module available to `import` statements under the given path
+#[wasm_bindgen]
+pub fn add_module(path: String, source: String) {
+    MODULES.with_borrow_mut(|modules| modules.insert(path, source));
+}
+
+#[wasm_bindgen]
+pub fn clear_modules() {
+    MODULES.with_borrow_mut(|modules| modules.clear());
+}
+
 #[wasm_bindgen]
 pub async fn run(code: &str) {
+    run_with_options(code, false, false).await
+}
+
+/// Print with the JS functions, the first time a program runs
+fn init_compiler() {
     if !COMPILER_INITIALIZED.load(std::sync::atomic::Ordering::Relaxed) {
         COMPILER_INITIALIZED.store(true, std::sync::atomic::Ordering::Relaxed);
-        init(print, println);
+        init(print_or_capture, println_or_capture);
+    }
+}
+
+/// Like `run`, but can print the bytecode before the program runs, and each instruction as it runs
+#[wasm_bindgen]
+pub async fn run_with_options(code: &str, print_code: bool, trace_execution: bool) {
+    init_compiler();
+
+    let setup = |vm: &mut compiler::vm::Vm<_, _>| {
+        vm.configure(VmOptions {
+            print_code,
+            trace_execution,
+            ..Default::default()
+        });
+        vm.define_async_native("Sleep", 1, sleep_native);
+        vm.define_async_native("Fetch", 1, fetch_native);
+    };
+    if let Err(error) = run_cached(code, &CompilerOptions::default(), read_async, setup).await {
+        println(render_error(&error, code));
+    }
+}
+
+/// Like `run`, with the options of `compiler::options::RunOptions::from_json`, like `{"optLevel":1,"traceExecution":true}`,
+/// so that every mode of the playground runs with the same build
+#[wasm_bindgen]
+pub async fn run_with_config(code: &str, options: &str) {
+    init_compiler();
+
+    let options = match RunOptions::from_json(options) {
+        Ok(options) => options,
+        Err(error) => return println(error),
+    };
+    let setup = |vm: &mut compiler::vm::Vm<_, _>| {
+        vm.configure(options.vm);
+        vm.define_async_native("Sleep", 1, sleep_native);
+        vm.define_async_native("Fetch", 1, fetch_native);
+    };
+    if let Err(error) = run_cached(code, &options.compiler, read_async, setup).await {
+        println(render_error(&error, code));
+    }
+}
+
+/// Compile the program, or take it from the compile cache if it ran before, and run it
+async fn run_cached<F, Fut>(
+    code: &str,
+    options: &CompilerOptions,
+    read_async: F,
+    setup: impl FnOnce(&mut compiler::vm::Vm<F, Fut>),
+) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = String>,
+{
+    let program = COMPILE_CACHE.with_borrow_mut(|cache| cache.compile(code, VirtualFileLoader, options))?;
+    for warning in &program.warnings {
+        println(warning.clone());
+    }
+    run_compiled(program, read_async, setup).await
+}
+
+/// How often programs were taken from the compile cache, as JSON, like `{"hits":3,"misses":1,"entries":1}`
+#[wasm_bindgen]
+pub fn compile_cache_stats() -> String {
+    COMPILE_CACHE.with_borrow(|cache| cache.stats().to_json())
+}
+
+/// The compile cache, for the page to save, like in IndexedDB, and load with `import_compile_cache` the next time
+#[wasm_bindgen]
+pub fn export_compile_cache() -> Vec<u8> {
+    COMPILE_CACHE.with_borrow(|cache| cache.to_bytes())
+}
+
+/// Replace the compile cache with one saved with `export_compile_cache`
+#[wasm_bindgen]
+pub fn import_compile_cache(bytes: &[u8]) -> Result<(), JsValue> {
+    let cache = CompileCache::from_bytes(bytes).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    COMPILE_CACHE.set(cache);
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn clear_compile_cache() {
+    COMPILE_CACHE.with_borrow_mut(|cache| cache.clear());
+}
+
+/// Run textual bytecode, in the format the bytecode is printed in with `print_code`, as the script of a program
+#[wasm_bindgen]
+pub async fn run_bytecode(code: &str, trace_execution: bool) {
+    init_compiler();
+
+    let setup = |vm: &mut compiler::vm::Vm<_, _>| {
+        vm.configure(VmOptions {
+            trace_execution,
+            ..Default::default()
+        });
+        vm.define_async_native("Sleep", 1, sleep_native);
+        vm.define_async_native("Fetch", 1, fetch_native);
+    };
+    if let Err(error) = run_bytecode_with(code, VirtualFileLoader, read_async, setup).await {
+        println(error.to_string());
+    }
+}
+
+/// The instructions of each function of the program as JSON, see `compiler::disassemble_to_json`, or its compile errors
+/// rendered as text. With `optimize`, the program is compiled with optimization level 1.
+#[wasm_bindgen]
+pub fn disassemble(code: &str, optimize: bool) -> Result<String, JsValue> {
+    let options = CompilerOptions {
+        opt_level: optimize as u8,
+        ..Default::default()
+    };
+    disassemble_to_json(code, VirtualFileLoader, &options).map_err(|error| JsValue::from_str(&render_error(&error, code)))
+}
+
+/// The program formatted with canonical indentation and spacing, or its compile errors rendered as text
+#[wasm_bindgen]
+pub fn format_source(code: &str) -> Result<String, JsValue> {
+    formatter::format_source(code).map_err(|error| JsValue::from_str(&render_error(&error, code)))
+}
+
+/// The highlighted tokens of the program as JSON, see `compiler::highlight::semantic_tokens_json`. It works on any
+/// code, even code that doesn't compile.
+#[wasm_bindgen]
+pub fn semantic_tokens(code: &str) -> String {
+    highlight::semantic_tokens_json(code)
+}
+
+/// The syntax tree of the program as JSON, see `compiler::ast::Program::to_json`, or its compile errors rendered as text
+#[wasm_bindgen]
+pub fn parse_to_ast(code: &str) -> Result<String, JsValue> {
+    ast::parse_to_ast(code)
+        .map(|program| program.to_json())
+        .map_err(|error| JsValue::from_str(error.render(code).trim_end()))
+}
+
+/// Like `run`, but instead of printing errors and warnings, returns them as JSON, see `compiler::diagnostic::result_json`
+#[wasm_bindgen]
+pub async fn run_json(code: &str) -> String {
+    init_compiler();
+
+    let setup = |vm: &mut compiler::vm::Vm<_, _>| {
+        vm.define_async_native("Sleep", 1, sleep_native);
+        vm.define_async_native("Fetch", 1, fetch_native);
+    };
+    let RunResult { warnings, result } = run_code_with(code, VirtualFileLoader, read_async, setup).await;
+    result_json(&result, &warnings)
+}
+
+/// Keep the output if `Engine::run` is running a program, and print it otherwise
+fn print_or_capture(output: String) {
+    if !capture(&output) {
+        print(output);
+    }
+}
+
+fn println_or_capture(output: String) {
+    if !capture(&format!("{output}\n")) {
+        println(output);
+    }
+}
+
+fn capture(output: &str) -> bool {
+    CAPTURED.with_borrow_mut(|captured| captured.as_mut().map(|captured| captured.push_str(output)).is_some())
+}
+
+/// Run the future, and return what it printed instead of printing it. Its output is kept in `CAPTURED` only while it is
+/// polled, so that runs waiting on `Sleep` or `Fetch` at the same time each get their own output.
+async fn capture_output<T>(future: impl Future<Output = T>) -> (T, String) {
+    let mut future = pin!(future);
+    let mut output = String::new();
+    let result = poll_fn(|cx| {
+        let _guard = CaptureGuard::new(&mut output);
+        future.as_mut().poll(cx)
+    })
+    .await;
+    (result, output)
+}
+
+/// Puts the output of a run in `CAPTURED`, and takes it back with the one before restored when dropped, also when the
+/// run panics
+struct CaptureGuard<'a> {
+    output: &'a mut String,
+    previous: Option<String>,
+}
+
+impl<'a> CaptureGuard<'a> {
+    fn new(output: &'a mut String) -> CaptureGuard<'a> {
+        let previous = CAPTURED.replace(Some(std::mem::take(output)));
+        CaptureGuard { output, previous }
     }
+}
+
+impl Drop for CaptureGuard<'_> {
+    fn drop(&mut self) {
+        *self.output = CAPTURED.replace(self.previous.take()).unwrap_or_default();
+    }
+}
+
+/// A program compiled with `Engine::compile`, waiting to run
+struct Program {
+    interner: Interner,
+    functions: Vec<Fun>,
+    modules: Vec<Module>,
+    vm: VmOptions,
+}
+
+/// Result of `Engine::compile`. `diagnostics` are objects of `compiler::diagnostic::Diagnostic::to_json`, with the
+/// warnings of a program that compiled, or the errors of one that didn't. Other errors, like bad options, are in `error`.
+#[wasm_bindgen(getter_with_clone)]
+pub struct JsResult {
+    pub ok: bool,
+    pub handle: Option<u32>, // For `Engine::run`, if the program compiled
+    pub diagnostics: JsValue,
+    pub error: Option<String>,
+    #[wasm_bindgen(js_name = compileMs)]
+    pub compile_ms: f64,
+}
+
+/// Result of `Engine::run`, with what the program printed. `runtimeError` is an object of
+/// `compiler::error::RuntimeError::to_json`, or null, and other errors, like an unknown handle, are in `error`.
+#[wasm_bindgen(getter_with_clone)]
+pub struct JsRunResult {
+    pub ok: bool,
+    pub output: String,
+    #[wasm_bindgen(js_name = runtimeError)]
+    pub runtime_error: JsValue,
+    pub error: Option<String>,
+    #[wasm_bindgen(js_name = runMs)]
+    pub run_ms: f64,
+}
+
+/// Compiling and running programs as separate steps, with results as objects instead of printed text:
+///
+/// ```js
+/// const compiled = Engine.compile(code, '{"optLevel":1}');
+/// if (compiled.ok) {
+///     const result = await Engine.run(compiled.handle);
+///     show(result.output, result.runtimeError);
+/// }
+/// ```
+#[wasm_bindgen]
+pub struct Engine;
+
+#[wasm_bindgen]
+impl Engine {
+    /// Compile the program with the options of `compiler::options::RunOptions::from_json`, if there are any. The
+    /// options of the VM are used when it runs.
+    pub fn compile(code: &str, options: Option<String>) -> JsResult {
+        init_compiler();
+
+        let mut result = JsResult {
+            ok: false,
+            handle: None,
+            diagnostics: diagnostics_to_js(&[]),
+            error: None,
+            compile_ms: 0.0,
+        };
+        let options = match options.as_deref().map_or(Ok(RunOptions::default()), RunOptions::from_json) {
+            Ok(options) => options,
+            Err(error) => {
+                result.error = Some(error);
+                return result;
+            }
+        };
+
+        let clock = SystemClock::default();
+        let mut interner = Interner::with_capacity(1024);
+        let mut functions = Vec::new();
+        let mut modules = ModuleRegistry::new(Box::new(VirtualFileLoader));
+        let compiled = Compiler::compile_program(Rc::from(code), &mut interner, &mut functions, &mut modules, &options.compiler);
+        result.compile_ms = clock.monotonic_seconds() * 1000.0;
+        match compiled {
+            Ok(compiled) => {
+                functions.push(compiled.script);
+                modules.modules[MAIN_MODULE].script = Some(functions.len() - 1);
+                let handle = NEXT_HANDLE.replace(NEXT_HANDLE.get().wrapping_add(1));
+                let program = Program {
+                    interner,
+                    functions,
+                    modules: modules.modules,
+                    vm: options.vm,
+                };
+                PROGRAMS.with_borrow_mut(|programs| programs.insert(handle, program));
+                result.ok = true;
+                result.handle = Some(handle);
+                result.diagnostics = diagnostics_to_js(&compiled.warnings);
+            }
+            Err(error) => result.diagnostics = diagnostics_to_js(&error.diagnostics),
+        }
+        result
+    }
+
+    /// Run a program compiled with `compile`. A program runs once, since running it changes it, like its globals, so
+    /// its handle can't be used again after.
+    pub async fn run(handle: u32) -> JsRunResult {
+        let mut result = JsRunResult {
+            ok: false,
+            output: String::new(),
+            runtime_error: JsValue::NULL,
+            error: None,
+            run_ms: 0.0,
+        };
+        let Some(mut program) = PROGRAMS.with_borrow_mut(|programs| programs.remove(&handle)) else {
+            result.error = Some(format!("No compiled program has handle {handle}"));
+            return result;
+        };
+
+        let mut vm = Vm::new(&mut program.interner, program.functions, program.modules, read_async);
+        vm.configure(program.vm);
+        vm.define_async_native("Sleep", 1, sleep_native);
+        vm.define_async_native("Fetch", 1, fetch_native);
+        let clock = SystemClock::default();
+        let (outcome, output) = capture_output(vm.interpret()).await;
+        result.run_ms = clock.monotonic_seconds() * 1000.0;
+        result.output = output;
+
+        match outcome {
+            Ok(_) => result.ok = true,
+            Err(error) => result.runtime_error = json_to_js(&error.to_json()),
+        }
+        result
+    }
+
+    /// Forget a compiled program that won't be run
+    pub fn release(handle: u32) {
+        PROGRAMS.with_borrow_mut(|programs| programs.remove(&handle));
+    }
+}
+
+fn diagnostics_to_js(diagnostics: &[Diagnostic]) -> JsValue {
+    let diagnostics: Vec<String> = diagnostics.iter().map(Diagnostic::to_json).collect();
+    json_to_js(&format!("[{}]", diagnostics.join(",")))
+}
 
-    run_code(code, read_async).await;
+/// JSON made by the compiler as a JS value
+fn json_to_js(json: &str) -> JsValue {
+    js_sys::JSON::parse(json).expect("Compiler made JSON that is not valid")
 }
@@ -1,6 +1,6 @@
 console.log("Snippets init inside worker");
 
-async function sleep(ms) {
+export async function sleep(ms) {
     return new Promise(resolve => setTimeout(resolve, ms));
 }
 
@@ -34,6 +34,15 @@ export async function readAsync(text) {
     return input;
 }
 
+// Resolves to the body of the response, or rejects with a message if the request fails
+export async function fetchText(url) {
+    const response = await fetch(url);
+    if (!response.ok) {
+        throw `Request to ${url} failed with status ${response.status}`;
+    }
+    return await response.text();
+}
+
 export function end() {
     postMessage({
         type: "run-end"